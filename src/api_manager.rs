@@ -1,64 +1,815 @@
-use std::collections::HashMap;
-use crate::{data_structures::{AppState, ErrorResponse, TradeInstruction, TradeResponse}, trade_executor::TradeExecutor};
-use actix_web::{web::{self}, HttpResponse};
+use std::{collections::{BTreeMap, HashMap}, time::Duration};
+use crate::{charges::{order_charges, round_trip, Segment}, basket::{check_margin, place_basket, BasketLeg, BasketReport, BasketStatus, MAX_BASKET_LEGS}, errors::{error_response, request_error_response}, expiry_calendar::ExpiryCalendar, rollover::RollStatus, market_time::ist, option_chain::{build_chain, underlying_key, ChainContracts, OptionChain}, option_strategy::build_strategy, option_pricing::{trading_years, ExerciseStyle, OptionInputs, OptionKind}, option_risk, portfolio::{self, Funds, OpenOrder, PortfolioHoldings, PortfolioPositions, PositionBook}, execution_algos::{AlgoError, Control}, idempotency::{validate_key, Claim, Reservation}, instrument_master::{Instrument, InstrumentMaster}, kite_client::KiteClient, market_protection::MarketQuote, market_data::{fetch_ticks, quote_keys, MAX_QUOTE_KEYS, QuoteBatch, QuoteKind, SourcedTick}, order_book::{analyse, DEFAULT_DEPTH_BAND_PCT}, data_structures::{AlgoRequest, AppState, BasketRequest, ChainQuery, ChargesRequest, CreateWatchlistRequest, ErrorResponse, PerformanceEntry, PricingRequest, PricingResponse, ProposalDecision, QuoteResponse, RankingQuery, RankingResponse, RolloverQuery, StrategyRequest, StreamCommand, StreamQuery, TradeInstruction, TradePreview, TradeResponse, WatchlistSymbolsRequest}, proposals::{DecisionError, Proposal}, tick_stream::TickSubscription, trade_executor::{PartialPlacement, TradeExecutor}, watchlist::DEFAULT_WATCHLIST};
+use futures_util::{stream, StreamExt};
+use actix_web::{body::to_bytes, web::{self}, HttpRequest, HttpResponse};
 use chrono::Utc;
 use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
 
-pub async fn execute_trade(app_state: web::Data<AppState>, instruction: web::Json<TradeInstruction>) -> HttpResponse {
-
-    let mut auth_manager = app_state.auth_manager.lock().unwrap();
+// The Kite client for a handler that can't do anything without a valid session
+async fn require_session(app_state: &AppState) -> Result<KiteClient, HttpResponse> {
+    let mut auth_manager = app_state.auth_manager.lock().await;
 
     if !auth_manager.is_token_valid() {
-        return HttpResponse::Unauthorized().json(ErrorResponse {
-            status: "Error".to_string(),
-            message: "Authentication token invalid or not found..".to_string()
-        });
+        return Err(HttpResponse::Unauthorized().json(ErrorResponse::new(
+            "session_expired",
+            "Authentication token invalid or not found..".to_string()
+        )));
     }
+    Ok(auth_manager.get_kite().clone())
+}
+
+/// Header an agent names itself with; it is recorded as the proposer of anything it sends.
+pub const AGENT_ID_HEADER: &str = "X-Agent-Id";
+/// Header carrying one of the `PROPOSAL_APPROVERS` keys.
+pub const APPROVER_KEY_HEADER: &str = "X-Approver-Key";
 
-    let kite = auth_manager.get_kite();
+pub async fn execute_trade(app_state: web::Data<AppState>, req: HttpRequest, instruction: web::Json<TradeInstruction>) -> HttpResponse {
+
+    let kite = match require_session(&app_state).await {
+        Ok(kite) => kite,
+        Err(response) => return response
+    };
     let mut final_instruction = instruction.0.clone();
 
     if final_instruction.symbol == "BEST PERFORMER" {
-        let mut market_data = app_state.market_data.lock().unwrap();
-        match market_data.best_performer(final_instruction.timeframe.unwrap_or(20)).await {
+        let watchlist = final_instruction.watchlist.as_deref().unwrap_or(DEFAULT_WATCHLIST);
+        // Only the snapshot is taken under the lock, the historical calls run without it
+        let ranking = app_state.market_data.lock().await.ranking(watchlist);
+        let best = match ranking {
+            Ok(ranking) => ranking.best_performer(final_instruction.timeframe.unwrap_or(20)).await,
+            Err(e) => Err(e)
+        };
+        match best {
             Ok(symbol) => {
                 final_instruction.symbol = symbol;
                 return HttpResponse::Ok().json(final_instruction.symbol.clone())
             },
             Err(e) => {
-                return HttpResponse::BadRequest().json(ErrorResponse {
-                status: "Error".to_string(),
-                message: format!("Failed to find out best performant stock: {}", e)
-                });
+                return ranking_error("Failed to find out best performant stock", e)
+            }
+        }
+    }
+
+    // The key is claimed before anything is proposed, so a retry finds the first proposal or order
+    let reservation = match claim_key(&app_state, &kite, &final_instruction, None).await {
+        Ok(Claimed::New(reservation)) => reservation,
+        Ok(Claimed::Answered(placement)) => return placement.into_response(),
+        Ok(Claimed::Proposed(proposal)) => return proposal_pending(*proposal, true),
+        Err(response) => return response
+    };
+
+    // Orders above the notional limit wait for a second party instead of going straight out
+    if final_instruction.action != "cancel" && app_state.proposals.has_notional_limit() {
+        let preview = match build_preview(&app_state, kite.clone(), true, &final_instruction).await {
+            Ok(preview) => preview,
+            Err(response) => return response
+        };
+        if app_state.proposals.requires_confirmation(preview.notional) {
+            let proposal = app_state.proposals.submit(final_instruction, preview, Some(caller(&app_state, &req)));
+            if let Some(reservation) = reservation {
+                reservation.propose(&proposal.id);
             }
+            println!("Order for {} held as proposal {} pending confirmation", proposal.instruction.symbol, proposal.id);
+            return proposal_pending(proposal, false);
         }
     }
 
-    let mut exeucutor = TradeExecutor::new(kite);
+    match send_trade(&app_state, kite, final_instruction, reservation).await {
+        Ok(placement) => placement.into_response(),
+        Err(response) => response
+    }
+}
 
-    match exeucutor.execute_instructions(&final_instruction) {
-        Ok(order_id) => {
-            HttpResponse::Ok().json(TradeResponse {
-                order_id,
+fn proposal_pending(proposal: Proposal, replayed: bool) -> HttpResponse {
+    let mut response = HttpResponse::Accepted();
+    if replayed {
+        response.insert_header(("Idempotent-Replayed", "true"));
+    }
+    response.json(json!({
+        "status": "Pending",
+        "code": "confirmation_required",
+        "message": format!("Orders above the notional limit need confirmation, confirm with POST /proposals/{}/confirm", proposal.id),
+        "proposal": proposal
+    }))
+}
+
+// Who is calling: an approver by their key, else the agent ID it sends, else its address
+fn caller(app_state: &AppState, req: &HttpRequest) -> String {
+    if let Some(approver) = approver(app_state, req) {
+        return approver;
+    }
+    req.headers().get(AGENT_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+        .or_else(|| req.peer_addr().map(|addr| addr.ip().to_string()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn approver(app_state: &AppState, req: &HttpRequest) -> Option<String> {
+    let key = req.headers().get(APPROVER_KEY_HEADER)?.to_str().ok()?;
+    app_state.proposals.approver(key.trim()).map(str::to_string)
+}
+
+enum Placement {
+    Placed(TradeResponse),
+    Replayed(TradeResponse)
+}
+
+impl Placement {
+    fn response(&self) -> &TradeResponse {
+        match self {
+            Placement::Placed(response) | Placement::Replayed(response) => response
+        }
+    }
+
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Placement::Placed(response) => HttpResponse::Ok().json(response),
+            Placement::Replayed(response) => HttpResponse::Ok().insert_header(("Idempotent-Replayed", "true")).json(response)
+        }
+    }
+}
+
+enum Claimed<'a> {
+    New(Option<Reservation<'a>>),
+    Answered(Placement),
+    Proposed(Box<Proposal>)
+}
+
+// A retried request with a known key gets the original answer instead of a second order
+async fn claim_key<'a>(app_state: &'a web::Data<AppState>, kite: &KiteClient, instruction: &TradeInstruction, proposal: Option<&str>) -> Result<Claimed<'a>, HttpResponse> {
+    let key = match instruction.idempotency_key.as_deref() {
+        Some(key) => key,
+        None => return Ok(Claimed::New(None))
+    };
+    if let Err(e) = validate_key(key) {
+        return Err(HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", e.to_string())));
+    }
+    let claim = match proposal {
+        Some(proposal_id) => app_state.idempotency.claim_proposal(key, instruction, proposal_id),
+        None => app_state.idempotency.claim(key, instruction)
+    };
+
+    match claim {
+        Claim::New(reservation) => Ok(Claimed::New(Some(reservation))),
+        Claim::Replay(response) => {
+            println!("Replaying order {} for idempotency key {}", response.order_id, key);
+            Ok(Claimed::Answered(Placement::Replayed(response)))
+        },
+        Claim::Reconcile(reservation, placed) => {
+            let response = reconcile_tagged(kite, key, instruction, &placed).await?;
+            reservation.complete(&response);
+            Ok(Claimed::Answered(Placement::Replayed(response)))
+        },
+        Claim::Proposed(proposal_id) => match app_state.proposals.get(&proposal_id) {
+            Some(Proposal { result: Some(response), .. }) => Ok(Claimed::Answered(Placement::Replayed(response))),
+            Some(proposal) => Ok(Claimed::Proposed(Box::new(proposal))),
+            None => Err(HttpResponse::Conflict().json(ErrorResponse::new(
+                "idempotency_key_reused",
+                format!("Idempotency key {} belongs to proposal {}, which is no longer held", key, proposal_id)
+            )))
+        },
+        Claim::InFlight => Err(key_in_flight(key)),
+        Claim::Mismatch => Err(key_reused(key))
+    }
+}
+
+fn key_in_flight(key: &str) -> HttpResponse {
+    HttpResponse::Conflict().json(ErrorResponse::new(
+        "request_in_progress",
+        format!("An order with idempotency key {} is still being placed", key)
+    ))
+}
+
+fn key_reused(key: &str) -> HttpResponse {
+    HttpResponse::UnprocessableEntity().json(ErrorResponse::new(
+        "idempotency_key_reused",
+        format!("Idempotency key {} was already used for a different instruction", key)
+    ))
+}
+
+// Claims the key and sends an instruction to Kite; confirmed proposals pass their ID to get past its hold
+async fn place_trade(app_state: &web::Data<AppState>, kite: KiteClient, instruction: TradeInstruction, proposal: Option<&str>) -> Result<Placement, HttpResponse> {
+    match claim_key(app_state, &kite, &instruction, proposal).await? {
+        Claimed::New(reservation) => send_trade(app_state, kite, instruction, reservation).await,
+        Claimed::Answered(placement) => Ok(placement),
+        Claimed::Proposed(proposal) => Err(HttpResponse::Conflict().json(ErrorResponse::new(
+            "idempotency_key_reused",
+            format!("Idempotency key is held by proposal {}", proposal.id)
+        )))
+    }
+}
+
+async fn send_trade(app_state: &web::Data<AppState>, kite: KiteClient, instruction: TradeInstruction, reservation: Option<Reservation<'_>>) -> Result<Placement, HttpResponse> {
+    let key = format!("{}:{}", instruction.exchange, instruction.symbol);
+    let mut exeucutor = executor_for(app_state, kite, &instruction, &key).await;
+
+    // Report the price and quantity actually sent, which differ from the request when auto-adjusted or protected
+    let plan = exeucutor.plan(&instruction).ok();
+    // A bad stop loss or target is caught before the entry goes out, not after
+    let exit_gtt = match plan.as_ref().map(|plan| exeucutor.exit_gtt(plan, &instruction)) {
+        Some(Ok(gtt)) => gtt,
+        Some(Err(e)) => return Err(HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", e.to_string()))),
+        None => None
+    };
+    let (quantity, price, mut warnings) = match &plan {
+        Some(plan) => (plan.params.quantity, plan.params.price, plan.adjustments.clone()),
+        None => (instruction.quantity, instruction.limit_price, Vec::new())
+    };
+    warnings.extend(exeucutor.market_order_warnings(&instruction));
+    for warning in &warnings {
+        println!("Order warning for {}: {}", instruction.symbol, warning);
+    }
+
+    match exeucutor.execute_instructions(&instruction).await {
+        Ok(order_ids) => {
+            // Protected market orders that don't fill are chased in the background
+            if let Some(plan) = plan.filter(|plan| instruction.is_market() && plan.params.order_type == "LIMIT") {
+                tokio::spawn(exeucutor.protection.reprice(exeucutor.kite.clone(), plan.params, order_ids.clone(), exeucutor.tick_size()));
+            }
+            let gtt_id = match &exit_gtt {
+                Some(gtt) => match exeucutor.kite.place_gtt(gtt).await {
+                    Ok(trigger_id) => Some(trigger_id),
+                    Err(e) => {
+                        println!("Failed to place the stop loss/target GTT for {}: {}", instruction.symbol, e);
+                        warnings.push(format!("Order placed but its stop loss/target GTT failed, the position is unprotected: {}", e));
+                        None
+                    }
+                },
+                None => None
+            };
+            let response = TradeResponse {
+                order_id: order_ids.first().cloned().unwrap_or_default(),
                 status: "Success".to_string(),
-                message: format!("Order placed successfully for: {}", final_instruction.symbol),
-                symbol: final_instruction.symbol,
-                quantity: instruction.quantity,
-                price: instruction.limit_price.expect("Cannot find the limit price.."),
-                timestamp: Utc::now().to_rfc3339()
-            })
+                message: format!("Order placed successfully for: {}", instruction.symbol),
+                symbol: instruction.symbol,
+                quantity,
+                price: price.unwrap_or(0.0),
+                timestamp: Utc::now().to_rfc3339(),
+                warnings,
+                idempotency_key: instruction.idempotency_key,
+                child_order_ids: if order_ids.len() > 1 { order_ids } else { Vec::new() },
+                gtt_id
+            };
+            if let Some(reservation) = reservation {
+                reservation.complete(&response);
+            }
+            Ok(Placement::Placed(response))
         },
         Err(e) => {
-            return HttpResponse::BadRequest().json(ErrorResponse {
-            status: "Error".to_string(),
-            message: format!("Failed to execute order: {}", e)
-            });
+            // Once anything may be at Kite the key stays held, a retry reconciles instead of placing again
+            if let (Some(reservation), Some(partial)) = (reservation, e.downcast_ref::<PartialPlacement>()) {
+                println!("Holding idempotency key for {} after a partial placement: {}", instruction.symbol, partial);
+                reservation.hold(partial.order_ids.clone());
+            }
+            Err(request_error_response("Failed to execute order", e))
+        }
+    }
+}
+
+// Rebuilds the response for an unsettled key from the orders Kite holds under its tag
+async fn reconcile_tagged(kite: &KiteClient, key: &str, instruction: &TradeInstruction, placed: &[String]) -> Result<TradeResponse, HttpResponse> {
+    let orders = kite.orders().await.map_err(|e| error_response("Failed to reconcile an earlier attempt with the order book", e))?;
+    let mut tagged: Vec<_> = orders.into_iter()
+        .filter(|order| order.tag.as_deref() == Some(key) || placed.contains(&order.order_id))
+        .collect();
+    tagged.sort_by(|a, b| a.order_timestamp.cmp(&b.order_timestamp).then(a.order_id.cmp(&b.order_id)));
+
+    if tagged.is_empty() {
+        return Err(HttpResponse::Conflict().json(ErrorResponse::new(
+            "outcome_unknown",
+            format!("An earlier attempt with idempotency key {} failed without a clear answer and no order tagged {} is in the order book yet; check again later or use a new key to place it", key, key)
+        )));
+    }
+
+    let order_ids: Vec<String> = tagged.iter().map(|order| order.order_id.clone()).collect();
+    let rejected = tagged.iter().filter(|order| order.status == "REJECTED" || order.status == "CANCELLED").count();
+    let mut warnings = Vec::new();
+    if rejected > 0 {
+        warnings.push(format!("{} of the reconciled orders were rejected or cancelled", rejected));
+    }
+    if instruction.stop_loss.is_some() || instruction.target.is_some() {
+        warnings.push("The stop loss/target GTT was not placed for the reconciled orders, protect the position separately".to_string());
+    }
+    println!("Reconciled idempotency key {} to orders {}", key, order_ids.join(", "));
+    Ok(TradeResponse {
+        order_id: order_ids[0].clone(),
+        status: "Reconciled".to_string(),
+        message: format!("Found {} orders for {} from an earlier attempt with this key, nothing was placed again", order_ids.len(), instruction.symbol),
+        symbol: instruction.symbol.clone(),
+        quantity: tagged.iter().map(|order| order.quantity).sum(),
+        price: tagged[0].price,
+        timestamp: Utc::now().to_rfc3339(),
+        warnings,
+        idempotency_key: Some(key.to_string()),
+        child_order_ids: if order_ids.len() > 1 { order_ids } else { Vec::new() },
+        gtt_id: None
+    })
+}
+
+pub async fn preview_trade(app_state: web::Data<AppState>, instruction: web::Json<TradeInstruction>) -> HttpResponse {
+    let (kite, session) = {
+        let mut auth_manager = app_state.auth_manager.lock().await;
+        (auth_manager.get_kite().clone(), auth_manager.is_token_valid())
+    };
+
+    match build_preview(&app_state, kite, session, &instruction).await {
+        Ok(preview) => HttpResponse::Ok().json(preview),
+        Err(response) => response
+    }
+}
+
+async fn build_preview(app_state: &web::Data<AppState>, kite: KiteClient, session: bool, instruction: &TradeInstruction) -> Result<TradePreview, HttpResponse> {
+    if instruction.symbol == "BEST PERFORMER" {
+        return Err(HttpResponse::BadRequest().json(ErrorResponse::new(
+            "invalid_request",
+            "Preview needs a concrete symbol, resolve BEST PERFORMER through /trade first".to_string()
+        )));
+    }
+    if let Some(Err(e)) = instruction.idempotency_key.as_deref().map(validate_key) {
+        return Err(HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", e.to_string())));
+    }
+
+    let key = format!("{}:{}", instruction.exchange, instruction.symbol);
+    let executor = executor_for(app_state, kite, instruction, &key).await;
+
+    let warnings = executor.market_order_warnings(instruction);
+    let reference_price = match instruction.limit_price {
+        Some(_) => None,
+        None => latest_ticks(app_state, std::slice::from_ref(&key), QuoteKind::Ltp).await.ok()
+            .and_then(|(ticks, _)| ticks.into_values().next())
+            .map(|sourced| sourced.tick.last_price)
+    };
+
+    executor.preview(instruction, reference_price, session, warnings).await
+        .map_err(|e| request_error_response("Failed to preview order", e))
+}
+
+pub async fn submit_proposal(app_state: web::Data<AppState>, req: HttpRequest, instruction: web::Json<TradeInstruction>) -> HttpResponse {
+    let instruction = instruction.into_inner();
+    let (kite, session) = {
+        let mut auth_manager = app_state.auth_manager.lock().await;
+        (auth_manager.get_kite().clone(), auth_manager.is_token_valid())
+    };
+
+    let reservation = match claim_key(&app_state, &kite, &instruction, None).await {
+        Ok(Claimed::New(reservation)) => reservation,
+        Ok(Claimed::Answered(placement)) => return placement.into_response(),
+        Ok(Claimed::Proposed(proposal)) => return HttpResponse::Ok().insert_header(("Idempotent-Replayed", "true")).json(proposal),
+        Err(response) => return response
+    };
+    let preview = match build_preview(&app_state, kite.clone(), session, &instruction).await {
+        Ok(preview) => preview,
+        Err(response) => return response
+    };
+    let auto_approve = session && app_state.proposals.auto_approves(preview.notional);
+    let proposal = app_state.proposals.submit(instruction, preview, Some(caller(&app_state, &req)));
+    if let Some(reservation) = reservation {
+        reservation.propose(&proposal.id);
+    }
+
+    if auto_approve {
+        return confirm(&app_state, kite, &proposal.id, "auto").await;
+    }
+    HttpResponse::Created().json(proposal)
+}
+
+pub async fn list_proposals(app_state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(app_state.proposals.list())
+}
+
+pub async fn get_proposal(app_state: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+    match app_state.proposals.get(&id) {
+        Some(proposal) => HttpResponse::Ok().json(proposal),
+        None => proposal_error(DecisionError::NotFound)
+    }
+}
+
+pub async fn confirm_proposal(app_state: web::Data<AppState>, req: HttpRequest, id: web::Path<String>) -> HttpResponse {
+    let approver = match approver(&app_state, &req) {
+        Some(approver) => approver,
+        None => return approver_required(&app_state)
+    };
+
+    let kite = match require_session(&app_state).await {
+        Ok(kite) => kite,
+        Err(response) => return response
+    };
+    confirm(&app_state, kite, &id, &approver).await
+}
+
+async fn confirm(app_state: &web::Data<AppState>, kite: KiteClient, id: &str, approver: &str) -> HttpResponse {
+    let proposal = match app_state.proposals.approve(id, approver) {
+        Ok(proposal) => proposal,
+        Err(e) => return proposal_error(e)
+    };
+    println!("Proposal {} approved by {}", proposal.id, approver);
+
+    match place_trade(app_state, kite, proposal.instruction, Some(id)).await {
+        Ok(placement) => {
+            app_state.proposals.record(id, Ok(placement.response().clone()));
+            placement.into_response()
+        },
+        Err(response) => {
+            let (response, reason) = failure_reason(response).await;
+            app_state.proposals.record(id, Err(reason));
+            response
         }
     }
 }
 
+// The code and message of a failed placement for the proposal's history, which may be ours rather
+// than Kite's; the response itself goes back to the client unchanged
+async fn failure_reason(response: HttpResponse) -> (HttpResponse, String) {
+    let status = response.status();
+    let (response, body) = response.into_parts();
+    let bytes = to_bytes(body).await.unwrap_or_default();
+    let reason = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(body) => format!("{}: {}", body["code"].as_str().unwrap_or("error"), body["message"].as_str().unwrap_or_default()),
+        Err(_) => format!("Failed with HTTP {}", status)
+    };
+    (response.set_body(bytes).map_into_boxed_body(), reason)
+}
+
+pub async fn reject_proposal(app_state: web::Data<AppState>, req: HttpRequest, id: web::Path<String>, decision: web::Json<ProposalDecision>) -> HttpResponse {
+    let approver = match approver(&app_state, &req) {
+        Some(approver) => approver,
+        None => return approver_required(&app_state)
+    };
+
+    match app_state.proposals.reject(&id, &approver, decision.into_inner().reason) {
+        Ok(proposal) => HttpResponse::Ok().json(proposal),
+        Err(e) => proposal_error(e)
+    }
+}
+
+fn approver_required(app_state: &AppState) -> HttpResponse {
+    let message = if app_state.proposals.has_approvers() {
+        format!("Confirming or rejecting a proposal needs a valid {} header", APPROVER_KEY_HEADER)
+    }
+    else {
+        "No approvers are configured, set PROPOSAL_APPROVERS to confirm or reject proposals".to_string()
+    };
+    HttpResponse::Unauthorized().json(ErrorResponse::new("approver_unauthenticated", message))
+}
+
+fn proposal_error(e: DecisionError) -> HttpResponse {
+    let body = ErrorResponse::new(e.code(), e.to_string());
+    match e {
+        DecisionError::NotFound => HttpResponse::NotFound().json(body),
+        DecisionError::Expired => HttpResponse::Gone().json(body),
+        DecisionError::AlreadyDecided(_) => HttpResponse::Conflict().json(body),
+        DecisionError::SelfApproval => HttpResponse::Forbidden().json(body)
+    }
+}
+
+/// Places a list of instructions all or nothing: margin-checked together, hedges first, and rolled back if a leg is rejected.
+pub async fn execute_basket(app_state: web::Data<AppState>, request: web::Json<BasketRequest>) -> HttpResponse {
+    let request = request.into_inner();
+    let instructions = request.legs.clone();
+    if instructions.is_empty() || instructions.len() > MAX_BASKET_LEGS {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            "invalid_request",
+            format!("A basket needs between 1 and {} legs, got {}", MAX_BASKET_LEGS, instructions.len())
+        ));
+    }
+
+    let kite = match require_session(&app_state).await {
+        Ok(kite) => kite,
+        Err(response) => return response
+    };
+
+    // Every leg is planned up front so one bad leg rejects the basket before anything is sent
+    let mut legs = Vec::new();
+    let mut errors = Vec::new();
+    for (index, instruction) in instructions.into_iter().enumerate() {
+        if instruction.symbol == "BEST PERFORMER" {
+            errors.push(format!("Leg {}: needs a concrete symbol", index));
+            continue;
+        }
+        if let Some(Err(e)) = instruction.idempotency_key.as_deref().map(validate_key) {
+            errors.push(format!("Leg {}: {}", index, e));
+            continue;
+        }
+        if instruction.stop_loss.is_some() || instruction.target.is_some() {
+            errors.push(format!("Leg {}: stop_loss and target are only placed by /trade, protect basket legs afterwards", index));
+            continue;
+        }
+
+        let key = format!("{}:{}", instruction.exchange, instruction.symbol);
+        let executor = executor_for(&app_state, kite.clone(), &instruction, &key).await;
+        match executor.plan(&instruction) {
+            Ok(plan) => legs.push(BasketLeg { instruction, executor, plan }),
+            Err(e) => errors.push(format!("Leg {} ({}): {}", index, key, e))
+        }
+    }
+    if let Some(Err(e)) = request.idempotency_key.as_deref().map(validate_key) {
+        errors.push(e.to_string());
+    }
+    if !errors.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", errors.join("; ")));
+    }
+
+    // Proposals hold a single instruction, so a basket with a leg above the limit can't be queued for confirmation
+    if app_state.proposals.has_notional_limit() {
+        for (index, leg) in legs.iter().enumerate() {
+            let notional = leg.plan.params.price.or(leg.executor.quote.ltp).map(|price| price * leg.plan.params.quantity as f64);
+            if app_state.proposals.requires_confirmation(notional) {
+                return HttpResponse::Forbidden().json(ErrorResponse::new(
+                    "confirmation_required",
+                    format!("Leg {} ({}) is above PROPOSAL_NOTIONAL_LIMIT or could not be priced; baskets can't be confirmed, place it through /proposals", index, leg.instruction.symbol)
+                ));
+            }
+        }
+    }
+
+    // A retried basket gets its first report back instead of being placed again
+    let reservation = match request.idempotency_key.as_deref() {
+        Some(key) => match app_state.basket_idempotency.claim(key, &request) {
+            Claim::New(reservation) => Some(reservation),
+            Claim::Replay(report) => {
+                println!("Replaying basket report for idempotency key {}", key);
+                return basket_response(report, true);
+            },
+            // Basket keys are only ever completed or released
+            Claim::InFlight | Claim::Reconcile(..) | Claim::Proposed(_) => return key_in_flight(key),
+            Claim::Mismatch => return key_reused(key)
+        },
+        None => None
+    };
+
+    let margin = match check_margin(&kite, &legs).await {
+        Ok(margin) => margin,
+        Err(e) => return error_response("Failed to margin-check basket", e)
+    };
+    if margin.available.is_some_and(|available| margin.required > available) {
+        println!("Basket rejected: needs {} margin, {:?} available", margin.required, margin.available);
+        return HttpResponse::UnprocessableEntity().json(BasketReport::insufficient_margin(&legs, margin));
+    }
+
+    let report = place_basket(&kite, &legs, margin, app_state.market_protection).await;
+    if let Some(reservation) = reservation {
+        reservation.complete(&report);
+    }
+    basket_response(report, false)
+}
+
+fn basket_response(report: BasketReport, replayed: bool) -> HttpResponse {
+    let mut response = match report.status {
+        BasketStatus::Placed => HttpResponse::Ok(),
+        BasketStatus::RollbackIncomplete => HttpResponse::InternalServerError(),
+        _ => HttpResponse::UnprocessableEntity()
+    };
+    if replayed {
+        response.insert_header(("Idempotent-Replayed", "true"));
+    }
+    response.json(report)
+}
+
+/// Starts working a parent order with a TWAP, VWAP or iceberg algo and returns its first report.
+pub async fn start_algo(app_state: web::Data<AppState>, request: web::Json<AlgoRequest>) -> HttpResponse {
+    let request = request.into_inner();
+    let instruction = &request.instruction;
+    if instruction.symbol == "BEST PERFORMER" {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", "Algos need a concrete symbol".to_string()));
+    }
+    if let Some(Err(e)) = instruction.idempotency_key.as_deref().map(validate_key) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", e.to_string()));
+    }
+    if instruction.stop_loss.is_some() || instruction.target.is_some() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            "invalid_request",
+            "stop_loss and target are only placed by /trade; algo child orders fill over time, protect the position once it is worked".to_string()
+        ));
+    }
+
+    let kite = match require_session(&app_state).await {
+        Ok(kite) => kite,
+        Err(response) => return response
+    };
+
+    // Children are priced from a fresh quote each slice, so the parent itself is planned unprotected
+    let key = format!("{}:{}", instruction.exchange, instruction.symbol);
+    let executor = executor_for(&app_state, kite, &TradeInstruction { price_type: "LIMIT".to_string(), ..instruction.clone() }, &key).await;
+    let plan = match executor.plan(instruction) {
+        Ok(plan) => plan,
+        Err(e) => return HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", e.to_string()))
+    };
+
+    // Proposals hold a single order, so an algo above the limit can't be queued for confirmation
+    if app_state.proposals.has_notional_limit() {
+        let price = match plan.params.price {
+            Some(price) => Some(price),
+            None => market_quote(&app_state, &key).await.ltp
+        };
+        if app_state.proposals.requires_confirmation(price.map(|price| price * plan.params.quantity as f64)) {
+            return HttpResponse::Forbidden().json(ErrorResponse::new(
+                "confirmation_required",
+                format!("{} is above PROPOSAL_NOTIONAL_LIMIT or could not be priced; algos can't be confirmed, place it through /proposals", instruction.symbol)
+            ));
+        }
+    }
+
+    // A retry gets the algo the key started instead of a second one working the same order
+    let reservation = match request.instruction.idempotency_key.as_deref() {
+        Some(key) => match app_state.algo_idempotency.claim(key, &request) {
+            Claim::New(reservation) => Some(reservation),
+            Claim::Replay(id) => return match app_state.algos.get(&id) {
+                Some(report) => HttpResponse::Ok().insert_header(("Idempotent-Replayed", "true")).json(report),
+                None => algo_error(AlgoError::NotFound)
+            },
+            // Algo keys are only ever completed or released
+            Claim::InFlight | Claim::Reconcile(..) | Claim::Proposed(_) => return key_in_flight(key),
+            Claim::Mismatch => return key_reused(key)
+        },
+        None => None
+    };
+
+    match app_state.algos.start(executor, plan, request.algo.clone(), app_state.market_protection) {
+        Ok(report) => {
+            println!("Started {:?} algo {} for {} {}", report.algo.kind, report.id, report.transaction_type, report.symbol);
+            if let Some(reservation) = reservation {
+                reservation.complete(&report.id);
+            }
+            HttpResponse::Accepted().json(report)
+        },
+        Err(e) => HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", e.to_string()))
+    }
+}
+
+pub async fn list_algos(app_state: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(app_state.algos.list())
+}
+
+pub async fn get_algo(app_state: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+    match app_state.algos.get(&id) {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => algo_error(AlgoError::NotFound)
+    }
+}
+
+pub async fn pause_algo(app_state: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+    control_algo(&app_state, &id, Control::Pause)
+}
+
+pub async fn resume_algo(app_state: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+    control_algo(&app_state, &id, Control::Run)
+}
+
+pub async fn cancel_algo(app_state: web::Data<AppState>, id: web::Path<String>) -> HttpResponse {
+    control_algo(&app_state, &id, Control::Cancel)
+}
+
+// The algo's task acts on the change at its next wake; the report returned is from before it
+fn control_algo(app_state: &web::Data<AppState>, id: &str, control: Control) -> HttpResponse {
+    match app_state.algos.control(id, control) {
+        Ok(report) => {
+            println!("Algo {} set to {:?}", id, control);
+            HttpResponse::Accepted().json(report)
+        },
+        Err(e) => algo_error(e)
+    }
+}
+
+fn algo_error(e: AlgoError) -> HttpResponse {
+    let body = ErrorResponse::new(e.code(), e.to_string());
+    match e {
+        AlgoError::NotFound => HttpResponse::NotFound().json(body),
+        AlgoError::Finished(_) => HttpResponse::Conflict().json(body)
+    }
+}
+
+// The instrument and freeze limit are looked up under the market data lock; the order itself is sent without it.
+// Market orders also get the quote they are protected with and their liquidity is checked against.
+async fn executor_for(app_state: &web::Data<AppState>, kite: KiteClient, instruction: &TradeInstruction, key: &str) -> TradeExecutor {
+    let (instrument, freeze_limit) = {
+        let market_data = app_state.market_data.lock().await;
+        let instrument = market_data.instrument(key);
+        let freeze_limit = instrument.as_ref().and_then(|instrument| market_data.freeze_limit(instrument));
+        (instrument, freeze_limit)
+    };
+
+    let executor = TradeExecutor::new(kite, instrument, freeze_limit);
+    // Stop loss and target GTTs need the LTP, and price their stop leg with the same protection
+    let exits = instruction.stop_loss.is_some() || instruction.target.is_some();
+    if (instruction.is_market() || exits) && instruction.action != "cancel" {
+        executor.with_market_protection(app_state.market_protection, market_quote(app_state, key).await)
+    }
+    else {
+        executor
+    }
+}
+
+// Depth comes from the live cache when it is fresh and from Kite REST otherwise
+async fn market_quote(app_state: &web::Data<AppState>, key: &str) -> MarketQuote {
+    let keys = [key.to_string()];
+    let sourced = match latest_ticks(app_state, &keys, QuoteKind::Depth).await.ok().and_then(|(ticks, _)| ticks.into_values().next()) {
+        Some(sourced) => Some(sourced),
+        None => latest_ticks(app_state, &keys, QuoteKind::Ltp).await.ok().and_then(|(ticks, _)| ticks.into_values().next())
+    };
+
+    MarketQuote {
+        ltp: sourced.as_ref().map(|sourced| sourced.tick.last_price),
+        book: sourced.and_then(|sourced| sourced.tick.depth.as_ref().map(|depth| analyse(depth, DEFAULT_DEPTH_BAND_PCT)))
+    }
+}
+
+pub async fn square_off_status(app_state: web::Data<AppState>) -> HttpResponse {
+    let square_off = &app_state.square_off;
+    HttpResponse::Ok().json(json!({
+        "square_off_time": square_off.at.format("%H:%M").to_string(),
+        "next_run": square_off.next_run(Utc::now().fixed_offset()).to_rfc3339(),
+        "last_report": square_off.last_report()
+    }))
+}
+
+pub async fn rollover_status(app_state: web::Data<AppState>) -> HttpResponse {
+    let rollover = &app_state.rollover;
+    HttpResponse::Ok().json(json!({
+        "rollover_time": rollover.at.format("%H:%M").to_string(),
+        "days_before": rollover.days_before,
+        "mode": rollover.mode,
+        "dry_run": rollover.dry_run,
+        "next_run": rollover.next_run(Utc::now().fixed_offset()).map(|next| next.to_rfc3339()),
+        "last_report": rollover.last_report()
+    }))
+}
+
+/// Rolls due NRML futures positions right away, or only plans them with `?dry_run=true`.
+pub async fn run_rollover(app_state: web::Data<AppState>, query: web::Query<RolloverQuery>) -> HttpResponse {
+    let rollover = &app_state.rollover;
+    let report = rollover.run(&app_state, query.dry_run.unwrap_or(rollover.dry_run)).await;
+    if report.failures.is_empty() && report.rolled.iter().all(|rolled| matches!(rolled.status, RollStatus::Rolled | RollStatus::DryRun)) {
+        HttpResponse::Ok().json(report)
+    }
+    else {
+        HttpResponse::BadGateway().json(report)
+    }
+}
+
+/// Brokerage and statutory charges on a buy, a sell or a round trip, from Zerodha's fee schedule.
+pub async fn calculate_charges(app_state: web::Data<AppState>, request: web::Json<ChargesRequest>) -> HttpResponse {
+    let exchange = request.exchange.trim().to_uppercase();
+    let segment = match request.segment {
+        Some(segment) => Ok(segment),
+        None => {
+            let instrument = match &request.symbol {
+                Some(symbol) => app_state.market_data.lock().await.instrument(&format!("{}:{}", exchange, symbol.trim())),
+                None => None
+            };
+            match (instrument, exchange.as_str()) {
+                (Some(instrument), _) => Segment::of(&exchange, &instrument.instrument_type, request.product.as_deref().unwrap_or("CNC")),
+                (None, "NSE" | "BSE") => Segment::of(&exchange, "EQ", request.product.as_deref().unwrap_or("CNC")),
+                (None, _) => Err(anyhow::anyhow!("Give a segment, or a symbol in the instrument master, for {} orders", exchange))
+            }
+        }
+    };
+    let segment = match segment {
+        Ok(segment) => segment,
+        Err(e) => return HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", e.to_string()))
+    };
+
+    let valid = |price: Option<f64>| price.is_none_or(|price| price > 0.0);
+    if request.quantity == 0 || !valid(request.buy_price) || !valid(request.sell_price) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", "quantity and prices must be positive".to_string()));
+    }
+    match (request.buy_price, request.sell_price) {
+        (Some(buy), Some(sell)) => HttpResponse::Ok().json(round_trip(segment, &exchange, request.quantity, buy, sell)),
+        (Some(price), None) => HttpResponse::Ok().json(order_charges(segment, &exchange, true, price, request.quantity)),
+        (None, Some(price)) => HttpResponse::Ok().json(order_charges(segment, &exchange, false, price, request.quantity)),
+        (None, None) => HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", "Give a buy_price, a sell_price or both".to_string()))
+    }
+}
+
+/// Weekly and monthly expiries of an underlying's options and futures.
+pub async fn get_expiries(app_state: web::Data<AppState>, underlying: web::Path<String>) -> HttpResponse {
+    let derivatives = app_state.market_data.lock().await.derivatives(&underlying);
+    if derivatives.is_empty() {
+        return HttpResponse::NotFound().json(ErrorResponse::new(
+            "not_found",
+            format!("No derivatives listed on {}, or the instrument master is not loaded yet", underlying)
+        ));
+    }
+    let today = Utc::now().with_timezone(&ist()).date_naive();
+    HttpResponse::Ok().json(ExpiryCalendar::new(&underlying, &derivatives, today))
+}
+
+/// Squares off MIS positions right away instead of waiting for the scheduled time.
+pub async fn run_square_off(app_state: web::Data<AppState>) -> HttpResponse {
+    let report = app_state.square_off.run(&app_state).await;
+    if report.failures.is_empty() {
+        HttpResponse::Ok().json(report)
+    }
+    else {
+        HttpResponse::BadGateway().json(report)
+    }
+}
+
 pub async fn get_login_url(app_state: web::Data<AppState>) -> HttpResponse {
-    let mut auth_manager = app_state.auth_manager.lock().unwrap();
+    let mut auth_manager = app_state.auth_manager.lock().await;
     let login_url = auth_manager.get_login_url();
 
     HttpResponse::Ok().json(json!({
@@ -75,12 +826,29 @@ pub async fn auth_callback(app_state: web::Data<AppState>, query: web::Query<Has
     println!("== END DEBUG ==");
 
     if let Some(request_token) = query.get("request_token") {
-        let mut auth_manager = app_state.auth_manager.lock().unwrap();
+        let mut auth_manager = app_state.auth_manager.lock().await;
         match auth_manager.generate_session(request_token).await {
             Ok(_) => {
+                let access_token = auth_manager.access_token.clone().unwrap_or_default();
+                let api_key = auth_manager.api_key.clone();
+                // Market data keeps its own handle on the client so quotes and history never wait on the auth lock
+                let kite = auth_manager.get_kite().clone();
+                drop(auth_manager);
+
+                let mut warnings = Vec::new();
+                match InstrumentMaster::from_kite(&kite).await {
+                    Ok(instruments) => {
+                        let mut market_data = app_state.market_data.lock().await;
+                        market_data.set_kite(kite, instruments);
+                        warnings.extend(market_data.initialize_ticker(&api_key, &access_token));
+                    },
+                    Err(e) => println!("Failed to load instruments, live prices disabled: {}", e)
+                }
+
                 HttpResponse::Ok().json(json!({
                     "status": "Successful".to_string(),
-                    "message": "Authentication successfull".to_string()
+                    "message": "Authentication successfull".to_string(),
+                    "warnings": warnings
                 }))
             },
             Err(e) => HttpResponse::BadRequest().json(json!({
@@ -90,14 +858,14 @@ pub async fn auth_callback(app_state: web::Data<AppState>, query: web::Query<Has
         }
     }
     else {
-        return HttpResponse::BadRequest().json(json!({
+        HttpResponse::BadRequest().json(json!({
             "status": "Unsuccessful".to_string(),
             "message": "Access token not found!".to_string()
-        }));
+        }))
     }
 }
 
-pub async fn handle_postback(payload: web::Json<Value>, _app_state: web::Data<AppState>) -> HttpResponse {
+pub async fn handle_postback(payload: web::Json<Value>, app_state: web::Data<AppState>) -> HttpResponse {
     println!("Received Postback: {:?}", serde_json::to_string_pretty(&payload));
 
     match payload.get("status") {
@@ -108,8 +876,545 @@ pub async fn handle_postback(payload: web::Json<Value>, _app_state: web::Data<Ap
             println!("Status not received")
         }
     }
+    app_state.market_data.lock().await.publish_order_update(payload.into_inner());
 
     HttpResponse::Ok().json(json!({
         "status": "received"
     }))
-}
\ No newline at end of file
+}
+
+/// Order updates as server-sent events, from the ticker connection and postbacks alike.
+pub async fn stream_order_updates(app_state: web::Data<AppState>) -> HttpResponse {
+    let updates = app_state.market_data.lock().await.subscribe_order_updates();
+
+    let events = stream::unfold(updates, |mut updates| async move {
+        let event = loop {
+            match tokio::time::timeout(SSE_KEEP_ALIVE, updates.recv()).await {
+                Ok(Ok(update)) => break format!("event: order\ndata: {}\n\n", update),
+                Ok(Err(RecvError::Lagged(skipped))) => println!("Order update client lagged, skipped {} updates", skipped),
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => break ": keep-alive\n\n".to_string()
+            }
+        };
+        Some((event, updates))
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events.map(|event| Ok::<_, actix_web::Error>(web::Bytes::from(event))))
+}
+
+pub async fn list_watchlists(app_state: web::Data<AppState>) -> HttpResponse {
+    let market_data = app_state.market_data.lock().await;
+    HttpResponse::Ok().json(market_data.watchlists().list())
+}
+
+pub async fn get_watchlist(app_state: web::Data<AppState>, name: web::Path<String>) -> HttpResponse {
+    let market_data = app_state.market_data.lock().await;
+
+    match market_data.watchlists().get(&name) {
+        Some(watchlist) => HttpResponse::Ok().json(watchlist),
+        None => HttpResponse::NotFound().json(ErrorResponse::new("not_found", format!("Watchlist not found: {}", name)))
+    }
+}
+
+pub async fn create_watchlist(app_state: web::Data<AppState>, request: web::Json<CreateWatchlistRequest>) -> HttpResponse {
+    let mut market_data = app_state.market_data.lock().await;
+    let request = request.into_inner();
+
+    match market_data.update_watchlists(|lists| lists.create(&request.name, request.owner.clone(), &request.symbols)) {
+        Ok(watchlist) => HttpResponse::Created().json(watchlist),
+        Err(e) => watchlist_error(e)
+    }
+}
+
+pub async fn replace_watchlist(app_state: web::Data<AppState>, name: web::Path<String>, request: web::Json<WatchlistSymbolsRequest>) -> HttpResponse {
+    let mut market_data = app_state.market_data.lock().await;
+
+    match market_data.update_watchlists(|lists| lists.replace(&name, &request.symbols)) {
+        Ok(watchlist) => HttpResponse::Ok().json(watchlist),
+        Err(e) => watchlist_error(e)
+    }
+}
+
+pub async fn add_watchlist_symbols(app_state: web::Data<AppState>, name: web::Path<String>, request: web::Json<WatchlistSymbolsRequest>) -> HttpResponse {
+    let mut market_data = app_state.market_data.lock().await;
+
+    match market_data.update_watchlists(|lists| lists.add_symbols(&name, &request.symbols)) {
+        Ok(watchlist) => HttpResponse::Ok().json(watchlist),
+        Err(e) => watchlist_error(e)
+    }
+}
+
+pub async fn remove_watchlist_symbol(app_state: web::Data<AppState>, path: web::Path<(String, String)>) -> HttpResponse {
+    let mut market_data = app_state.market_data.lock().await;
+    let (name, symbol) = path.into_inner();
+
+    match market_data.update_watchlists(|lists| lists.remove_symbol(&name, &symbol)) {
+        Ok(watchlist) => HttpResponse::Ok().json(watchlist),
+        Err(e) => watchlist_error(e)
+    }
+}
+
+pub async fn delete_watchlist(app_state: web::Data<AppState>, name: web::Path<String>) -> HttpResponse {
+    let mut market_data = app_state.market_data.lock().await;
+
+    match market_data.update_watchlists(|lists| lists.delete(&name)) {
+        Ok(watchlist) => HttpResponse::Ok().json(watchlist),
+        Err(e) => watchlist_error(e)
+    }
+}
+
+/// The option chain of an underlying for one expiry, with quotes and locally computed IV and Greeks.
+pub async fn get_option_chain(app_state: web::Data<AppState>, underlying: web::Path<String>, query: web::Query<ChainQuery>) -> HttpResponse {
+    match load_chain(&app_state, &underlying, &query).await {
+        Ok(chain) => HttpResponse::Ok().json(chain),
+        Err(response) => response
+    }
+}
+
+/// Resolves a straddle, strangle, vertical, iron condor or butterfly to contracts and prices it at expiry.
+pub async fn build_option_strategy(app_state: web::Data<AppState>, request: web::Json<StrategyRequest>) -> HttpResponse {
+    let query = ChainQuery { expiry: request.expiry, strikes: None, dividend_yield: request.dividend_yield };
+    let chain = match load_chain(&app_state, &request.underlying, &query).await {
+        Ok(chain) => chain,
+        Err(response) => return response
+    };
+    match build_strategy(&chain, &request.spec, request.product.clone()) {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => HttpResponse::UnprocessableEntity().json(ErrorResponse::new(
+            "unresolvable_strategy",
+            format!("Could not build the {:?} on {}: {}", request.spec.strategy, request.underlying, e)
+        ))
+    }
+}
+
+// Errors come back as the response to send
+async fn load_chain(app_state: &web::Data<AppState>, underlying: &str, query: &ChainQuery) -> Result<OptionChain, HttpResponse> {
+    let options = app_state.market_data.lock().await.options(underlying);
+    if options.is_empty() {
+        return Err(HttpResponse::NotFound().json(ErrorResponse::new(
+            "not_found",
+            format!("No options listed on {}, or the instrument master is not loaded yet", underlying)
+        )));
+    }
+
+    let now = Utc::now().with_timezone(&ist());
+    let contracts = ChainContracts::select(options, query.expiry, now.date_naive())
+        .map_err(|e| HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", format!("{}: {}", underlying, e))))?;
+
+    let spot_key = underlying_key(underlying);
+    let spot = latest_ticks(app_state, std::slice::from_ref(&spot_key), QuoteKind::Ltp).await.ok()
+        .and_then(|(ticks, _)| ticks.into_values().next())
+        .map(|sourced| sourced.tick.last_price);
+
+    // A call and a put per strike, within the quote request limit
+    let strikes = query.strikes.unwrap_or(usize::MAX).clamp(1, MAX_QUOTE_KEYS / 2);
+    let contracts = contracts.nearest_strikes(spot, strikes);
+    let ticks = latest_ticks(app_state, &contracts.keys(), QuoteKind::Depth).await
+        .map_err(|e| error_response("Failed to fetch option quotes", e))?.0;
+
+    Ok(build_chain(underlying, contracts, spot, &ticks, app_state.risk_free_rate, query.dividend_yield.unwrap_or(0.0), now))
+}
+
+/// Prices an option from a volatility, or solves its implied volatility from a price, with Greeks.
+pub async fn price_option(app_state: web::Data<AppState>, request: web::Json<PricingRequest>) -> HttpResponse {
+    if !(request.spot > 0.0 && request.strike > 0.0) || request.volatility.is_some() == request.price.is_some() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            "invalid_request",
+            "Need a positive spot and strike, and either a volatility or a price".to_string()
+        ));
+    }
+
+    let now = Utc::now().with_timezone(&ist());
+    let inputs = OptionInputs {
+        kind: request.kind,
+        style: request.style.unwrap_or(ExerciseStyle::European),
+        spot: request.spot,
+        strike: request.strike,
+        years: trading_years(now, request.expiry),
+        rate: request.rate.unwrap_or(app_state.risk_free_rate),
+        dividend_yield: request.dividend_yield.unwrap_or(0.0)
+    };
+    let volatility = match (request.volatility, request.price) {
+        (Some(volatility), _) => Some(volatility).filter(|volatility| *volatility > 0.0),
+        (None, Some(price)) => inputs.implied_volatility(price),
+        (None, None) => None
+    };
+    match volatility {
+        Some(volatility) => HttpResponse::Ok().json(PricingResponse {
+            years_to_expiry: inputs.years,
+            volatility,
+            price: inputs.price(volatility),
+            greeks: inputs.greeks(volatility)
+        }),
+        None => HttpResponse::UnprocessableEntity().json(ErrorResponse::new(
+            "no_implied_volatility",
+            "No volatility prices the option at that price; it is expired, below intrinsic value or above any sensible premium".to_string()
+        ))
+    }
+}
+
+/// Net and day positions, revalued at the latest prices.
+pub async fn get_positions(app_state: web::Data<AppState>) -> HttpResponse {
+    let kite = match require_session(&app_state).await {
+        Ok(kite) => kite,
+        Err(response) => return response
+    };
+    let positions = match kite.positions().await {
+        Ok(positions) => positions,
+        Err(e) => return error_response("Failed to fetch positions", e)
+    };
+
+    let keys = portfolio::quote_keys(positions.net.iter().chain(&positions.day).map(|p| (p.exchange.as_str(), p.tradingsymbol.as_str())));
+    let ticks = portfolio_ticks(&app_state, &keys).await;
+    HttpResponse::Ok().json(PortfolioPositions {
+        net: PositionBook::new(&positions.net, &ticks),
+        day: PositionBook::new(&positions.day, &ticks)
+    })
+}
+
+/// Demat holdings, revalued at the latest prices.
+pub async fn get_holdings(app_state: web::Data<AppState>) -> HttpResponse {
+    let kite = match require_session(&app_state).await {
+        Ok(kite) => kite,
+        Err(response) => return response
+    };
+    let holdings = match kite.holdings().await {
+        Ok(holdings) => holdings,
+        Err(e) => return error_response("Failed to fetch holdings", e)
+    };
+
+    let keys = portfolio::quote_keys(holdings.iter().map(|h| (h.exchange.as_str(), h.tradingsymbol.as_str())));
+    let ticks = portfolio_ticks(&app_state, &keys).await;
+    HttpResponse::Ok().json(PortfolioHoldings::new(&holdings, &ticks))
+}
+
+/// Available and utilised margins of the equity and commodity segments.
+pub async fn get_funds(app_state: web::Data<AppState>) -> HttpResponse {
+    let kite = match require_session(&app_state).await {
+        Ok(kite) => kite,
+        Err(response) => return response
+    };
+    match kite.margins().await {
+        Ok(margins) => HttpResponse::Ok().json(Funds::from(&margins)),
+        Err(e) => error_response("Failed to fetch funds", e)
+    }
+}
+
+/// Today's orders that can still fill, with how far each is from LTP.
+pub async fn get_open_orders(app_state: web::Data<AppState>) -> HttpResponse {
+    let kite = match require_session(&app_state).await {
+        Ok(kite) => kite,
+        Err(response) => return response
+    };
+    let orders = match kite.orders().await {
+        Ok(orders) => orders,
+        Err(e) => return error_response("Failed to fetch orders", e)
+    };
+
+    let keys = portfolio::quote_keys(orders.iter().filter(|o| OpenOrder::is_open(o)).map(|o| (o.exchange.as_str(), o.tradingsymbol.as_str())));
+    let ticks = portfolio_ticks(&app_state, &keys).await;
+    HttpResponse::Ok().json(OpenOrder::list(&orders, &ticks))
+}
+
+// A failed quote only costs the enrichment: rows fall back to the broker's own last price
+async fn portfolio_ticks(app_state: &web::Data<AppState>, keys: &[String]) -> BTreeMap<String, SourcedTick> {
+    if keys.is_empty() {
+        return BTreeMap::new();
+    }
+    let mut ticks = BTreeMap::new();
+    for chunk in keys.chunks(MAX_QUOTE_KEYS) {
+        match latest_ticks(app_state, chunk, QuoteKind::Ltp).await {
+            Ok((fetched, _)) => ticks.extend(fetched),
+            Err(e) => println!("Failed to fetch portfolio prices: {}", e)
+        }
+    }
+    ticks
+}
+
+/// Greeks of the open option positions, per position and summed per underlying.
+pub async fn get_option_greeks(app_state: web::Data<AppState>) -> HttpResponse {
+    let kite = match require_session(&app_state).await {
+        Ok(kite) => kite,
+        Err(response) => return response
+    };
+    let positions = match kite.positions().await {
+        Ok(positions) => positions.net,
+        Err(e) => return error_response("Failed to fetch positions", e)
+    };
+
+    let options: Vec<(Instrument, i64)> = {
+        let market_data = app_state.market_data.lock().await;
+        positions.iter()
+            .filter(|position| position.quantity != 0)
+            .filter_map(|position| market_data.instrument(&format!("{}:{}", position.exchange, position.tradingsymbol)).map(|instrument| (instrument, position.quantity)))
+            .filter(|(instrument, _)| OptionKind::from_instrument_type(&instrument.instrument_type).is_some())
+            .collect()
+    };
+    let ticks = match latest_ticks(&app_state, &option_risk::quote_keys(&options), QuoteKind::Ltp).await {
+        Ok((ticks, _)) => ticks,
+        Err(e) => return error_response("Failed to fetch option prices", e)
+    };
+
+    let now = Utc::now().with_timezone(&ist());
+    HttpResponse::Ok().json(option_risk::aggregate(&options, &ticks, app_state.risk_free_rate, now))
+}
+
+pub async fn rank_watchlist(app_state: web::Data<AppState>, name: web::Path<String>, query: web::Query<RankingQuery>) -> HttpResponse {
+    let ranking = app_state.market_data.lock().await.ranking(&name);
+    let performances = match ranking {
+        Ok(ranking) => ranking.rank_performers(query.timeframe.unwrap_or(20)).await,
+        Err(e) => Err(e)
+    };
+
+    match performances {
+        Ok(performances) => HttpResponse::Ok().json(RankingResponse {
+            watchlist: name.into_inner(),
+            ranking: performances.ranked.into_iter()
+                .map(|(symbol, performance)| PerformanceEntry { symbol, performance })
+                .collect(),
+            skipped: performances.skipped
+        }),
+        Err(e) => ranking_error("Failed to rank watchlist", e)
+    }
+}
+
+fn ranking_error(context: &str, e: anyhow::Error) -> HttpResponse {
+    let message = e.to_string();
+    if message.starts_with("Watchlist not found") || message.starts_with("Index universe not found") {
+        HttpResponse::NotFound().json(ErrorResponse::new("not_found", format!("{}: {}", context, message)))
+    }
+    else {
+        error_response(context, e)
+    }
+}
+
+pub async fn list_universes(app_state: web::Data<AppState>) -> HttpResponse {
+    let market_data = app_state.market_data.lock().await;
+    HttpResponse::Ok().json(market_data.universes().list())
+}
+
+pub async fn get_universe(app_state: web::Data<AppState>, name: web::Path<String>) -> HttpResponse {
+    let market_data = app_state.market_data.lock().await;
+
+    match market_data.universes().get(&name) {
+        Some(universe) => HttpResponse::Ok().json(universe),
+        None => HttpResponse::NotFound().json(ErrorResponse::new("not_found", format!("Index universe not found: {}", name)))
+    }
+}
+
+pub async fn reload_universes(app_state: web::Data<AppState>) -> HttpResponse {
+    let mut market_data = app_state.market_data.lock().await;
+
+    match market_data.reload_universes() {
+        Ok(count) => HttpResponse::Ok().json(json!({
+            "status": "Success",
+            "universes": count
+        })),
+        Err(e) => error_response("Failed to reload index universes", e)
+    }
+}
+
+pub async fn get_quotes(app_state: web::Data<AppState>, query: web::Query<Vec<(String, String)>>) -> HttpResponse {
+    serve_quotes(app_state, query.into_inner(), QuoteKind::Quote).await
+}
+
+pub async fn get_ltp(app_state: web::Data<AppState>, query: web::Query<Vec<(String, String)>>) -> HttpResponse {
+    serve_quotes(app_state, query.into_inner(), QuoteKind::Ltp).await
+}
+
+pub async fn get_ohlc(app_state: web::Data<AppState>, query: web::Query<Vec<(String, String)>>) -> HttpResponse {
+    serve_quotes(app_state, query.into_inner(), QuoteKind::Ohlc).await
+}
+
+// Accepts Kite's own style (?i=NSE:INFY&i=NSE:TCS) as well as a comma separated ?i=NSE:INFY,NSE:TCS
+async fn serve_quotes(app_state: web::Data<AppState>, params: Vec<(String, String)>, kind: QuoteKind) -> HttpResponse {
+    let symbols: Vec<String> = params.iter()
+        .filter(|(key, _)| key == "i")
+        .flat_map(|(_, value)| split_symbols(value))
+        .collect();
+    if let Err(e) = quote_keys(&symbols) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", e.to_string()));
+    }
+
+    match latest_ticks(&app_state, &symbols, kind).await {
+        Ok((ticks, missing)) => {
+            let batch = QuoteBatch::quotes(ticks, missing, kind);
+            HttpResponse::Ok().json(QuoteResponse {
+                status: "Success".to_string(),
+                data: batch.data,
+                missing: batch.missing
+            })
+        },
+        Err(e) => error_response("Failed to fetch quotes", e)
+    }
+}
+
+// Fresh ticks come straight from the price cache; only the rest go to Kite REST, outside the market data lock
+async fn latest_ticks(app_state: &web::Data<AppState>, symbols: &[String], kind: QuoteKind) -> Result<(BTreeMap<String, SourcedTick>, Vec<String>), anyhow::Error> {
+    let (mut ticks, pending) = app_state.prices.fresh_ticks(quote_keys(symbols)?, kind);
+    if pending.is_empty() {
+        return Ok((ticks, Vec::new()));
+    }
+
+    let kite = app_state.market_data.lock().await.kite();
+    let (fetched, missing) = fetch_ticks(kite.as_ref(), &pending, kind).await?;
+    ticks.extend(fetched);
+    Ok((ticks, missing))
+}
+
+pub async fn get_depth(app_state: web::Data<AppState>, query: web::Query<Vec<(String, String)>>) -> HttpResponse {
+    let params = query.into_inner();
+    let symbols: Vec<String> = params.iter()
+        .filter(|(key, _)| key == "i")
+        .flat_map(|(_, value)| split_symbols(value))
+        .collect();
+    let band_pct = params.iter()
+        .find(|(key, _)| key == "band")
+        .and_then(|(_, value)| value.parse::<f64>().ok())
+        .unwrap_or(DEFAULT_DEPTH_BAND_PCT);
+    if let Err(e) = quote_keys(&symbols) {
+        return HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", e.to_string()));
+    }
+
+    match latest_ticks(&app_state, &symbols, QuoteKind::Depth).await {
+        Ok((ticks, missing)) => {
+            let batch = QuoteBatch::depth(ticks, missing, band_pct);
+            HttpResponse::Ok().json(QuoteResponse {
+                status: "Success".to_string(),
+                data: batch.data,
+                missing: batch.missing
+            })
+        },
+        Err(e) => error_response("Failed to fetch market depth", e)
+    }
+}
+
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+pub async fn stream_ticks(app_state: web::Data<AppState>, query: web::Query<StreamQuery>) -> HttpResponse {
+    let symbols = split_symbols(query.symbols.as_deref().unwrap_or(""));
+    let market_data = app_state.market_data.lock().await;
+    let (resolved, unknown) = market_data.resolve_instruments(&symbols);
+
+    if resolved.is_empty() {
+        return HttpResponse::BadRequest().json(ErrorResponse::new(
+            "invalid_request",
+            format!("No known symbols to stream, pass ?symbols=NSE:INFY,NSE:TCS (unknown: {:?})", unknown)
+        ));
+    }
+
+    let mut subscription = market_data.subscribe_ticks();
+    subscription.add(resolved);
+    drop(market_data);
+
+    let opening = format!("event: subscribed\ndata: {}\n\n", json!({
+        "symbols": subscription.symbols(),
+        "unknown": unknown
+    }));
+
+    let ticks = stream::unfold(subscription, |mut subscription| async move {
+        let event = match tokio::time::timeout(SSE_KEEP_ALIVE, subscription.next()).await {
+            Ok(Some(tick)) => format!("event: tick\ndata: {}\n\n", serde_json::to_string(&*tick).unwrap_or_default()),
+            Ok(None) => return None,
+            // Comment lines keep proxies from closing an idle stream outside market hours
+            Err(_) => ": keep-alive\n\n".to_string()
+        };
+        Some((event, subscription))
+    });
+
+    let body = stream::once(async move { opening })
+        .chain(ticks)
+        .map(|event| Ok::<_, actix_web::Error>(web::Bytes::from(event)));
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+pub async fn stream_ticks_ws(app_state: web::Data<AppState>, req: HttpRequest, body: web::Payload, query: web::Query<StreamQuery>) -> Result<HttpResponse, actix_web::Error> {
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+
+    let mut subscription = app_state.market_data.lock().await.subscribe_ticks();
+    let initial = split_symbols(query.symbols.as_deref().unwrap_or(""));
+    if !initial.is_empty() {
+        let reply = apply_stream_command(&app_state, &mut subscription, "subscribe", &initial).await;
+        let _ = session.text(reply.to_string()).await;
+    }
+
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::select! {
+                message = messages.next() => match message {
+                    Some(Ok(actix_ws::Message::Text(text))) => {
+                        let reply = match serde_json::from_str::<StreamCommand>(&text) {
+                            Ok(command) => apply_stream_command(&app_state, &mut subscription, &command.a, &command.v).await,
+                            Err(e) => json!({ "type": "error", "message": format!("Invalid command: {}", e) })
+                        };
+                        if session.text(reply.to_string()).await.is_err() {
+                            break;
+                        }
+                    },
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            break;
+                        }
+                    },
+                    Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+                tick = subscription.next() => match tick {
+                    Some(tick) => {
+                        if session.text(serde_json::to_string(&*tick).unwrap_or_default()).await.is_err() {
+                            break;
+                        }
+                    },
+                    None => break
+                }
+            }
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+async fn apply_stream_command(app_state: &web::Data<AppState>, subscription: &mut TickSubscription, action: &str, symbols: &[String]) -> Value {
+    let (resolved, unknown) = app_state.market_data.lock().await.resolve_instruments(symbols);
+
+    match action {
+        "subscribe" => subscription.add(resolved),
+        "unsubscribe" => {
+            let tokens: Vec<u32> = resolved.iter().map(|(token, _)| *token).collect();
+            subscription.remove(&tokens);
+        },
+        _ => return json!({ "type": "error", "message": format!("Unsupported action: {}", action) })
+    }
+
+    json!({
+        "type": "subscriptions",
+        "symbols": subscription.symbols(),
+        "unknown": unknown
+    })
+}
+
+fn split_symbols(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn watchlist_error(e: anyhow::Error) -> HttpResponse {
+    let message = format!("Watchlist update failed: {}", e);
+
+    if e.to_string().starts_with("Watchlist not found") {
+        HttpResponse::NotFound().json(ErrorResponse::new("not_found", message))
+    }
+    else {
+        HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", message))
+    }
+}
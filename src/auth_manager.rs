@@ -1,10 +1,8 @@
-use kiteconnect::connect::KiteConnect;
-use reqwest::Client;
-use sha2::{Digest, Sha256};
 use std::{env, time::{Duration, Instant}};
+use crate::kite_client::KiteClient;
 
 pub struct AuthManager {
-    pub kite: KiteConnect,
+    pub kite: KiteClient,
     pub api_key: String,
     pub api_secret: String,
     pub access_token: Option<String>,
@@ -12,18 +10,18 @@ pub struct AuthManager {
 }
 
 impl AuthManager {
-    pub fn new(api_key: String, api_secret: String) -> Self {
-        Self {
-            kite: KiteConnect::new(&api_key, ""),
+    pub fn new(api_key: String, api_secret: String) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            kite: KiteClient::new(&api_key)?,
             api_key,
             api_secret,
             access_token: Some(env::var("ACCESS_TOKEN").expect("access token not set!")),
             token_expiry: None
-        }
+        })
     }
 
     pub fn set_access_token(&mut self, access_token: String) {
-        self.kite = KiteConnect::new(&self.api_key, &access_token);
+        self.kite = self.kite.with_access_token(&access_token);
         self.access_token = Some(access_token);
         self.token_expiry = Some(Instant::now() + Duration::from_secs(12 * 60 * 60));
     }
@@ -35,7 +33,7 @@ impl AuthManager {
         }
     }
 
-    pub fn get_kite(&mut self) -> &KiteConnect {
+    pub fn get_kite(&mut self) -> &KiteClient {
         &self.kite
     }
 
@@ -84,35 +82,9 @@ impl AuthManager {
         println!("API secret: {}", self.api_secret);
         println!("== END DEBUG ==");
 
-        let checksum_input = format!("{}{}{}", self.api_key, request_token, self.api_secret);
-        let checksum = format!("{:x}", Sha256::digest(checksum_input.as_bytes()));
-        println!("Checksum: {:?}", checksum);
-
-        let client = Client::new();
-        let response = client.post("https://api.kite.trade/session/token")
-        .form(&[
-            ("api_key", &self.api_key),
-            ("api_secret", &self.api_secret),
-            ("checksum", &checksum)
-        ])
-        .send()
-        .await?
-        .json::<serde_json::Value>()
-        .await?;
-
-        if let Some(data) = response.get("data") {
-            if let Some(access_token) = data.get("access_token") {
-                let token_str = access_token.as_str().unwrap().to_string();
-                self.access_token = Some(token_str.clone());
-                println!("access token successfully stored: {}", token_str);
-                return Ok(());
-            }
-            else {
-                Err("access token wasn't stored".into())
-            }
-        }
-        else {
-            Err("Failed to get access token from API response".into())
-        }
+        let session = self.kite.generate_session(request_token, &self.api_secret).await?;
+        self.set_access_token(session.access_token.clone());
+        println!("access token successfully stored: {}", session.access_token);
+        Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,289 @@
+use std::time::Duration;
+use chrono::Utc;
+use serde::Serialize;
+use crate::{data_structures::TradeInstruction, kite_client::KiteClient, kite_models::{MarginOrder, Order, OrderParams}, market_protection::{MarketProtection, MarketQuote}, order_validation::validate_order, rate_limiter::Priority, trade_executor::{OrderPlan, TradeExecutor, ORDER_VARIETY}};
+
+/// Kite's basket margin API takes at most this many orders.
+pub const MAX_BASKET_LEGS: usize = 20;
+
+// How long to wait for the exchange to accept or reject a leg before moving on to the next one
+const ACCEPTANCE_TIMEOUT: Duration = Duration::from_secs(5);
+const ACCEPTANCE_POLL: Duration = Duration::from_millis(500);
+const ROLLBACK_TAG: &str = "basket-rollback";
+
+/// A leg that has been planned and is ready to place.
+pub struct BasketLeg {
+    pub instruction: TradeInstruction,
+    pub executor: TradeExecutor,
+    pub plan: OrderPlan
+}
+
+impl BasketLeg {
+    fn is_option(&self) -> bool {
+        self.executor.instrument.as_ref().is_some_and(|i| matches!(i.instrument_type.as_str(), "CE" | "PE"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LegStatus {
+    NotPlaced,
+    Placed,
+    Rejected,
+    /// Rolled back before anything filled
+    Cancelled,
+    /// Rolled back with an opposite order for what had filled
+    Reversed,
+    RollbackFailed
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LegReport {
+    pub index: usize,
+    pub symbol: String,
+    pub transaction_type: String,
+    pub quantity: u32,
+    pub price: Option<f64>,
+    pub status: LegStatus,
+    pub order_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reversal_order_ids: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BasketStatus {
+    Placed,
+    /// Not enough margin for the basket as a whole, nothing was sent
+    InsufficientMargin,
+    /// A leg was rejected and every leg before it was cancelled or reversed
+    RolledBack,
+    /// A leg was rejected and at least one earlier leg could not be undone
+    RollbackIncomplete
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BasketMargin {
+    /// Margin for the basket after existing positions, with hedges offset
+    pub required: f64,
+    /// Margin for the basket on its own
+    pub initial: f64,
+    pub available: Option<f64>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BasketReport {
+    pub status: BasketStatus,
+    pub margin: BasketMargin,
+    pub legs: Vec<LegReport>,
+    pub timestamp: String
+}
+
+impl BasketReport {
+    pub fn insufficient_margin(legs: &[BasketLeg], margin: BasketMargin) -> Self {
+        Self { status: BasketStatus::InsufficientMargin, margin, legs: leg_reports(legs), timestamp: Utc::now().to_rfc3339() }
+    }
+}
+
+fn leg_reports(legs: &[BasketLeg]) -> Vec<LegReport> {
+    legs.iter().enumerate().map(|(index, leg)| LegReport {
+        index,
+        symbol: format!("{}:{}", leg.plan.params.exchange, leg.plan.params.tradingsymbol),
+        transaction_type: leg.plan.params.transaction_type.clone(),
+        quantity: leg.plan.params.quantity,
+        price: leg.plan.params.price,
+        status: LegStatus::NotPlaced,
+        order_ids: Vec::new(),
+        reversal_order_ids: Vec::new(),
+        error: None
+    }).collect()
+}
+
+/// Hedges go first so the short legs after them get the hedge benefit: option buys, then sells,
+/// which also free cash in a rebalance, then the remaining buys. Order within a group is kept.
+pub fn placement_order(legs: &[(bool, bool)]) -> Vec<usize> {
+    let rank = |(option, buying): (bool, bool)| match (option, buying) {
+        (true, true) => 0,
+        (_, false) => 1,
+        (false, true) => 2
+    };
+    let mut order: Vec<usize> = (0..legs.len()).collect();
+    order.sort_by_key(|i| rank(legs[*i]));
+    order
+}
+
+/// Prices the basket as a whole with Kite, counting existing positions, against the free equity margin.
+pub async fn check_margin(kite: &KiteClient, legs: &[BasketLeg]) -> Result<BasketMargin, anyhow::Error> {
+    let orders: Vec<MarginOrder> = legs.iter().map(|leg| MarginOrder::from_params(ORDER_VARIETY, &leg.plan.params)).collect();
+    let margins = kite.basket_margins(&orders, true).await?;
+    let funds = kite.margins().await?;
+
+    Ok(BasketMargin {
+        required: margins.final_margin.total,
+        initial: margins.initial.total,
+        available: funds.equity.map(|equity| equity.net)
+    })
+}
+
+/// Places the legs hedges first. If one is rejected, the legs already placed are undone in
+/// reverse: open orders are cancelled and anything filled is closed with a protected limit order.
+pub async fn place_basket(kite: &KiteClient, legs: &[BasketLeg], margin: BasketMargin, protection: MarketProtection) -> BasketReport {
+    let mut reports = leg_reports(legs);
+    let order = placement_order(&legs.iter().map(|leg| (leg.is_option(), leg.plan.params.transaction_type == "BUY")).collect::<Vec<_>>());
+    let mut placed = Vec::new();
+
+    for index in order {
+        let leg = &legs[index];
+        let result = match place(kite, &leg.plan, leg.executor.priority(&leg.plan.params), &mut reports[index].order_ids).await {
+            Ok(()) => accepted(kite, &reports[index].order_ids).await,
+            Err(e) => Err(e.to_string())
+        };
+
+        placed.push(index);
+        match result {
+            Ok(()) => reports[index].status = LegStatus::Placed,
+            Err(e) => {
+                println!("Basket leg {} ({}) failed, rolling back: {}", index, reports[index].symbol, e);
+                reports[index].status = LegStatus::Rejected;
+                reports[index].error = Some(e);
+
+                let mut complete = true;
+                for index in placed.into_iter().rev() {
+                    complete &= rollback(kite, &legs[index], &mut reports[index], protection).await;
+                }
+                let status = if complete { BasketStatus::RolledBack } else { BasketStatus::RollbackIncomplete };
+                return BasketReport { status, margin, legs: reports, timestamp: Utc::now().to_rfc3339() };
+            }
+        }
+    }
+
+    BasketReport { status: BasketStatus::Placed, margin, legs: reports, timestamp: Utc::now().to_rfc3339() }
+}
+
+// Unlike `TradeExecutor::place_plan`, every child placed is recorded even when a later one fails, so a rollback covers it
+async fn place(kite: &KiteClient, plan: &OrderPlan, priority: Priority, order_ids: &mut Vec<String>) -> Result<(), anyhow::Error> {
+    for child in plan.child_orders() {
+        order_ids.push(kite.place_order(ORDER_VARIETY, &child, priority).await?);
+    }
+    Ok(())
+}
+
+// Kite returns an order ID as soon as it takes the order; the exchange may still reject it
+async fn accepted(kite: &KiteClient, order_ids: &[String]) -> Result<(), String> {
+    for order_id in order_ids {
+        let started = tokio::time::Instant::now();
+        loop {
+            match latest(kite, order_id).await {
+                Ok(order) if matches!(order.status.as_str(), "REJECTED" | "CANCELLED") => {
+                    return Err(format!("Order {} {}: {}", order_id, order.status, order.status_message.unwrap_or_default()));
+                },
+                Ok(order) if matches!(order.status.as_str(), "OPEN" | "COMPLETE" | "TRIGGER PENDING") => break,
+                Ok(_) | Err(_) if started.elapsed() < ACCEPTANCE_TIMEOUT => tokio::time::sleep(ACCEPTANCE_POLL).await,
+                // Still with the exchange; treat it as live so a rollback would cover it
+                _ => break
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn latest(kite: &KiteClient, order_id: &str) -> Result<Order, anyhow::Error> {
+    kite.order_history(order_id).await?.pop().ok_or_else(|| anyhow::anyhow!("No history for order {}", order_id))
+}
+
+// True when the leg is flat again
+async fn rollback(kite: &KiteClient, leg: &BasketLeg, report: &mut LegReport, protection: MarketProtection) -> bool {
+    let mut filled = 0;
+    let mut errors = Vec::new();
+
+    for order_id in &report.order_ids {
+        let mut order = match latest(kite, order_id).await {
+            Ok(order) => order,
+            Err(e) => {
+                errors.push(format!("Could not load order {}: {}", order_id, e));
+                continue;
+            }
+        };
+        if !matches!(order.status.as_str(), "COMPLETE" | "REJECTED" | "CANCELLED") {
+            if let Err(e) = kite.cancel_order(ORDER_VARIETY, order_id).await {
+                errors.push(format!("Could not cancel order {}: {}", order_id, e));
+            }
+            // Anything that filled before the cancel landed still has to be reversed
+            if let Ok(after) = latest(kite, order_id).await {
+                order = after;
+            }
+        }
+        filled += order.filled_quantity;
+    }
+
+    if filled > 0 {
+        match reverse(kite, leg, filled, protection).await {
+            Ok(order_ids) => report.reversal_order_ids = order_ids,
+            Err(e) => errors.push(format!("Could not reverse {} filled: {}", filled, e))
+        }
+    }
+
+    if report.status != LegStatus::Rejected {
+        report.status = match (errors.is_empty(), filled > 0) {
+            (false, _) => LegStatus::RollbackFailed,
+            (true, true) => LegStatus::Reversed,
+            (true, false) => LegStatus::Cancelled
+        };
+    }
+    if !errors.is_empty() {
+        let error = errors.join("; ");
+        println!("Basket rollback of {} incomplete: {}", report.symbol, error);
+        report.error = Some(match report.error.take() {
+            Some(reason) => format!("{}; {}", reason, error),
+            None => error
+        });
+    }
+    errors.is_empty()
+}
+
+async fn reverse(kite: &KiteClient, leg: &BasketLeg, quantity: u32, protection: MarketProtection) -> Result<Vec<String>, anyhow::Error> {
+    let params = &leg.plan.params;
+    let key = format!("{}:{}", params.exchange, params.tradingsymbol);
+    let buying = params.transaction_type != "BUY";
+    let quote = kite.quote(std::slice::from_ref(&key)).await?.remove(&key)
+        .ok_or_else(|| anyhow::anyhow!("No quote for {}", key))?;
+    let protection = if protection.is_enabled() { protection } else { MarketProtection::default() };
+    let (price, _) = protection.limit_price(buying, &MarketQuote::from_quote(&quote), leg.executor.tick_size())?;
+
+    let reversal = OrderPlan {
+        params: OrderParams {
+            transaction_type: if buying { "BUY" } else { "SELL" }.to_string(),
+            quantity,
+            order_type: "LIMIT".to_string(),
+            price: Some(price),
+            trigger_price: None,
+            tag: Some(ROLLBACK_TAG.to_string()),
+            ..params.clone()
+        },
+        slices: match &leg.executor.instrument {
+            Some(instrument) => validate_order(instrument, buying, Some(price), quantity, false, leg.executor.freeze_limit)?.slices,
+            None => vec![quantity]
+        },
+        adjustments: Vec::new()
+    };
+    let mut order_ids = Vec::new();
+    match place(kite, &reversal, Priority::Exit, &mut order_ids).await {
+        Ok(()) => Ok(order_ids),
+        Err(e) if order_ids.is_empty() => Err(e),
+        Err(e) => Err(e.context(format!("Reversal partly placed as {}", order_ids.join(", "))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hedges_go_first_then_sells_then_buys() {
+        // (option, buying): a short strangle with wings, plus a cash rebalance
+        let legs = [(true, false), (true, true), (false, true), (false, false), (true, true), (true, false)];
+        assert_eq!(placement_order(&legs), vec![1, 4, 0, 3, 5, 2]);
+    }
+}
@@ -0,0 +1,258 @@
+use serde::{Deserialize, Serialize};
+use crate::kite_models::{GstCharges, OrderCharges};
+
+/// GST on brokerage, exchange transaction charges and SEBI fees, in percent.
+pub const GST_PCT: f64 = 18.0;
+/// SEBI turnover fee, ₹10 a crore.
+pub const SEBI_FEE_PCT: f64 = 0.0001;
+/// CDSL charge per scrip on a day with delivery sells, before GST.
+pub const DP_CHARGE: f64 = 13.5;
+const MAX_BROKERAGE: f64 = 20.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Segment {
+    EquityDelivery,
+    EquityIntraday,
+    EquityFutures,
+    EquityOptions,
+    CurrencyFutures,
+    CurrencyOptions,
+    CommodityFutures,
+    CommodityOptions
+}
+
+impl Segment {
+    /// The fee segment of an order from its exchange, instrument type (`EQ`, `FUT`, `CE`, `PE`) and product.
+    pub fn of(exchange: &str, instrument_type: &str, product: &str) -> Result<Self, anyhow::Error> {
+        let option = matches!(instrument_type, "CE" | "PE");
+        let intraday = product.trim().eq_ignore_ascii_case("MIS");
+        match exchange {
+            "NSE" | "BSE" if intraday => Ok(Segment::EquityIntraday),
+            "NSE" | "BSE" => Ok(Segment::EquityDelivery),
+            "NFO" | "BFO" if option => Ok(Segment::EquityOptions),
+            "NFO" | "BFO" => Ok(Segment::EquityFutures),
+            "CDS" | "BCD" if option => Ok(Segment::CurrencyOptions),
+            "CDS" | "BCD" => Ok(Segment::CurrencyFutures),
+            "MCX" if option => Ok(Segment::CommodityOptions),
+            "MCX" => Ok(Segment::CommodityFutures),
+            _ => Err(anyhow::anyhow!("No fee schedule for exchange {}", exchange))
+        }
+    }
+
+    // Zerodha's published rates, as percentages of turnover (premium turnover for options)
+    fn schedule(&self, exchange: &str) -> Schedule {
+        let bse = matches!(exchange, "BSE" | "BFO" | "BCD");
+        match self {
+            Segment::EquityDelivery => Schedule {
+                brokerage: Brokerage::Free, tax_type: "stt", tax_buy_pct: 0.1, tax_sell_pct: 0.1,
+                exchange_pct: if bse { 0.00375 } else { 0.00297 }, stamp_buy_pct: 0.015
+            },
+            Segment::EquityIntraday => Schedule {
+                brokerage: Brokerage::Percent(0.03), tax_type: "stt", tax_buy_pct: 0.0, tax_sell_pct: 0.025,
+                exchange_pct: if bse { 0.00375 } else { 0.00297 }, stamp_buy_pct: 0.003
+            },
+            Segment::EquityFutures => Schedule {
+                brokerage: Brokerage::Percent(0.03), tax_type: "stt", tax_buy_pct: 0.0, tax_sell_pct: 0.02,
+                exchange_pct: if bse { 0.0 } else { 0.00173 }, stamp_buy_pct: 0.002
+            },
+            Segment::EquityOptions => Schedule {
+                brokerage: Brokerage::Flat, tax_type: "stt", tax_buy_pct: 0.0, tax_sell_pct: 0.1,
+                exchange_pct: if bse { 0.0325 } else { 0.03503 }, stamp_buy_pct: 0.003
+            },
+            Segment::CurrencyFutures => Schedule {
+                brokerage: Brokerage::Percent(0.03), tax_type: "", tax_buy_pct: 0.0, tax_sell_pct: 0.0,
+                exchange_pct: if bse { 0.00045 } else { 0.00035 }, stamp_buy_pct: 0.0001
+            },
+            Segment::CurrencyOptions => Schedule {
+                brokerage: Brokerage::Flat, tax_type: "", tax_buy_pct: 0.0, tax_sell_pct: 0.0,
+                exchange_pct: if bse { 0.001 } else { 0.0311 }, stamp_buy_pct: 0.0001
+            },
+            Segment::CommodityFutures => Schedule {
+                brokerage: Brokerage::Percent(0.03), tax_type: "ctt", tax_buy_pct: 0.0, tax_sell_pct: 0.01,
+                exchange_pct: 0.0021, stamp_buy_pct: 0.002
+            },
+            Segment::CommodityOptions => Schedule {
+                brokerage: Brokerage::Flat, tax_type: "ctt", tax_buy_pct: 0.0, tax_sell_pct: 0.05,
+                exchange_pct: 0.0418, stamp_buy_pct: 0.003
+            }
+        }
+    }
+}
+
+enum Brokerage {
+    Free,
+    /// ₹20 an executed order
+    Flat,
+    /// This percentage of turnover, up to ₹20 an executed order
+    Percent(f64)
+}
+
+struct Schedule {
+    brokerage: Brokerage,
+    tax_type: &'static str,
+    tax_buy_pct: f64,
+    tax_sell_pct: f64,
+    exchange_pct: f64,
+    stamp_buy_pct: f64
+}
+
+/// What one executed order costs. STT/CTT and stamp duty are rounded to the rupee as contract
+/// notes do, everything else to the paisa.
+#[derive(Debug, Clone, Serialize)]
+pub struct Charges {
+    pub segment: Segment,
+    pub turnover: f64,
+    pub brokerage: f64,
+    pub transaction_tax: f64,
+    /// `stt`, `ctt`, or empty for currency
+    pub transaction_tax_type: String,
+    pub exchange_turnover_charge: f64,
+    pub sebi_turnover_charge: f64,
+    pub stamp_duty: f64,
+    pub gst: f64,
+    /// Delivery sells only, once per scrip per day
+    pub dp_charges: f64,
+    pub total: f64
+}
+
+/// Rounds a rupee amount to the nearest paisa.
+pub fn paise(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+/// Charges on one executed order of `quantity` units at `price`.
+pub fn order_charges(segment: Segment, exchange: &str, buying: bool, price: f64, quantity: u32) -> Charges {
+    let schedule = segment.schedule(exchange);
+    let turnover = price * quantity as f64;
+    let pct = |rate: f64| turnover * rate / 100.0;
+
+    let brokerage = paise(match schedule.brokerage {
+        Brokerage::Free => 0.0,
+        Brokerage::Flat => MAX_BROKERAGE,
+        Brokerage::Percent(rate) => pct(rate).min(MAX_BROKERAGE)
+    });
+    let transaction_tax = pct(if buying { schedule.tax_buy_pct } else { schedule.tax_sell_pct }).round();
+    let exchange_turnover_charge = paise(pct(schedule.exchange_pct));
+    let sebi_turnover_charge = paise(pct(SEBI_FEE_PCT));
+    let stamp_duty = if buying { pct(schedule.stamp_buy_pct).round() } else { 0.0 };
+    let gst = paise((brokerage + exchange_turnover_charge + sebi_turnover_charge) * GST_PCT / 100.0);
+    let dp_charges = if segment == Segment::EquityDelivery && !buying { paise(DP_CHARGE * (1.0 + GST_PCT / 100.0)) } else { 0.0 };
+
+    Charges {
+        segment,
+        turnover: paise(turnover),
+        brokerage,
+        transaction_tax,
+        transaction_tax_type: schedule.tax_type.to_string(),
+        exchange_turnover_charge,
+        sebi_turnover_charge,
+        stamp_duty,
+        gst,
+        dp_charges,
+        total: paise(brokerage + transaction_tax + exchange_turnover_charge + sebi_turnover_charge + stamp_duty + gst + dp_charges)
+    }
+}
+
+/// A buy and a sell of the same quantity, with the P&L after charges.
+#[derive(Debug, Clone, Serialize)]
+pub struct RoundTrip {
+    pub buy: Charges,
+    pub sell: Charges,
+    pub total_charges: f64,
+    pub gross_pnl: f64,
+    pub net_pnl: f64,
+    /// How far the price has to move, per unit, to cover the charges
+    pub points_to_breakeven: f64
+}
+
+pub fn round_trip(segment: Segment, exchange: &str, quantity: u32, buy_price: f64, sell_price: f64) -> RoundTrip {
+    let buy = order_charges(segment, exchange, true, buy_price, quantity);
+    let sell = order_charges(segment, exchange, false, sell_price, quantity);
+    let total_charges = paise(buy.total + sell.total);
+    let gross_pnl = paise((sell_price - buy_price) * quantity as f64);
+    RoundTrip {
+        buy,
+        sell,
+        total_charges,
+        gross_pnl,
+        net_pnl: paise(gross_pnl - total_charges),
+        points_to_breakeven: if quantity > 0 { paise(total_charges / quantity as f64) } else { 0.0 }
+    }
+}
+
+/// Charges on an order sent as several child orders of `quantities`, each paying its own brokerage.
+/// DP charges are per scrip per day, so they are counted once.
+pub fn split_order_charges(segment: Segment, exchange: &str, buying: bool, price: f64, quantities: &[u32]) -> Option<Charges> {
+    let mut children = quantities.iter().map(|&quantity| order_charges(segment, exchange, buying, price, quantity));
+    let mut sum = children.next()?;
+    for child in children {
+        sum.turnover = paise(sum.turnover + child.turnover);
+        sum.brokerage = paise(sum.brokerage + child.brokerage);
+        sum.transaction_tax += child.transaction_tax;
+        sum.exchange_turnover_charge = paise(sum.exchange_turnover_charge + child.exchange_turnover_charge);
+        sum.sebi_turnover_charge = paise(sum.sebi_turnover_charge + child.sebi_turnover_charge);
+        sum.stamp_duty += child.stamp_duty;
+        sum.gst = paise(sum.gst + child.gst);
+        sum.total = paise(sum.total + child.total - child.dp_charges);
+    }
+    Some(sum)
+}
+
+impl From<&Charges> for OrderCharges {
+    /// In the shape Kite returns charges with margins; Kite has no DP field, so those are only in `total`.
+    fn from(charges: &Charges) -> Self {
+        OrderCharges {
+            transaction_tax: charges.transaction_tax,
+            transaction_tax_type: charges.transaction_tax_type.clone(),
+            exchange_turnover_charge: charges.exchange_turnover_charge,
+            sebi_turnover_charge: charges.sebi_turnover_charge,
+            brokerage: charges.brokerage,
+            stamp_duty: charges.stamp_duty,
+            gst: GstCharges { igst: charges.gst, cgst: 0.0, sgst: 0.0, total: charges.gst },
+            total: charges.total
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intraday_round_trip_matches_the_published_calculator() {
+        // 1000 shares bought at 100 and sold at 101 on NSE
+        let trip = round_trip(Segment::EquityIntraday, "NSE", 1000, 100.0, 101.0);
+        assert_eq!(trip.buy.brokerage + trip.sell.brokerage, 40.0);
+        assert_eq!(trip.sell.transaction_tax, 25.0);
+        assert_eq!(trip.buy.transaction_tax, 0.0);
+        assert_eq!(trip.buy.stamp_duty, 3.0);
+        assert!((trip.buy.exchange_turnover_charge + trip.sell.exchange_turnover_charge - 5.97).abs() < 1e-9);
+        assert!((trip.total_charges - 82.48).abs() < 0.02, "total {}", trip.total_charges);
+        assert!((trip.net_pnl - (1000.0 - trip.total_charges)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn segments_follow_exchange_product_and_type() {
+        assert_eq!(Segment::of("NSE", "EQ", "CNC").unwrap(), Segment::EquityDelivery);
+        assert_eq!(Segment::of("NSE", "EQ", " mis").unwrap(), Segment::EquityIntraday);
+        assert_eq!(Segment::of("NFO", "PE", "NRML").unwrap(), Segment::EquityOptions);
+        assert_eq!(Segment::of("MCX", "FUT", "NRML").unwrap(), Segment::CommodityFutures);
+        assert!(Segment::of("NYSE", "EQ", "CNC").is_err());
+
+        // Options pay a flat ₹20 and STT on the sold premium; only delivery sells pay DP charges
+        let sell = order_charges(Segment::EquityOptions, "NFO", false, 100.0, 50);
+        assert_eq!((sell.brokerage, sell.transaction_tax, sell.dp_charges), (20.0, 5.0, 0.0));
+        assert_eq!(order_charges(Segment::EquityDelivery, "NSE", false, 1500.0, 10).dp_charges, 15.93);
+    }
+
+    #[test]
+    fn split_orders_pay_brokerage_per_child() {
+        let split = split_order_charges(Segment::EquityOptions, "NFO", true, 100.0, &[1800, 1800, 400]).unwrap();
+        let whole = order_charges(Segment::EquityOptions, "NFO", true, 100.0, 4000);
+        assert_eq!(split.brokerage, 60.0);
+        assert_eq!(split.turnover, whole.turnover);
+        assert!((split.total - whole.total - 40.0 * (1.0 + GST_PCT / 100.0)).abs() < 2.0, "split {} whole {}", split.total, whole.total);
+        assert!(split_order_charges(Segment::EquityOptions, "NFO", true, 100.0, &[]).is_none());
+    }
+}
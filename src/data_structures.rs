@@ -1,8 +1,11 @@
-use std::sync::{Arc, Mutex};
+use std::{collections::BTreeMap, sync::Arc};
+use chrono::NaiveDate;
 use serde::{Deserialize, Serialize};
-use crate::{auth_manager::AuthManager, market_data::MarketData};
+use serde_json::Value;
+use tokio::sync::Mutex;
+use crate::{auth_manager::AuthManager, basket::BasketReport, charges::Segment, execution_algos::{AlgoParams, AlgoStore}, idempotency::IdempotencyStore, kite_models::{GttParams, OrderCharges, OrderMargin, OrderParams}, market_data::{MarketData, SkippedSymbol}, market_protection::MarketProtection, option_pricing::{ExerciseStyle, Greeks, OptionKind}, option_strategy::StrategySpec, price_cache::PriceCache, proposals::ProposalStore, rollover::Rollover, square_off::SquareOff};
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TradeInstruction {
     pub action: String,
     pub symbol: String,
@@ -13,10 +16,25 @@ pub struct TradeInstruction {
     pub stop_loss: Option<f64>,
     pub target: Option<f64>,
     pub order_id: Option<String>,
-    pub timeframe: Option<u64>
+    pub timeframe: Option<u64>,
+    pub watchlist: Option<String>,
+    /// Repeats with the same key inside the window return the first response; also sent as the Kite order tag
+    pub idempotency_key: Option<String>,
+    /// Round off-tick prices and part lots in the safe direction instead of rejecting the order
+    #[serde(default)]
+    pub auto_adjust: bool,
+    /// `CNC` (delivery), `MIS` (intraday) or `NRML` (F&O carry forward); CNC or NRML by segment when absent
+    pub product: Option<String>
 }
 
-#[derive(Debug, Serialize)]
+impl TradeInstruction {
+    /// Whether this is a market order; `price_type` is matched the way `/trade` accepts it, in any case.
+    pub fn is_market(&self) -> bool {
+        self.price_type.trim().eq_ignore_ascii_case("MARKET")
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct TradeResponse {
     pub order_id: String,
     pub status: String,
@@ -24,16 +42,234 @@ pub struct TradeResponse {
     pub symbol: String,
     pub quantity: u32,
     pub price: f64,
-    pub timestamp: String
+    pub timestamp: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Every order placed when the quantity was split at the freeze limit, `order_id` first
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub child_order_ids: Vec<String>,
+    /// The GTT holding the stop loss and target
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gtt_id: Option<u64>
+}
+
+/// Everything `/trade` would do for an instruction, without placing it.
+#[derive(Debug, Clone, Serialize)]
+pub struct TradePreview {
+    pub status: String,
+    pub variety: String,
+    pub order: OrderParams,
+    pub instrument_token: Option<u32>,
+    pub tick_size: Option<f64>,
+    pub lot_size: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub child_quantities: Vec<u32>,
+    pub reference_price: Option<f64>,
+    pub notional: Option<f64>,
+    pub margin: MarginEstimate,
+    pub charges: Option<OrderCharges>,
+    pub exposure: Option<Exposure>,
+    /// The stop loss and target GTT placed once the order is in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_gtt: Option<GttParams>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MarginEstimate {
+    /// `kite` when priced by Kite's order margins API, `local` when estimated from the notional
+    pub source: String,
+    pub required: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breakdown: Option<OrderMargin>
+}
+
+/// The account's position in the instrument and its cash, before and after the order fills.
+#[derive(Debug, Clone, Serialize)]
+pub struct Exposure {
+    pub holding_quantity: i64,
+    pub position_quantity: i64,
+    pub post_trade_quantity: i64,
+    pub post_trade_value: Option<f64>,
+    pub available_cash: Option<f64>,
+    pub cash_after: Option<f64>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BasketRequest {
+    pub legs: Vec<TradeInstruction>,
+    /// Makes the whole basket safe to retry; the legs' own keys only tag their orders
+    pub idempotency_key: Option<String>
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AlgoRequest {
+    #[serde(flatten)]
+    pub instruction: TradeInstruction,
+    pub algo: AlgoParams
+}
+
+/// The approver is whoever holds the `X-Approver-Key`, not anything named in the body.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ProposalDecision {
+    pub reason: Option<String>
 }
 
 #[derive(Debug, Serialize)]
 pub struct ErrorResponse {
     pub status: String,
-    pub message: String
+    pub code: String,
+    pub message: String,
+    pub retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>
+}
+
+impl ErrorResponse {
+    pub fn new(code: &str, message: String) -> Self {
+        Self {
+            status: "Error".to_string(),
+            code: code.to_string(),
+            message,
+            retryable: false,
+            retry_after_secs: None
+        }
+    }
 }
 
 pub struct AppState {
     pub auth_manager: Mutex<AuthManager>,
-    pub market_data: Arc<Mutex<MarketData>>
-}
\ No newline at end of file
+    pub market_data: Arc<Mutex<MarketData>>,
+    pub prices: Arc<PriceCache>,
+    pub idempotency: IdempotencyStore,
+    /// Algo keys replay the ID of the algo they started
+    pub algo_idempotency: IdempotencyStore<AlgoRequest, String>,
+    pub basket_idempotency: IdempotencyStore<BasketRequest, BasketReport>,
+    pub proposals: ProposalStore,
+    pub square_off: SquareOff,
+    pub market_protection: MarketProtection,
+    pub algos: AlgoStore,
+    pub risk_free_rate: f64,
+    pub rollover: Rollover
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWatchlistRequest {
+    pub name: String,
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub symbols: Vec<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WatchlistSymbolsRequest {
+    pub symbols: Vec<String>
+}
+
+/// Prices an option from a volatility, or solves the volatility from a price; exactly one of the two.
+#[derive(Debug, Deserialize)]
+pub struct PricingRequest {
+    pub kind: OptionKind,
+    pub spot: f64,
+    pub strike: f64,
+    pub expiry: NaiveDate,
+    /// European when absent
+    pub style: Option<ExerciseStyle>,
+    /// `RISK_FREE_RATE` when absent
+    pub rate: Option<f64>,
+    pub dividend_yield: Option<f64>,
+    pub volatility: Option<f64>,
+    pub price: Option<f64>
+}
+
+#[derive(Debug, Serialize)]
+pub struct PricingResponse {
+    pub years_to_expiry: f64,
+    pub volatility: f64,
+    pub price: f64,
+    pub greeks: Greeks
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StrategyRequest {
+    pub underlying: String,
+    /// The nearest expiry when absent
+    pub expiry: Option<NaiveDate>,
+    pub dividend_yield: Option<f64>,
+    /// Passed through to the basket legs
+    pub product: Option<String>,
+    #[serde(flatten)]
+    pub spec: StrategySpec
+}
+
+/// Charges for a buy, a sell, or both as a round trip when both prices are given.
+#[derive(Debug, Deserialize)]
+pub struct ChargesRequest {
+    pub exchange: String,
+    /// Looked up in the instrument master to tell futures from options; needed unless `segment` is given
+    pub symbol: Option<String>,
+    pub segment: Option<Segment>,
+    /// CNC when absent; only MIS changes the equity rates
+    pub product: Option<String>,
+    pub quantity: u32,
+    pub buy_price: Option<f64>,
+    pub sell_price: Option<f64>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RolloverQuery {
+    /// Overrides `ROLLOVER_DRY_RUN` for this run
+    pub dry_run: Option<bool>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChainQuery {
+    pub expiry: Option<NaiveDate>,
+    /// How many strikes around the spot to include; the full chain when it fits in one quote request
+    pub strikes: Option<usize>,
+    /// Continuous annualised yield of the underlying, 0 when absent
+    pub dividend_yield: Option<f64>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RankingQuery {
+    pub timeframe: Option<u64>
+}
+
+#[derive(Debug, Serialize)]
+pub struct PerformanceEntry {
+    pub symbol: String,
+    pub performance: f64
+}
+
+#[derive(Debug, Serialize)]
+pub struct RankingResponse {
+    pub watchlist: String,
+    pub ranking: Vec<PerformanceEntry>,
+    /// Members that could not be ranked: unknown to the instrument master, or without history
+    pub skipped: Vec<SkippedSymbol>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StreamQuery {
+    pub symbols: Option<String>
+}
+
+// Mirrors Kite's own websocket protocol: {"a": "subscribe", "v": ["NSE:INFY"]}
+#[derive(Debug, Deserialize)]
+pub struct StreamCommand {
+    pub a: String,
+    #[serde(default)]
+    pub v: Vec<String>
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuoteResponse {
+    pub status: String,
+    pub data: BTreeMap<String, Value>,
+    pub missing: Vec<String>
+}
@@ -0,0 +1,196 @@
+use std::{fmt, time::Duration};
+use actix_web::{http::StatusCode, HttpResponse};
+use crate::data_structures::ErrorResponse;
+
+/// What went wrong talking to Kite. The first nine mirror Kite's own `error_type` values,
+/// the rest cover failures that never produced a Kite error body or were stopped before sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KiteErrorKind {
+    Token,
+    User,
+    Order,
+    Input,
+    Margin,
+    Holding,
+    Network,
+    Data,
+    General,
+    RateLimited,
+    Timeout,
+    Unavailable,
+    BadResponse,
+    DailyLimit
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KiteError {
+    pub kind: KiteErrorKind,
+    pub message: String
+}
+
+impl KiteError {
+    pub fn new(kind: KiteErrorKind, message: impl Into<String>) -> Self {
+        Self { kind, message: message.into() }
+    }
+
+    /// Classifies an error envelope. HTTP 429 wins over whatever `error_type` came with it.
+    pub fn from_response(http_status: u16, error_type: Option<&str>, message: String) -> Self {
+        let kind = match (http_status, error_type) {
+            (429, _) => KiteErrorKind::RateLimited,
+            (_, Some("TokenException")) => KiteErrorKind::Token,
+            (_, Some("UserException")) | (_, Some("PermissionException")) => KiteErrorKind::User,
+            (_, Some("OrderException")) => KiteErrorKind::Order,
+            (_, Some("InputException")) => KiteErrorKind::Input,
+            (_, Some("MarginException")) => KiteErrorKind::Margin,
+            (_, Some("HoldingException")) => KiteErrorKind::Holding,
+            (_, Some("NetworkException")) => KiteErrorKind::Network,
+            (_, Some("DataException")) => KiteErrorKind::Data,
+            (403, None) => KiteErrorKind::Token,
+            (500..=599, None) => KiteErrorKind::Unavailable,
+            _ => KiteErrorKind::General
+        };
+        Self::new(kind, message)
+    }
+
+    pub fn from_transport(endpoint: &str, e: &reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Self::new(KiteErrorKind::Timeout, format!("Kite {} request timed out", endpoint))
+        }
+        else {
+            Self::new(KiteErrorKind::Unavailable, format!("Kite {} request failed: {}", endpoint, e))
+        }
+    }
+
+    /// Stable machine-readable code for API clients.
+    pub fn code(&self) -> &'static str {
+        match self.kind {
+            KiteErrorKind::Token => "session_expired",
+            KiteErrorKind::User => "account_restricted",
+            KiteErrorKind::Order => "order_rejected",
+            KiteErrorKind::Input => "invalid_input",
+            KiteErrorKind::Margin => "insufficient_margin",
+            KiteErrorKind::Holding => "insufficient_holdings",
+            KiteErrorKind::Network => "exchange_unreachable",
+            KiteErrorKind::Data => "upstream_data_error",
+            KiteErrorKind::General => "upstream_error",
+            KiteErrorKind::RateLimited => "rate_limited",
+            KiteErrorKind::Timeout => "upstream_timeout",
+            KiteErrorKind::Unavailable => "upstream_unavailable",
+            KiteErrorKind::BadResponse => "bad_upstream_response",
+            KiteErrorKind::DailyLimit => "daily_order_limit"
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self.kind {
+            KiteErrorKind::Token => StatusCode::UNAUTHORIZED,
+            KiteErrorKind::User => StatusCode::FORBIDDEN,
+            KiteErrorKind::Input => StatusCode::BAD_REQUEST,
+            KiteErrorKind::Order | KiteErrorKind::Margin | KiteErrorKind::Holding => StatusCode::UNPROCESSABLE_ENTITY,
+            KiteErrorKind::RateLimited | KiteErrorKind::DailyLimit => StatusCode::TOO_MANY_REQUESTS,
+            KiteErrorKind::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            KiteErrorKind::Network | KiteErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            KiteErrorKind::Data | KiteErrorKind::General | KiteErrorKind::BadResponse => StatusCode::BAD_GATEWAY
+        }
+    }
+
+    /// Whether the same request can simply be sent again. A timed out order may still have
+    /// reached the exchange, so callers placing orders should check the order book first.
+    pub fn retryable(&self) -> bool {
+        matches!(self.kind, KiteErrorKind::Network | KiteErrorKind::Data | KiteErrorKind::RateLimited | KiteErrorKind::Timeout | KiteErrorKind::Unavailable)
+    }
+
+    /// Whether Kite may have acted on a request that failed this way: the request went out but no
+    /// clear answer came back. Only rejections Kite spelled out, or ones stopped before sending, are
+    /// known not to have placed anything.
+    pub fn outcome_unknown(&self) -> bool {
+        matches!(self.kind, KiteErrorKind::Timeout | KiteErrorKind::Unavailable | KiteErrorKind::BadResponse | KiteErrorKind::Network | KiteErrorKind::Data | KiteErrorKind::General)
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self.kind {
+            KiteErrorKind::RateLimited => Some(Duration::from_secs(1)),
+            KiteErrorKind::Network | KiteErrorKind::Data | KiteErrorKind::Timeout | KiteErrorKind::Unavailable => Some(Duration::from_secs(2)),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for KiteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for KiteError {}
+
+/// The Kite error behind `e`, if there is one anywhere in its chain.
+pub fn kite_error(e: &anyhow::Error) -> Option<&KiteError> {
+    e.chain().find_map(|cause| cause.downcast_ref::<KiteError>())
+}
+
+/// Kite failures keep their own status and code; anything else failed on our side.
+pub fn error_response(context: &str, e: anyhow::Error) -> HttpResponse {
+    kite_error_response(context, &e)
+        .unwrap_or_else(|| HttpResponse::InternalServerError().json(ErrorResponse::new("internal_error", format!("{}: {}", context, e))))
+}
+
+/// For calls whose own errors all come from checking the request, like order validation:
+/// Kite failures as in `error_response`, anything else is the caller's request.
+pub fn request_error_response(context: &str, e: anyhow::Error) -> HttpResponse {
+    kite_error_response(context, &e)
+        .unwrap_or_else(|| HttpResponse::BadRequest().json(ErrorResponse::new("invalid_request", format!("{}: {}", context, e))))
+}
+
+fn kite_error_response(context: &str, e: &anyhow::Error) -> Option<HttpResponse> {
+    let kite = kite_error(e)?;
+    let mut response = HttpResponse::build(kite.status());
+    if let Some(retry_after) = kite.retry_after() {
+        response.insert_header(("Retry-After", retry_after.as_secs().to_string()));
+    }
+    Some(response.json(ErrorResponse {
+        status: "Error".to_string(),
+        code: kite.code().to_string(),
+        message: format!("{}: {}", context, e),
+        retryable: kite.retryable(),
+        retry_after_secs: kite.retry_after().map(|d| d.as_secs())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kite_error_types_map_to_codes_and_statuses() {
+        let margin = KiteError::from_response(400, Some("MarginException"), "Insufficient funds".to_string());
+        assert_eq!((margin.code(), margin.status(), margin.retryable()), ("insufficient_margin", StatusCode::UNPROCESSABLE_ENTITY, false));
+
+        let token = KiteError::from_response(403, Some("TokenException"), "Invalid session".to_string());
+        assert_eq!((token.code(), token.status()), ("session_expired", StatusCode::UNAUTHORIZED));
+
+        let network = KiteError::from_response(503, Some("NetworkException"), "OMS unreachable".to_string());
+        assert!(network.retryable());
+
+        let throttled = KiteError::from_response(429, Some("NetworkException"), "Too many requests".to_string());
+        assert_eq!(throttled.kind, KiteErrorKind::RateLimited);
+        assert!(!throttled.outcome_unknown() && !margin.outcome_unknown());
+        assert!(KiteError::new(KiteErrorKind::Timeout, "Kite orders request timed out").outcome_unknown());
+    }
+
+    #[test]
+    fn kite_errors_are_found_through_context() {
+        let e = anyhow::Error::new(KiteError::new(KiteErrorKind::Holding, "No holdings")).context("Failed to execute order");
+        assert_eq!(kite_error(&e).map(|k| k.code()), Some("insufficient_holdings"));
+        assert!(kite_error(&anyhow::anyhow!("Watchlist not found: x")).is_none());
+    }
+
+    #[test]
+    fn other_errors_are_ours_unless_they_come_from_the_request() {
+        assert_eq!(error_response("Failed to reload index universes", anyhow::anyhow!("Permission denied")).status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(request_error_response("Failed to execute order", anyhow::anyhow!("Limit price required for limit order")).status(), StatusCode::BAD_REQUEST);
+
+        let timeout = anyhow::Error::new(KiteError::new(KiteErrorKind::Timeout, "Kite orders request timed out"));
+        assert_eq!(request_error_response("Failed to execute order", timeout).status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+}
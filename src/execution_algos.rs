@@ -0,0 +1,550 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::Duration};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, time::Instant};
+use crate::{kite_client::KiteClient, kite_models::{OrderParams, Quote}, market_protection::{MarketProtection, MarketQuote}, order_validation::{is_derivative, round_to_tick}, rate_limiter::Priority, trade_executor::{OrderPlan, TradeExecutor, ORDER_VARIETY}};
+
+/// How often an algo wakes to check fills and send the next slice unless `interval_secs` says otherwise.
+pub const DEFAULT_ALGO_INTERVAL: Duration = Duration::from_secs(30);
+
+// Each wake costs a quote and an order history call per working child, so don't go faster than this
+const MIN_ALGO_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlgoKind {
+    /// Even slices over `duration_secs`
+    Twap,
+    /// Slices sized at `participation_rate` of the market volume traded since the last one
+    Vwap,
+    /// One `slice_size` child working at a time until the parent is filled
+    Iceberg
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlgoParams {
+    pub kind: AlgoKind,
+    pub duration_secs: Option<u64>,
+    pub interval_secs: Option<u64>,
+    pub participation_rate: Option<f64>,
+    /// Largest child order; required for iceberg
+    pub slice_size: Option<u32>,
+    /// Worst price any child is sent at, on top of the instruction's own limit price
+    pub price_limit: Option<f64>
+}
+
+impl AlgoParams {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let duration = self.duration_secs.filter(|secs| *secs > 0);
+        match self.kind {
+            AlgoKind::Twap | AlgoKind::Vwap if duration.is_none() => return Err(anyhow::anyhow!("duration_secs is required for {:?}", self.kind)),
+            AlgoKind::Vwap if !self.participation_rate.is_some_and(|rate| rate > 0.0 && rate <= 1.0) => {
+                return Err(anyhow::anyhow!("participation_rate between 0 and 1 is required for VWAP"));
+            },
+            AlgoKind::Iceberg if self.slice_size.is_none_or(|size| size == 0) => return Err(anyhow::anyhow!("slice_size is required for iceberg")),
+            _ => {}
+        }
+        if self.price_limit.is_some_and(|limit| limit <= 0.0) {
+            return Err(anyhow::anyhow!("price_limit must be positive"));
+        }
+        Ok(())
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval_secs.map(Duration::from_secs).unwrap_or(DEFAULT_ALGO_INTERVAL).max(MIN_ALGO_INTERVAL)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlgoStatus {
+    Running,
+    Paused,
+    /// Filled, or stopped at the end of its duration with the rest cancelled
+    Completed,
+    Cancelled,
+    Failed
+}
+
+impl AlgoStatus {
+    fn finished(&self) -> bool {
+        matches!(self, AlgoStatus::Completed | AlgoStatus::Cancelled | AlgoStatus::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Run,
+    Pause,
+    Cancel
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChildOrder {
+    pub order_id: String,
+    pub quantity: u32,
+    pub price: f64,
+    pub filled_quantity: u32,
+    pub average_price: f64,
+    pub status: String
+}
+
+impl ChildOrder {
+    fn is_open(&self) -> bool {
+        !matches!(self.status.as_str(), "COMPLETE" | "CANCELLED" | "REJECTED")
+    }
+
+    fn pending(&self) -> u32 {
+        if self.is_open() { self.quantity.saturating_sub(self.filled_quantity) } else { 0 }
+    }
+}
+
+/// Progress of an algo and how its fills compare with the price on arrival and the market VWAP
+/// over the same period. Slippage is in basis points and positive when the fills were worse.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlgoReport {
+    pub id: String,
+    pub algo: AlgoParams,
+    pub status: AlgoStatus,
+    pub symbol: String,
+    pub transaction_type: String,
+    pub target_quantity: u32,
+    pub filled_quantity: u32,
+    pub average_price: Option<f64>,
+    pub arrival_price: Option<f64>,
+    pub market_vwap: Option<f64>,
+    pub slippage_vs_arrival_bps: Option<f64>,
+    pub slippage_vs_vwap_bps: Option<f64>,
+    pub children: Vec<ChildOrder>,
+    pub started_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ended_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+impl AlgoReport {
+    fn open_quantity(&self) -> u32 {
+        self.children.iter().map(ChildOrder::pending).sum()
+    }
+
+    fn summarise(&mut self) {
+        self.filled_quantity = self.children.iter().map(|child| child.filled_quantity).sum();
+        let value: f64 = self.children.iter().map(|child| child.filled_quantity as f64 * child.average_price).sum();
+        self.average_price = (self.filled_quantity > 0).then(|| value / self.filled_quantity as f64);
+
+        let buying = self.transaction_type == "BUY";
+        self.slippage_vs_arrival_bps = self.average_price.zip(self.arrival_price).map(|(fill, arrival)| slippage_bps(buying, fill, arrival));
+        self.slippage_vs_vwap_bps = self.average_price.zip(self.market_vwap).map(|(fill, vwap)| slippage_bps(buying, fill, vwap));
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AlgoError {
+    NotFound,
+    Finished(AlgoStatus)
+}
+
+impl AlgoError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AlgoError::NotFound => "not_found",
+            AlgoError::Finished(_) => "algo_finished"
+        }
+    }
+}
+
+impl std::fmt::Display for AlgoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlgoError::NotFound => write!(f, "Algo not found"),
+            AlgoError::Finished(status) => write!(f, "Algo is already {:?}", status)
+        }
+    }
+}
+
+struct Algo {
+    report: Arc<Mutex<AlgoReport>>,
+    control: watch::Sender<Control>
+}
+
+/// Running and finished execution algos, each worked by its own task.
+#[derive(Default)]
+pub struct AlgoStore {
+    algos: Mutex<HashMap<String, Algo>>,
+    next_id: Mutex<u64>
+}
+
+impl std::fmt::Debug for AlgoStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AlgoStore").field("algos", &self.algos.lock().unwrap().len()).finish()
+    }
+}
+
+impl AlgoStore {
+    /// Starts working `plan` as a parent order. Children are limit orders priced with `protection`
+    /// from a fresh quote on every slice, never beyond `price_limit` or the plan's own limit price.
+    pub fn start(&self, executor: TradeExecutor, plan: OrderPlan, params: AlgoParams, protection: MarketProtection) -> Result<AlgoReport, anyhow::Error> {
+        params.validate()?;
+
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            // Doubles as the children's order tag, which Kite caps at 20 characters
+            format!("A{}{:04}", Utc::now().format("%Y%m%d%H%M%S"), *next_id % 10_000)
+        };
+        let buying = plan.params.transaction_type == "BUY";
+        let price_limit = match (params.price_limit, plan.params.price) {
+            (Some(a), Some(b)) => Some(if buying { a.min(b) } else { a.max(b) }),
+            (a, b) => a.or(b)
+        };
+
+        let report = AlgoReport {
+            id: id.clone(),
+            algo: params.clone(),
+            status: AlgoStatus::Running,
+            symbol: format!("{}:{}", plan.params.exchange, plan.params.tradingsymbol),
+            transaction_type: plan.params.transaction_type.clone(),
+            target_quantity: plan.params.quantity,
+            filled_quantity: 0,
+            average_price: None,
+            arrival_price: None,
+            market_vwap: None,
+            slippage_vs_arrival_bps: None,
+            slippage_vs_vwap_bps: None,
+            children: Vec::new(),
+            started_at: Utc::now().to_rfc3339(),
+            ended_at: None,
+            error: None
+        };
+        let shared = Arc::new(Mutex::new(report.clone()));
+        let (control, receiver) = watch::channel(Control::Run);
+
+        let lot = executor.instrument.as_ref().filter(|i| is_derivative(i)).map(|i| i.lot_size.max(1)).unwrap_or(1);
+        let max_child = [params.slice_size, executor.freeze_limit].into_iter().flatten().min();
+        let worker = Worker {
+            template: OrderParams { order_type: "LIMIT".to_string(), tag: Some(id.clone()), ..plan.params.clone() },
+            kite: executor.kite.clone(),
+            priority: executor.priority(&plan.params),
+            protection: if protection.is_enabled() { protection } else { MarketProtection::default() },
+            tick: executor.tick_size(),
+            lot,
+            max_child,
+            price_limit,
+            deadline: params.duration_secs.map(|secs| Instant::now() + Duration::from_secs(secs)),
+            params,
+            key: report.symbol.clone(),
+            report: shared.clone(),
+            control: receiver,
+            volume_seen: None,
+            session_start: None
+        };
+        tokio::spawn(worker.run());
+
+        self.algos.lock().unwrap().insert(id, Algo { report: shared, control });
+        Ok(report)
+    }
+
+    pub fn get(&self, id: &str) -> Option<AlgoReport> {
+        self.algos.lock().unwrap().get(id).map(|algo| algo.report.lock().unwrap().clone())
+    }
+
+    /// Every algo started since the server came up, newest first.
+    pub fn list(&self) -> Vec<AlgoReport> {
+        let mut list: Vec<AlgoReport> = self.algos.lock().unwrap().values().map(|algo| algo.report.lock().unwrap().clone()).collect();
+        list.sort_by(|a, b| b.id.cmp(&a.id));
+        list
+    }
+
+    pub fn control(&self, id: &str, control: Control) -> Result<AlgoReport, AlgoError> {
+        let algos = self.algos.lock().unwrap();
+        let algo = algos.get(id).ok_or(AlgoError::NotFound)?;
+        let report = algo.report.lock().unwrap().clone();
+        if report.status.finished() {
+            return Err(AlgoError::Finished(report.status));
+        }
+        algo.control.send_replace(control);
+        Ok(report)
+    }
+}
+
+struct Worker {
+    kite: KiteClient,
+    template: OrderParams,
+    params: AlgoParams,
+    priority: Priority,
+    protection: MarketProtection,
+    tick: f64,
+    lot: u32,
+    max_child: Option<u32>,
+    price_limit: Option<f64>,
+    deadline: Option<Instant>,
+    key: String,
+    report: Arc<Mutex<AlgoReport>>,
+    control: watch::Receiver<Control>,
+    // Cumulative day volume at the last slice, for VWAP participation
+    volume_seen: Option<f64>,
+    // Day volume and VWAP when the algo started, to back out the market VWAP over its lifetime
+    session_start: Option<(f64, f64)>
+}
+
+impl Worker {
+    async fn run(mut self) {
+        loop {
+            let control = *self.control.borrow_and_update();
+            match control {
+                Control::Cancel => return self.finish(AlgoStatus::Cancelled, None).await,
+                Control::Pause => {
+                    // Nothing works in the market while paused, and the time paused doesn't count against the duration
+                    self.cancel_open().await;
+                    self.update(|report| report.status = AlgoStatus::Paused);
+                    let paused = Instant::now();
+                    if self.control.changed().await.is_err() {
+                        return self.finish(AlgoStatus::Cancelled, None).await;
+                    }
+                    self.deadline = self.deadline.map(|deadline| deadline + paused.elapsed());
+                    continue;
+                },
+                Control::Run => self.update(|report| report.status = AlgoStatus::Running)
+            }
+
+            match self.step().await {
+                Ok(true) => return self.finish(AlgoStatus::Completed, None).await,
+                Ok(false) => {},
+                Err(e) => return self.finish(AlgoStatus::Failed, Some(e.to_string())).await
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(self.params.interval()) => {},
+                changed = self.control.changed() => if changed.is_err() {
+                    return self.finish(AlgoStatus::Cancelled, None).await;
+                }
+            }
+        }
+    }
+
+    // One wake: refresh fills, re-price what is still working and send the next slice. True once done.
+    async fn step(&mut self) -> Result<bool, anyhow::Error> {
+        let quote = self.kite.quote(std::slice::from_ref(&self.key)).await?.remove(&self.key)
+            .ok_or_else(|| anyhow::anyhow!("No quote for {}", self.key))?;
+        self.observe(&quote);
+        self.refresh().await;
+
+        let (target, filled, open) = {
+            let report = self.report.lock().unwrap();
+            (report.target_quantity, report.filled_quantity, report.open_quantity())
+        };
+        let past_deadline = self.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        if filled >= target || past_deadline {
+            return Ok(true);
+        }
+
+        let price = self.price(&quote)?;
+        self.reprice_open(price).await;
+
+        let unallocated = target.saturating_sub(filled + open);
+        let quantity = match self.params.kind {
+            AlgoKind::Twap => {
+                let left = self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())).unwrap_or_default();
+                twap_slice(unallocated, left, self.params.interval(), self.lot)
+            },
+            AlgoKind::Vwap => {
+                let volume = quote.volume.unwrap_or_default();
+                let traded = self.volume_seen.map(|seen| (volume - seen).max(0.0)).unwrap_or_default();
+                self.volume_seen = Some(volume);
+                participation_slice(traded, self.params.participation_rate.unwrap_or_default(), unallocated, self.lot)
+            },
+            // The next child only goes out once the last one is done
+            AlgoKind::Iceberg if open > 0 => 0,
+            AlgoKind::Iceberg => unallocated
+        };
+        let quantity = cap_to_lots(quantity.min(unallocated), self.max_child, self.lot);
+
+        if quantity > 0 {
+            let child = OrderParams { quantity, price: Some(price), ..self.template.clone() };
+            let order_id = self.kite.place_order(ORDER_VARIETY, &child, self.priority).await?;
+            println!("Algo {} sent {} {} at {} as {}", self.id(), quantity, self.key, price, order_id);
+            self.update(|report| report.children.push(ChildOrder {
+                order_id, quantity, price, filled_quantity: 0, average_price: 0.0, status: "OPEN".to_string()
+            }));
+        }
+        Ok(false)
+    }
+
+    fn observe(&mut self, quote: &Quote) {
+        let day = quote.volume.zip(quote.average_price);
+        if self.session_start.is_none() {
+            self.session_start = day;
+        }
+        if self.volume_seen.is_none() {
+            self.volume_seen = quote.volume;
+        }
+
+        let vwap = self.session_start.zip(day).and_then(|(start, end)| interval_vwap(start, end));
+        self.update(|report| {
+            report.arrival_price.get_or_insert(quote.last_price);
+            if vwap.is_some() {
+                report.market_vwap = vwap;
+            }
+        });
+    }
+
+    fn price(&self, quote: &Quote) -> Result<f64, anyhow::Error> {
+        let buying = self.template.transaction_type == "BUY";
+        let (price, _) = self.protection.limit_price(buying, &MarketQuote::from_quote(quote), self.tick)?;
+        Ok(match self.price_limit {
+            Some(limit) if buying => price.min(round_to_tick(limit, self.tick, true)),
+            Some(limit) => price.max(round_to_tick(limit, self.tick, false)),
+            None => price
+        })
+    }
+
+    async fn refresh(&self) {
+        let open: Vec<String> = self.report.lock().unwrap().children.iter()
+            .filter(|child| child.is_open())
+            .map(|child| child.order_id.clone())
+            .collect();
+
+        for order_id in open {
+            let latest = match self.kite.order_history(&order_id).await {
+                Ok(mut history) => history.pop(),
+                Err(e) => {
+                    println!("Algo {} failed to refresh order {}: {}", self.id(), order_id, e);
+                    None
+                }
+            };
+            if let Some(order) = latest {
+                self.update(|report| if let Some(child) = report.children.iter_mut().find(|child| child.order_id == order_id) {
+                    child.filled_quantity = order.filled_quantity;
+                    child.average_price = order.average_price;
+                    child.status = order.status.clone();
+                    child.price = order.price;
+                });
+            }
+        }
+        self.update(AlgoReport::summarise);
+    }
+
+    async fn reprice_open(&self, price: f64) {
+        let stale: Vec<ChildOrder> = self.report.lock().unwrap().children.iter()
+            .filter(|child| child.pending() > 0 && (child.price - price).abs() >= self.tick / 2.0)
+            .cloned()
+            .collect();
+
+        for child in stale {
+            let modified = OrderParams { quantity: child.quantity, price: Some(price), ..self.template.clone() };
+            match self.kite.modify_order(ORDER_VARIETY, &child.order_id, &modified).await {
+                Ok(_) => self.update(|report| if let Some(c) = report.children.iter_mut().find(|c| c.order_id == child.order_id) {
+                    c.price = price;
+                }),
+                Err(e) => println!("Algo {} failed to re-price order {}: {}", self.id(), child.order_id, e)
+            }
+        }
+    }
+
+    async fn cancel_open(&self) {
+        let open: Vec<String> = self.report.lock().unwrap().children.iter()
+            .filter(|child| child.pending() > 0)
+            .map(|child| child.order_id.clone())
+            .collect();
+
+        for order_id in open {
+            if let Err(e) = self.kite.cancel_order(ORDER_VARIETY, &order_id).await {
+                println!("Algo {} failed to cancel order {}: {}", self.id(), order_id, e);
+            }
+        }
+        self.refresh().await;
+    }
+
+    async fn finish(mut self, status: AlgoStatus, error: Option<String>) {
+        self.cancel_open().await;
+        if let Ok(Some(quote)) = self.kite.quote(std::slice::from_ref(&self.key)).await.map(|mut quotes| quotes.remove(&self.key)) {
+            self.observe(&quote);
+        }
+
+        self.update(|report| {
+            report.status = status;
+            report.ended_at = Some(Utc::now().to_rfc3339());
+            report.error = error;
+            report.summarise();
+        });
+        let report = self.report.lock().unwrap().clone();
+        println!(
+            "Algo {} {:?}: filled {} of {} at {:?} (arrival {:?}, VWAP {:?})",
+            report.id, report.status, report.filled_quantity, report.target_quantity, report.average_price, report.arrival_price, report.market_vwap
+        );
+    }
+
+    fn id(&self) -> String {
+        self.report.lock().unwrap().id.clone()
+    }
+
+    fn update<F: FnOnce(&mut AlgoReport)>(&self, change: F) {
+        change(&mut self.report.lock().unwrap());
+    }
+}
+
+/// An even share of what is left over the slices remaining before the deadline.
+pub fn twap_slice(unallocated: u32, time_left: Duration, interval: Duration, lot: u32) -> u32 {
+    let slices_left = (time_left.as_secs_f64() / interval.as_secs_f64()).ceil().max(1.0) as u32;
+    let lots = unallocated / lot;
+    lots.div_ceil(slices_left) * lot
+}
+
+/// `rate` of the volume the market traded since the last slice, in whole lots.
+pub fn participation_slice(traded: f64, rate: f64, unallocated: u32, lot: u32) -> u32 {
+    let quantity = ((traded * rate) as u32).min(unallocated);
+    quantity / lot * lot
+}
+
+fn cap_to_lots(quantity: u32, cap: Option<u32>, lot: u32) -> u32 {
+    let quantity = match cap {
+        Some(cap) => quantity.min((cap / lot * lot).max(lot)),
+        None => quantity
+    };
+    quantity / lot * lot
+}
+
+/// Market VWAP between two snapshots of the day's cumulative volume and VWAP.
+pub fn interval_vwap(start: (f64, f64), end: (f64, f64)) -> Option<f64> {
+    let ((start_volume, start_vwap), (end_volume, end_vwap)) = (start, end);
+    let volume = end_volume - start_volume;
+    (volume > 0.0).then(|| (end_volume * end_vwap - start_volume * start_vwap) / volume)
+}
+
+fn slippage_bps(buying: bool, fill: f64, reference: f64) -> f64 {
+    let bps = (fill - reference) / reference * 10_000.0;
+    if buying { bps } else { -bps }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn twap_spreads_what_is_left_over_the_remaining_slices() {
+        let interval = Duration::from_secs(30);
+        assert_eq!(twap_slice(1000, Duration::from_secs(300), interval, 1), 100);
+        // Behind schedule, the last slice takes everything
+        assert_eq!(twap_slice(730, Duration::from_secs(20), interval, 1), 730);
+        // F&O slices stay in whole lots
+        assert_eq!(twap_slice(500, Duration::from_secs(120), interval, 50), 150);
+    }
+
+    #[test]
+    fn participation_follows_market_volume_in_lots() {
+        assert_eq!(participation_slice(12_000.0, 0.1, 5000, 1), 1200);
+        assert_eq!(participation_slice(12_000.0, 0.1, 800, 1), 800);
+        assert_eq!(participation_slice(900.0, 0.1, 5000, 50), 50);
+        assert_eq!(cap_to_lots(1200, Some(1000), 75), 975);
+    }
+
+    #[test]
+    fn market_vwap_is_backed_out_of_the_day_totals() {
+        // 1000 traded at 100 before the algo, then 1000 more at 102
+        assert_eq!(interval_vwap((1000.0, 100.0), (2000.0, 101.0)), Some(102.0));
+        assert_eq!(interval_vwap((1000.0, 100.0), (1000.0, 100.0)), None);
+        assert_eq!(slippage_bps(true, 101.0, 100.0), 100.0);
+        assert_eq!(slippage_bps(false, 101.0, 100.0), -100.0);
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use chrono::{Datelike, NaiveDate};
+use serde::Serialize;
+use crate::instrument_master::Instrument;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiryKind {
+    Weekly,
+    /// The last expiry of the month, which futures also expire on
+    Monthly
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Expiry {
+    pub date: NaiveDate,
+    pub kind: ExpiryKind,
+    pub days_to_expiry: i64,
+    /// The future expiring that day, when one is listed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub future: Option<String>,
+    pub options: usize
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExpiryCalendar {
+    pub underlying: String,
+    pub expiries: Vec<Expiry>
+}
+
+impl ExpiryCalendar {
+    /// Every expiry on or after `today` across an underlying's options and futures. Weeklies and
+    /// monthlies aren't flagged in the instrument dump, so the last expiry of a calendar month, or one
+    /// with a future on it, counts as the monthly.
+    pub fn new(underlying: &str, derivatives: &[Instrument], today: NaiveDate) -> Self {
+        let mut by_date: BTreeMap<NaiveDate, (Option<String>, usize)> = BTreeMap::new();
+        for instrument in derivatives {
+            let date = match instrument.expiry {
+                Some(date) if date >= today => date,
+                _ => continue
+            };
+            let entry = by_date.entry(date).or_default();
+            if instrument.instrument_type == "FUT" {
+                entry.0 = Some(instrument.key());
+            }
+            else {
+                entry.1 += 1;
+            }
+        }
+
+        let mut last_in_month: BTreeMap<(i32, u32), NaiveDate> = BTreeMap::new();
+        for date in by_date.keys() {
+            last_in_month.insert((date.year(), date.month()), *date);
+        }
+
+        let expiries = by_date.into_iter().map(|(date, (future, options))| {
+            let monthly = future.is_some() || last_in_month.get(&(date.year(), date.month())) == Some(&date);
+            Expiry {
+                date,
+                kind: if monthly { ExpiryKind::Monthly } else { ExpiryKind::Weekly },
+                days_to_expiry: (date - today).num_days(),
+                future,
+                options
+            }
+        }).collect();
+        Self { underlying: underlying.trim().to_uppercase(), expiries }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contract(instrument_type: &str, expiry: &str) -> Instrument {
+        Instrument {
+            instrument_token: 1,
+            exchange_token: 1,
+            tradingsymbol: format!("NIFTY{}{}", expiry, instrument_type),
+            name: "NIFTY".to_string(),
+            expiry: NaiveDate::parse_from_str(expiry, "%Y-%m-%d").ok(),
+            strike: 0.0,
+            tick_size: 0.05,
+            lot_size: 50,
+            instrument_type: instrument_type.to_string(),
+            segment: "NFO".to_string(),
+            exchange: "NFO".to_string()
+        }
+    }
+
+    #[test]
+    fn the_last_expiry_of_each_month_is_the_monthly() {
+        let today = NaiveDate::from_ymd_opt(2024, 1, 12).unwrap();
+        let contracts: Vec<Instrument> = ["2024-01-11", "2024-01-18", "2024-01-25", "2024-02-01", "2024-02-29"].iter()
+            .flat_map(|expiry| [contract("CE", expiry), contract("PE", expiry)])
+            .chain([contract("FUT", "2024-01-25"), contract("FUT", "2024-02-29"), contract("FUT", "2024-03-28")])
+            .collect();
+
+        let calendar = ExpiryCalendar::new("nifty", &contracts, today);
+        let kinds: Vec<(String, ExpiryKind)> = calendar.expiries.iter().map(|e| (e.date.to_string(), e.kind)).collect();
+        assert_eq!(kinds, vec![
+            ("2024-01-18".to_string(), ExpiryKind::Weekly),
+            ("2024-01-25".to_string(), ExpiryKind::Monthly),
+            ("2024-02-01".to_string(), ExpiryKind::Weekly),
+            ("2024-02-29".to_string(), ExpiryKind::Monthly),
+            ("2024-03-28".to_string(), ExpiryKind::Monthly)
+        ]);
+        assert_eq!(calendar.expiries[0].days_to_expiry, 6);
+        assert_eq!(calendar.expiries[4].options, 0);
+    }
+}
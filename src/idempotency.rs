@@ -0,0 +1,297 @@
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+use crate::data_structures::{TradeInstruction, TradeResponse};
+
+/// How long a key keeps returning its original response unless `IDEMPOTENCY_WINDOW_SECS` says otherwise.
+pub const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Kite accepts order tags of up to 20 characters.
+pub const MAX_KEY_LEN: usize = 20;
+
+#[derive(Debug)]
+enum Entry<I, R> {
+    // `placed` is set while a retry reconciles an unsettled attempt, so dropping it keeps the key held
+    InFlight { instruction: I, started: Instant, placed: Option<Vec<String>> },
+    // Failed after something may have reached Kite: the key stays held until it is reconciled
+    Unsettled { instruction: I, order_ids: Vec<String>, since: Instant },
+    // Held back for confirmation: a retry gets the proposal, its confirmation places the order
+    Proposed { instruction: I, proposal_id: String, since: Instant },
+    Done { instruction: I, response: Box<R>, finished: Instant }
+}
+
+impl<I, R> Entry<I, R> {
+    fn instruction(&self) -> &I {
+        match self {
+            Entry::InFlight { instruction, .. } | Entry::Unsettled { instruction, .. } | Entry::Proposed { instruction, .. } | Entry::Done { instruction, .. } => instruction
+        }
+    }
+
+    fn since(&self) -> Instant {
+        match self {
+            Entry::InFlight { started, .. } => *started,
+            Entry::Unsettled { since, .. } | Entry::Proposed { since, .. } => *since,
+            Entry::Done { finished, .. } => *finished
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Claim<'a, I = TradeInstruction, R = TradeResponse> {
+    /// First time this key is seen: place the order, then `complete` the reservation.
+    New(Reservation<'a, I, R>),
+    /// The key already placed an order inside the window.
+    Replay(R),
+    /// An earlier attempt failed after some of its orders (`order_ids`, possibly none) may have
+    /// reached Kite. Look them up by tag instead of placing again, then `complete` or `hold`.
+    Reconcile(Reservation<'a, I, R>, Vec<String>),
+    /// The key is held by a proposal waiting for confirmation.
+    Proposed(String),
+    /// The first request with this key is still waiting on Kite.
+    InFlight,
+    /// The key was used for a different instruction.
+    Mismatch
+}
+
+/// Remembers the response for each idempotency key so retried calls don't place twice. `/trade`
+/// keys store the `TradeResponse`; baskets and algos keep stores of their own.
+#[derive(Debug)]
+pub struct IdempotencyStore<I = TradeInstruction, R = TradeResponse> {
+    window: Duration,
+    entries: Mutex<HashMap<String, Entry<I, R>>>
+}
+
+impl<I: Clone + PartialEq, R: Clone> Default for IdempotencyStore<I, R> {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDEMPOTENCY_WINDOW)
+    }
+}
+
+impl<I: Clone + PartialEq, R: Clone> IdempotencyStore<I, R> {
+    pub fn new(window: Duration) -> Self {
+        Self { window, entries: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn claim(&self, key: &str, instruction: &I) -> Claim<'_, I, R> {
+        self.claim_as(key, instruction, None)
+    }
+
+    /// Claims a key for placing confirmed `proposal_id`, the one proposal allowed past its hold.
+    pub fn claim_proposal(&self, key: &str, instruction: &I, proposal_id: &str) -> Claim<'_, I, R> {
+        self.claim_as(key, instruction, Some(proposal_id))
+    }
+
+    fn claim_as(&self, key: &str, instruction: &I, proposal: Option<&str>) -> Claim<'_, I, R> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.since().elapsed() < self.window);
+
+        match entries.get(key) {
+            Some(entry) if entry.instruction() != instruction => Claim::Mismatch,
+            Some(Entry::Done { response, .. }) => Claim::Replay(R::clone(response)),
+            Some(Entry::Proposed { proposal_id, .. }) if Some(proposal_id.as_str()) != proposal => Claim::Proposed(proposal_id.clone()),
+            Some(Entry::Proposed { .. }) => {
+                entries.insert(key.to_string(), Entry::InFlight { instruction: instruction.clone(), started: Instant::now(), placed: None });
+                Claim::New(Reservation { store: self, key: Some(key.to_string()) })
+            },
+            Some(Entry::InFlight { .. }) => Claim::InFlight,
+            Some(Entry::Unsettled { order_ids, .. }) => {
+                let order_ids = order_ids.clone();
+                entries.insert(key.to_string(), Entry::InFlight { instruction: instruction.clone(), started: Instant::now(), placed: Some(order_ids.clone()) });
+                Claim::Reconcile(Reservation { store: self, key: Some(key.to_string()) }, order_ids)
+            },
+            None => {
+                entries.insert(key.to_string(), Entry::InFlight { instruction: instruction.clone(), started: Instant::now(), placed: None });
+                Claim::New(Reservation { store: self, key: Some(key.to_string()) })
+            }
+        }
+    }
+}
+
+/// An in-flight key. Dropping it without `complete` or `hold` (an order Kite rejected, a disconnected
+/// client) releases the key so the caller can try again; a key being reconciled goes back to unsettled.
+#[derive(Debug)]
+pub struct Reservation<'a, I = TradeInstruction, R = TradeResponse> {
+    store: &'a IdempotencyStore<I, R>,
+    key: Option<String>
+}
+
+impl<I, R: Clone> Reservation<'_, I, R> {
+    pub fn complete(mut self, response: &R) {
+        if let Some(key) = self.key.take() {
+            let mut entries = self.store.entries.lock().unwrap();
+            if let Some(Entry::InFlight { instruction, .. }) = entries.remove(&key) {
+                entries.insert(key, Entry::Done { instruction, response: Box::new(response.clone()), finished: Instant::now() });
+            }
+        }
+    }
+
+    /// Hands the key to a proposal, so retries get it back instead of proposing again.
+    pub fn propose(mut self, proposal_id: &str) {
+        if let Some(key) = self.key.take() {
+            let mut entries = self.store.entries.lock().unwrap();
+            if let Some(Entry::InFlight { instruction, .. }) = entries.remove(&key) {
+                entries.insert(key, Entry::Proposed { instruction, proposal_id: proposal_id.to_string(), since: Instant::now() });
+            }
+        }
+    }
+
+    /// Keeps the key after a failure that may have left orders at Kite, so a retry reconciles
+    /// `order_ids` and whatever else carries the tag instead of placing again.
+    pub fn hold(mut self, order_ids: Vec<String>) {
+        if let Some(key) = self.key.take() {
+            let mut entries = self.store.entries.lock().unwrap();
+            if let Some(Entry::InFlight { instruction, .. }) = entries.remove(&key) {
+                entries.insert(key, Entry::Unsettled { instruction, order_ids, since: Instant::now() });
+            }
+        }
+    }
+}
+
+impl<I, R> Drop for Reservation<'_, I, R> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let mut entries = self.store.entries.lock().unwrap();
+            match entries.remove(&key) {
+                Some(Entry::InFlight { instruction, placed: Some(order_ids), .. }) => {
+                    entries.insert(key, Entry::Unsettled { instruction, order_ids, since: Instant::now() });
+                },
+                Some(Entry::InFlight { .. }) | None => {},
+                Some(other) => {
+                    entries.insert(key, other);
+                }
+            }
+        }
+    }
+}
+
+pub fn validate_key(key: &str) -> Result<(), anyhow::Error> {
+    let valid = !key.is_empty()
+        && key.len() <= MAX_KEY_LEN
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    }
+    else {
+        Err(anyhow::anyhow!("Invalid idempotency_key '{}': use up to {} letters, digits, '-' or '_' so it fits a Kite order tag", key, MAX_KEY_LEN))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instruction(quantity: u32) -> TradeInstruction {
+        TradeInstruction {
+            action: "buy".to_string(),
+            symbol: "INFY".to_string(),
+            exchange: "NSE".to_string(),
+            quantity,
+            price_type: "MARKET".to_string(),
+            limit_price: None,
+            stop_loss: None,
+            target: None,
+            order_id: None,
+            timeframe: None,
+            watchlist: None,
+            idempotency_key: Some("agent1-42".to_string()),
+            auto_adjust: false,
+            product: None
+        }
+    }
+
+    fn response() -> TradeResponse {
+        TradeResponse {
+            order_id: "240115000000001".to_string(),
+            status: "Success".to_string(),
+            message: "Order placed successfully for: INFY".to_string(),
+            symbol: "INFY".to_string(),
+            quantity: 10,
+            price: 0.0,
+            timestamp: "2024-01-15T09:15:00+00:00".to_string(),
+            warnings: Vec::new(),
+            idempotency_key: Some("agent1-42".to_string()),
+            child_order_ids: Vec::new(),
+            gtt_id: None
+        }
+    }
+
+    #[test]
+    fn repeats_replay_the_original_response() {
+        let store: IdempotencyStore = IdempotencyStore::default();
+        match store.claim("agent1-42", &instruction(10)) {
+            Claim::New(reservation) => {
+                assert!(matches!(store.claim("agent1-42", &instruction(10)), Claim::InFlight));
+                reservation.complete(&response());
+            },
+            other => panic!("expected a new claim, got {:?}", other)
+        }
+
+        match store.claim("agent1-42", &instruction(10)) {
+            Claim::Replay(replayed) => assert_eq!(replayed.order_id, "240115000000001"),
+            other => panic!("expected a replay, got {:?}", other)
+        }
+        assert!(matches!(store.claim("agent1-42", &instruction(20)), Claim::Mismatch));
+    }
+
+    #[test]
+    fn failed_orders_release_the_key() {
+        let store: IdempotencyStore = IdempotencyStore::default();
+        drop(store.claim("agent1-42", &instruction(10)));
+        assert!(matches!(store.claim("agent1-42", &instruction(10)), Claim::New(_)));
+    }
+
+    #[test]
+    fn partial_failures_hold_the_key_for_reconciliation() {
+        let store: IdempotencyStore = IdempotencyStore::default();
+        if let Claim::New(reservation) = store.claim("agent1-42", &instruction(10)) {
+            reservation.hold(vec!["240115000000001".to_string()]);
+        }
+
+        match store.claim("agent1-42", &instruction(10)) {
+            Claim::Reconcile(reservation, order_ids) => {
+                assert_eq!(order_ids, vec!["240115000000001".to_string()]);
+                assert!(matches!(store.claim("agent1-42", &instruction(10)), Claim::InFlight));
+                drop(reservation);
+            },
+            other => panic!("expected a reconcile, got {:?}", other)
+        }
+
+        // A reconcile that gives up leaves the key held
+        match store.claim("agent1-42", &instruction(10)) {
+            Claim::Reconcile(reservation, _) => reservation.complete(&response()),
+            other => panic!("expected a reconcile, got {:?}", other)
+        }
+        assert!(matches!(store.claim("agent1-42", &instruction(10)), Claim::Replay(_)));
+    }
+
+    #[test]
+    fn proposed_keys_are_only_placed_by_their_proposal() {
+        let store: IdempotencyStore = IdempotencyStore::default();
+        if let Claim::New(reservation) = store.claim("agent1-42", &instruction(10)) {
+            reservation.propose("P1");
+        }
+
+        assert!(matches!(store.claim("agent1-42", &instruction(10)), Claim::Proposed(id) if id == "P1"));
+        assert!(matches!(store.claim_proposal("agent1-42", &instruction(10), "P2"), Claim::Proposed(_)));
+        match store.claim_proposal("agent1-42", &instruction(10), "P1") {
+            Claim::New(reservation) => reservation.complete(&response()),
+            other => panic!("expected the proposal to claim the key, got {:?}", other)
+        }
+        assert!(matches!(store.claim("agent1-42", &instruction(10)), Claim::Replay(_)));
+    }
+
+    #[test]
+    fn keys_expire_after_the_window() {
+        let store: IdempotencyStore = IdempotencyStore::new(Duration::ZERO);
+        if let Claim::New(reservation) = store.claim("agent1-42", &instruction(10)) {
+            reservation.complete(&response());
+        }
+        assert!(matches!(store.claim("agent1-42", &instruction(10)), Claim::New(_)));
+    }
+
+    #[test]
+    fn keys_must_fit_a_kite_tag() {
+        assert!(validate_key("momentum_v2-0042").is_ok());
+        assert!(validate_key("0f8fad5b-d9cb-469f-a165-70867728950e").is_err());
+        assert!(validate_key("agent 1").is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+use chrono::Utc;
+use serde::Serialize;
+use crate::instrument_master::{InstrumentMaster, DEFAULT_EXCHANGE};
+
+/// Prefix that routes a universe name to an index instead of a watchlist, e.g. `index:nifty50`.
+pub const INDEX_PREFIX: &str = "index:";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Constituent {
+    pub symbol: String,
+    pub company: String,
+    pub industry: String,
+    pub series: String,
+    pub isin: String,
+    pub instrument_token: Option<u32>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct IndexUniverse {
+    pub name: String,
+    pub source: String,
+    pub constituents: Vec<Constituent>,
+    pub unresolved: Vec<String>,
+    pub loaded_at: String
+}
+
+impl IndexUniverse {
+    pub fn symbols(&self) -> Vec<String> {
+        self.constituents.iter().map(|c| c.symbol.clone()).collect()
+    }
+
+    fn resolve(&mut self, instruments: &InstrumentMaster) {
+        if instruments.is_empty() {
+            return;
+        }
+
+        self.unresolved.clear();
+        for constituent in &mut self.constituents {
+            constituent.instrument_token = instruments.token(&constituent.symbol);
+            if constituent.instrument_token.is_none() {
+                self.unresolved.push(constituent.symbol.clone());
+            }
+        }
+    }
+}
+
+/// Index constituent lists loaded from the CSVs NSE publishes (`ind_nifty50list.csv` and friends).
+#[derive(Debug)]
+pub struct IndexUniverses {
+    dir: PathBuf,
+    universes: BTreeMap<String, IndexUniverse>
+}
+
+impl IndexUniverses {
+    pub fn load(dir: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let mut universes = Self { dir: dir.as_ref().to_path_buf(), universes: BTreeMap::new() };
+        universes.reload()?;
+        Ok(universes)
+    }
+
+    /// Re-reads every CSV in the directory, replacing the previous set of universes.
+    pub fn reload(&mut self) -> Result<usize, anyhow::Error> {
+        let mut universes = BTreeMap::new();
+
+        if self.dir.is_dir() {
+            for entry in fs::read_dir(&self.dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()).map(|e| e.eq_ignore_ascii_case("csv")) != Some(true) {
+                    continue;
+                }
+
+                match parse_index_file(&path) {
+                    Ok(universe) => {
+                        universes.insert(universe.name.clone(), universe);
+                    },
+                    Err(e) => println!("Skipping index file {}: {}", path.display(), e)
+                }
+            }
+        }
+        else {
+            println!("Index directory {} not found, no index universes loaded", self.dir.display());
+        }
+
+        self.universes = universes;
+        Ok(self.universes.len())
+    }
+
+    /// Maps every constituent to its instrument token through the instrument master.
+    pub fn resolve(&mut self, instruments: &InstrumentMaster) {
+        for universe in self.universes.values_mut() {
+            universe.resolve(instruments);
+        }
+    }
+
+    pub fn list(&self) -> Vec<&IndexUniverse> {
+        self.universes.values().collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&IndexUniverse> {
+        let name = name.strip_prefix(INDEX_PREFIX).unwrap_or(name);
+        self.universes.get(&universe_name(name))
+    }
+}
+
+/// `ind_nifty50list.csv` becomes `nifty50`, `NIFTY BANK` becomes `niftybank`.
+pub fn universe_name(raw: &str) -> String {
+    let lower = raw.to_lowercase();
+    let trimmed = lower.strip_prefix("ind_").unwrap_or(&lower);
+    let trimmed = trimmed.strip_suffix("list").unwrap_or(trimmed);
+    trimmed.chars().filter(|c| c.is_ascii_alphanumeric()).collect()
+}
+
+fn parse_index_file(path: &Path) -> Result<IndexUniverse, anyhow::Error> {
+    let stem = path.file_stem().and_then(|s| s.to_str())
+        .ok_or_else(|| anyhow::anyhow!("Invalid file name"))?;
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(path)?;
+
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name));
+    let symbol_col = column("Symbol").ok_or_else(|| anyhow::anyhow!("Missing Symbol column"))?;
+    let company_col = column("Company Name");
+    let industry_col = column("Industry");
+    let series_col = column("Series");
+    let isin_col = column("ISIN Code");
+
+    let mut constituents = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let field = |col: Option<usize>| col.and_then(|c| record.get(c)).unwrap_or("").to_string();
+
+        let symbol = field(Some(symbol_col)).to_uppercase();
+        if symbol.is_empty() {
+            continue;
+        }
+
+        constituents.push(Constituent {
+            symbol: format!("{}:{}", DEFAULT_EXCHANGE, symbol),
+            company: field(company_col),
+            industry: field(industry_col),
+            series: field(series_col),
+            isin: field(isin_col),
+            instrument_token: None
+        });
+    }
+
+    if constituents.is_empty() {
+        return Err(anyhow::anyhow!("No constituents found"));
+    }
+
+    Ok(IndexUniverse {
+        name: universe_name(stem),
+        source: path.display().to_string(),
+        constituents,
+        unresolved: Vec::new(),
+        loaded_at: Utc::now().to_rfc3339()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument_master::Instrument;
+
+    fn equity(tradingsymbol: &str, instrument_token: u32) -> Instrument {
+        Instrument {
+            instrument_token,
+            exchange_token: 1,
+            tradingsymbol: tradingsymbol.to_string(),
+            name: tradingsymbol.to_string(),
+            expiry: None,
+            strike: 0.0,
+            tick_size: 0.05,
+            lot_size: 1,
+            instrument_type: "EQ".to_string(),
+            segment: "NSE".to_string(),
+            exchange: "NSE".to_string()
+        }
+    }
+
+    #[test]
+    fn nse_constituent_csvs_load_and_resolve() {
+        let dir = std::env::temp_dir().join(format!("index-universes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("ind_niftybanklist.csv"), "Company Name,Industry,Symbol,Series,ISIN Code\n\
+            HDFC Bank Ltd.,Financial Services,HDFCBANK,EQ,INE040A01034\n\
+            ICICI Bank Ltd.,Financial Services, icicibank ,EQ,INE090A01021\n\
+            ,,,,\n\
+            Old Bank Ltd.,Financial Services,OLDBANK,EQ,INE000000000\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not an index").unwrap();
+        fs::write(dir.join("broken.csv"), "Company Name,Series\nNobody,EQ\n").unwrap();
+
+        let mut universes = IndexUniverses::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(universes.list().len(), 1);
+        let bank = universes.get("index:niftybank").unwrap();
+        assert_eq!(bank.symbols(), vec!["NSE:HDFCBANK", "NSE:ICICIBANK", "NSE:OLDBANK"]);
+        assert_eq!((bank.constituents[0].company.as_str(), bank.constituents[1].isin.as_str()), ("HDFC Bank Ltd.", "INE090A01021"));
+
+        // Members the instrument master doesn't list are kept but flagged
+        let mut instruments = InstrumentMaster::default();
+        instruments.insert(equity("HDFCBANK", 341249));
+        instruments.insert(equity("ICICIBANK", 1270529));
+        universes.resolve(&instruments);
+        let bank = universes.get("NIFTY BANK").unwrap();
+        assert_eq!(bank.constituents[1].instrument_token, Some(1270529));
+        assert_eq!(bank.unresolved, vec!["NSE:OLDBANK"]);
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use crate::kite_client::KiteClient;
+
+pub const DEFAULT_EXCHANGE: &str = "NSE";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Instrument {
+    pub instrument_token: u32,
+    pub exchange_token: u32,
+    pub tradingsymbol: String,
+    pub name: String,
+    pub expiry: Option<NaiveDate>,
+    pub strike: f64,
+    pub tick_size: f64,
+    pub lot_size: u32,
+    pub instrument_type: String,
+    pub segment: String,
+    pub exchange: String
+}
+
+impl Instrument {
+    pub fn key(&self) -> String {
+        format!("{}:{}", self.exchange, self.tradingsymbol)
+    }
+}
+
+/// In-memory copy of Kite's instrument dump, indexed by token and by `EXCHANGE:SYMBOL`.
+#[derive(Debug, Default)]
+pub struct InstrumentMaster {
+    instruments: HashMap<u32, Instrument>,
+    keys: HashMap<String, u32>,
+    // CE, PE and FUT contracts by underlying name
+    derivatives: HashMap<String, Vec<u32>>
+}
+
+/// Normalises `infy`, `NSE:INFY` or `nse:infy` into the `EXCHANGE:SYMBOL` form Kite uses.
+pub fn instrument_key(symbol: &str) -> String {
+    let symbol = symbol.trim().to_uppercase();
+    if symbol.contains(':') {
+        symbol
+    }
+    else {
+        format!("{}:{}", DEFAULT_EXCHANGE, symbol)
+    }
+}
+
+impl InstrumentMaster {
+    pub async fn from_kite(kite: &KiteClient) -> Result<Self, anyhow::Error> {
+        let mut master = Self::default();
+        for instrument in kite.instruments().await? {
+            master.insert(instrument);
+        }
+
+        if master.is_empty() {
+            return Err(anyhow::anyhow!("Instrument dump received from Kite is empty"));
+        }
+        Ok(master)
+    }
+
+    pub fn insert(&mut self, instrument: Instrument) {
+        self.keys.insert(instrument.key(), instrument.instrument_token);
+        if matches!(instrument.instrument_type.as_str(), "CE" | "PE" | "FUT") {
+            self.derivatives.entry(instrument.name.to_uppercase()).or_default().push(instrument.instrument_token);
+        }
+        self.instruments.insert(instrument.instrument_token, instrument);
+    }
+
+    pub fn len(&self) -> usize {
+        self.instruments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instruments.is_empty()
+    }
+
+    pub fn get(&self, instrument_token: u32) -> Option<&Instrument> {
+        self.instruments.get(&instrument_token)
+    }
+
+    pub fn lookup(&self, symbol: &str) -> Option<&Instrument> {
+        self.keys.get(&instrument_key(symbol))
+            .and_then(|token| self.instruments.get(token))
+    }
+
+    pub fn token(&self, symbol: &str) -> Option<u32> {
+        self.keys.get(&instrument_key(symbol)).copied()
+    }
+
+    /// Every listed option and future on `underlying` (`NIFTY`, `RELIANCE`, ...), across expiries.
+    pub fn derivatives(&self, underlying: &str) -> Vec<&Instrument> {
+        self.derivatives.get(&underlying.trim().to_uppercase())
+            .map(|tokens| tokens.iter().filter_map(|token| self.instruments.get(token)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn options(&self, underlying: &str) -> Vec<&Instrument> {
+        self.derivatives(underlying).into_iter().filter(|i| matches!(i.instrument_type.as_str(), "CE" | "PE")).collect()
+    }
+
+    /// Futures on `underlying`, nearest expiry first.
+    pub fn futures(&self, underlying: &str) -> Vec<&Instrument> {
+        let mut futures: Vec<&Instrument> = self.derivatives(underlying).into_iter().filter(|i| i.instrument_type == "FUT").collect();
+        futures.sort_by_key(|i| i.expiry);
+        futures
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Instrument> {
+        self.instruments.values()
+    }
+}
@@ -0,0 +1,318 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use reqwest::{header::AUTHORIZATION, Client, Method, RequestBuilder, StatusCode};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use crate::{errors::{KiteError, KiteErrorKind}, instrument_master::Instrument, kite_models::{BasketMargins, Candle, Envelope, Gtt, GttParams, GttReceipt, HistoricalData, Holding, MarginOrder, Margins, Order, OrderMargin, OrderParams, OrderReceipt, Positions, Quote, Session}, rate_limiter::{retry_delay, Priority, RateClass, RateLimiter, MAX_READ_RETRIES}};
+
+pub const KITE_API_URL: &str = "https://api.kite.trade";
+pub const KITE_LOGIN_URL: &str = "https://kite.zerodha.com/connect/login";
+
+/// Groups of Kite endpoints that share a timeout. Order calls fail fast so a slow
+/// historical or instrument download can never hold up placing or cancelling an order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Session,
+    Orders,
+    Quotes,
+    Historical,
+    Portfolio,
+    Margins,
+    Gtt,
+    Instruments
+}
+
+impl Endpoint {
+    pub fn timeout(&self) -> Duration {
+        match self {
+            Endpoint::Orders | Endpoint::Gtt => Duration::from_secs(5),
+            Endpoint::Quotes => Duration::from_secs(3),
+            Endpoint::Session | Endpoint::Portfolio | Endpoint::Margins => Duration::from_secs(10),
+            Endpoint::Historical => Duration::from_secs(30),
+            Endpoint::Instruments => Duration::from_secs(60)
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Endpoint::Session => "session",
+            Endpoint::Orders => "orders",
+            Endpoint::Quotes => "quotes",
+            Endpoint::Historical => "historical",
+            Endpoint::Portfolio => "portfolio",
+            Endpoint::Margins => "margins",
+            Endpoint::Gtt => "gtt",
+            Endpoint::Instruments => "instruments"
+        }
+    }
+
+    /// Which of Kite's rate limits a call counts against. Cancels and modifications share the
+    /// order placement bucket so they queue, by priority, with new orders.
+    pub fn rate_class(&self, method: &Method) -> RateClass {
+        match self {
+            Endpoint::Quotes => RateClass::Quote,
+            Endpoint::Historical => RateClass::Historical,
+            Endpoint::Orders if method != Method::GET => RateClass::OrderPlacement,
+            _ => RateClass::Other
+        }
+    }
+}
+
+/// A request that has not yet been through the rate limiter.
+struct KiteRequest {
+    builder: RequestBuilder,
+    method: Method,
+    endpoint: Endpoint,
+    priority: Priority
+}
+
+impl KiteRequest {
+    fn map(self, f: impl FnOnce(RequestBuilder) -> RequestBuilder) -> Self {
+        Self { builder: f(self.builder), ..self }
+    }
+
+    fn priority(self, priority: Priority) -> Self {
+        Self { priority, ..self }
+    }
+}
+
+/// Async Kite Connect v3 REST client. Clones share one pooled HTTP client and one rate limiter,
+/// so handing a copy to each task is cheap, keeps connections warm and stays within Kite's limits.
+#[derive(Debug, Clone)]
+pub struct KiteClient {
+    http: Client,
+    limiter: Arc<RateLimiter>,
+    api_key: String,
+    access_token: String
+}
+
+impl KiteClient {
+    pub fn new(api_key: &str) -> Result<Self, anyhow::Error> {
+        let http = Client::builder()
+            .pool_max_idle_per_host(32)
+            .pool_idle_timeout(Duration::from_secs(90))
+            .connect_timeout(Duration::from_secs(5))
+            .tcp_keepalive(Duration::from_secs(30))
+            .build()?;
+
+        Ok(Self { http, limiter: Arc::new(RateLimiter::default()), api_key: api_key.to_string(), access_token: String::new() })
+    }
+
+    /// A client for the same app and connection pool, authenticated with `access_token`.
+    pub fn with_access_token(&self, access_token: &str) -> Self {
+        Self { access_token: access_token.to_string(), ..self.clone() }
+    }
+
+    pub fn login_url(&self) -> String {
+        format!("{}?v=3&api_key={}", KITE_LOGIN_URL, self.api_key)
+    }
+
+    /// Orders still allowed today under Kite's daily cap.
+    pub fn orders_remaining(&self) -> u32 {
+        self.limiter.orders_remaining()
+    }
+
+    fn request(&self, method: Method, path: &str, endpoint: Endpoint) -> KiteRequest {
+        let builder = self.http.request(method.clone(), format!("{}{}", KITE_API_URL, path))
+            .header("X-Kite-Version", "3")
+            .timeout(endpoint.timeout());
+        let builder = if self.access_token.is_empty() {
+            builder
+        }
+        else {
+            builder.header(AUTHORIZATION, format!("token {}:{}", self.api_key, self.access_token))
+        };
+
+        // Writes default to new-entry priority; reads wait behind anything that moves money
+        let priority = if method == Method::GET { Priority::Read } else { Priority::Entry };
+        KiteRequest { builder, method, endpoint, priority }
+    }
+
+    /// Sends `request` once a rate limit token is free. Reads are retried with backoff on 429s and
+    /// transient failures; writes never are, since a lost response may still have placed the order.
+    async fn execute<T>(&self, request: KiteRequest, parse: impl Fn(StatusCode, &[u8]) -> Result<T, KiteError>) -> Result<T, anyhow::Error> {
+        let KiteRequest { mut builder, method, endpoint, priority } = request;
+        let class = endpoint.rate_class(&method);
+        let mut attempt = 0;
+
+        loop {
+            attempt += 1;
+            let retry = if method == Method::GET { builder.try_clone() } else { None };
+
+            self.limiter.acquire(class, priority).await;
+            if class == RateClass::OrderPlacement && method == Method::POST {
+                self.limiter.reserve_order()?;
+            }
+
+            let result = match builder.send().await {
+                Ok(response) => {
+                    let status = response.status();
+                    match response.bytes().await {
+                        Ok(body) => parse(status, &body),
+                        Err(e) => Err(KiteError::from_transport(endpoint.as_str(), &e))
+                    }
+                },
+                Err(e) => Err(KiteError::from_transport(endpoint.as_str(), &e))
+            };
+
+            match (result, retry) {
+                (Err(e), Some(next)) if e.retryable() && attempt <= MAX_READ_RETRIES => {
+                    let delay = retry_delay(attempt, &e);
+                    println!("Kite {} request failed ({}), retrying in {:?}", endpoint.as_str(), e, delay);
+                    tokio::time::sleep(delay).await;
+                    builder = next;
+                },
+                (result, _) => return result.map_err(anyhow::Error::from)
+            }
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: KiteRequest) -> Result<T, anyhow::Error> {
+        let endpoint = request.endpoint;
+        self.execute(request, |status, body| {
+            Err(match serde_json::from_slice::<Envelope<T>>(body) {
+                Ok(Envelope { data: Some(data), .. }) if status.is_success() => return Ok(data),
+                Ok(envelope) => KiteError::from_response(
+                    status.as_u16(),
+                    envelope.error_type.as_deref(),
+                    envelope.message.unwrap_or_else(|| format!("Kite {} request returned {}", endpoint.as_str(), status))
+                ),
+                Err(_) if !status.is_success() => KiteError::from_response(status.as_u16(), None, format!("Kite {} request returned {}", endpoint.as_str(), status)),
+                Err(e) => KiteError::new(KiteErrorKind::BadResponse, format!("Unexpected response from Kite {}: {}", endpoint.as_str(), e))
+            })
+        }).await
+    }
+
+    pub async fn generate_session(&self, request_token: &str, api_secret: &str) -> Result<Session, anyhow::Error> {
+        let checksum = format!("{:x}", Sha256::digest(format!("{}{}{}", self.api_key, request_token, api_secret).as_bytes()));
+        let request = self.request(Method::POST, "/session/token", Endpoint::Session)
+            .map(|r| r.form(&[("api_key", self.api_key.as_str()), ("request_token", request_token), ("checksum", &checksum)]));
+        self.send(request).await
+    }
+
+    /// Places an order. Pass `Priority::Exit` for orders that close a position so they are
+    /// never stuck behind new entries when the order rate limit is saturated.
+    pub async fn place_order(&self, variety: &str, params: &OrderParams, priority: Priority) -> Result<String, anyhow::Error> {
+        let request = self.request(Method::POST, &format!("/orders/{}", variety), Endpoint::Orders)
+            .map(|r| r.form(params))
+            .priority(priority);
+        let receipt: OrderReceipt = self.send(request).await?;
+        Ok(receipt.order_id)
+    }
+
+    pub async fn modify_order(&self, variety: &str, order_id: &str, params: &OrderParams) -> Result<String, anyhow::Error> {
+        let request = self.request(Method::PUT, &format!("/orders/{}/{}", variety, order_id), Endpoint::Orders).map(|r| r.form(params));
+        let receipt: OrderReceipt = self.send(request).await?;
+        Ok(receipt.order_id)
+    }
+
+    pub async fn cancel_order(&self, variety: &str, order_id: &str) -> Result<String, anyhow::Error> {
+        let request = self.request(Method::DELETE, &format!("/orders/{}/{}", variety, order_id), Endpoint::Orders).priority(Priority::Exit);
+        let receipt: OrderReceipt = self.send(request).await?;
+        Ok(receipt.order_id)
+    }
+
+    pub async fn orders(&self) -> Result<Vec<Order>, anyhow::Error> {
+        self.send(self.request(Method::GET, "/orders", Endpoint::Orders)).await
+    }
+
+    pub async fn order_history(&self, order_id: &str) -> Result<Vec<Order>, anyhow::Error> {
+        self.send(self.request(Method::GET, &format!("/orders/{}", order_id), Endpoint::Orders)).await
+    }
+
+    async fn quotes(&self, path: &str, instruments: &[String]) -> Result<HashMap<String, Quote>, anyhow::Error> {
+        let query: Vec<(&str, &str)> = instruments.iter().map(|i| ("i", i.as_str())).collect();
+        self.send(self.request(Method::GET, path, Endpoint::Quotes).map(|r| r.query(&query))).await
+    }
+
+    /// Full quotes with depth, up to 500 `EXCHANGE:SYMBOL` keys.
+    pub async fn quote(&self, instruments: &[String]) -> Result<HashMap<String, Quote>, anyhow::Error> {
+        self.quotes("/quote", instruments).await
+    }
+
+    pub async fn ohlc(&self, instruments: &[String]) -> Result<HashMap<String, Quote>, anyhow::Error> {
+        self.quotes("/quote/ohlc", instruments).await
+    }
+
+    pub async fn ltp(&self, instruments: &[String]) -> Result<HashMap<String, Quote>, anyhow::Error> {
+        self.quotes("/quote/ltp", instruments).await
+    }
+
+    /// Candles between two exchange-local `YYYY-MM-DD HH:MM:SS` timestamps.
+    pub async fn historical_data(&self, instrument_token: u32, interval: &str, from: &str, to: &str, continuous: bool, oi: bool) -> Result<Vec<Candle>, anyhow::Error> {
+        let request = self.request(Method::GET, &format!("/instruments/historical/{}/{}", instrument_token, interval), Endpoint::Historical)
+            .map(|r| r.query(&[("from", from), ("to", to), ("continuous", if continuous { "1" } else { "0" }), ("oi", if oi { "1" } else { "0" })]));
+        let data: HistoricalData = self.send(request).await?;
+        Ok(data.candles)
+    }
+
+    pub async fn holdings(&self) -> Result<Vec<Holding>, anyhow::Error> {
+        self.send(self.request(Method::GET, "/portfolio/holdings", Endpoint::Portfolio)).await
+    }
+
+    pub async fn positions(&self) -> Result<Positions, anyhow::Error> {
+        self.send(self.request(Method::GET, "/portfolio/positions", Endpoint::Portfolio)).await
+    }
+
+    pub async fn margins(&self) -> Result<Margins, anyhow::Error> {
+        self.send(self.request(Method::GET, "/user/margins", Endpoint::Margins)).await
+    }
+
+    /// Margin required for each order on its own.
+    pub async fn order_margins(&self, orders: &[MarginOrder]) -> Result<Vec<OrderMargin>, anyhow::Error> {
+        self.send(self.request(Method::POST, "/margins/orders", Endpoint::Margins).map(|r| r.json(orders))).await
+    }
+
+    /// Margin required for the orders placed together, counting the hedge benefit between them.
+    pub async fn basket_margins(&self, orders: &[MarginOrder], consider_positions: bool) -> Result<BasketMargins, anyhow::Error> {
+        let request = self.request(Method::POST, "/margins/basket", Endpoint::Margins)
+            .map(|r| r.query(&[("consider_positions", consider_positions)]).json(orders));
+        self.send(request).await
+    }
+
+    pub async fn gtts(&self) -> Result<Vec<Gtt>, anyhow::Error> {
+        self.send(self.request(Method::GET, "/gtt/triggers", Endpoint::Gtt)).await
+    }
+
+    pub async fn gtt(&self, trigger_id: u64) -> Result<Gtt, anyhow::Error> {
+        self.send(self.request(Method::GET, &format!("/gtt/triggers/{}", trigger_id), Endpoint::Gtt)).await
+    }
+
+    pub async fn place_gtt(&self, params: &GttParams) -> Result<u64, anyhow::Error> {
+        // Kite takes the condition and orders as JSON strings inside a form body
+        let request = self.request(Method::POST, "/gtt/triggers", Endpoint::Gtt).map(|r| r.form(&[
+            ("type", params.kind.clone()),
+            ("condition", json!(params.condition).to_string()),
+            ("orders", json!(params.orders).to_string())
+        ]));
+        let receipt: GttReceipt = self.send(request).await?;
+        Ok(receipt.trigger_id)
+    }
+
+    pub async fn delete_gtt(&self, trigger_id: u64) -> Result<u64, anyhow::Error> {
+        let request = self.request(Method::DELETE, &format!("/gtt/triggers/{}", trigger_id), Endpoint::Gtt);
+        let receipt: GttReceipt = self.send(request).await?;
+        Ok(receipt.trigger_id)
+    }
+
+    /// The full instrument dump. Unlike every other endpoint this one is CSV, not JSON.
+    pub async fn instruments(&self) -> Result<Vec<Instrument>, anyhow::Error> {
+        let body = self.execute(self.request(Method::GET, "/instruments", Endpoint::Instruments), |status, body| {
+            if status.is_success() {
+                Ok(body.to_vec())
+            }
+            else {
+                Err(KiteError::from_response(status.as_u16(), None, format!("Kite instruments request returned {}", status)))
+            }
+        }).await?;
+        let mut reader = csv::Reader::from_reader(body.as_slice());
+        let mut instruments = Vec::new();
+        for row in reader.deserialize::<Instrument>() {
+            match row {
+                Ok(instrument) => instruments.push(instrument),
+                Err(e) => println!("Skipping unreadable instrument row: {}", e)
+            }
+        }
+        Ok(instruments)
+    }
+}
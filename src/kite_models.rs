@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use crate::tick_stream::{Depth, Ohlc};
+
+/// Every Kite REST response is wrapped in this envelope; `data` is absent on errors.
+#[derive(Debug, Deserialize)]
+pub struct Envelope<T> {
+    pub status: String,
+    pub data: Option<T>,
+    pub message: Option<String>,
+    pub error_type: Option<String>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Session {
+    pub user_id: String,
+    pub access_token: String,
+    #[serde(default)]
+    pub public_token: Option<String>,
+    #[serde(default)]
+    pub login_time: Option<String>
+}
+
+/// Parameters for placing or modifying a regular order, sent form-encoded.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OrderParams {
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub transaction_type: String,
+    pub quantity: u32,
+    pub product: String,
+    pub order_type: String,
+    pub validity: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger_price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disclosed_quantity: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OrderReceipt {
+    pub order_id: String
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Order {
+    pub order_id: String,
+    pub parent_order_id: Option<String>,
+    pub exchange_order_id: Option<String>,
+    pub status: String,
+    pub status_message: Option<String>,
+    pub variety: String,
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub instrument_token: u32,
+    pub transaction_type: String,
+    pub order_type: String,
+    pub product: String,
+    pub validity: String,
+    pub quantity: u32,
+    pub filled_quantity: u32,
+    pub pending_quantity: u32,
+    pub cancelled_quantity: u32,
+    pub disclosed_quantity: u32,
+    pub price: f64,
+    pub trigger_price: f64,
+    pub average_price: f64,
+    pub tag: Option<String>,
+    pub order_timestamp: Option<String>,
+    pub exchange_timestamp: Option<String>
+}
+
+/// One entry of `/quote`, `/quote/ohlc` or `/quote/ltp`. The lighter endpoints leave the
+/// fields they don't carry out.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Quote {
+    pub instrument_token: u32,
+    pub last_price: f64,
+    pub last_quantity: Option<f64>,
+    pub average_price: Option<f64>,
+    pub volume: Option<f64>,
+    pub buy_quantity: Option<f64>,
+    pub sell_quantity: Option<f64>,
+    pub ohlc: Option<Ohlc>,
+    pub net_change: Option<f64>,
+    pub oi: Option<f64>,
+    pub depth: Option<Depth>,
+    pub timestamp: Option<String>,
+    pub last_trade_time: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoricalData {
+    pub candles: Vec<Candle>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "CandleRow")]
+pub struct Candle {
+    pub timestamp: String,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: u64,
+    pub oi: Option<u64>
+}
+
+// Kite sends candles as bare arrays: [timestamp, open, high, low, close, volume(, oi)]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CandleRow {
+    WithOi(String, f64, f64, f64, f64, u64, u64),
+    Plain(String, f64, f64, f64, f64, u64)
+}
+
+impl From<CandleRow> for Candle {
+    fn from(row: CandleRow) -> Self {
+        let (timestamp, open, high, low, close, volume, oi) = match row {
+            CandleRow::WithOi(t, o, h, l, c, v, oi) => (t, o, h, l, c, v, Some(oi)),
+            CandleRow::Plain(t, o, h, l, c, v) => (t, o, h, l, c, v, None)
+        };
+        Self { timestamp, open, high, low, close, volume, oi }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Holding {
+    pub tradingsymbol: String,
+    pub exchange: String,
+    pub instrument_token: u32,
+    pub isin: String,
+    pub product: String,
+    pub quantity: i64,
+    pub t1_quantity: i64,
+    pub realised_quantity: i64,
+    pub collateral_quantity: i64,
+    pub average_price: f64,
+    pub last_price: f64,
+    pub close_price: f64,
+    pub pnl: f64,
+    pub day_change: f64,
+    pub day_change_percentage: f64
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Position {
+    pub tradingsymbol: String,
+    pub exchange: String,
+    pub instrument_token: u32,
+    pub product: String,
+    pub quantity: i64,
+    pub overnight_quantity: i64,
+    pub multiplier: f64,
+    pub average_price: f64,
+    pub close_price: f64,
+    pub last_price: f64,
+    pub value: f64,
+    pub pnl: f64,
+    pub m2m: f64,
+    pub unrealised: f64,
+    pub realised: f64,
+    pub buy_quantity: i64,
+    pub buy_price: f64,
+    pub buy_value: f64,
+    pub sell_quantity: i64,
+    pub sell_price: f64,
+    pub sell_value: f64
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Positions {
+    pub net: Vec<Position>,
+    pub day: Vec<Position>
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AvailableMargins {
+    pub adhoc_margin: f64,
+    pub cash: f64,
+    pub opening_balance: f64,
+    pub live_balance: f64,
+    pub collateral: f64,
+    pub intraday_payin: f64
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SegmentMargins {
+    pub enabled: bool,
+    pub net: f64,
+    pub available: AvailableMargins,
+    pub utilised: HashMap<String, f64>
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Margins {
+    pub equity: Option<SegmentMargins>,
+    pub commodity: Option<SegmentMargins>
+}
+
+/// One order to price with `/margins/orders`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MarginOrder {
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub transaction_type: String,
+    pub variety: String,
+    pub product: String,
+    pub order_type: String,
+    pub quantity: u32,
+    pub price: f64,
+    pub trigger_price: f64
+}
+
+impl MarginOrder {
+    pub fn from_params(variety: &str, params: &OrderParams) -> Self {
+        Self {
+            exchange: params.exchange.clone(),
+            tradingsymbol: params.tradingsymbol.clone(),
+            transaction_type: params.transaction_type.clone(),
+            variety: variety.to_string(),
+            product: params.product.clone(),
+            order_type: params.order_type.clone(),
+            quantity: params.quantity,
+            price: params.price.unwrap_or(0.0),
+            trigger_price: params.trigger_price.unwrap_or(0.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MarginPnl {
+    pub realised: f64,
+    pub unrealised: f64
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OrderMargin {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub tradingsymbol: String,
+    pub exchange: String,
+    pub span: f64,
+    pub exposure: f64,
+    pub option_premium: f64,
+    pub additional: f64,
+    pub bo: f64,
+    pub cash: f64,
+    pub var: f64,
+    pub pnl: MarginPnl,
+    pub leverage: f64,
+    pub charges: OrderCharges,
+    pub total: f64
+}
+
+/// `/margins/basket`: the margin for the orders together, with hedges offset, on their own
+/// (`initial`) and after existing positions (`final`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BasketMargins {
+    pub initial: OrderMargin,
+    #[serde(rename = "final")]
+    pub final_margin: OrderMargin,
+    pub orders: Vec<OrderMargin>
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GstCharges {
+    pub igst: f64,
+    pub cgst: f64,
+    pub sgst: f64,
+    pub total: f64
+}
+
+/// Statutory charges and brokerage Kite expects for an order, as returned with its margins.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OrderCharges {
+    pub transaction_tax: f64,
+    pub transaction_tax_type: String,
+    pub exchange_turnover_charge: f64,
+    pub sebi_turnover_charge: f64,
+    pub brokerage: f64,
+    pub stamp_duty: f64,
+    pub gst: GstCharges,
+    pub total: f64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GttCondition {
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub trigger_values: Vec<f64>,
+    pub last_price: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instrument_token: Option<u32>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GttOrder {
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub transaction_type: String,
+    pub quantity: u32,
+    pub order_type: String,
+    pub product: String,
+    pub price: f64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Gtt {
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub status: String,
+    pub condition: GttCondition,
+    pub orders: Vec<GttOrder>,
+    #[serde(default)]
+    pub created_at: Option<String>,
+    #[serde(default)]
+    pub updated_at: Option<String>,
+    #[serde(default)]
+    pub expires_at: Option<String>
+}
+
+/// A GTT to place: `single` carries one trigger value and order, `two-leg` an OCO pair.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GttParams {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub condition: GttCondition,
+    pub orders: Vec<GttOrder>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GttReceipt {
+    pub trigger_id: u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candles_parse_with_and_without_oi() {
+        let body = r#"{"status":"success","data":{"candles":[
+            ["2024-01-15T09:15:00+0530",1600.5,1605,1598.25,1602,12500],
+            ["2024-01-15T09:16:00+0530",1602,1603.5,1601,1603,8300,4200]
+        ]}}"#;
+        let envelope: Envelope<HistoricalData> = serde_json::from_str(body).unwrap();
+        let candles = envelope.data.unwrap().candles;
+
+        assert_eq!(candles[0].close, 1602.0);
+        assert_eq!(candles[0].oi, None);
+        assert_eq!(candles[1].volume, 8300);
+        assert_eq!(candles[1].oi, Some(4200));
+    }
+
+    #[test]
+    fn quotes_parse_from_every_quote_endpoint() {
+        let ltp = r#"{"instrument_token":408065,"last_price":1602.5}"#;
+        let full = r#"{"instrument_token":408065,"last_price":1602.5,"volume":120000,"net_change":4.5,
+            "ohlc":{"open":1598,"high":1610,"low":1595,"close":1598},
+            "depth":{"buy":[{"price":1602.4,"quantity":50,"orders":2}],"sell":[{"price":1602.6,"quantity":40,"orders":1}]}}"#;
+
+        let ltp: Quote = serde_json::from_str(ltp).unwrap();
+        assert!(ltp.ohlc.is_none() && ltp.depth.is_none());
+
+        let full: Quote = serde_json::from_str(full).unwrap();
+        assert_eq!(full.ohlc.unwrap().high, 1610.0);
+        assert_eq!(full.depth.unwrap().sell[0].quantity, 40.0);
+    }
+
+    #[test]
+    fn error_envelope_carries_type_and_message() {
+        let body = r#"{"status":"error","message":"Insufficient funds","data":null,"error_type":"MarginException"}"#;
+        let envelope: Envelope<OrderReceipt> = serde_json::from_str(body).unwrap();
+
+        assert!(envelope.data.is_none());
+        assert_eq!(envelope.error_type.as_deref(), Some("MarginException"));
+    }
+}
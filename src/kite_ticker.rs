@@ -0,0 +1,102 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::{net::TcpStream, sync::Notify, task::JoinHandle};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use crate::{market_data::{MarketDataHandler, Subscriptions}, tick_decoder::{decode_packet, decode_text, packet_slices}};
+
+pub const KITE_TICKER_URL: &str = "wss://ws.kite.trade";
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+type KiteSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Our own connection to Kite's websocket. Frames are decoded in-crate and handed to
+/// the `MarketDataHandler`; subscription changes are pushed as soon as they are made.
+pub struct KiteTickerClient {
+    url: String,
+    handler: Arc<MarketDataHandler>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    changed: Arc<Notify>
+}
+
+impl KiteTickerClient {
+    pub fn new(api_key: &str, access_token: &str, handler: Arc<MarketDataHandler>, subscriptions: Arc<Mutex<Subscriptions>>, changed: Arc<Notify>) -> Self {
+        Self {
+            url: format!("{}?api_key={}&access_token={}", KITE_TICKER_URL, api_key, access_token),
+            handler,
+            subscriptions,
+            changed
+        }
+    }
+
+    /// Runs the connection in the background, reconnecting with backoff until aborted.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut delay = Duration::from_secs(1);
+
+            loop {
+                match connect_async(self.url.as_str()).await {
+                    Ok((socket, _)) => {
+                        println!("WebSocket connection established");
+                        delay = Duration::from_secs(1);
+                        match self.session(socket).await {
+                            Ok(_) => println!("WebSocket connection closed."),
+                            Err(e) => println!("WebSocket connection error occured: {}", e)
+                        }
+                    },
+                    Err(e) => println!("WebSocket connection failed: {}", e)
+                }
+
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        })
+    }
+
+    async fn session(&self, socket: KiteSocket) -> Result<(), anyhow::Error> {
+        let (mut write, mut read) = socket.split();
+
+        // A fresh connection starts with nothing subscribed
+        self.subscriptions.lock().unwrap().reset_active();
+        self.push_subscriptions(&mut write).await?;
+
+        loop {
+            tokio::select! {
+                frame = read.next() => match frame {
+                    Some(Ok(Message::Binary(bytes))) => {
+                        for packet in packet_slices(&bytes) {
+                            match packet.and_then(|packet| decode_packet(packet).map(|tick| (tick, packet))) {
+                                Ok((tick, packet)) => self.handler.on_tick(&tick, packet),
+                                Err(e) => println!("Dropping malformed tick packet: {}", e)
+                            }
+                        }
+                    },
+                    Some(Ok(Message::Text(text))) => self.handler.on_message(decode_text(&text)),
+                    Some(Ok(Message::Ping(payload))) => write.send(Message::Pong(payload)).await?,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => {},
+                    Some(Err(e)) => return Err(e.into())
+                },
+                _ = self.changed.notified() => self.push_subscriptions(&mut write).await?
+            }
+        }
+    }
+
+    async fn push_subscriptions<S>(&self, write: &mut S) -> Result<(), anyhow::Error>
+    where S: SinkExt<Message> + Unpin, S::Error: std::error::Error + Send + Sync + 'static
+    {
+        let (added, removed) = self.subscriptions.lock().unwrap().take_changes();
+
+        if !added.is_empty() {
+            write.send(Message::Text(json!({ "a": "subscribe", "v": added }).to_string())).await?;
+            // Full mode so stream clients get OHLC and depth, not just the last price
+            write.send(Message::Text(json!({ "a": "mode", "v": ["full", added] }).to_string())).await?;
+            println!("Subscribed to {} instruments", added.len());
+        }
+        if !removed.is_empty() {
+            write.send(Message::Text(json!({ "a": "unsubscribe", "v": removed }).to_string())).await?;
+            println!("Unsubscribed from {} instruments", removed.len());
+        }
+        Ok(())
+    }
+}
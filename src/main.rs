@@ -1,14 +1,53 @@
-use std::{env, io, sync::{Arc, Mutex}};
+use std::{env, io, sync::Arc, time::Duration};
 use actix_web::{web, App, HttpServer};
-use api_manager::{auth_callback, execute_trade, get_login_url, handle_postback};
+use api_manager::{add_watchlist_symbols, auth_callback, create_watchlist, delete_watchlist, execute_trade, get_depth, get_login_url, get_ltp, get_ohlc, get_quotes, get_watchlist, handle_postback, preview_trade, square_off_status, run_square_off, rollover_status, run_rollover, get_expiries, calculate_charges, get_positions, get_holdings, get_funds, get_open_orders, execute_basket, build_option_strategy, get_option_chain, get_option_greeks, price_option, start_algo, list_algos, get_algo, pause_algo, resume_algo, cancel_algo, submit_proposal, list_proposals, get_proposal, confirm_proposal, reject_proposal, get_universe, list_universes, list_watchlists, rank_watchlist, reload_universes, remove_watchlist_symbol, replace_watchlist, stream_ticks, stream_ticks_ws, stream_order_updates};
 use auth_manager::AuthManager;
 use data_structures::AppState;
+use idempotency::{IdempotencyStore, DEFAULT_IDEMPOTENCY_WINDOW};
+use proposals::{parse_approvers, ProposalStore, DEFAULT_PROPOSAL_TTL};
+use execution_algos::AlgoStore;
+use option_chain::DEFAULT_RISK_FREE_RATE;
+use market_protection::{MarketProtection, ProtectionMode};
+use rollover::{Rollover, RolloverMode, DEFAULT_ROLLOVER_DAYS, DEFAULT_ROLLOVER_TIME};
+use market_time::parse_time;
+use square_off::{SquareOff, DEFAULT_EXIT_PROTECTION_PCT, DEFAULT_SQUARE_OFF_TIME};
 use market_data::MarketData;
+use tick_recorder::ReplaySpeed;
+use tokio::sync::Mutex;
 pub mod auth_manager;
 pub mod data_structures;
 pub mod market_data;
 pub mod trade_executor;
 pub mod api_manager;
+pub mod instrument_master;
+pub mod index_universe;
+pub mod watchlist;
+pub mod tick_stream;
+pub mod order_book;
+pub mod tick_decoder;
+pub mod kite_ticker;
+pub mod kite_client;
+pub mod kite_models;
+pub mod errors;
+pub mod tick_recorder;
+pub mod price_cache;
+pub mod rate_limiter;
+pub mod idempotency;
+pub mod proposals;
+pub mod order_validation;
+pub mod market_time;
+pub mod square_off;
+pub mod rollover;
+pub mod expiry_calendar;
+pub mod market_protection;
+pub mod execution_algos;
+pub mod basket;
+pub mod charges;
+pub mod option_pricing;
+pub mod option_chain;
+pub mod option_risk;
+pub mod option_strategy;
+pub mod portfolio;
 
 #[actix_web::main]
 
@@ -18,13 +57,86 @@ async fn main() -> io::Result<()> {
     let api_key = env::var("API_KEY").expect("API key not set!");
     let api_secret = env::var("API_SECRET").expect("API secret not found!");
 
-    let auth_manager = AuthManager::new(api_key, api_secret);
-    let market_data = Arc::new(Mutex::new(MarketData::new()));
+    let watchlist_path = env::var("WATCHLIST_PATH").unwrap_or_else(|_| "data/watchlists.json".to_string());
+    let index_dir = env::var("INDEX_DIR").unwrap_or_else(|_| "data/indices".to_string());
+
+    let auth_manager = AuthManager::new(api_key, api_secret).expect("Failed to create Kite client!");
+    let mut market_data = MarketData::new(&watchlist_path, &index_dir).expect("Failed to load watchlists!");
+
+    if let Ok(freeze_path) = env::var("FREEZE_QTY_PATH") {
+        let count = market_data.load_freeze_limits(&freeze_path).expect("Failed to load freeze quantities!");
+        println!("Loaded {} freeze quantity limits", count);
+    }
+    if let Ok(record_dir) = env::var("TICK_RECORD_DIR") {
+        market_data.enable_recording(&record_dir).expect("Failed to start tick recorder!");
+    }
+    if let Ok(replay_path) = env::var("REPLAY_PATH") {
+        let speed = ReplaySpeed::parse(&env::var("REPLAY_SPEED").unwrap_or_else(|_| "1x".to_string()))
+            .expect("Invalid REPLAY_SPEED!");
+        market_data.start_replay(&replay_path, speed).expect("Failed to start tick replay!");
+    }
+    let prices = market_data.prices();
+    let idempotency_window = match env::var("IDEMPOTENCY_WINDOW_SECS") {
+        Ok(secs) => Duration::from_secs(secs.parse().expect("Invalid IDEMPOTENCY_WINDOW_SECS!")),
+        Err(_) => DEFAULT_IDEMPOTENCY_WINDOW
+    };
+    let proposal_ttl = match env::var("PROPOSAL_TTL_SECS") {
+        Ok(secs) => Duration::from_secs(secs.parse().expect("Invalid PROPOSAL_TTL_SECS!")),
+        Err(_) => DEFAULT_PROPOSAL_TTL
+    };
+    let notional_limit = env::var("PROPOSAL_NOTIONAL_LIMIT").ok()
+        .map(|limit| limit.parse::<f64>().expect("Invalid PROPOSAL_NOTIONAL_LIMIT!"));
+    let auto_approve_below = env::var("AUTO_APPROVE_NOTIONAL").ok()
+        .map(|limit| limit.parse::<f64>().expect("Invalid AUTO_APPROVE_NOTIONAL!"));
+    let approvers = parse_approvers(&env::var("PROPOSAL_APPROVERS").unwrap_or_default()).expect("Invalid PROPOSAL_APPROVERS!");
+    if notional_limit.is_some() && approvers.is_empty() {
+        println!("PROPOSAL_NOTIONAL_LIMIT is set without PROPOSAL_APPROVERS, orders above it can't be confirmed");
+    }
+    let square_off_time = parse_time(&env::var("SQUARE_OFF_TIME").unwrap_or_else(|_| DEFAULT_SQUARE_OFF_TIME.to_string()))
+        .expect("Invalid SQUARE_OFF_TIME!");
+    let exit_protection_pct = match env::var("EXIT_PROTECTION_PCT") {
+        Ok(pct) => pct.parse::<f64>().expect("Invalid EXIT_PROTECTION_PCT!"),
+        Err(_) => DEFAULT_EXIT_PROTECTION_PCT
+    };
+    let risk_free_rate = match env::var("RISK_FREE_RATE") {
+        Ok(rate) => rate.parse::<f64>().expect("Invalid RISK_FREE_RATE!"),
+        Err(_) => DEFAULT_RISK_FREE_RATE
+    };
+    // Scheduled rollovers only run with ROLLOVER_SCHEDULE=true; POST /rollover works either way
+    let rollover = Rollover::new(
+        parse_time(&env::var("ROLLOVER_TIME").unwrap_or_else(|_| DEFAULT_ROLLOVER_TIME.to_string())).expect("Invalid ROLLOVER_TIME!"),
+        env::var("ROLLOVER_DAYS").map(|days| days.parse::<i64>().expect("Invalid ROLLOVER_DAYS!")).unwrap_or(DEFAULT_ROLLOVER_DAYS),
+        env::var("ROLLOVER_MODE").map(|mode| mode.parse::<RolloverMode>().expect("Invalid ROLLOVER_MODE!")).unwrap_or(RolloverMode::Spread),
+        env::var("ROLLOVER_DRY_RUN").map(|dry_run| dry_run.parse::<bool>().expect("Invalid ROLLOVER_DRY_RUN!")).unwrap_or(false),
+        env::var("ROLLOVER_SCHEDULE").map(|scheduled| scheduled.parse::<bool>().expect("Invalid ROLLOVER_SCHEDULE!")).unwrap_or(false)
+    );
+    let defaults = MarketProtection::default();
+    let market_protection = MarketProtection {
+        mode: env::var("MARKET_PROTECTION").map(|mode| mode.parse::<ProtectionMode>().expect("Invalid MARKET_PROTECTION!")).unwrap_or(defaults.mode),
+        pct: env::var("MARKET_PROTECTION_PCT").map(|pct| pct.parse().expect("Invalid MARKET_PROTECTION_PCT!")).unwrap_or(defaults.pct),
+        depth_ticks: env::var("MARKET_PROTECTION_TICKS").map(|ticks| ticks.parse().expect("Invalid MARKET_PROTECTION_TICKS!")).unwrap_or(defaults.depth_ticks),
+        reprice_after: env::var("MARKET_REPRICE_SECS").ok().map(|secs| Duration::from_secs(secs.parse().expect("Invalid MARKET_REPRICE_SECS!"))),
+        max_reprices: env::var("MARKET_MAX_REPRICES").map(|count| count.parse().expect("Invalid MARKET_MAX_REPRICES!")).unwrap_or(defaults.max_reprices)
+    };
+    assert!(market_protection.pct > 0.0 && market_protection.pct < 100.0, "MARKET_PROTECTION_PCT must be between 0 and 100!");
+    let market_data = Arc::new(Mutex::new(market_data));
 
     let app_state = web::Data::new(AppState {
         auth_manager: Mutex::new(auth_manager),
-        market_data: market_data.clone()
+        market_data: market_data.clone(),
+        prices,
+        idempotency: IdempotencyStore::new(idempotency_window),
+        algo_idempotency: IdempotencyStore::new(idempotency_window),
+        basket_idempotency: IdempotencyStore::new(idempotency_window),
+        proposals: ProposalStore::new(proposal_ttl, notional_limit, auto_approve_below).with_approvers(approvers),
+        square_off: SquareOff::new(square_off_time, exit_protection_pct),
+        market_protection,
+        algos: AlgoStore::default(),
+        risk_free_rate,
+        rollover
     });
+    tokio::spawn(square_off::run_scheduler(app_state.clone()));
+    tokio::spawn(rollover::run_scheduler(app_state.clone()));
 
     println!("Starting server at http://127.0.0.1:8080");
     println!("Redirect URL: http://127.0.0.1:8080/auth/callback");
@@ -34,9 +146,54 @@ async fn main() -> io::Result<()> {
         App::new()
             .app_data(app_state.clone())
             .route("/trade", web::post().to(execute_trade))
+            .route("/trade/preview", web::post().to(preview_trade))
+            .route("/basket", web::post().to(execute_basket))
+            .route("/charges", web::post().to(calculate_charges))
+            .route("/options/chain/{underlying}", web::get().to(get_option_chain))
+            .route("/options/price", web::post().to(price_option))
+            .route("/options/greeks", web::get().to(get_option_greeks))
+            .route("/options/strategy", web::post().to(build_option_strategy))
+            .route("/portfolio/positions", web::get().to(get_positions))
+            .route("/portfolio/holdings", web::get().to(get_holdings))
+            .route("/funds", web::get().to(get_funds))
+            .route("/orders/open", web::get().to(get_open_orders))
+            .route("/algos", web::get().to(list_algos))
+            .route("/algos", web::post().to(start_algo))
+            .route("/algos/{id}", web::get().to(get_algo))
+            .route("/algos/{id}/pause", web::post().to(pause_algo))
+            .route("/algos/{id}/resume", web::post().to(resume_algo))
+            .route("/algos/{id}/cancel", web::post().to(cancel_algo))
+            .route("/square-off", web::get().to(square_off_status))
+            .route("/square-off", web::post().to(run_square_off))
+            .route("/rollover", web::get().to(rollover_status))
+            .route("/rollover", web::post().to(run_rollover))
+            .route("/expiries/{underlying}", web::get().to(get_expiries))
+            .route("/proposals", web::get().to(list_proposals))
+            .route("/proposals", web::post().to(submit_proposal))
+            .route("/proposals/{id}", web::get().to(get_proposal))
+            .route("/proposals/{id}/confirm", web::post().to(confirm_proposal))
+            .route("/proposals/{id}/reject", web::post().to(reject_proposal))
             .route("/auth", web::get().to(get_login_url))
             .route("/auth/callback", web::get().to(auth_callback))
             .route("webhook/postback", web::post().to(handle_postback))
+            .route("/watchlists", web::get().to(list_watchlists))
+            .route("/watchlists", web::post().to(create_watchlist))
+            .route("/watchlists/{name}", web::get().to(get_watchlist))
+            .route("/watchlists/{name}", web::put().to(replace_watchlist))
+            .route("/watchlists/{name}", web::delete().to(delete_watchlist))
+            .route("/watchlists/{name}/symbols", web::post().to(add_watchlist_symbols))
+            .route("/watchlists/{name}/symbols/{symbol}", web::delete().to(remove_watchlist_symbol))
+            .route("/watchlists/{name}/ranking", web::get().to(rank_watchlist))
+            .route("/universes", web::get().to(list_universes))
+            .route("/universes/reload", web::post().to(reload_universes))
+            .route("/universes/{name}", web::get().to(get_universe))
+            .route("/quote", web::get().to(get_quotes))
+            .route("/ltp", web::get().to(get_ltp))
+            .route("/ohlc", web::get().to(get_ohlc))
+            .route("/depth", web::get().to(get_depth))
+            .route("/stream/ticks", web::get().to(stream_ticks))
+            .route("/stream/ticks/ws", web::get().to(stream_ticks_ws))
+            .route("/stream/orders", web::get().to(stream_order_updates))
     })
     .bind("127.0.0.1:8080")?
     .run()
@@ -1,192 +1,681 @@
-use std::{cmp::Ordering, collections::{HashMap, HashSet}, sync::{Arc, Mutex}, time::Duration};
-use anyhow::Ok;
-use chrono::Utc;
-use kiteconnect::{connect::KiteConnect, ticker::{KiteTicker, WebSocketHandler, KiteTickerHandler}};
+use std::{cmp::Ordering, collections::{BTreeMap, HashMap, HashSet}, sync::{Arc, Mutex}, time::Duration};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{sync::{broadcast, Notify}, task::JoinHandle};
+use crate::{kite_client::KiteClient, kite_models::Candle, kite_ticker::KiteTickerClient, order_book::analyse, price_cache::PriceCache, market_time::ist, tick_decoder::{Tick, TickerMessage}, tick_recorder::{ReplaySpeed, TickRecorder, TickReplay}, tick_stream::{TickData, TickSubscription, TICK_CHANNEL_CAPACITY}, index_universe::{IndexUniverses, INDEX_PREFIX}, instrument_master::{instrument_key, Instrument, InstrumentMaster}, order_validation::FreezeLimits, watchlist::WatchlistStore};
 
 pub struct MarketData {
-    kite: Option<KiteConnect>,
-    ticker: Option<KiteTicker>,
-    live_prices: HashMap<String, f64>,
-    watchlist: HashSet<String>
+    kite: Option<KiteClient>,
+    ticker: Option<JoinHandle<()>>,
+    // The recording `ticker` is playing back, when it is a replay rather than the live feed
+    replaying: Option<String>,
+    live_ticks: Arc<PriceCache>,
+    instruments: InstrumentMaster,
+    freeze_limits: FreezeLimits,
+    watchlists: WatchlistStore,
+    universes: IndexUniverses,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    ticks: broadcast::Sender<Arc<TickData>>,
+    order_updates: broadcast::Sender<Value>,
+    recorder: Option<TickRecorder>
 }
 
-#[derive(Debug)]
-pub struct MarketDataHandler {
-    prices: Arc<Mutex<HashMap<String, f64>>>
+/// Tokens the watchlists and stream clients want versus tokens the ticker is actually subscribed to.
+/// Every change wakes the ticker connection so it can push the difference upstream.
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    wanted: HashMap<u32, String>,
+    streamed: HashMap<u32, (String, usize)>,
+    active: HashSet<u32>,
+    changed: Arc<Notify>
 }
 
-impl KiteTickerHandler for MarketDataHandler {
-    fn on_open<T>(&mut self, ws: &mut WebSocketHandler<T>)
-    where T: KiteTickerHandler
-    {
-        let ws_ = ws.subscribe(vec![123456]);
-        println!("WebSocket connection established: {:?}", ws_);
+impl Subscriptions {
+    pub fn changed(&self) -> Arc<Notify> {
+        self.changed.clone()
     }
 
-    fn on_ticks<T>(&mut self, _ws: &mut WebSocketHandler<T>, ticks: Vec<serde_json::Value>)
-    where T: KiteTickerHandler
-    {
-        let mut prices = self.prices.lock().unwrap();
-        println!("ticks: {:?}", ticks);
-
-        for tick in ticks {
-            if let (Some(symbol), Some(last_price)) = (
-                tick.get("symbol").and_then(|s| s.as_str()),
-                tick.get("last_price").and_then(|p| p.as_f64())
-            ) {
-                prices.insert(symbol.to_string(), last_price);
+    fn set_wanted(&mut self, wanted: HashMap<u32, String>) {
+        self.wanted = wanted;
+        self.changed.notify_one();
+    }
+
+    pub fn reset_active(&mut self) {
+        self.active.clear();
+    }
+
+    /// Tokens to subscribe and unsubscribe to bring the connection in line, assuming both succeed.
+    pub fn take_changes(&mut self) -> (Vec<u32>, Vec<u32>) {
+        let added: Vec<u32> = self.wanted.keys()
+            .chain(self.streamed.keys())
+            .filter(|token| !self.active.contains(token))
+            .copied()
+            .collect::<HashSet<u32>>()
+            .into_iter()
+            .collect();
+        let removed: Vec<u32> = self.active.iter()
+            .filter(|token| !self.wants(**token))
+            .copied()
+            .collect();
+
+        self.active.extend(&added);
+        for token in &removed {
+            self.active.remove(token);
+        }
+        (added, removed)
+    }
+
+    fn symbol(&self, token: u32) -> Option<&String> {
+        self.wanted.get(&token).or_else(|| self.streamed.get(&token).map(|(symbol, _)| symbol))
+    }
+
+    fn wants(&self, token: u32) -> bool {
+        self.wanted.contains_key(&token) || self.streamed.contains_key(&token)
+    }
+
+    pub fn retain_stream(&mut self, token: u32, symbol: &str) {
+        self.streamed.entry(token).or_insert_with(|| (symbol.to_string(), 0)).1 += 1;
+        self.changed.notify_one();
+    }
+
+    pub fn release_stream(&mut self, token: u32) {
+        if let Some((_, clients)) = self.streamed.get_mut(&token) {
+            *clients -= 1;
+            if *clients == 0 {
+                self.streamed.remove(&token);
+                self.changed.notify_one();
             }
-            println!("Fellow on_ticks callback for prices: {:?}", prices);
         }
     }
+}
 
-    fn on_close<T>(&mut self, _ws: &mut WebSocketHandler<T>)
-    where T: KiteTickerHandler
-    {
-        println!("WebSocket connection closed.");
+/// Ticks older than this are not trusted for quotes and the REST API is asked instead.
+pub const LIVE_QUOTE_MAX_AGE: Duration = Duration::from_secs(3);
+pub const MAX_QUOTE_KEYS: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuoteKind {
+    Quote,
+    Ltp,
+    Ohlc,
+    Depth
+}
+
+impl QuoteKind {
+    // Kite accepts up to 500 instruments per /quote call and 1000 for /quote/ltp and /quote/ohlc
+    fn batch_size(&self) -> usize {
+        match self {
+            QuoteKind::Quote | QuoteKind::Depth => 500,
+            QuoteKind::Ltp | QuoteKind::Ohlc => 1000
+        }
     }
 
-    fn on_error<T>(&mut self, _ws: &mut WebSocketHandler<T>)
-    where T: KiteTickerHandler
-    {
-        println!("WebSocket connection error occured..");
+    pub fn covered_by(&self, tick: &TickData) -> bool {
+        match self {
+            QuoteKind::Ltp => true,
+            QuoteKind::Ohlc => tick.ohlc.is_some(),
+            QuoteKind::Quote => tick.ohlc.is_some() && tick.volume.is_some(),
+            QuoteKind::Depth => tick.depth.is_some()
+        }
+    }
+
+    fn project(&self, tick: &TickData) -> Value {
+        match self {
+            QuoteKind::Ltp => json!({
+                "instrument_token": tick.instrument_token,
+                "last_price": tick.last_price
+            }),
+            QuoteKind::Ohlc => json!({
+                "instrument_token": tick.instrument_token,
+                "last_price": tick.last_price,
+                "ohlc": tick.ohlc
+            }),
+            QuoteKind::Depth => json!({
+                "instrument_token": tick.instrument_token,
+                "last_price": tick.last_price,
+                "depth": tick.depth
+            }),
+            QuoteKind::Quote => serde_json::to_value(tick).unwrap_or(Value::Null)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SourcedTick {
+    pub tick: Arc<TickData>,
+    pub source: &'static str,
+    pub age_ms: Option<u64>
+}
+
+#[derive(Debug, Default)]
+pub struct QuoteBatch {
+    pub data: BTreeMap<String, Value>,
+    pub missing: Vec<String>
+}
+
+impl QuoteBatch {
+    pub fn quotes(ticks: BTreeMap<String, SourcedTick>, missing: Vec<String>, kind: QuoteKind) -> Self {
+        let data = ticks.into_iter()
+            .map(|(key, sourced)| {
+                let mut quote = kind.project(&sourced.tick);
+                quote["source"] = json!(sourced.source);
+                if let Some(age_ms) = sourced.age_ms {
+                    quote["age_ms"] = json!(age_ms);
+                }
+                (key, quote)
+            })
+            .collect();
+
+        Self { data, missing }
+    }
+
+    pub fn depth(ticks: BTreeMap<String, SourcedTick>, missing: Vec<String>, band_pct: f64) -> Self {
+        let data = ticks.into_iter()
+            .filter_map(|(key, sourced)| {
+                let analytics = analyse(sourced.tick.depth.as_ref()?, band_pct);
+                let mut entry = QuoteKind::Depth.project(&sourced.tick);
+                entry["analytics"] = json!(analytics);
+                entry["source"] = json!(sourced.source);
+                if let Some(age_ms) = sourced.age_ms {
+                    entry["age_ms"] = json!(age_ms);
+                }
+                Some((key, entry))
+            })
+            .collect();
+
+        Self { data, missing }
+    }
+}
+
+/// Normalises requested symbols to de-duplicated `EXCHANGE:SYMBOL` keys, within Kite's per-request limit.
+pub fn quote_keys(symbols: &[String]) -> Result<Vec<String>, anyhow::Error> {
+    let mut keys: Vec<String> = Vec::new();
+    for key in symbols.iter().map(|s| instrument_key(s)) {
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    if keys.is_empty() {
+        return Err(anyhow::anyhow!("No instruments requested"));
+    }
+    if keys.len() > MAX_QUOTE_KEYS {
+        return Err(anyhow::anyhow!("Too many instruments: {} (max {})", keys.len(), MAX_QUOTE_KEYS));
+    }
+    Ok(keys)
+}
+
+#[derive(Debug)]
+pub struct MarketDataHandler {
+    ticks_cache: Arc<PriceCache>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    ticks: broadcast::Sender<Arc<TickData>>,
+    order_updates: broadcast::Sender<Value>,
+    recorder: Option<TickRecorder>
+}
+
+impl MarketDataHandler {
+    /// A decoded tick from the live feed; `packet` is the raw bytes it was decoded from.
+    pub fn on_tick(&self, tick: &Tick, packet: &[u8]) {
+        let symbol = match self.subscriptions.lock().unwrap().symbol(tick.instrument_token) {
+            Some(symbol) => symbol.clone(),
+            None => return
+        };
+
+        if let Some(recorder) = &self.recorder {
+            recorder.record(packet, tick, &symbol);
+        }
+        self.publish(tick, symbol);
+    }
+
+    /// A tick read back from a recording, published under the symbol it was recorded with.
+    pub fn on_replayed_tick(&self, tick: &Tick, symbol: &str) {
+        self.publish(tick, symbol.to_string());
+    }
+
+    fn publish(&self, tick: &Tick, symbol: String) {
+        let data = Arc::new(TickData::from_tick(tick, &symbol));
+        self.ticks_cache.update(symbol, data.clone());
+        // No receivers just means nobody is streaming right now
+        let _ = self.ticks.send(data);
+    }
+
+    pub fn on_message(&self, message: TickerMessage) {
+        match message {
+            TickerMessage::OrderUpdate(update) => {
+                println!("Order update: {}", update);
+                let _ = self.order_updates.send(update);
+            },
+            TickerMessage::Error(error) => println!("Ticker error: {}", error),
+            TickerMessage::Message(message) => println!("Ticker message: {}", message),
+            TickerMessage::Unknown(text) => println!("Unrecognised ticker message: {}", text)
+        }
     }
 }
 
 impl MarketData {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(watchlist_path: &str, index_dir: &str) -> Result<Self, anyhow::Error> {
+        Ok(Self {
             kite: None,
             ticker: None,
-            live_prices: HashMap::new(),
-            watchlist: ["RELIANCE", "TCS", "HDFCBANK", "INFY", "SBIN", "TATAMOTORS", "ITC"]
-            .iter().map(|x| x.to_string()).collect()
-        }
-    }
-
-    pub fn initialize_ticker(&mut self, api_key: &str, access_token: &str) {
-        if let Some(kite) = &self.kite {
-            let instruments = kite.instruments(Some("TATAMOTORS"));
-            let mut tokens = Vec::new();
-            for instrument in instruments.into_iter() {
-                if let (Some(symbol), Some(last_token)) = (
-                    instrument.get("symbol").and_then(|s| s.as_str()),
-                    instrument.get("last_token").and_then(|t| t.as_f64())
-                ) {
-                    if self.watchlist.contains(symbol) {
-                        tokens.push(last_token);
-                    }
-                }
+            replaying: None,
+            live_ticks: Arc::new(PriceCache::default()),
+            instruments: InstrumentMaster::default(),
+            freeze_limits: FreezeLimits::default(),
+            watchlists: WatchlistStore::load(watchlist_path)?,
+            universes: IndexUniverses::load(index_dir)?,
+            subscriptions: Arc::new(Mutex::new(Subscriptions::default())),
+            ticks: broadcast::channel(TICK_CHANNEL_CAPACITY).0,
+            order_updates: broadcast::channel(TICK_CHANNEL_CAPACITY).0,
+            recorder: None
+        })
+    }
+
+    /// Records every tick from the live feed into daily files under `dir`.
+    pub fn enable_recording(&mut self, dir: &str) -> Result<(), anyhow::Error> {
+        self.recorder = Some(TickRecorder::spawn(dir)?);
+        println!("Recording ticks to {}", dir);
+        Ok(())
+    }
+
+    fn handler(&self) -> Arc<MarketDataHandler> {
+        Arc::new(MarketDataHandler {
+            ticks_cache: self.live_ticks.clone(),
+            subscriptions: self.subscriptions.clone(),
+            ticks: self.ticks.clone(),
+            order_updates: self.order_updates.clone(),
+            recorder: self.recorder.clone()
+        })
+    }
+
+    /// Plays recorded ticks back through the same handler as the live feed. Needs no Kite session.
+    pub fn start_replay(&mut self, path: &str, speed: ReplaySpeed) -> Result<(), anyhow::Error> {
+        let replay = TickReplay::new(path, speed)?;
+        let handler = self.handler();
+
+        let task = tokio::spawn(async move {
+            if let Err(e) = replay.run(handler).await {
+                println!("Tick replay failed: {}", e);
+            }
+        });
+        if let Some(previous) = self.ticker.replace(task) {
+            previous.abort();
+        }
+        self.replaying = Some(path.to_string());
+        Ok(())
+    }
+
+    /// Connects the live ticker, unless a replay is still feeding the same handler; then the reason
+    /// it was not started is returned.
+    pub fn initialize_ticker(&mut self, api_key: &str, access_token: &str) -> Option<String> {
+        if let Some(path) = &self.replaying {
+            if self.ticker.as_ref().is_some_and(|task| !task.is_finished()) {
+                let reason = format!("Tick replay of {} is still running, the live ticker was not started", path);
+                println!("{}", reason);
+                return Some(reason);
             }
+            self.replaying = None;
+        }
+
+        if self.kite.is_some() {
+            self.sync_subscriptions();
+
+            let handler = self.handler();
+            let changed = self.subscriptions.lock().unwrap().changed();
+            let client = KiteTickerClient::new(api_key, access_token, handler, self.subscriptions.clone(), changed);
 
-            if !tokens.is_empty() {
-                let shared_prices = Arc::new(Mutex::new(self.live_prices.clone()));
-                let handler = MarketDataHandler { prices: shared_prices.clone() };
-                let mut ticker = KiteTicker::new(api_key, access_token);
-                let _ = ticker.connect(handler, None);
-                self.ticker = Some(ticker);
+            // A new session token replaces the previous connection
+            if let Some(previous) = self.ticker.replace(client.spawn()) {
+                previous.abort();
             }
         }
+        None
     }
 
-    pub fn set_kite(&mut self, kite: KiteConnect) {
+    /// The live price cache, readable without holding the market data lock.
+    pub fn prices(&self) -> Arc<PriceCache> {
+        self.live_ticks.clone()
+    }
+
+    /// Order updates from the ticker connection and from Kite postbacks, in arrival order. An
+    /// order can show up from both.
+    pub fn subscribe_order_updates(&self) -> broadcast::Receiver<Value> {
+        self.order_updates.subscribe()
+    }
+
+    pub fn publish_order_update(&self, update: Value) {
+        // No receivers just means nobody is streaming right now
+        let _ = self.order_updates.send(update);
+    }
+
+    /// Installs a session's client together with the instrument dump it downloaded.
+    pub fn set_kite(&mut self, kite: KiteClient, instruments: InstrumentMaster) {
+        self.instruments = instruments;
+        self.universes.resolve(&self.instruments);
         self.kite = Some(kite);
+        println!("Loaded {} instruments", self.instruments.len());
+    }
+
+    /// A handle on the REST client so callers can make Kite calls after releasing the market data lock.
+    pub fn kite(&self) -> Option<KiteClient> {
+        self.kite.clone()
     }
 
-    pub fn get_quote(&mut self, symbol: &str)  -> Result<f64, anyhow::Error> {
-        if let Some(price) = self.live_prices.get(symbol) {
-            return Ok(*price);
+    pub fn watchlists(&self) -> &WatchlistStore {
+        &self.watchlists
+    }
+
+    /// Applies a change to the watchlists and re-derives the ticker subscriptions from the result.
+    pub fn update_watchlists<F, R>(&mut self, change: F) -> Result<R, anyhow::Error>
+    where F: FnOnce(&mut WatchlistStore) -> Result<R, anyhow::Error>
+    {
+        let result = change(&mut self.watchlists)?;
+        self.sync_subscriptions();
+        Ok(result)
+    }
+
+    pub fn universes(&self) -> &IndexUniverses {
+        &self.universes
+    }
+
+    pub fn reload_universes(&mut self) -> Result<usize, anyhow::Error> {
+        let count = self.universes.reload()?;
+        self.universes.resolve(&self.instruments);
+        Ok(count)
+    }
+
+    /// Symbols of a watchlist, or of an index universe when the name starts with `index:`.
+    pub fn universe_symbols(&self, name: &str) -> Result<Vec<String>, anyhow::Error> {
+        if name.starts_with(INDEX_PREFIX) {
+            return match self.universes.get(name) {
+                Some(universe) => Ok(universe.symbols()),
+                None => Err(anyhow::anyhow!("Index universe not found: {}", name))
+            };
         }
 
-        if let Some(kite) = &self.kite {
-            let quotes = kite.quote((&[symbol]).to_vec())?;
-            if let Some(quote) = quotes.get(symbol) {
-                if let Some(last_price) =  quote.get("symbol")
-                .and_then(|f| f.as_f64()) {
-                    return Ok(last_price);
-                }
-                else {
-                    return Err(anyhow::anyhow!("Unable to fetch the last price for: {}", symbol));
+        match self.watchlists.get(name) {
+            Some(list) => Ok(list.symbols.clone()),
+            None => Err(anyhow::anyhow!("Watchlist not found: {}", name))
+        }
+    }
+
+    /// Splits symbols into resolved `(token, EXCHANGE:SYMBOL)` pairs and the ones the instrument master doesn't know.
+    pub fn resolve_instruments(&self, symbols: &[String]) -> (Vec<(u32, String)>, Vec<String>) {
+        let mut resolved = Vec::new();
+        let mut unknown = Vec::new();
+
+        for symbol in symbols {
+            match self.instruments.lookup(symbol) {
+                Some(instrument) => resolved.push((instrument.instrument_token, instrument.key())),
+                // Offline (e.g. during a replay) the only instruments we know are the ones that have ticked
+                None => match self.live_ticks.get(&instrument_key(symbol)) {
+                    Some(cached) => resolved.push((cached.tick.instrument_token, cached.tick.symbol.clone())),
+                    None => unknown.push(symbol.clone())
                 }
             }
-            else {
-                return Err(anyhow::anyhow!("Unable to fetch the quote for: {}", symbol));
-            }
         }
-        else {
-            return Err(anyhow::anyhow!("Unable to get quote for: {}", symbol));
-        }
-    }
-
-    pub fn get_instrumental_token(&self, symbol: &str) -> Result<String, anyhow::Error> {
-        if let Some(kite) = &self.kite {
-            let instruments = kite.instruments(Some("ITC"));
-            let token = String::from("");
-            for ele in instruments.into_iter() {
-                if let (Some(sym), Some(token)) = (
-                    ele.get("symbol").and_then(|s| s.as_str()),
-                    ele.get("token").and_then(|t| t.as_str())
-                ) {
-                    if sym == symbol {
-                        return Ok(token.to_string());
-                    }
-                }
-                else {
-                    return Err(anyhow::anyhow!("Unable to get the token for: {}", symbol));
-                }
+        (resolved, unknown)
+    }
+
+    pub fn subscribe_ticks(&self) -> TickSubscription {
+        TickSubscription::new(self.ticks.subscribe(), self.subscriptions.clone())
+    }
+
+    pub fn sync_subscriptions(&mut self) {
+        let mut wanted = HashMap::new();
+
+        for symbol in self.watchlists.all_symbols() {
+            match self.instruments.token(&symbol) {
+                Some(token) => {
+                    wanted.insert(token, symbol);
+                },
+                None if !self.instruments.is_empty() => {
+                    println!("No instrument found for watchlist symbol: {}", symbol)
+                },
+                None => {}
             }
-            Ok(token)
         }
-        else {
-            return Err(anyhow::anyhow!("Unable to connect kite for: {}", symbol));
+        self.subscriptions.lock().unwrap().set_wanted(wanted);
+    }
+
+    pub async fn get_quote(&self, symbol: &str) -> Result<f64, anyhow::Error> {
+        let key = instrument_key(symbol);
+        if let Some(sourced) = self.live_ticks.fresh(&key, QuoteKind::Ltp) {
+            return Ok(sourced.tick.last_price);
         }
+
+        let (ticks, _) = fetch_ticks(self.kite.as_ref(), std::slice::from_ref(&key), QuoteKind::Ltp).await?;
+        match ticks.get(&key) {
+            Some(sourced) => Ok(sourced.tick.last_price),
+            None => Err(anyhow::anyhow!("Unable to fetch the last price for: {}", symbol))
+        }
+    }
+
+    pub fn instrument(&self, symbol: &str) -> Option<Instrument> {
+        self.instruments.lookup(symbol).cloned()
+    }
+
+    pub fn options(&self, underlying: &str) -> Vec<Instrument> {
+        self.instruments.options(underlying).into_iter().cloned().collect()
     }
 
-    pub fn historical_data(& self, symbol: &str, from: i64, to: i64) -> Result<Vec<serde_json::Value>, anyhow::Error> {
-        if let Some(kite) = &self.kite {
-            let token = self.get_instrumental_token(symbol)?;
-            let data = kite.historical_data(&token, &from.to_string(), &to.to_string(), "minute", "true".into())?;
-            Ok(vec![data])
+    pub fn derivatives(&self, underlying: &str) -> Vec<Instrument> {
+        self.instruments.derivatives(underlying).into_iter().cloned().collect()
+    }
+
+    pub fn futures(&self, underlying: &str) -> Vec<Instrument> {
+        self.instruments.futures(underlying).into_iter().cloned().collect()
+    }
+
+    pub fn load_freeze_limits(&mut self, path: &str) -> Result<usize, anyhow::Error> {
+        self.freeze_limits = FreezeLimits::load(path)?;
+        Ok(self.freeze_limits.len())
+    }
+
+    pub fn freeze_limit(&self, instrument: &Instrument) -> Option<u32> {
+        self.freeze_limits.get(instrument)
+    }
+
+    pub fn get_instrumental_token(&self, symbol: &str) -> Result<u32, anyhow::Error> {
+        match self.instruments.token(symbol) {
+            Some(token) => Ok(token),
+            None => Err(anyhow::anyhow!("Unable to get the token for: {}", symbol))
         }
-        else {
-            return Err(anyhow::anyhow!("Unable to get historical data from KiteConnect for: {}", symbol));
+    }
+
+    /// Everything ranking a watchlist or index universe needs, taken under the lock so the
+    /// historical calls themselves run without it.
+    pub fn ranking(&self, watchlist: &str) -> Result<PerformanceRanking, anyhow::Error> {
+        let kite = match &self.kite {
+            Some(kite) => kite.clone(),
+            None => return Err(anyhow::anyhow!("Unable to get historical data from Kite for: {}", watchlist))
+        };
+
+        // Symbols the instrument master doesn't know (delisted or renamed index members) are
+        // reported rather than failing the whole ranking
+        let mut instruments = Vec::new();
+        let mut skipped = Vec::new();
+        for symbol in self.universe_symbols(watchlist)? {
+            match self.get_instrumental_token(&symbol) {
+                Ok(token) => instruments.push((symbol, token)),
+                Err(e) => skipped.push(SkippedSymbol { symbol, reason: e.to_string() })
+            }
+        }
+
+        Ok(PerformanceRanking { kite, watchlist: watchlist.to_string(), instruments, skipped })
+    }
+}
+
+/// Fetches keys from Kite REST in batches; keys Kite returns nothing for, or all of them
+/// when there is no session yet, come back as missing.
+pub async fn fetch_ticks(kite: Option<&KiteClient>, keys: &[String], kind: QuoteKind) -> Result<(BTreeMap<String, SourcedTick>, Vec<String>), anyhow::Error> {
+    let mut ticks = BTreeMap::new();
+    let mut missing = Vec::new();
+
+    let kite = match kite {
+        Some(kite) => kite,
+        None => return Ok((ticks, keys.to_vec()))
+    };
+
+    for chunk in keys.chunks(kind.batch_size()) {
+        let mut response = match kind {
+            QuoteKind::Quote | QuoteKind::Depth => kite.quote(chunk).await?,
+            QuoteKind::Ltp => kite.ltp(chunk).await?,
+            QuoteKind::Ohlc => kite.ohlc(chunk).await?
+        };
+
+        for key in chunk {
+            match response.remove(key) {
+                Some(quote) => {
+                    let tick = TickData::from_quote(&quote, key);
+                    ticks.insert(key.clone(), SourcedTick { tick: Arc::new(tick), source: "rest", age_ms: None });
+                },
+                None => missing.push(key.clone())
+            }
         }
     }
+    Ok((ticks, missing))
+}
+
+/// A symbol left out of a ranking, with the reason.
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedSymbol {
+    pub symbol: String,
+    pub reason: String
+}
 
-    pub async fn best_performer(&mut self, timeframe_units: u64) -> Result<String, anyhow::Error> {
+/// Symbols best first by percentage change, and the ones that could not be ranked.
+#[derive(Debug, Clone, Default)]
+pub struct Performances {
+    pub ranked: Vec<(String, f64)>,
+    pub skipped: Vec<SkippedSymbol>
+}
+
+pub struct PerformanceRanking {
+    kite: KiteClient,
+    watchlist: String,
+    instruments: Vec<(String, u32)>,
+    skipped: Vec<SkippedSymbol>
+}
+
+impl PerformanceRanking {
+    pub async fn historical_data(&self, instrument_token: u32, from: i64, to: i64) -> Result<Vec<Candle>, anyhow::Error> {
+        self.kite.historical_data(instrument_token, "minute", &kite_timestamp(from), &kite_timestamp(to), false, false).await
+    }
+
+    /// Percentage change over the timeframe for every symbol, best first. History calls queue on
+    /// Kite's 3 per second historical limit, so a large watchlist takes a few seconds. Symbols
+    /// without a token or enough history are skipped; it only fails when nothing could be ranked
+    /// because Kite itself failed.
+    pub async fn rank_performers(&self, timeframe_units: u64) -> Result<Performances, anyhow::Error> {
         let now = Utc::now();
         let from = now - Duration::from_secs(timeframe_units);
-        let mut performances = Vec::new();
-
-        for symbol in &self.watchlist {
-            let data = self.historical_data(&symbol, from.timestamp(), now.timestamp())?;
-            if data.len() >= 2 {
-                let first_ = &data[0];
-                let last_ = &data[data.len() - 1];
-                let first_price = first_.get("close").and_then(|f| f.as_f64()).unwrap_or(0.0);
-                let last_price = last_.get("close").and_then(|f| f.as_f64()).unwrap_or(0.0);
-
-                if first_price > 0.0 {
-                    let performance = (last_price - first_price) / first_price * 100.0;
-                    performances.push((symbol.clone(), performance));
-                }
-                else {
-                    return Err(anyhow::anyhow!("Invalid price for symbol: {}", symbol));
+        let mut performances = Performances { ranked: Vec::new(), skipped: self.skipped.clone() };
+        let mut last_error = None;
+
+        for (symbol, token) in &self.instruments {
+            let data = match self.historical_data(*token, from.timestamp(), now.timestamp()).await {
+                Ok(data) => data,
+                Err(e) => {
+                    performances.skipped.push(SkippedSymbol { symbol: symbol.clone(), reason: format!("Failed to fetch history: {}", e) });
+                    last_error = Some(e);
+                    continue;
                 }
+            };
+            let reason = if data.len() < 2 {
+                "not enough candles in timeframe"
             }
-            else {
-                return Err(anyhow::anyhow!("Invalid data received for: {}", symbol));
+            else if data[0].close <= 0.0 {
+                "invalid price in candles"
             }
+            else {
+                let first_price = data[0].close;
+                let last_price = data[data.len() - 1].close;
+                performances.ranked.push((symbol.clone(), (last_price - first_price) / first_price * 100.0));
+                continue;
+            };
+            println!("Skipping {}: {}", symbol, reason);
+            performances.skipped.push(SkippedSymbol { symbol: symbol.clone(), reason: reason.to_string() });
+        }
+
+        if let (true, Some(e)) = (performances.ranked.is_empty(), last_error) {
+            return Err(e);
         }
         // Sort in order by descending performance
-        performances.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        performances.ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        Ok(performances)
+    }
+
+    pub async fn best_performer(&self, timeframe_units: u64) -> Result<String, anyhow::Error> {
+        let performances = self.rank_performers(timeframe_units).await?;
 
-        if let Some((symbol, performance)) = performances.first() {
-            println!("Best performer: {} ({}%)", symbol, performance);
+        if let Some((symbol, performance)) = performances.ranked.first() {
+            println!("Best performer in {}: {} ({}%)", self.watchlist, symbol, performance);
             Ok(symbol.clone())
         }
         else {
-            return Err(anyhow::anyhow!("No performance data is available.."));
+            Err(anyhow::anyhow!("No performance data is available for watchlist: {}", self.watchlist))
         }
     }
-}
\ No newline at end of file
+}
+
+// Kite's historical API expects exchange-local (IST) wall clock timestamps
+fn kite_timestamp(epoch_secs: i64) -> String {
+    DateTime::from_timestamp(epoch_secs, 0)
+        .unwrap_or_default()
+        .with_timezone(&ist())
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick_decoder::TickMode;
+
+    fn tick(token: u32, mode: TickMode) -> Arc<TickData> {
+        let tick = Tick { instrument_token: token, mode, tradable: true, last_price: 100.0, has_depth: mode == TickMode::Full, ..Tick::default() };
+        Arc::new(TickData::from_tick(&tick, "NSE:INFY"))
+    }
+
+    #[test]
+    fn quote_keys_are_normalised_deduplicated_and_capped() {
+        let keys = quote_keys(&["infy".to_string(), "NSE:INFY".to_string(), " nfo:nifty24janfut ".to_string()]).unwrap();
+        assert_eq!(keys, vec!["NSE:INFY", "NFO:NIFTY24JANFUT"]);
+        assert!(quote_keys(&[]).is_err());
+
+        let too_many: Vec<String> = (0..=MAX_QUOTE_KEYS).map(|i| format!("SYM{}", i)).collect();
+        assert!(quote_keys(&too_many).is_err());
+        assert_eq!(quote_keys(&too_many[..MAX_QUOTE_KEYS]).unwrap().len(), MAX_QUOTE_KEYS);
+        // Kite takes 500 keys per /quote call and 1000 per /quote/ltp or /quote/ohlc call
+        assert_eq!(too_many.chunks(QuoteKind::Quote.batch_size()).count(), 2);
+        assert_eq!(too_many.chunks(QuoteKind::Ltp.batch_size()).count(), 1);
+    }
+
+    #[tokio::test]
+    async fn live_ticks_are_used_only_when_they_carry_what_was_asked_for() {
+        let cache = PriceCache::default();
+        cache.update("NSE:INFY".to_string(), tick(408065, TickMode::Quote));
+        let keys = vec!["NSE:INFY".to_string(), "NSE:TCS".to_string()];
+
+        let (ticks, pending) = cache.fresh_ticks(keys.clone(), QuoteKind::Ohlc);
+        assert_eq!((ticks["NSE:INFY"].source, pending), ("ticker", vec!["NSE:TCS".to_string()]));
+        // A quote-mode tick has no depth, so depth goes to REST for both
+        let (ticks, pending) = cache.fresh_ticks(keys.clone(), QuoteKind::Depth);
+        assert!(ticks.is_empty());
+        assert_eq!(pending, keys);
+
+        // Without a session whatever is left over comes back missing instead of failing
+        let (fetched, missing) = fetch_ticks(None, &pending, QuoteKind::Depth).await.unwrap();
+        assert!(fetched.is_empty());
+        assert_eq!(missing, keys);
+
+        let (ticks, _) = cache.fresh_ticks(keys, QuoteKind::Ltp);
+        let batch = QuoteBatch::quotes(ticks, vec!["NSE:TCS".to_string()], QuoteKind::Ltp);
+        assert_eq!(batch.data["NSE:INFY"]["source"], "ticker");
+        assert_eq!(batch.data["NSE:INFY"]["last_price"], 100.0);
+        assert!(batch.data["NSE:INFY"].get("ohlc").is_none());
+        assert_eq!(batch.missing, vec!["NSE:TCS"]);
+    }
+}
@@ -0,0 +1,193 @@
+use std::{str::FromStr, time::Duration};
+use crate::{kite_client::KiteClient, kite_models::{OrderParams, Quote}, order_book::{analyse, BookAnalytics, DEFAULT_DEPTH_BAND_PCT}, order_validation::round_to_tick, trade_executor::ORDER_VARIETY};
+
+/// Furthest a protected market order may be priced from the LTP, in percent.
+pub const DEFAULT_PROTECTION_PCT: f64 = 2.0;
+/// Ticks through the best opposite quote a depth-priced order goes.
+pub const DEFAULT_DEPTH_TICKS: u32 = 2;
+pub const DEFAULT_MAX_REPRICES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectionMode {
+    /// Market orders are sent as they are
+    Off,
+    /// Limit at LTP ± the protection percentage
+    Ltp,
+    /// Limit a few ticks through the best opposite quote, capped at the LTP band
+    Depth
+}
+
+impl FromStr for ProtectionMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.trim().to_lowercase().as_str() {
+            "off" | "none" => Ok(ProtectionMode::Off),
+            "ltp" => Ok(ProtectionMode::Ltp),
+            "depth" => Ok(ProtectionMode::Depth),
+            _ => Err(anyhow::anyhow!("Unknown market protection mode '{}', use off, ltp or depth", mode))
+        }
+    }
+}
+
+/// What a protected market order is priced from.
+#[derive(Debug, Clone, Default)]
+pub struct MarketQuote {
+    pub ltp: Option<f64>,
+    pub book: Option<BookAnalytics>
+}
+
+impl MarketQuote {
+    pub fn from_quote(quote: &Quote) -> Self {
+        Self {
+            ltp: Some(quote.last_price),
+            book: quote.depth.as_ref().map(|depth| analyse(depth, DEFAULT_DEPTH_BAND_PCT))
+        }
+    }
+}
+
+/// Turns market orders into marketable limit orders so a thin book can't fill them arbitrarily far
+/// from the last price, and optionally re-prices the ones still open after `reprice_after`.
+#[derive(Debug, Clone, Copy)]
+pub struct MarketProtection {
+    pub mode: ProtectionMode,
+    pub pct: f64,
+    pub depth_ticks: u32,
+    pub reprice_after: Option<Duration>,
+    pub max_reprices: u32
+}
+
+impl Default for MarketProtection {
+    fn default() -> Self {
+        Self { mode: ProtectionMode::Ltp, pct: DEFAULT_PROTECTION_PCT, depth_ticks: DEFAULT_DEPTH_TICKS, reprice_after: None, max_reprices: DEFAULT_MAX_REPRICES }
+    }
+}
+
+impl MarketProtection {
+    pub fn off() -> Self {
+        Self { mode: ProtectionMode::Off, ..Self::default() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.mode != ProtectionMode::Off
+    }
+
+    /// The limit price for a market order on `tick`, with a note on how it was derived. Never further
+    /// than `pct` from the LTP; buys are rounded down and sells up so rounding stays inside that band.
+    pub fn limit_price(&self, buying: bool, quote: &MarketQuote, tick: f64) -> Result<(f64, String), anyhow::Error> {
+        let ltp = quote.ltp.filter(|ltp| *ltp > 0.0);
+        let cap = ltp.map(|ltp| if buying {
+            round_to_tick(ltp * (1.0 + self.pct / 100.0), tick, true)
+        }
+        else {
+            round_to_tick(ltp * (1.0 - self.pct / 100.0), tick, false)
+        });
+        let best = quote.book.as_ref().and_then(|book| if buying { book.best_ask } else { book.best_bid });
+
+        match (self.mode, best, cap) {
+            (ProtectionMode::Depth, Some(best), cap) => {
+                let offset = self.depth_ticks as f64 * tick;
+                let price = if buying { round_to_tick(best + offset, tick, true) } else { round_to_tick(best - offset, tick, false).max(tick) };
+                match cap {
+                    Some(cap) if (buying && price > cap) || (!buying && price < cap) => Ok((cap, format!(
+                        "Market order sent as LIMIT {}: {} ticks through the best {} {} is beyond the {}% band around LTP {}",
+                        cap, self.depth_ticks, if buying { "offer" } else { "bid" }, best, self.pct, ltp.unwrap_or_default()
+                    ))),
+                    _ => Ok((price, format!("Market order sent as LIMIT {}, {} ticks through the best {} {}", price, self.depth_ticks, if buying { "offer" } else { "bid" }, best)))
+                }
+            },
+            (_, _, Some(cap)) => Ok((cap, format!("Market order sent as LIMIT {}, {}% from LTP {}", cap, self.pct, ltp.unwrap_or_default()))),
+            _ => Err(anyhow::anyhow!("No last price to protect the market order with, send a LIMIT order or set MARKET_PROTECTION=off"))
+        }
+    }
+
+    /// Waits `reprice_after`, then moves any of `order_ids` still open to a price from a fresh quote,
+    /// up to `max_reprices` times. Runs after the order is placed, so failures are only logged.
+    pub async fn reprice(self, kite: KiteClient, params: OrderParams, order_ids: Vec<String>, tick: f64) {
+        let after = match self.reprice_after {
+            Some(after) if self.is_enabled() => after,
+            _ => return
+        };
+        let key = format!("{}:{}", params.exchange, params.tradingsymbol);
+        let buying = params.transaction_type == "BUY";
+        let mut open = order_ids;
+
+        for attempt in 1..=self.max_reprices {
+            tokio::time::sleep(after).await;
+
+            let mut unfilled = Vec::new();
+            for order_id in open {
+                match kite.order_history(&order_id).await {
+                    Ok(history) => match history.last() {
+                        Some(order) if order.status == "OPEN" && order.pending_quantity > 0 => unfilled.push(order.clone()),
+                        _ => {}
+                    },
+                    Err(e) => println!("Failed to check order {} for re-pricing: {}", order_id, e)
+                }
+            }
+            if unfilled.is_empty() {
+                return;
+            }
+
+            let price = match self.quote(&kite, &key).await.and_then(|quote| self.limit_price(buying, &quote, tick)) {
+                Ok((price, _)) => price,
+                Err(e) => {
+                    println!("Failed to re-price {}: {}", key, e);
+                    return;
+                }
+            };
+
+            open = Vec::new();
+            for order in unfilled {
+                if (order.price - price).abs() >= tick / 2.0 {
+                    // Kite takes the total quantity on a modify, not what is left
+                    let modified = OrderParams { quantity: order.quantity, price: Some(price), ..params.clone() };
+                    match kite.modify_order(ORDER_VARIETY, &order.order_id, &modified).await {
+                        Ok(_) => println!("Re-priced order {} for {} from {} to {} ({} of {})", order.order_id, key, order.price, price, attempt, self.max_reprices),
+                        Err(e) => println!("Failed to re-price order {} for {}: {}", order.order_id, key, e)
+                    }
+                }
+                open.push(order.order_id);
+            }
+        }
+        println!("Stopped re-pricing {} after {} attempts, {} orders still open", key, self.max_reprices, open.len());
+    }
+
+    async fn quote(&self, kite: &KiteClient, key: &str) -> Result<MarketQuote, anyhow::Error> {
+        let keys = [key.to_string()];
+        let quote = match self.mode {
+            ProtectionMode::Depth => kite.quote(&keys).await?,
+            _ => kite.ltp(&keys).await?
+        }.remove(key);
+        Ok(quote.as_ref().map(MarketQuote::from_quote).unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tick_stream::{Depth, DepthLevel};
+
+    fn quote(ltp: f64, bid: f64, ask: f64) -> MarketQuote {
+        let level = |price| DepthLevel { price, quantity: 100.0, orders: 1.0 };
+        let depth = Depth { buy: vec![level(bid)], sell: vec![level(ask)] };
+        MarketQuote { ltp: Some(ltp), book: Some(analyse(&depth, DEFAULT_DEPTH_BAND_PCT)) }
+    }
+
+    #[test]
+    fn ltp_protection_stays_inside_the_band() {
+        let protection = MarketProtection::default();
+        assert_eq!(protection.limit_price(true, &quote(101.23, 101.2, 101.25), 0.05).unwrap().0, 103.25);
+        assert_eq!(protection.limit_price(false, &quote(101.23, 101.2, 101.25), 0.05).unwrap().0, 99.25);
+        assert!(protection.limit_price(true, &MarketQuote::default(), 0.05).is_err());
+    }
+
+    #[test]
+    fn depth_protection_goes_through_the_best_quote_up_to_the_cap() {
+        let protection = MarketProtection { mode: ProtectionMode::Depth, ..MarketProtection::default() };
+        assert_eq!(protection.limit_price(true, &quote(100.0, 99.9, 100.1), 0.05).unwrap().0, 100.2);
+        assert_eq!(protection.limit_price(false, &quote(100.0, 99.9, 100.1), 0.05).unwrap().0, 99.8);
+        // A gapped offer is capped at LTP + 2%
+        assert_eq!(protection.limit_price(true, &quote(100.0, 99.9, 104.0), 0.05).unwrap().0, 102.0);
+    }
+}
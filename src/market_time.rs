@@ -0,0 +1,25 @@
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveTime, Weekday};
+
+/// India Standard Time, the offset Kite and the exchanges keep every time in.
+pub fn ist() -> FixedOffset {
+    FixedOffset::east_opt(5 * 3600 + 30 * 60).unwrap()
+}
+
+/// Parses `HH:MM` in IST.
+pub fn parse_time(time: &str) -> Result<NaiveTime, anyhow::Error> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .map_err(|e| anyhow::anyhow!("Invalid time '{}', expected HH:MM: {}", time, e))
+}
+
+/// The next weekday at `at` IST strictly after `now`.
+pub fn next_weekday_at(at: NaiveTime, now: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+    let now = now.with_timezone(&ist());
+    let mut day = now.date_naive();
+    loop {
+        let candidate = day.and_time(at).and_local_timezone(ist()).unwrap();
+        if candidate > now && !matches!(day.weekday(), Weekday::Sat | Weekday::Sun) {
+            return candidate;
+        }
+        day += Duration::days(1);
+    }
+}
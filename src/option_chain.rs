@@ -0,0 +1,255 @@
+use std::collections::{BTreeMap, BTreeSet};
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use serde::Serialize;
+use crate::{instrument_master::Instrument, market_data::SourcedTick, option_pricing::{trading_years, ExerciseStyle, Greeks, OptionInputs, OptionKind}};
+
+/// Annualised rate options are priced with unless `RISK_FREE_RATE` says otherwise, roughly the 91-day T-bill.
+pub const DEFAULT_RISK_FREE_RATE: f64 = 0.065;
+
+// Index options are listed under these names; their spot is quoted under another
+const INDEX_SPOT_KEYS: [(&str, &str); 6] = [
+    ("NIFTY", "NSE:NIFTY 50"),
+    ("BANKNIFTY", "NSE:NIFTY BANK"),
+    ("FINNIFTY", "NSE:NIFTY FIN SERVICE"),
+    ("MIDCPNIFTY", "NSE:NIFTY MID SELECT"),
+    ("SENSEX", "BSE:SENSEX"),
+    ("BANKEX", "BSE:BANKEX")
+];
+
+/// The quote key of the underlying's spot: the index for index options, the NSE stock otherwise.
+pub fn underlying_key(underlying: &str) -> String {
+    let underlying = underlying.trim().to_uppercase();
+    match INDEX_SPOT_KEYS.iter().find(|(name, _)| *name == underlying) {
+        Some((_, key)) => key.to_string(),
+        None => format!("NSE:{}", underlying)
+    }
+}
+
+/// Index options are European; stock options are treated as American.
+pub fn exercise_style(underlying: &str) -> ExerciseStyle {
+    let underlying = underlying.trim().to_uppercase();
+    if INDEX_SPOT_KEYS.iter().any(|(name, _)| *name == underlying) { ExerciseStyle::European } else { ExerciseStyle::American }
+}
+
+/// The contracts of one expiry, and every expiry still open.
+#[derive(Debug, Clone)]
+pub struct ChainContracts {
+    pub expiry: NaiveDate,
+    pub expiries: Vec<NaiveDate>,
+    pub contracts: Vec<Instrument>
+}
+
+impl ChainContracts {
+    /// Picks `expiry`, or the nearest one on or after `today`, out of every option on an underlying.
+    pub fn select(options: Vec<Instrument>, expiry: Option<NaiveDate>, today: NaiveDate) -> Result<Self, anyhow::Error> {
+        let expiries: Vec<NaiveDate> = options.iter()
+            .filter_map(|option| option.expiry)
+            .filter(|expiry| *expiry >= today)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+
+        let expiry = match expiry {
+            Some(expiry) if expiries.contains(&expiry) => expiry,
+            Some(expiry) => return Err(anyhow::anyhow!("No open contracts expire on {}, expiries are {:?}", expiry, expiries)),
+            None => *expiries.first().ok_or_else(|| anyhow::anyhow!("No open expiries"))?
+        };
+        let contracts = options.into_iter().filter(|option| option.expiry == Some(expiry)).collect();
+        Ok(Self { expiry, expiries, contracts })
+    }
+
+    /// Keeps the `count` strikes closest to `spot`, so a chain fits in one quote request.
+    pub fn nearest_strikes(mut self, spot: Option<f64>, count: usize) -> Self {
+        let mut strikes: Vec<f64> = self.strikes();
+        if strikes.len() <= count {
+            return self;
+        }
+        if let Some(spot) = spot {
+            strikes.sort_by(|a, b| (a - spot).abs().total_cmp(&(b - spot).abs()));
+        }
+        else {
+            // Without a spot the middle of the listed range is the best guess at the money
+            let middle = strikes[strikes.len() / 2];
+            strikes.sort_by(|a, b| (a - middle).abs().total_cmp(&(b - middle).abs()));
+        }
+        strikes.truncate(count);
+        self.contracts.retain(|contract| strikes.contains(&contract.strike));
+        self
+    }
+
+    fn strikes(&self) -> Vec<f64> {
+        let mut strikes: Vec<f64> = self.contracts.iter().map(|contract| contract.strike).collect();
+        strikes.sort_by(f64::total_cmp);
+        strikes.dedup();
+        strikes
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.contracts.iter().map(Instrument::key).collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OptionQuote {
+    pub tradingsymbol: String,
+    pub instrument_token: u32,
+    pub ltp: Option<f64>,
+    pub bid: Option<f64>,
+    pub ask: Option<f64>,
+    pub oi: Option<f64>,
+    pub volume: Option<f64>,
+    /// Annualised, solved from the bid/ask mid when both sides are quoted and the LTP otherwise
+    pub iv: Option<f64>,
+    pub greeks: Option<Greeks>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainRow {
+    pub strike: f64,
+    pub call: Option<OptionQuote>,
+    pub put: Option<OptionQuote>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OptionChain {
+    pub underlying: String,
+    pub spot_key: String,
+    pub spot: Option<f64>,
+    pub expiry: NaiveDate,
+    pub expiries: Vec<NaiveDate>,
+    pub style: ExerciseStyle,
+    /// In trading time, see `trading_years`
+    pub years_to_expiry: f64,
+    pub rate: f64,
+    pub dividend_yield: f64,
+    pub exchange: Option<String>,
+    pub lot_size: Option<u32>,
+    pub tick_size: Option<f64>,
+    pub atm_strike: Option<f64>,
+    pub rows: Vec<ChainRow>,
+    /// Contracts with no fresh quote, left without prices
+    pub missing: Vec<String>
+}
+
+/// Lays the contracts out by strike and fills in quotes from `ticks`, with IV and Greeks
+/// against `spot` wherever there is both a price and a spot.
+pub fn build_chain(underlying: &str, contracts: ChainContracts, spot: Option<f64>, ticks: &BTreeMap<String, SourcedTick>, rate: f64, dividend_yield: f64, now: DateTime<FixedOffset>) -> OptionChain {
+    let years = trading_years(now, contracts.expiry);
+    let style = exercise_style(underlying);
+    let mut rows: BTreeMap<i64, ChainRow> = BTreeMap::new();
+    let mut missing = Vec::new();
+
+    for contract in &contracts.contracts {
+        let kind = match OptionKind::from_instrument_type(&contract.instrument_type) {
+            Some(kind) => kind,
+            None => continue
+        };
+        let key = contract.key();
+        let tick = ticks.get(&key).map(|sourced| &sourced.tick);
+        if tick.is_none() {
+            missing.push(key);
+        }
+
+        let best = |levels: Option<&Vec<crate::tick_stream::DepthLevel>>| levels
+            .and_then(|levels| levels.iter().find(|level| level.price > 0.0 && level.quantity > 0.0))
+            .map(|level| level.price);
+        let bid = best(tick.and_then(|tick| tick.depth.as_ref()).map(|depth| &depth.buy));
+        let ask = best(tick.and_then(|tick| tick.depth.as_ref()).map(|depth| &depth.sell));
+        let ltp = tick.map(|tick| tick.last_price).filter(|ltp| *ltp > 0.0);
+        let price = bid.zip(ask).map(|(bid, ask)| (bid + ask) / 2.0).or(ltp);
+
+        let inputs = spot.map(|spot| OptionInputs { kind, style, spot, strike: contract.strike, years, rate, dividend_yield });
+        let iv = inputs.zip(price).and_then(|(inputs, price)| inputs.implied_volatility(price));
+        let quote = OptionQuote {
+            tradingsymbol: contract.tradingsymbol.clone(),
+            instrument_token: contract.instrument_token,
+            ltp,
+            bid,
+            ask,
+            oi: tick.and_then(|tick| tick.oi),
+            volume: tick.and_then(|tick| tick.volume),
+            iv,
+            greeks: inputs.zip(iv).map(|(inputs, iv)| inputs.greeks(iv))
+        };
+
+        // Strikes are keyed in paise so they sort and match exactly
+        let row = rows.entry((contract.strike * 100.0).round() as i64).or_insert(ChainRow { strike: contract.strike, call: None, put: None });
+        match kind {
+            OptionKind::Call => row.call = Some(quote),
+            OptionKind::Put => row.put = Some(quote)
+        }
+    }
+
+    let rows: Vec<ChainRow> = rows.into_values().collect();
+    let atm_strike = spot.and_then(|spot| rows.iter().map(|row| row.strike).min_by(|a, b| (a - spot).abs().total_cmp(&(b - spot).abs())));
+    OptionChain {
+        underlying: underlying.trim().to_uppercase(),
+        spot_key: underlying_key(underlying),
+        spot,
+        expiry: contracts.expiry,
+        expiries: contracts.expiries,
+        style,
+        years_to_expiry: years,
+        rate,
+        dividend_yield,
+        exchange: contracts.contracts.first().map(|contract| contract.exchange.clone()),
+        lot_size: contracts.contracts.first().map(|contract| contract.lot_size),
+        tick_size: contracts.contracts.first().map(|contract| contract.tick_size),
+        atm_strike,
+        rows,
+        missing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option(strike: f64, instrument_type: &str, expiry: &str) -> Instrument {
+        Instrument {
+            instrument_token: strike as u32 + if instrument_type == "CE" { 0 } else { 1 },
+            exchange_token: 1,
+            tradingsymbol: format!("NIFTY{}{}", strike, instrument_type),
+            name: "NIFTY".to_string(),
+            expiry: NaiveDate::parse_from_str(expiry, "%Y-%m-%d").ok(),
+            strike,
+            tick_size: 0.05,
+            lot_size: 50,
+            instrument_type: instrument_type.to_string(),
+            segment: "NFO-OPT".to_string(),
+            exchange: "NFO".to_string()
+        }
+    }
+
+    #[test]
+    fn picks_the_nearest_open_expiry_and_strikes_around_spot() {
+        let today = NaiveDate::from_ymd_opt(2024, 1, 19).unwrap();
+        let options: Vec<Instrument> = ["2024-01-18", "2024-01-25", "2024-02-01"].iter()
+            .flat_map(|expiry| (0..10).flat_map(move |i| ["CE", "PE"].map(|t| option(21_500.0 + 50.0 * i as f64, t, expiry))))
+            .collect();
+
+        let chain = ChainContracts::select(options.clone(), None, today).unwrap();
+        assert_eq!(chain.expiry, NaiveDate::from_ymd_opt(2024, 1, 25).unwrap());
+        assert_eq!(chain.expiries.len(), 2);
+        assert_eq!(chain.contracts.len(), 20);
+        assert!(ChainContracts::select(options, NaiveDate::from_ymd_opt(2024, 1, 18), today).is_err());
+
+        let near = chain.nearest_strikes(Some(21_630.0), 3);
+        assert_eq!(near.strikes(), vec![21_600.0, 21_650.0, 21_700.0]);
+    }
+
+    #[test]
+    fn rows_pair_calls_and_puts_with_greeks() {
+        let today = NaiveDate::from_ymd_opt(2024, 1, 19).unwrap();
+        let contracts = ChainContracts::select(vec![option(21_600.0, "CE", "2024-01-25"), option(21_600.0, "PE", "2024-01-25")], None, today).unwrap();
+        let now = DateTime::parse_from_rfc3339("2024-01-19T10:00:00+05:30").unwrap();
+
+        let chain = build_chain("nifty", contracts, Some(21_630.0), &BTreeMap::new(), DEFAULT_RISK_FREE_RATE, 0.0, now);
+        assert_eq!(chain.spot_key, "NSE:NIFTY 50");
+        assert_eq!(chain.style, ExerciseStyle::European);
+        assert_eq!(chain.atm_strike, Some(21_600.0));
+        assert_eq!(chain.rows.len(), 1);
+        assert!(chain.rows[0].call.is_some() && chain.rows[0].put.is_some());
+        assert_eq!(chain.missing.len(), 2);
+    }
+}
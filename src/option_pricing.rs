@@ -0,0 +1,380 @@
+use chrono::{DateTime, Datelike, FixedOffset, NaiveDate, NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+use crate::market_time::ist;
+
+/// Time to expiry is measured in trading time: session minutes left, over this many sessions a year.
+pub const TRADING_DAYS_PER_YEAR: f64 = 252.0;
+const SESSION_MINUTES: f64 = 375.0;
+
+// Implied volatility search bounds, as annualised fractions
+const MIN_VOLATILITY: f64 = 1e-4;
+const MAX_VOLATILITY: f64 = 5.0;
+const PRICE_TOLERANCE: f64 = 1e-8;
+const MAX_ITERATIONS: usize = 100;
+
+fn session_time(date: NaiveDate, hour: u32, minute: u32) -> DateTime<FixedOffset> {
+    date.and_time(NaiveTime::from_hms_opt(hour, minute, 0).unwrap()).and_local_timezone(ist()).unwrap()
+}
+
+/// Contracts expire at the 15:30 IST close on their expiry date.
+pub fn expiry_time(expiry: NaiveDate) -> DateTime<FixedOffset> {
+    session_time(expiry, 15, 30)
+}
+
+/// Years to the close on `expiry` in trading time: the 09:15-15:30 IST weekday sessions left,
+/// so nights and weekends don't decay the option. Exchange holidays are counted as sessions.
+pub fn trading_years(now: DateTime<FixedOffset>, expiry: NaiveDate) -> f64 {
+    let mut minutes = 0.0;
+    let mut date = now.with_timezone(&ist()).date_naive();
+    while date <= expiry {
+        if !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) {
+            let open = session_time(date, 9, 15).max(now);
+            let close = session_time(date, 15, 30);
+            if close > open {
+                minutes += (close - open).num_seconds() as f64 / 60.0;
+            }
+        }
+        date = date.succ_opt().unwrap();
+    }
+    minutes / (TRADING_DAYS_PER_YEAR * SESSION_MINUTES)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OptionKind {
+    Call,
+    Put
+}
+
+impl OptionKind {
+    /// `CE` and `PE` from the instrument master.
+    pub fn from_instrument_type(instrument_type: &str) -> Option<Self> {
+        match instrument_type {
+            "CE" => Some(OptionKind::Call),
+            "PE" => Some(OptionKind::Put),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExerciseStyle {
+    European,
+    /// Priced as European, floored at the value of exercising now
+    American
+}
+
+/// Theta is per trading day, vega per volatility point and rho per rate point (both 0.01).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Greeks {
+    pub delta: f64,
+    pub gamma: f64,
+    pub theta: f64,
+    pub vega: f64,
+    pub rho: f64
+}
+
+impl Greeks {
+    /// The Greeks of a position of `quantity` units, negative when short.
+    pub fn scaled(&self, quantity: f64) -> Greeks {
+        Greeks {
+            delta: self.delta * quantity,
+            gamma: self.gamma * quantity,
+            theta: self.theta * quantity,
+            vega: self.vega * quantity,
+            rho: self.rho * quantity
+        }
+    }
+
+    pub fn add(&mut self, other: &Greeks) {
+        self.delta += other.delta;
+        self.gamma += other.gamma;
+        self.theta += other.theta;
+        self.vega += other.vega;
+        self.rho += other.rho;
+    }
+}
+
+/// An option under Black-Scholes-Merton. `years` is the time to expiry, `rate` the continuously
+/// compounded risk-free rate and `dividend_yield` the continuous yield, all annualised.
+#[derive(Debug, Clone, Copy)]
+pub struct OptionInputs {
+    pub kind: OptionKind,
+    pub style: ExerciseStyle,
+    pub spot: f64,
+    pub strike: f64,
+    pub years: f64,
+    pub rate: f64,
+    pub dividend_yield: f64
+}
+
+impl OptionInputs {
+    fn d1_d2(&self, volatility: f64) -> (f64, f64) {
+        let sd = volatility * self.years.sqrt();
+        let d1 = ((self.spot / self.strike).ln() + (self.rate - self.dividend_yield + volatility * volatility / 2.0) * self.years) / sd;
+        (d1, d1 - sd)
+    }
+
+    // The European value with no time value left
+    fn lower_bound(&self) -> f64 {
+        let years = self.years.max(0.0);
+        let forward_spot = self.spot * (-self.dividend_yield * years).exp();
+        let forward_strike = self.strike * (-self.rate * years).exp();
+        match self.kind {
+            OptionKind::Call => (forward_spot - forward_strike).max(0.0),
+            OptionKind::Put => (forward_strike - forward_spot).max(0.0)
+        }
+    }
+
+    fn exercise_value(&self) -> f64 {
+        match self.kind {
+            OptionKind::Call => (self.spot - self.strike).max(0.0),
+            OptionKind::Put => (self.strike - self.spot).max(0.0)
+        }
+    }
+
+    fn european_price(&self, volatility: f64) -> f64 {
+        if self.years <= 0.0 || volatility <= 0.0 {
+            return self.lower_bound();
+        }
+        let (d1, d2) = self.d1_d2(volatility);
+        let spot = self.spot * (-self.dividend_yield * self.years).exp();
+        let strike = self.strike * (-self.rate * self.years).exp();
+        match self.kind {
+            OptionKind::Call => spot * norm_cdf(d1) - strike * norm_cdf(d2),
+            OptionKind::Put => strike * norm_cdf(-d2) - spot * norm_cdf(-d1)
+        }
+    }
+
+    // An American option worth no more than exercising it now behaves like the underlying
+    fn exercised(&self, volatility: f64) -> bool {
+        self.style == ExerciseStyle::American && self.exercise_value() > 0.0 && self.exercise_value() >= self.european_price(volatility)
+    }
+
+    pub fn price(&self, volatility: f64) -> f64 {
+        match self.style {
+            ExerciseStyle::European => self.european_price(volatility),
+            ExerciseStyle::American => self.european_price(volatility).max(self.exercise_value())
+        }
+    }
+
+    pub fn greeks(&self, volatility: f64) -> Greeks {
+        if self.years <= 0.0 || volatility <= 0.0 || self.exercised(volatility) {
+            let delta = match self.kind {
+                OptionKind::Call if self.spot > self.strike => 1.0,
+                OptionKind::Put if self.spot < self.strike => -1.0,
+                _ => 0.0
+            };
+            return Greeks { delta, ..Greeks::default() };
+        }
+
+        let (d1, d2) = self.d1_d2(volatility);
+        let spot_discount = (-self.dividend_yield * self.years).exp();
+        let strike = self.strike * (-self.rate * self.years).exp();
+        let sqrt_t = self.years.sqrt();
+        let decay = -self.spot * spot_discount * norm_pdf(d1) * volatility / (2.0 * sqrt_t);
+
+        let (delta, theta, rho) = match self.kind {
+            OptionKind::Call => (
+                spot_discount * norm_cdf(d1),
+                decay - self.rate * strike * norm_cdf(d2) + self.dividend_yield * self.spot * spot_discount * norm_cdf(d1),
+                self.years * strike * norm_cdf(d2)
+            ),
+            OptionKind::Put => (
+                -spot_discount * norm_cdf(-d1),
+                decay + self.rate * strike * norm_cdf(-d2) - self.dividend_yield * self.spot * spot_discount * norm_cdf(-d1),
+                -self.years * strike * norm_cdf(-d2)
+            )
+        };
+        Greeks {
+            delta,
+            gamma: spot_discount * norm_pdf(d1) / (self.spot * volatility * sqrt_t),
+            theta: theta / TRADING_DAYS_PER_YEAR,
+            vega: self.spot * spot_discount * norm_pdf(d1) * sqrt_t / 100.0,
+            rho: rho / 100.0
+        }
+    }
+
+    /// The volatility that prices the option at `price`. Newton's method from a Brenner-Subrahmanyam
+    /// guess, falling back to Brent's method on the whole bracket when a step leaves it or vega is
+    /// too flat to follow. `None` when no volatility up to 500% gives the price, or when an American
+    /// option is priced at its exercise value and any low volatility would do.
+    pub fn implied_volatility(&self, price: f64) -> Option<f64> {
+        if self.years <= 0.0 || price <= 0.0 {
+            return None;
+        }
+        let error = |volatility: f64| self.price(volatility) - price;
+        if error(MIN_VOLATILITY) > PRICE_TOLERANCE || error(MAX_VOLATILITY) < -PRICE_TOLERANCE {
+            return None;
+        }
+        if self.style == ExerciseStyle::American && price <= self.exercise_value() + PRICE_TOLERANCE {
+            return None;
+        }
+
+        let mut volatility = ((2.0 * std::f64::consts::PI / self.years).sqrt() * price / self.spot).clamp(0.05, 2.0);
+        for _ in 0..MAX_ITERATIONS {
+            let error = error(volatility);
+            if error.abs() < PRICE_TOLERANCE {
+                return Some(volatility);
+            }
+            let vega = self.greeks(volatility).vega * 100.0;
+            let step = volatility - error / vega;
+            if vega < 1e-10 || step <= MIN_VOLATILITY || step >= MAX_VOLATILITY {
+                break;
+            }
+            volatility = step;
+        }
+        brent(error, MIN_VOLATILITY, MAX_VOLATILITY, 1e-12)
+    }
+}
+
+// Brent's root finder on a bracket [a, b] where `f` changes sign, after Numerical Recipes' zbrent
+fn brent(f: impl Fn(f64) -> f64, mut a: f64, mut b: f64, tolerance: f64) -> Option<f64> {
+    let (mut fa, mut fb) = (f(a), f(b));
+    if fa * fb > 0.0 {
+        return None;
+    }
+    let (mut c, mut fc) = (b, fb);
+    let (mut d, mut e) = (b - a, b - a);
+
+    for _ in 0..MAX_ITERATIONS {
+        if fb * fc > 0.0 {
+            c = a;
+            fc = fa;
+            d = b - a;
+            e = d;
+        }
+        if fc.abs() < fb.abs() {
+            a = b;
+            b = c;
+            c = a;
+            fa = fb;
+            fb = fc;
+            fc = fa;
+        }
+        let tol = 2.0 * f64::EPSILON * b.abs() + tolerance / 2.0;
+        let m = (c - b) / 2.0;
+        if m.abs() <= tol || fb == 0.0 {
+            return Some(b);
+        }
+
+        if e.abs() >= tol && fa.abs() > fb.abs() {
+            // Inverse quadratic interpolation, or the secant when only two points are distinct
+            let s = fb / fa;
+            let (mut p, mut q) = if a == c {
+                (2.0 * m * s, 1.0 - s)
+            }
+            else {
+                let (q, r) = (fa / fc, fb / fc);
+                (s * (2.0 * m * q * (q - r) - (b - a) * (r - 1.0)), (q - 1.0) * (r - 1.0) * (s - 1.0))
+            };
+            if p > 0.0 {
+                q = -q;
+            }
+            else {
+                p = -p;
+            }
+            if 2.0 * p < (3.0 * m * q - (tol * q).abs()).min((e * q).abs()) {
+                e = d;
+                d = p / q;
+            }
+            else {
+                d = m;
+                e = m;
+            }
+        }
+        else {
+            d = m;
+            e = m;
+        }
+
+        a = b;
+        fa = fb;
+        b += if d.abs() > tol { d } else { tol.copysign(m) };
+        fb = f(b);
+    }
+    Some(b)
+}
+
+pub fn norm_pdf(x: f64) -> f64 {
+    (-x * x / 2.0).exp() / (2.0 * std::f64::consts::PI).sqrt()
+}
+
+/// Standard normal CDF, Hart's double precision approximation.
+pub fn norm_cdf(x: f64) -> f64 {
+    let z = x.abs();
+    let tail = if z > 37.0 {
+        0.0
+    }
+    else if z < 7.071_067_811_865_47 {
+        let n = ((((((0.035_262_496_599_891_1 * z + 0.700_383_064_443_688) * z + 6.373_962_203_531_65) * z + 33.912_866_078_383) * z
+            + 112.079_291_497_871) * z + 221.213_596_169_931) * z + 220.206_867_912_376) * (-z * z / 2.0).exp();
+        let d = ((((((0.088_388_347_648_318_4 * z + 1.755_667_163_182_64) * z + 16.064_177_579_207) * z + 86.780_732_202_946_1) * z
+            + 296.564_248_779_674) * z + 637.333_633_378_831) * z + 793.826_512_519_948) * z + 440.413_735_824_752;
+        n / d
+    }
+    else {
+        let f = z + 1.0 / (z + 2.0 / (z + 3.0 / (z + 4.0 / (z + 0.65))));
+        (-z * z / 2.0).exp() / (f * 2.506_628_274_631)
+    };
+    if x <= 0.0 { tail } else { 1.0 - tail }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn european(kind: OptionKind, spot: f64, strike: f64, years: f64, rate: f64, dividend_yield: f64) -> OptionInputs {
+        OptionInputs { kind, style: ExerciseStyle::European, spot, strike, years, rate, dividend_yield }
+    }
+
+    fn close(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() < tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn prices_and_greeks_match_reference_values() {
+        // Hull, Options, Futures and Other Derivatives, example 15.6
+        close(european(OptionKind::Call, 42.0, 40.0, 0.5, 0.1, 0.0).price(0.2), 4.7594, 1e-4);
+        close(european(OptionKind::Put, 42.0, 40.0, 0.5, 0.1, 0.0).price(0.2), 0.8086, 1e-4);
+        // Haug, The Complete Guide to Option Pricing Formulas, generalised Black-Scholes with a yield
+        close(european(OptionKind::Put, 100.0, 95.0, 0.5, 0.1, 0.05).price(0.2), 2.4648, 1e-4);
+
+        // Hull's running Greeks example, with theta per year and vega and rho per unit
+        let greeks = european(OptionKind::Call, 49.0, 50.0, 20.0 / 52.0, 0.05, 0.0).greeks(0.2);
+        close(greeks.delta, 0.522, 1e-3);
+        close(greeks.gamma, 0.066, 1e-3);
+        close(greeks.theta * TRADING_DAYS_PER_YEAR, -4.31, 1e-2);
+        close(greeks.vega * 100.0, 12.1, 0.05);
+        close(greeks.rho * 100.0, 8.91, 1e-2);
+    }
+
+    #[test]
+    fn implied_volatility_recovers_the_input() {
+        for kind in [OptionKind::Call, OptionKind::Put] {
+            for (strike, volatility) in [(22_200.0, 0.18), (18_000.0, 0.45), (26_000.0, 0.9), (22_000.0, 0.02)] {
+                let option = european(kind, 22_000.0, strike, 30.0 / 365.0, 0.065, 0.012);
+                let iv = option.implied_volatility(option.price(volatility)).unwrap();
+                close(iv, volatility, 1e-6);
+            }
+        }
+        let option = european(OptionKind::Call, 22_000.0, 22_200.0, 30.0 / 365.0, 0.065, 0.0);
+        assert_eq!(option.implied_volatility(0.0), None);
+        assert_eq!(option.implied_volatility(22_000.0), None);
+    }
+
+    #[test]
+    fn american_options_are_floored_at_exercise_and_time_is_trading_time() {
+        let put = OptionInputs { style: ExerciseStyle::American, ..european(OptionKind::Put, 80.0, 100.0, 1.0, 0.1, 0.0) };
+        assert_eq!(put.price(0.1), 20.0);
+        assert_eq!(put.greeks(0.1).delta, -1.0);
+        assert!(put.price(0.6) > european(OptionKind::Put, 80.0, 100.0, 1.0, 0.1, 0.0).price(0.6) - 1e-12);
+
+        // Friday 15:00 to Monday's close: half an hour on Friday and Monday's whole session
+        let now = DateTime::parse_from_rfc3339("2024-01-19T15:00:00+05:30").unwrap();
+        let monday = NaiveDate::from_ymd_opt(2024, 1, 22).unwrap();
+        close(trading_years(now, monday) * TRADING_DAYS_PER_YEAR * SESSION_MINUTES, 405.0, 1e-9);
+        assert_eq!(trading_years(expiry_time(monday), monday), 0.0);
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+use chrono::{DateTime, FixedOffset};
+use serde::Serialize;
+use crate::{instrument_master::Instrument, market_data::SourcedTick, option_chain::{exercise_style, underlying_key}, option_pricing::{trading_years, Greeks, OptionInputs, OptionKind}};
+
+/// One open option position with its Greeks scaled by the signed quantity.
+#[derive(Debug, Clone, Serialize)]
+pub struct PositionGreeks {
+    pub symbol: String,
+    pub underlying: String,
+    pub quantity: i64,
+    pub ltp: f64,
+    pub spot: f64,
+    pub iv: f64,
+    pub greeks: Greeks
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GreeksExposure {
+    pub positions: Vec<PositionGreeks>,
+    /// Summed per underlying; deltas on different underlyings don't add up
+    pub by_underlying: BTreeMap<String, Greeks>,
+    /// Positions left out, with the reason
+    pub unpriced: Vec<String>
+}
+
+/// The quote keys `aggregate` needs: every option and every underlying spot.
+pub fn quote_keys(positions: &[(Instrument, i64)]) -> Vec<String> {
+    let mut keys: Vec<String> = positions.iter()
+        .flat_map(|(instrument, _)| [instrument.key(), underlying_key(&instrument.name)])
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// Prices each option position off its LTP and underlying spot from `ticks` and adds up the Greeks.
+pub fn aggregate(positions: &[(Instrument, i64)], ticks: &BTreeMap<String, SourcedTick>, rate: f64, now: DateTime<FixedOffset>) -> GreeksExposure {
+    let mut exposure = GreeksExposure::default();
+    let price = |key: &str| ticks.get(key).map(|sourced| sourced.tick.last_price).filter(|price| *price > 0.0);
+
+    for (instrument, quantity) in positions {
+        let key = instrument.key();
+        let (kind, expiry) = match (OptionKind::from_instrument_type(&instrument.instrument_type), instrument.expiry) {
+            (Some(kind), Some(expiry)) => (kind, expiry),
+            _ => continue
+        };
+        let (ltp, spot) = match (price(&key), price(&underlying_key(&instrument.name))) {
+            (Some(ltp), Some(spot)) => (ltp, spot),
+            _ => {
+                exposure.unpriced.push(format!("{}: no price for the option or its underlying", key));
+                continue;
+            }
+        };
+
+        let inputs = OptionInputs {
+            kind,
+            style: exercise_style(&instrument.name),
+            spot,
+            strike: instrument.strike,
+            years: trading_years(now, expiry),
+            rate,
+            dividend_yield: 0.0
+        };
+        let iv = match inputs.implied_volatility(ltp) {
+            Some(iv) => iv,
+            None => {
+                exposure.unpriced.push(format!("{}: no implied volatility prices it at {}", key, ltp));
+                continue;
+            }
+        };
+
+        let greeks = inputs.greeks(iv).scaled(*quantity as f64);
+        exposure.by_underlying.entry(instrument.name.clone()).or_default().add(&greeks);
+        exposure.positions.push(PositionGreeks { symbol: key, underlying: instrument.name.clone(), quantity: *quantity, ltp, spot, iv, greeks });
+    }
+    exposure
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use std::sync::Arc;
+    use crate::{kite_models::Quote, tick_stream::TickData};
+
+    fn option(tradingsymbol: &str, strike: f64, instrument_type: &str) -> Instrument {
+        Instrument {
+            instrument_token: 1,
+            exchange_token: 1,
+            tradingsymbol: tradingsymbol.to_string(),
+            name: "NIFTY".to_string(),
+            expiry: NaiveDate::from_ymd_opt(2024, 1, 25),
+            strike,
+            tick_size: 0.05,
+            lot_size: 50,
+            instrument_type: instrument_type.to_string(),
+            segment: "NFO-OPT".to_string(),
+            exchange: "NFO".to_string()
+        }
+    }
+
+    fn tick(key: &str, last_price: f64) -> SourcedTick {
+        let quote: Quote = serde_json::from_value(serde_json::json!({ "instrument_token": 1, "last_price": last_price })).unwrap();
+        SourcedTick { tick: Arc::new(TickData::from_quote(&quote, key)), source: "kite", age_ms: None }
+    }
+
+    #[test]
+    fn a_short_straddle_is_near_delta_neutral_and_short_gamma() {
+        let positions = vec![(option("NIFTY24125CE", 21_600.0, "CE"), -50), (option("NIFTY24125PE", 21_600.0, "PE"), -50)];
+        let ticks: BTreeMap<String, SourcedTick> = [("NFO:NIFTY24125CE", 180.0), ("NFO:NIFTY24125PE", 150.0), ("NSE:NIFTY 50", 21_630.0)]
+            .into_iter().map(|(key, price)| (key.to_string(), tick(key, price))).collect();
+        assert_eq!(quote_keys(&positions).len(), 3);
+
+        let now = DateTime::parse_from_rfc3339("2024-01-19T10:00:00+05:30").unwrap();
+        let exposure = aggregate(&positions, &ticks, 0.065, now);
+        let nifty = exposure.by_underlying["NIFTY"];
+        assert_eq!(exposure.positions.len(), 2);
+        assert!(nifty.delta.abs() < 10.0, "delta {}", nifty.delta);
+        assert!(nifty.gamma < 0.0 && nifty.theta > 0.0 && nifty.vega < 0.0);
+    }
+}
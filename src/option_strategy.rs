@@ -0,0 +1,440 @@
+use serde::{Deserialize, Serialize};
+use crate::{charges::paise, data_structures::{BasketRequest, TradeInstruction}, option_chain::{ChainRow, OptionChain, OptionQuote}, option_pricing::{Greeks, OptionKind}, order_validation::round_to_tick};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    Straddle,
+    Strangle,
+    Vertical,
+    IronCondor,
+    Butterfly
+}
+
+/// Long strategies pay a net debit and short ones collect a credit: a short iron condor sells
+/// the inner strikes, a long butterfly buys the wings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Long,
+    Short
+}
+
+/// How the strike nearest the money is picked: `"atm"`, `{"offset": n}` strikes out of the money
+/// from ATM (negative goes in the money), or `{"delta": 0.25}` for the closest absolute delta.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrikeRule {
+    Atm,
+    Offset(i32),
+    Delta(f64)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StrategySpec {
+    pub strategy: StrategyKind,
+    pub direction: Direction,
+    pub lots: u32,
+    /// ATM for straddles, verticals and butterflies, one strike out for strangles and condors when absent
+    pub strike: Option<StrikeRule>,
+    /// Strikes between a vertical's legs, a condor's short and long legs or a butterfly's body and wings; 1 when absent
+    pub width: Option<u32>,
+    /// The side of a vertical or butterfly; calls when absent
+    pub option: Option<OptionKind>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StrategyLeg {
+    pub tradingsymbol: String,
+    pub kind: OptionKind,
+    pub strike: f64,
+    pub action: String,
+    /// Units, lots times the lot size
+    pub quantity: u32,
+    /// The bid for sells and the ask for buys, or the LTP when that side is empty
+    pub price: f64,
+    pub iv: Option<f64>,
+    pub greeks: Option<Greeks>
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PayoffPoint {
+    pub spot: f64,
+    pub pnl: f64
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StrategyReport {
+    pub strategy: StrategyKind,
+    pub direction: Direction,
+    pub underlying: String,
+    pub expiry: chrono::NaiveDate,
+    pub spot: f64,
+    pub lots: u32,
+    pub lot_size: u32,
+    pub legs: Vec<StrategyLeg>,
+    /// Paid when positive, received when negative
+    pub net_premium: f64,
+    /// Best P&L at expiry; absent when unlimited
+    pub max_profit: Option<f64>,
+    /// Worst P&L at expiry, negative for a loss; absent when unlimited
+    pub max_loss: Option<f64>,
+    pub breakevens: Vec<f64>,
+    /// Summed over the legs; absent when a leg has no implied volatility
+    pub greeks: Option<Greeks>,
+    /// P&L at expiry at every strike and a little beyond the outer ones
+    pub payoff: Vec<PayoffPoint>,
+    /// The legs as LIMIT orders at the leg prices, the body to send to `POST /basket`
+    pub basket: BasketRequest
+}
+
+// A leg before it is priced: which side, which row of the chain and how many lots, negative when sold
+struct LegPlan {
+    kind: OptionKind,
+    row: usize,
+    ratio: i64
+}
+
+/// Resolves `spec` against the strikes and quotes of `chain` and prices it at expiry.
+pub fn build_strategy(chain: &OptionChain, spec: &StrategySpec, product: Option<String>) -> Result<StrategyReport, anyhow::Error> {
+    if spec.lots == 0 {
+        return Err(anyhow::anyhow!("lots must be at least 1"));
+    }
+    let spot = chain.spot.ok_or_else(|| anyhow::anyhow!("No spot price for {}, strikes can't be picked", chain.spot_key))?;
+    let lot_size = chain.lot_size.ok_or_else(|| anyhow::anyhow!("No contracts in the chain"))?;
+    let width = spec.width.unwrap_or(1) as i64;
+    if width == 0 {
+        return Err(anyhow::anyhow!("width must be at least 1"));
+    }
+    let strikes = Strikes::new(&chain.rows, spot);
+
+    let plans = match spec.strategy {
+        StrategyKind::Straddle => {
+            let body = strikes.pick(spec.strike.unwrap_or(StrikeRule::Atm), OptionKind::Call)?;
+            vec![LegPlan { kind: OptionKind::Call, row: body, ratio: 1 }, LegPlan { kind: OptionKind::Put, row: body, ratio: 1 }]
+        },
+        StrategyKind::Strangle => {
+            let rule = spec.strike.unwrap_or(StrikeRule::Offset(1));
+            vec![
+                LegPlan { kind: OptionKind::Call, row: strikes.pick(rule, OptionKind::Call)?, ratio: 1 },
+                LegPlan { kind: OptionKind::Put, row: strikes.pick(rule, OptionKind::Put)?, ratio: 1 }
+            ]
+        },
+        StrategyKind::Vertical => {
+            let kind = spec.option.unwrap_or(OptionKind::Call);
+            let near = strikes.pick(spec.strike.unwrap_or(StrikeRule::Atm), kind)?;
+            vec![LegPlan { kind, row: near, ratio: 1 }, LegPlan { kind, row: strikes.shift(near, width, kind)?, ratio: -1 }]
+        },
+        StrategyKind::IronCondor => {
+            let rule = spec.strike.unwrap_or(StrikeRule::Offset(1));
+            let (call, put) = (strikes.pick(rule, OptionKind::Call)?, strikes.pick(rule, OptionKind::Put)?);
+            vec![
+                LegPlan { kind: OptionKind::Call, row: call, ratio: 1 },
+                LegPlan { kind: OptionKind::Put, row: put, ratio: 1 },
+                LegPlan { kind: OptionKind::Call, row: strikes.shift(call, width, OptionKind::Call)?, ratio: -1 },
+                LegPlan { kind: OptionKind::Put, row: strikes.shift(put, width, OptionKind::Put)?, ratio: -1 }
+            ]
+        },
+        StrategyKind::Butterfly => {
+            let kind = spec.option.unwrap_or(OptionKind::Call);
+            let body = strikes.pick(spec.strike.unwrap_or(StrikeRule::Atm), kind)?;
+            vec![
+                LegPlan { kind, row: strikes.shift(body, -width, OptionKind::Call)?, ratio: 1 },
+                LegPlan { kind, row: body, ratio: -2 },
+                LegPlan { kind, row: strikes.shift(body, width, OptionKind::Call)?, ratio: 1 }
+            ]
+        }
+    };
+    let sign = if spec.direction == Direction::Long { 1 } else { -1 };
+    let tick = chain.tick_size.unwrap_or(crate::order_validation::DEFAULT_TICK_SIZE);
+
+    let mut legs = Vec::new();
+    let mut positions = Vec::new();
+    for plan in plans {
+        let ratio = plan.ratio * sign;
+        let row = &chain.rows[plan.row];
+        let quote = match plan.kind {
+            OptionKind::Call => row.call.as_ref(),
+            OptionKind::Put => row.put.as_ref()
+        }.ok_or_else(|| anyhow::anyhow!("No {:?} listed at strike {}", plan.kind, row.strike))?;
+        let price = leg_price(quote, ratio > 0, tick)
+            .ok_or_else(|| anyhow::anyhow!("No price for {}", quote.tradingsymbol))?;
+        let units = ratio * (spec.lots * lot_size) as i64;
+
+        positions.push(Position { kind: plan.kind, strike: row.strike, units: units as f64, price });
+        legs.push(StrategyLeg {
+            tradingsymbol: quote.tradingsymbol.clone(),
+            kind: plan.kind,
+            strike: row.strike,
+            action: if ratio > 0 { "buy" } else { "sell" }.to_string(),
+            quantity: units.unsigned_abs() as u32,
+            price,
+            iv: quote.iv,
+            greeks: quote.greeks
+        });
+    }
+
+    let payoff = Payoff::new(&positions);
+    let greeks = legs.iter().zip(&positions).try_fold(Greeks::default(), |mut total, (leg, position)| {
+        total.add(&leg.greeks?.scaled(position.units));
+        Some(total)
+    });
+    let basket_legs = legs.iter().map(|leg| TradeInstruction {
+        action: leg.action.clone(),
+        symbol: leg.tradingsymbol.clone(),
+        exchange: chain.exchange.clone().unwrap_or_else(|| "NFO".to_string()),
+        quantity: leg.quantity,
+        price_type: "LIMIT".to_string(),
+        limit_price: Some(leg.price),
+        stop_loss: None,
+        target: None,
+        order_id: None,
+        timeframe: None,
+        watchlist: None,
+        idempotency_key: None,
+        auto_adjust: false,
+        product: product.clone()
+    }).collect();
+
+    Ok(StrategyReport {
+        strategy: spec.strategy,
+        direction: spec.direction,
+        underlying: chain.underlying.clone(),
+        expiry: chain.expiry,
+        spot,
+        lots: spec.lots,
+        lot_size,
+        legs,
+        net_premium: positions.iter().map(|position| position.units * position.price).sum(),
+        max_profit: payoff.max_profit(),
+        max_loss: payoff.max_loss(),
+        breakevens: payoff.breakevens(),
+        greeks,
+        payoff: payoff.points(spot),
+        basket: BasketRequest { legs: basket_legs, idempotency_key: None }
+    })
+}
+
+// Buys pay the offer and sells hit the bid, so the payoff is what the basket would actually lock in
+fn leg_price(quote: &OptionQuote, buying: bool, tick: f64) -> Option<f64> {
+    let side = if buying { quote.ask } else { quote.bid };
+    side.or(quote.ltp).map(|price| round_to_tick(price, tick, !buying))
+}
+
+struct Strikes<'a> {
+    rows: &'a [ChainRow],
+    atm: usize
+}
+
+impl<'a> Strikes<'a> {
+    fn new(rows: &'a [ChainRow], spot: f64) -> Self {
+        let atm = (0..rows.len())
+            .min_by(|a, b| (rows[*a].strike - spot).abs().total_cmp(&(rows[*b].strike - spot).abs()))
+            .unwrap_or_default();
+        Self { rows, atm }
+    }
+
+    fn pick(&self, rule: StrikeRule, kind: OptionKind) -> Result<usize, anyhow::Error> {
+        match rule {
+            StrikeRule::Atm => self.shift(self.atm, 0, kind),
+            StrikeRule::Offset(strikes) => self.shift(self.atm, strikes as i64, kind),
+            StrikeRule::Delta(target) => (0..self.rows.len())
+                .filter_map(|row| {
+                    let quote = match kind {
+                        OptionKind::Call => self.rows[row].call.as_ref(),
+                        OptionKind::Put => self.rows[row].put.as_ref()
+                    };
+                    quote.and_then(|quote| quote.greeks).map(|greeks| (row, (greeks.delta.abs() - target.abs()).abs()))
+                })
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(row, _)| row)
+                .ok_or_else(|| anyhow::anyhow!("No {:?} in the chain has a delta to match {}", kind, target))
+        }
+    }
+
+    // `strikes` further out of the money for `kind`: up the chain for calls, down for puts
+    fn shift(&self, row: usize, strikes: i64, kind: OptionKind) -> Result<usize, anyhow::Error> {
+        if self.rows.is_empty() {
+            return Err(anyhow::anyhow!("No strikes in the chain"));
+        }
+        let target = match kind {
+            OptionKind::Call => row as i64 + strikes,
+            OptionKind::Put => row as i64 - strikes
+        };
+        if target < 0 || target >= self.rows.len() as i64 {
+            return Err(anyhow::anyhow!(
+                "Strike {} {:+} is outside the chain ({} to {})",
+                self.rows[row].strike, strikes, self.rows[0].strike, self.rows[self.rows.len() - 1].strike
+            ));
+        }
+        Ok(target as usize)
+    }
+}
+
+struct Position {
+    kind: OptionKind,
+    strike: f64,
+    units: f64,
+    price: f64
+}
+
+// P&L at expiry is piecewise linear with kinks at the strikes, so it is enough to look at those
+// and at the slope past the highest one
+struct Payoff<'a> {
+    positions: &'a [Position],
+    strikes: Vec<f64>
+}
+
+impl<'a> Payoff<'a> {
+    fn new(positions: &'a [Position]) -> Self {
+        let mut strikes: Vec<f64> = positions.iter().map(|position| position.strike).collect();
+        strikes.sort_by(f64::total_cmp);
+        strikes.dedup();
+        Self { positions, strikes }
+    }
+
+    fn pnl(&self, spot: f64) -> f64 {
+        self.positions.iter().map(|position| {
+            let intrinsic = match position.kind {
+                OptionKind::Call => (spot - position.strike).max(0.0),
+                OptionKind::Put => (position.strike - spot).max(0.0)
+            };
+            position.units * (intrinsic - position.price)
+        }).sum()
+    }
+
+    // Change in P&L per point above the highest strike, where only the calls are in the money
+    fn upper_slope(&self) -> f64 {
+        self.positions.iter().filter(|position| position.kind == OptionKind::Call).map(|position| position.units).sum()
+    }
+
+    fn kinks(&self) -> Vec<(f64, f64)> {
+        std::iter::once(0.0).chain(self.strikes.iter().copied()).map(|spot| (spot, self.pnl(spot))).collect()
+    }
+
+    fn max_profit(&self) -> Option<f64> {
+        if self.upper_slope() > 1e-9 {
+            return None;
+        }
+        self.kinks().into_iter().map(|(_, pnl)| pnl).reduce(f64::max)
+    }
+
+    fn max_loss(&self) -> Option<f64> {
+        if self.upper_slope() < -1e-9 {
+            return None;
+        }
+        self.kinks().into_iter().map(|(_, pnl)| pnl).reduce(f64::min)
+    }
+
+    fn breakevens(&self) -> Vec<f64> {
+        let kinks = self.kinks();
+        let mut breakevens = Vec::new();
+        for pair in kinks.windows(2) {
+            let ((s0, p0), (s1, p1)) = (pair[0], pair[1]);
+            if p0 == 0.0 && s0 > 0.0 {
+                breakevens.push(s0);
+            }
+            else if p0 * p1 < 0.0 {
+                breakevens.push(s0 + (s1 - s0) * p0 / (p0 - p1));
+            }
+        }
+        if let Some(&(last, pnl)) = kinks.last() {
+            let slope = self.upper_slope();
+            if pnl == 0.0 && last > 0.0 {
+                breakevens.push(last);
+            }
+            else if pnl * slope < 0.0 {
+                breakevens.push(last - pnl / slope);
+            }
+        }
+        breakevens.into_iter().map(paise).collect()
+    }
+
+    fn points(&self, spot: f64) -> Vec<PayoffPoint> {
+        let (low, high) = match (self.strikes.first(), self.strikes.last()) {
+            (Some(low), Some(high)) => (low.min(spot) * 0.9, high.max(spot) * 1.1),
+            _ => return Vec::new()
+        };
+        let mut spots: Vec<f64> = [low, spot, high].into_iter().chain(self.strikes.iter().copied()).collect();
+        spots.sort_by(f64::total_cmp);
+        spots.dedup();
+        spots.into_iter().map(|spot| PayoffPoint { spot, pnl: self.pnl(spot) }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use crate::option_pricing::ExerciseStyle;
+
+    // Strikes 100 apart around 22,000; time value falls off 150, 90, 50, 25 away from the money
+    fn chain() -> OptionChain {
+        let quote = |strike: f64, kind: &str, otm: i64| {
+            let premium = [150.0, 90.0, 50.0, 25.0][otm.unsigned_abs() as usize] + if otm < 0 { -100.0 * otm as f64 } else { 0.0 };
+            Some(OptionQuote {
+                tradingsymbol: format!("NIFTY{}{}", strike, kind),
+                instrument_token: 1,
+                ltp: Some(premium),
+                bid: Some(premium),
+                ask: Some(premium),
+                oi: None,
+                volume: None,
+                iv: None,
+                greeks: None
+            })
+        };
+        let rows = (-3..=3).map(|i: i64| {
+            let strike = 22_000.0 + 100.0 * i as f64;
+            ChainRow { strike, call: quote(strike, "CE", i), put: quote(strike, "PE", -i) }
+        }).collect();
+        OptionChain {
+            underlying: "NIFTY".to_string(),
+            spot_key: "NSE:NIFTY 50".to_string(),
+            spot: Some(22_010.0),
+            expiry: NaiveDate::from_ymd_opt(2024, 1, 25).unwrap(),
+            expiries: Vec::new(),
+            style: ExerciseStyle::European,
+            years_to_expiry: 0.02,
+            rate: 0.065,
+            dividend_yield: 0.0,
+            exchange: Some("NFO".to_string()),
+            lot_size: Some(50),
+            tick_size: Some(0.05),
+            atm_strike: Some(22_000.0),
+            rows,
+            missing: Vec::new()
+        }
+    }
+
+    fn spec(strategy: StrategyKind, direction: Direction) -> StrategySpec {
+        StrategySpec { strategy, direction, lots: 1, strike: None, width: None, option: None }
+    }
+
+    #[test]
+    fn a_long_straddle_breaks_even_a_premium_either_side() {
+        let report = build_strategy(&chain(), &spec(StrategyKind::Straddle, Direction::Long), None).unwrap();
+        assert_eq!(report.legs.len(), 2);
+        assert_eq!(report.net_premium, 300.0 * 50.0);
+        assert_eq!(report.breakevens, vec![21_700.0, 22_300.0]);
+        assert_eq!(report.max_profit, None);
+        assert_eq!(report.max_loss, Some(-15_000.0));
+        assert!(report.basket.legs.iter().all(|leg| leg.action == "buy" && leg.quantity == 50 && leg.exchange == "NFO"));
+    }
+
+    #[test]
+    fn a_short_iron_condor_has_bounded_risk() {
+        let report = build_strategy(&chain(), &spec(StrategyKind::IronCondor, Direction::Short), None).unwrap();
+        let strikes: Vec<(f64, &str)> = report.legs.iter().map(|leg| (leg.strike, leg.action.as_str())).collect();
+        assert_eq!(strikes, vec![(22_100.0, "sell"), (21_900.0, "sell"), (22_200.0, "buy"), (21_800.0, "buy")]);
+        // A credit of 2 x (90 - 50) a unit against wings 100 wide
+        assert_eq!(report.net_premium, -80.0 * 50.0);
+        assert_eq!(report.max_profit, Some(4_000.0));
+        assert_eq!(report.max_loss, Some(-1_000.0));
+        assert_eq!(report.breakevens, vec![21_820.0, 22_180.0]);
+
+        let mut wide = spec(StrategyKind::Butterfly, Direction::Long);
+        wide.width = Some(5);
+        assert!(build_strategy(&chain(), &wide, None).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use serde::Serialize;
+use crate::tick_stream::{Depth, DepthLevel};
+
+/// Percentage band around mid used when no explicit band is requested.
+pub const DEFAULT_DEPTH_BAND_PCT: f64 = 0.5;
+/// Spreads wider than this (in basis points of mid) are flagged before market orders.
+pub const THIN_BOOK_SPREAD_BPS: f64 = 50.0;
+/// The opposite side within the band should hold at least this multiple of the order notional.
+pub const THIN_BOOK_COVERAGE: f64 = 2.0;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BookAnalytics {
+    pub best_bid: Option<f64>,
+    pub best_ask: Option<f64>,
+    pub spread: Option<f64>,
+    pub spread_bps: Option<f64>,
+    pub mid: Option<f64>,
+    pub microprice: Option<f64>,
+    pub top_imbalance: Option<f64>,
+    pub imbalance: Option<f64>,
+    pub band_pct: f64,
+    pub bid_notional_within_band: f64,
+    pub ask_notional_within_band: f64
+}
+
+fn live_levels(levels: &[DepthLevel]) -> impl Iterator<Item = &DepthLevel> {
+    // Kite pads missing levels with zeros
+    levels.iter().filter(|level| level.price > 0.0 && level.quantity > 0.0)
+}
+
+fn imbalance(bid_quantity: f64, ask_quantity: f64) -> Option<f64> {
+    let total = bid_quantity + ask_quantity;
+    if total > 0.0 {
+        Some((bid_quantity - ask_quantity) / total)
+    }
+    else {
+        None
+    }
+}
+
+/// Derives spread, mid, microprice, imbalance and the notional resting within `band_pct` of mid.
+pub fn analyse(depth: &Depth, band_pct: f64) -> BookAnalytics {
+    let best_bid = live_levels(&depth.buy).next();
+    let best_ask = live_levels(&depth.sell).next();
+
+    let (spread, spread_bps, mid, microprice, top_imbalance) = match (best_bid, best_ask) {
+        (Some(bid), Some(ask)) => {
+            let mid = (bid.price + ask.price) / 2.0;
+            let spread = ask.price - bid.price;
+            // Microprice leans towards the side with less resting size, which is where price tends to move
+            let microprice = (bid.price * ask.quantity + ask.price * bid.quantity) / (bid.quantity + ask.quantity);
+            (Some(spread), Some(spread / mid * 10_000.0), Some(mid), Some(microprice), imbalance(bid.quantity, ask.quantity))
+        },
+        _ => (None, None, None, None, None)
+    };
+
+    let bid_quantity: f64 = live_levels(&depth.buy).map(|level| level.quantity).sum();
+    let ask_quantity: f64 = live_levels(&depth.sell).map(|level| level.quantity).sum();
+
+    let (bid_notional_within_band, ask_notional_within_band) = match mid {
+        Some(mid) => {
+            let floor = mid * (1.0 - band_pct / 100.0);
+            let ceiling = mid * (1.0 + band_pct / 100.0);
+            (
+                live_levels(&depth.buy).filter(|l| l.price >= floor).map(|l| l.price * l.quantity).sum(),
+                live_levels(&depth.sell).filter(|l| l.price <= ceiling).map(|l| l.price * l.quantity).sum()
+            )
+        },
+        None => (0.0, 0.0)
+    };
+
+    BookAnalytics {
+        best_bid: best_bid.map(|level| level.price),
+        best_ask: best_ask.map(|level| level.price),
+        spread,
+        spread_bps,
+        mid,
+        microprice,
+        top_imbalance,
+        imbalance: imbalance(bid_quantity, ask_quantity),
+        band_pct,
+        bid_notional_within_band,
+        ask_notional_within_band
+    }
+}
+
+/// Reasons a market order of `quantity` on `side` ("buy"/"sell") would walk a thin book.
+pub fn thin_book_warnings(analytics: &BookAnalytics, side: &str, quantity: u32) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    let (reference, available, opposite) = if side == "buy" {
+        (analytics.best_ask, analytics.ask_notional_within_band, "offers")
+    }
+    else {
+        (analytics.best_bid, analytics.bid_notional_within_band, "bids")
+    };
+
+    let reference = match reference {
+        Some(price) => price,
+        None => {
+            warnings.push(format!("No {} in the order book, a market order may fill far from the last price", opposite));
+            return warnings;
+        }
+    };
+
+    if let Some(spread_bps) = analytics.spread_bps.filter(|bps| *bps > THIN_BOOK_SPREAD_BPS) {
+        warnings.push(format!("Wide spread: {:.1} bps", spread_bps));
+    }
+
+    let notional = reference * quantity as f64;
+    if available < notional * THIN_BOOK_COVERAGE {
+        warnings.push(format!(
+            "Thin book: only {:.0} of {} within {}% of mid for an order worth {:.0}",
+            available, opposite, analytics.band_pct, notional
+        ));
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(levels: &[(f64, f64)]) -> Vec<DepthLevel> {
+        levels.iter().map(|(price, quantity)| DepthLevel { price: *price, quantity: *quantity, orders: 1.0 }).collect()
+    }
+
+    fn close(actual: Option<f64>, expected: f64) -> bool {
+        actual.is_some_and(|actual| (actual - expected).abs() < 1e-9)
+    }
+
+    #[test]
+    fn analytics_from_a_padded_five_level_book() {
+        let depth = Depth {
+            buy: levels(&[(99.9, 300.0), (99.8, 200.0), (99.0, 1000.0), (0.0, 0.0), (0.0, 0.0)]),
+            sell: levels(&[(100.1, 100.0), (100.2, 400.0), (0.0, 0.0), (0.0, 0.0), (0.0, 0.0)])
+        };
+        let book = analyse(&depth, 0.5);
+        assert_eq!((book.best_bid, book.best_ask), (Some(99.9), Some(100.1)));
+        assert!(close(book.mid, 100.0) && close(book.spread, 0.2) && close(book.spread_bps, 20.0));
+        // Three times the size on the bid pulls the microprice towards the offer
+        assert!(close(book.microprice, 100.05));
+        assert!(close(book.top_imbalance, 0.5) && close(book.imbalance, 0.5));
+        // The 99.0 bid is outside 0.5% of mid
+        assert!(close(Some(book.bid_notional_within_band), 99.9 * 300.0 + 99.8 * 200.0));
+        assert!(close(Some(book.ask_notional_within_band), 100.1 * 100.0 + 100.2 * 400.0));
+    }
+
+    #[test]
+    fn thin_and_one_sided_books_are_flagged() {
+        let depth = Depth { buy: levels(&[(99.9, 300.0), (99.8, 200.0)]), sell: levels(&[(100.1, 100.0), (100.2, 400.0)]) };
+        let book = analyse(&depth, 0.5);
+        assert!(thin_book_warnings(&book, "buy", 100).is_empty());
+        let warnings = thin_book_warnings(&book, "buy", 300);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Thin book"), "{:?}", warnings);
+
+        let wide = analyse(&Depth { buy: levels(&[(99.0, 1000.0)]), sell: levels(&[(101.0, 1000.0)]) }, 2.0);
+        assert_eq!(thin_book_warnings(&wide, "sell", 10), vec!["Wide spread: 200.0 bps"]);
+
+        let one_sided = analyse(&Depth { buy: levels(&[(99.0, 1000.0)]), sell: levels(&[(0.0, 0.0)]) }, 0.5);
+        assert!(one_sided.mid.is_none() && one_sided.spread.is_none());
+        assert!(thin_book_warnings(&one_sided, "buy", 1)[0].starts_with("No offers"));
+    }
+}
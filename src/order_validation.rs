@@ -0,0 +1,204 @@
+use std::{collections::HashMap, path::Path};
+use crate::instrument_master::Instrument;
+
+/// NSE and BSE index derivative freeze quantities, used when no `qtyfreeze.csv` is loaded.
+const INDEX_FREEZE_LIMITS: [(&str, u32); 6] = [
+    ("NIFTY", 1800),
+    ("BANKNIFTY", 900),
+    ("FINNIFTY", 1800),
+    ("MIDCPNIFTY", 2800),
+    ("SENSEX", 1000),
+    ("BANKEX", 900)
+];
+
+/// Tick size assumed for instruments missing from the instrument master.
+pub const DEFAULT_TICK_SIZE: f64 = 0.05;
+
+/// Largest quantity the exchange accepts in a single F&O order, per underlying.
+#[derive(Debug, Clone)]
+pub struct FreezeLimits {
+    limits: HashMap<String, u32>
+}
+
+impl Default for FreezeLimits {
+    fn default() -> Self {
+        Self { limits: INDEX_FREEZE_LIMITS.iter().map(|(name, qty)| (name.to_string(), *qty)).collect() }
+    }
+}
+
+impl FreezeLimits {
+    /// Loads NSE's `qtyfreeze.csv` (`SYMBOL`, `VOL_FRZ_QTY`) on top of the built-in index limits.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(path)?;
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| headers.iter().position(|h| h.eq_ignore_ascii_case(name));
+        let symbol_col = column("SYMBOL").ok_or_else(|| anyhow::anyhow!("Missing SYMBOL column"))?;
+        let qty_col = column("VOL_FRZ_QTY").ok_or_else(|| anyhow::anyhow!("Missing VOL_FRZ_QTY column"))?;
+
+        let mut limits = Self::default();
+        for record in reader.records() {
+            let record = record?;
+            let symbol = record.get(symbol_col).unwrap_or("").to_uppercase();
+            match record.get(qty_col).and_then(|qty| qty.parse::<u32>().ok()) {
+                Some(qty) if !symbol.is_empty() && qty > 0 => {
+                    limits.limits.insert(symbol, qty);
+                },
+                _ => println!("Skipping freeze quantity row for '{}'", symbol)
+            }
+        }
+        Ok(limits)
+    }
+
+    pub fn len(&self) -> usize {
+        self.limits.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    /// Freeze limit for a derivative, looked up by its underlying. Cash segment orders have none.
+    pub fn get(&self, instrument: &Instrument) -> Option<u32> {
+        if !is_derivative(instrument) {
+            return None;
+        }
+        self.limits.get(&instrument.name.to_uppercase()).copied()
+    }
+}
+
+/// Futures and options trade in lots; cash equities trade in shares.
+pub fn is_derivative(instrument: &Instrument) -> bool {
+    matches!(instrument.instrument_type.as_str(), "FUT" | "CE" | "PE")
+}
+
+/// Price and quantity as they will be sent, plus how the order is split across child orders.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidatedOrder {
+    pub price: Option<f64>,
+    pub quantity: u32,
+    pub slices: Vec<u32>,
+    pub adjustments: Vec<String>
+}
+
+/// Checks `price` against the tick size and `quantity` against the lot size, and splits the order at
+/// the freeze limit. With `auto_adjust` off bad values are errors; with it on they are rounded in the
+/// safe direction: buy prices down, sell prices up, quantities down to whole lots.
+pub fn validate_order(instrument: &Instrument, buying: bool, price: Option<f64>, quantity: u32, auto_adjust: bool, freeze_limit: Option<u32>) -> Result<ValidatedOrder, anyhow::Error> {
+    let mut adjustments = Vec::new();
+    let key = instrument.key();
+
+    let price = match price {
+        Some(price) if price <= 0.0 => return Err(anyhow::anyhow!("Price must be positive, got {}", price)),
+        Some(price) if instrument.tick_size > 0.0 && !on_tick(price, instrument.tick_size) => {
+            if !auto_adjust {
+                return Err(anyhow::anyhow!(
+                    "Price {} for {} is not a multiple of the tick size {}, pass auto_adjust to round it",
+                    price, key, instrument.tick_size
+                ));
+            }
+            let adjusted = round_to_tick(price, instrument.tick_size, buying);
+            adjustments.push(format!("Price {} rounded {} to {} (tick size {})", price, if buying { "down" } else { "up" }, adjusted, instrument.tick_size));
+            Some(adjusted)
+        },
+        other => other
+    };
+
+    let lot = if is_derivative(instrument) { instrument.lot_size.max(1) } else { 1 };
+    let quantity = if quantity == 0 {
+        return Err(anyhow::anyhow!("Quantity must be at least one"));
+    }
+    else if !quantity.is_multiple_of(lot) {
+        let adjusted = quantity / lot * lot;
+        if !auto_adjust {
+            return Err(anyhow::anyhow!("Quantity {} for {} is not a multiple of the lot size {}, pass auto_adjust to round it", quantity, key, lot));
+        }
+        if adjusted == 0 {
+            return Err(anyhow::anyhow!("Quantity {} for {} is less than one lot of {}", quantity, key, lot));
+        }
+        adjustments.push(format!("Quantity {} rounded down to {} ({} lots of {})", quantity, adjusted, adjusted / lot, lot));
+        adjusted
+    }
+    else {
+        quantity
+    };
+
+    let slices = match freeze_limit {
+        // Each child has to be whole lots and at most the freeze limit
+        Some(limit) if quantity > limit => {
+            let per_child = (limit / lot * lot).max(lot);
+            let mut slices = vec![per_child; (quantity / per_child) as usize];
+            if !quantity.is_multiple_of(per_child) {
+                slices.push(quantity % per_child);
+            }
+            adjustments.push(format!("Quantity {} is above the freeze limit of {}, split into {} orders", quantity, limit, slices.len()));
+            slices
+        },
+        _ => vec![quantity]
+    };
+
+    Ok(ValidatedOrder { price, quantity, slices, adjustments })
+}
+
+fn on_tick(price: f64, tick: f64) -> bool {
+    let ticks = price / tick;
+    (ticks - ticks.round()).abs() < 1e-6
+}
+
+/// Rounds `price` onto the tick grid, down or up.
+pub fn round_to_tick(price: f64, tick: f64, down: bool) -> f64 {
+    let ticks = price / tick;
+    // Nudge first so a price already a hair away from a tick is not pushed a whole tick further
+    let ticks = if down { (ticks + 1e-9).floor() } else { (ticks - 1e-9).ceil() };
+    (ticks * tick * 10_000.0).round() / 10_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instrument(instrument_type: &str, tick_size: f64, lot_size: u32) -> Instrument {
+        Instrument {
+            instrument_token: 1,
+            exchange_token: 1,
+            tradingsymbol: if instrument_type == "EQ" { "INFY".to_string() } else { "NIFTY24JAN21500CE".to_string() },
+            name: if instrument_type == "EQ" { "INFOSYS".to_string() } else { "NIFTY".to_string() },
+            expiry: None,
+            strike: 0.0,
+            tick_size,
+            lot_size,
+            instrument_type: instrument_type.to_string(),
+            segment: if instrument_type == "EQ" { "NSE".to_string() } else { "NFO-OPT".to_string() },
+            exchange: if instrument_type == "EQ" { "NSE".to_string() } else { "NFO".to_string() }
+        }
+    }
+
+    #[test]
+    fn off_tick_prices_are_rejected_or_rounded_the_safe_way() {
+        let infy = instrument("EQ", 0.05, 1);
+        assert!(validate_order(&infy, true, Some(1523.37), 10, false, None).is_err());
+        assert_eq!(validate_order(&infy, true, Some(1523.37), 10, true, None).unwrap().price, Some(1523.35));
+        assert_eq!(validate_order(&infy, false, Some(1523.37), 10, true, None).unwrap().price, Some(1523.4));
+        assert_eq!(validate_order(&infy, true, Some(1523.35), 10, false, None).unwrap().adjustments, Vec::<String>::new());
+    }
+
+    #[test]
+    fn derivative_quantities_are_whole_lots() {
+        let option = instrument("CE", 0.05, 50);
+        assert!(validate_order(&option, true, None, 120, false, None).is_err());
+        assert_eq!(validate_order(&option, true, None, 120, true, None).unwrap().quantity, 100);
+        assert!(validate_order(&option, true, None, 30, true, None).is_err());
+        // Lot size does not apply to cash equities
+        assert_eq!(validate_order(&instrument("EQ", 0.05, 1), true, None, 7, false, None).unwrap().quantity, 7);
+    }
+
+    #[test]
+    fn orders_above_the_freeze_limit_are_split() {
+        let option = instrument("CE", 0.05, 50);
+        let limits = FreezeLimits::default();
+        let order = validate_order(&option, true, None, 4000, false, limits.get(&option)).unwrap();
+
+        assert_eq!(order.slices, vec![1800, 1800, 400]);
+        assert_eq!(order.slices.iter().sum::<u32>(), 4000);
+        assert_eq!(limits.get(&instrument("EQ", 0.05, 1)), None);
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+use crate::{charges::paise, kite_models::{Holding, Margins, Order, Position, SegmentMargins}, market_data::SourcedTick};
+
+/// Order statuses that can still fill.
+pub const OPEN_STATUSES: [&str; 6] = ["OPEN", "TRIGGER PENDING", "AMO REQ RECEIVED", "OPEN PENDING", "MODIFY PENDING", "VALIDATION PENDING"];
+
+/// The price a row is valued at: the freshest tick we have, else whatever the broker last reported.
+#[derive(Debug, Clone, Serialize)]
+pub struct Valuation {
+    pub ltp: f64,
+    /// `ticker`, `rest`, or `broker` when no quote came back
+    pub ltp_source: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ltp_age_ms: Option<u64>
+}
+
+fn valuation(ticks: &BTreeMap<String, SourcedTick>, key: &str, broker_price: f64) -> Valuation {
+    match ticks.get(key).filter(|sourced| sourced.tick.last_price > 0.0) {
+        Some(sourced) => Valuation { ltp: sourced.tick.last_price, ltp_source: sourced.source, ltp_age_ms: sourced.age_ms },
+        None => Valuation { ltp: broker_price, ltp_source: "broker", ltp_age_ms: None }
+    }
+}
+
+fn key(exchange: &str, tradingsymbol: &str) -> String {
+    format!("{}:{}", exchange, tradingsymbol)
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Long,
+    Short,
+    Flat
+}
+
+impl Side {
+    fn of(quantity: i64) -> Self {
+        match quantity {
+            q if q > 0 => Side::Long,
+            q if q < 0 => Side::Short,
+            _ => Side::Flat
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PortfolioPosition {
+    pub symbol: String,
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub instrument_token: u32,
+    pub product: String,
+    pub side: Side,
+    /// Signed, negative when short
+    pub quantity: i64,
+    pub overnight_quantity: i64,
+    pub average_price: f64,
+    #[serde(flatten)]
+    pub valuation: Valuation,
+    pub buy_quantity: i64,
+    pub sell_quantity: i64,
+    pub unrealised_pnl: f64,
+    pub realised_pnl: f64,
+    pub pnl: f64
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PositionBook {
+    pub positions: Vec<PortfolioPosition>,
+    pub unrealised_pnl: f64,
+    pub realised_pnl: f64,
+    pub pnl: f64
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PortfolioPositions {
+    /// Everything carried, including today's trades
+    pub net: PositionBook,
+    /// Today's trades only
+    pub day: PositionBook
+}
+
+impl PositionBook {
+    /// Revalues positions at the latest prices the way Kite does: P&L is the sell value less the
+    /// buy value plus the open quantity marked at LTP, and the unrealised part is the open
+    /// quantity's move from the average price.
+    pub fn new(positions: &[Position], ticks: &BTreeMap<String, SourcedTick>) -> Self {
+        let positions: Vec<PortfolioPosition> = positions.iter().map(|position| {
+            let symbol = key(&position.exchange, &position.tradingsymbol);
+            let valuation = valuation(ticks, &symbol, position.last_price);
+            let multiplier = if position.multiplier > 0.0 { position.multiplier } else { 1.0 };
+            let open = position.quantity as f64 * multiplier;
+            let pnl = position.sell_value - position.buy_value + open * valuation.ltp;
+            let unrealised = open * (valuation.ltp - position.average_price);
+
+            PortfolioPosition {
+                symbol,
+                exchange: position.exchange.clone(),
+                tradingsymbol: position.tradingsymbol.clone(),
+                instrument_token: position.instrument_token,
+                product: position.product.clone(),
+                side: Side::of(position.quantity),
+                quantity: position.quantity,
+                overnight_quantity: position.overnight_quantity,
+                average_price: position.average_price,
+                valuation,
+                buy_quantity: position.buy_quantity,
+                sell_quantity: position.sell_quantity,
+                unrealised_pnl: paise(unrealised),
+                realised_pnl: paise(pnl - unrealised),
+                pnl: paise(pnl)
+            }
+        }).collect();
+
+        Self {
+            unrealised_pnl: paise(positions.iter().map(|p| p.unrealised_pnl).sum()),
+            realised_pnl: paise(positions.iter().map(|p| p.realised_pnl).sum()),
+            pnl: paise(positions.iter().map(|p| p.pnl).sum()),
+            positions
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PortfolioHolding {
+    pub symbol: String,
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub instrument_token: u32,
+    pub isin: String,
+    /// Settled plus T1 shares
+    pub quantity: i64,
+    pub t1_quantity: i64,
+    pub collateral_quantity: i64,
+    pub average_price: f64,
+    #[serde(flatten)]
+    pub valuation: Valuation,
+    pub close_price: f64,
+    pub invested: f64,
+    pub current_value: f64,
+    pub unrealised_pnl: f64,
+    pub unrealised_pnl_pct: f64,
+    pub day_change: f64,
+    pub day_change_pct: f64
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PortfolioHoldings {
+    pub holdings: Vec<PortfolioHolding>,
+    pub invested: f64,
+    pub current_value: f64,
+    pub unrealised_pnl: f64,
+    pub day_change: f64
+}
+
+fn pct(part: f64, whole: f64) -> f64 {
+    if whole > 0.0 { paise(part / whole * 100.0) } else { 0.0 }
+}
+
+impl PortfolioHoldings {
+    pub fn new(holdings: &[Holding], ticks: &BTreeMap<String, SourcedTick>) -> Self {
+        let holdings: Vec<PortfolioHolding> = holdings.iter().map(|holding| {
+            let symbol = key(&holding.exchange, &holding.tradingsymbol);
+            let valuation = valuation(ticks, &symbol, holding.last_price);
+            let quantity = holding.quantity + holding.t1_quantity;
+            let invested = quantity as f64 * holding.average_price;
+            let current_value = quantity as f64 * valuation.ltp;
+            let day_change = if holding.close_price > 0.0 { valuation.ltp - holding.close_price } else { 0.0 };
+
+            PortfolioHolding {
+                symbol,
+                exchange: holding.exchange.clone(),
+                tradingsymbol: holding.tradingsymbol.clone(),
+                instrument_token: holding.instrument_token,
+                isin: holding.isin.clone(),
+                quantity,
+                t1_quantity: holding.t1_quantity,
+                collateral_quantity: holding.collateral_quantity,
+                average_price: holding.average_price,
+                close_price: holding.close_price,
+                invested: paise(invested),
+                current_value: paise(current_value),
+                unrealised_pnl: paise(current_value - invested),
+                unrealised_pnl_pct: pct(current_value - invested, invested),
+                day_change: paise(day_change * quantity as f64),
+                day_change_pct: pct(day_change, holding.close_price),
+                valuation
+            }
+        }).collect();
+
+        Self {
+            invested: paise(holdings.iter().map(|h| h.invested).sum()),
+            current_value: paise(holdings.iter().map(|h| h.current_value).sum()),
+            unrealised_pnl: paise(holdings.iter().map(|h| h.unrealised_pnl).sum()),
+            day_change: paise(holdings.iter().map(|h| h.day_change).sum()),
+            holdings
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentFunds {
+    pub enabled: bool,
+    /// What can be used for new orders
+    pub available: f64,
+    pub used: f64,
+    pub opening_balance: f64,
+    pub cash: f64,
+    pub live_balance: f64,
+    pub collateral: f64,
+    pub intraday_payin: f64,
+    pub adhoc_margin: f64,
+    /// Kite's `utilised` breakdown (`span`, `exposure`, `option_premium`, ...), zeros left out
+    pub utilised: BTreeMap<String, f64>
+}
+
+impl From<&SegmentMargins> for SegmentFunds {
+    fn from(margins: &SegmentMargins) -> Self {
+        let available = &margins.available;
+        Self {
+            enabled: margins.enabled,
+            available: margins.net,
+            used: margins.utilised.get("debits").copied().unwrap_or_default(),
+            opening_balance: available.opening_balance,
+            cash: available.cash,
+            live_balance: available.live_balance,
+            collateral: available.collateral,
+            intraday_payin: available.intraday_payin,
+            adhoc_margin: available.adhoc_margin,
+            utilised: margins.utilised.iter().filter(|(_, amount)| **amount != 0.0).map(|(name, amount)| (name.clone(), *amount)).collect()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Funds {
+    pub equity: Option<SegmentFunds>,
+    pub commodity: Option<SegmentFunds>
+}
+
+impl From<&Margins> for Funds {
+    fn from(margins: &Margins) -> Self {
+        Self {
+            equity: margins.equity.as_ref().map(SegmentFunds::from),
+            commodity: margins.commodity.as_ref().map(SegmentFunds::from)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenOrder {
+    pub order_id: String,
+    pub symbol: String,
+    pub exchange: String,
+    pub tradingsymbol: String,
+    pub status: String,
+    pub variety: String,
+    pub transaction_type: String,
+    pub order_type: String,
+    pub product: String,
+    pub validity: String,
+    pub quantity: u32,
+    pub filled_quantity: u32,
+    pub pending_quantity: u32,
+    pub price: f64,
+    pub trigger_price: f64,
+    #[serde(flatten)]
+    pub valuation: Valuation,
+    /// How far the order's limit (or trigger) price is from LTP, in percent of LTP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance_pct: Option<f64>,
+    pub tag: Option<String>,
+    pub order_timestamp: Option<String>
+}
+
+impl OpenOrder {
+    pub fn is_open(order: &Order) -> bool {
+        OPEN_STATUSES.contains(&order.status.as_str())
+    }
+
+    /// The orders that can still fill, oldest first.
+    pub fn list(orders: &[Order], ticks: &BTreeMap<String, SourcedTick>) -> Vec<Self> {
+        orders.iter().filter(|order| Self::is_open(order)).map(|order| {
+            let symbol = key(&order.exchange, &order.tradingsymbol);
+            let valuation = valuation(ticks, &symbol, 0.0);
+            let reference = if order.price > 0.0 { order.price } else { order.trigger_price };
+            let distance_pct = (valuation.ltp > 0.0 && reference > 0.0).then(|| paise((reference - valuation.ltp) / valuation.ltp * 100.0));
+
+            OpenOrder {
+                order_id: order.order_id.clone(),
+                symbol,
+                exchange: order.exchange.clone(),
+                tradingsymbol: order.tradingsymbol.clone(),
+                status: order.status.clone(),
+                variety: order.variety.clone(),
+                transaction_type: order.transaction_type.clone(),
+                order_type: order.order_type.clone(),
+                product: order.product.clone(),
+                validity: order.validity.clone(),
+                quantity: order.quantity,
+                filled_quantity: order.filled_quantity,
+                pending_quantity: order.pending_quantity,
+                price: order.price,
+                trigger_price: order.trigger_price,
+                valuation,
+                distance_pct,
+                tag: order.tag.clone(),
+                order_timestamp: order.order_timestamp.clone()
+            }
+        }).collect()
+    }
+}
+
+/// The `EXCHANGE:SYMBOL` keys to price, de-duplicated.
+pub fn quote_keys<'a>(rows: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<String> {
+    let mut keys: Vec<String> = rows.into_iter().map(|(exchange, tradingsymbol)| key(exchange, tradingsymbol)).collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::{kite_models::Quote, tick_stream::TickData};
+
+    fn tick(key: &str, last_price: f64) -> (String, SourcedTick) {
+        let quote: Quote = serde_json::from_value(serde_json::json!({ "instrument_token": 1, "last_price": last_price })).unwrap();
+        (key.to_string(), SourcedTick { tick: Arc::new(TickData::from_quote(&quote, key)), source: "ticker", age_ms: Some(120) })
+    }
+
+    #[test]
+    fn positions_are_revalued_at_the_live_price() {
+        // Bought 100 at 100, sold 40 at 110; 60 still open, last traded at 105
+        let infy = Position {
+            tradingsymbol: "INFY".to_string(),
+            exchange: "NSE".to_string(),
+            product: "MIS".to_string(),
+            quantity: 60,
+            multiplier: 1.0,
+            average_price: 100.0,
+            last_price: 101.0,
+            buy_quantity: 100,
+            buy_value: 10_000.0,
+            sell_quantity: 40,
+            sell_value: 4_400.0,
+            ..Default::default()
+        };
+        let ticks: BTreeMap<String, SourcedTick> = [tick("NSE:INFY", 105.0)].into_iter().collect();
+
+        let book = PositionBook::new(std::slice::from_ref(&infy), &ticks);
+        let position = &book.positions[0];
+        assert_eq!((position.valuation.ltp, position.valuation.ltp_source), (105.0, "ticker"));
+        assert_eq!((position.pnl, position.unrealised_pnl, position.realised_pnl), (700.0, 300.0, 400.0));
+
+        // Without a quote the broker's last price is used
+        let stale = PositionBook::new(&[infy], &BTreeMap::new());
+        assert_eq!((stale.positions[0].valuation.ltp_source, stale.pnl), ("broker", 460.0));
+    }
+
+    #[test]
+    fn holdings_count_t1_shares_and_only_open_orders_are_listed() {
+        let holding = Holding {
+            tradingsymbol: "TCS".to_string(),
+            exchange: "NSE".to_string(),
+            quantity: 8,
+            t1_quantity: 2,
+            average_price: 3_000.0,
+            close_price: 3_200.0,
+            ..Default::default()
+        };
+        let ticks: BTreeMap<String, SourcedTick> = [tick("NSE:TCS", 3_300.0)].into_iter().collect();
+        let holdings = PortfolioHoldings::new(&[holding], &ticks);
+        assert_eq!((holdings.invested, holdings.current_value, holdings.unrealised_pnl, holdings.day_change), (30_000.0, 33_000.0, 3_000.0, 1_000.0));
+        assert_eq!(holdings.holdings[0].unrealised_pnl_pct, 10.0);
+
+        let order = |id: &str, status: &str| Order { order_id: id.to_string(), status: status.to_string(), exchange: "NSE".to_string(), tradingsymbol: "TCS".to_string(), price: 3_267.0, ..Default::default() };
+        let open = OpenOrder::list(&[order("1", "COMPLETE"), order("2", "OPEN"), order("3", "TRIGGER PENDING"), order("4", "REJECTED")], &ticks);
+        assert_eq!(open.iter().map(|o| o.order_id.as_str()).collect::<Vec<_>>(), vec!["2", "3"]);
+        assert_eq!(open[0].distance_pct, Some(-1.0));
+    }
+}
@@ -0,0 +1,200 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{Arc, Mutex}, time::Instant};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use crate::{market_data::{QuoteKind, SourcedTick, LIVE_QUOTE_MAX_AGE}, tick_stream::TickData};
+
+#[derive(Debug, Clone)]
+pub struct CachedTick {
+    pub tick: Arc<TickData>,
+    pub received: Instant
+}
+
+#[derive(Debug, Default)]
+struct PriceSlot {
+    latest: ArcSwapOption<CachedTick>
+}
+
+/// Latest tick per instrument. Every instrument owns a slot that is swapped atomically on each
+/// tick, and the symbol -> slot index is an immutable snapshot that is only replaced when an
+/// instrument ticks for the first time. Readers never take a lock and never wait on the ticker.
+#[derive(Debug, Default)]
+pub struct PriceCache {
+    slots: ArcSwap<HashMap<String, Arc<PriceSlot>>>,
+    // Serialises writers adding new instruments; readers never touch it
+    growing: Mutex<()>
+}
+
+impl PriceCache {
+    pub fn update(&self, symbol: String, tick: Arc<TickData>) {
+        let cached = Arc::new(CachedTick { tick, received: Instant::now() });
+
+        if let Some(slot) = self.slots.load().get(&symbol) {
+            slot.latest.store(Some(cached));
+            return;
+        }
+
+        let _growing = self.growing.lock().unwrap();
+        let mut slots = HashMap::clone(&self.slots.load());
+        slots.entry(symbol).or_default().latest.store(Some(cached));
+        self.slots.store(Arc::new(slots));
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<CachedTick>> {
+        self.slots.load().get(key)?.latest.load_full()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.load().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The cached tick for `key` if it is recent enough and carries the fields `kind` needs.
+    pub fn fresh(&self, key: &str, kind: QuoteKind) -> Option<SourcedTick> {
+        let cached = self.get(key)?;
+        let age = cached.received.elapsed();
+
+        (age <= LIVE_QUOTE_MAX_AGE && kind.covered_by(&cached.tick)).then(|| SourcedTick {
+            tick: cached.tick.clone(),
+            source: "ticker",
+            age_ms: Some(age.as_millis() as u64)
+        })
+    }
+
+    /// Splits keys into fresh cached ticks and the keys that still need fetching.
+    pub fn fresh_ticks(&self, keys: Vec<String>, kind: QuoteKind) -> (BTreeMap<String, SourcedTick>, Vec<String>) {
+        let mut ticks = BTreeMap::new();
+        let mut pending = Vec::new();
+
+        for key in keys {
+            match self.fresh(&key, kind) {
+                Some(sourced) => {
+                    ticks.insert(key, sourced);
+                },
+                None => pending.push(key)
+            }
+        }
+        (ticks, pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::atomic::{AtomicBool, Ordering}, thread, time::Duration};
+    use crate::tick_decoder::{Tick, TickMode};
+
+    const INSTRUMENTS: u32 = 1_000;
+    const READERS: usize = 4;
+    const RUN_FOR: Duration = Duration::from_secs(3);
+
+    type Read = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+    type Write = Arc<dyn Fn(&Arc<TickData>) + Send + Sync>;
+
+    fn tick(token: u32, price: f64) -> Arc<TickData> {
+        let tick = Tick { instrument_token: token, mode: TickMode::Full, tradable: true, last_price: price, has_depth: true, ..Tick::default() };
+        Arc::new(TickData::from_tick(&tick, &format!("NSE:SYM{}", token)))
+    }
+
+    fn percentile(sorted: &[Duration], pct: f64) -> Duration {
+        sorted[((sorted.len() - 1) as f64 * pct) as usize]
+    }
+
+    /// p99 read latency across 1,000 instruments while a writer replays a full-mode feed as fast as it
+    /// can, against the `Mutex<HashMap>` this replaced.
+    /// Run with `cargo test --release price_cache -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn read_latency_under_tick_load() {
+        let ticks: Vec<Arc<TickData>> = (0..INSTRUMENTS).map(|token| tick(token, 100.0 + token as f64)).collect();
+        let keys: Vec<String> = ticks.iter().map(|tick| tick.symbol.clone()).collect();
+
+        let locked: Arc<Mutex<HashMap<String, CachedTick>>> = Arc::default();
+        let lock_free: Arc<PriceCache> = Arc::default();
+        for tick in &ticks {
+            locked.lock().unwrap().insert(tick.symbol.clone(), CachedTick { tick: tick.clone(), received: Instant::now() });
+            lock_free.update(tick.symbol.clone(), tick.clone());
+        }
+
+        let measure = |name: &str, read: Read, write: Write| {
+            let running = Arc::new(AtomicBool::new(true));
+            let writer = {
+                let (running, ticks, write) = (running.clone(), ticks.clone(), write.clone());
+                thread::spawn(move || {
+                    let mut written = 0u64;
+                    while running.load(Ordering::Relaxed) {
+                        for tick in &ticks {
+                            write(tick);
+                        }
+                        written += ticks.len() as u64;
+                    }
+                    written
+                })
+            };
+
+            let started = Instant::now();
+            let readers: Vec<_> = (0..READERS).map(|reader| {
+                let (keys, read) = (keys.clone(), read.clone());
+                thread::spawn(move || {
+                    let mut latencies = Vec::new();
+                    let mut i = reader;
+                    while started.elapsed() < RUN_FOR {
+                        let start = Instant::now();
+                        assert!(read(&keys[i % keys.len()]));
+                        latencies.push(start.elapsed());
+                        i += 7;
+                    }
+                    latencies
+                })
+            }).collect();
+
+            let mut latencies: Vec<Duration> = readers.into_iter().flat_map(|r| r.join().unwrap()).collect();
+            let elapsed = started.elapsed();
+            running.store(false, Ordering::Relaxed);
+            let written = writer.join().unwrap();
+
+            latencies.sort();
+            println!(
+                "{:<10} reads {:>8}  p50 {:>8?}  p99 {:>8?}  p99.9 {:>8?}  max {:>10?}  ticks written {:>10} ({:.0}/s)",
+                name, latencies.len(), percentile(&latencies, 0.50), percentile(&latencies, 0.99),
+                percentile(&latencies, 0.999), latencies[latencies.len() - 1], written, written as f64 / elapsed.as_secs_f64()
+            );
+            percentile(&latencies, 0.99)
+        };
+
+        let mutex_p99 = {
+            let (reads, writes) = (locked.clone(), locked.clone());
+            measure(
+                "mutex",
+                Arc::new(move |key: &str| reads.lock().unwrap().get(key).map(|cached| cached.tick.clone()).is_some()),
+                Arc::new(move |tick: &Arc<TickData>| {
+                    writes.lock().unwrap().insert(tick.symbol.clone(), CachedTick { tick: tick.clone(), received: Instant::now() });
+                })
+            )
+        };
+        let lock_free_p99 = {
+            let (reads, writes) = (lock_free.clone(), lock_free.clone());
+            measure(
+                "lock-free",
+                Arc::new(move |key: &str| reads.get(key).is_some()),
+                Arc::new(move |tick: &Arc<TickData>| writes.update(tick.symbol.clone(), tick.clone()))
+            )
+        };
+
+        println!("p99 read latency with {} instruments: mutex {:?}, lock-free {:?}", INSTRUMENTS, mutex_p99, lock_free_p99);
+    }
+
+    #[test]
+    fn first_tick_adds_a_slot_and_later_ticks_replace_it() {
+        let cache = PriceCache::default();
+        cache.update("NSE:SYM1".to_string(), tick(1, 100.0));
+        cache.update("NSE:SYM2".to_string(), tick(2, 200.0));
+        cache.update("NSE:SYM1".to_string(), tick(1, 101.0));
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("NSE:SYM1").unwrap().tick.last_price, 101.0);
+        assert!(cache.get("NSE:SYM3").is_none());
+        assert!(cache.fresh("NSE:SYM2", QuoteKind::Ltp).is_some());
+    }
+}
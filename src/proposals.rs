@@ -0,0 +1,363 @@
+use std::{collections::HashMap, sync::Mutex, time::{Duration, Instant}};
+use chrono::Utc;
+use serde::Serialize;
+use crate::data_structures::{TradeInstruction, TradePreview, TradeResponse};
+
+pub const DEFAULT_PROPOSAL_TTL: Duration = Duration::from_secs(5 * 60);
+
+// Decided proposals stay listed this long for auditing before they are dropped
+const DECIDED_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProposalStatus {
+    Pending,
+    /// Confirmed and being sent to Kite
+    Approved,
+    Placed,
+    Failed,
+    Rejected,
+    Expired
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Proposal {
+    pub id: String,
+    pub status: ProposalStatus,
+    pub instruction: TradeInstruction,
+    pub preview: TradePreview,
+    pub proposed_by: Option<String>,
+    pub created_at: String,
+    pub expires_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decided_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decided_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<TradeResponse>,
+    #[serde(skip)]
+    expires: Instant,
+    #[serde(skip)]
+    updated: Instant
+}
+
+impl Proposal {
+    fn decide(&mut self, status: ProposalStatus, by: &str, reason: Option<String>) {
+        self.status = status;
+        self.decided_by = Some(by.to_string());
+        self.decided_at = Some(Utc::now().to_rfc3339());
+        self.reason = reason;
+        self.updated = Instant::now();
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecisionError {
+    NotFound,
+    Expired,
+    AlreadyDecided(ProposalStatus),
+    SelfApproval
+}
+
+impl DecisionError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            DecisionError::NotFound => "not_found",
+            DecisionError::Expired => "proposal_expired",
+            DecisionError::AlreadyDecided(_) => "proposal_decided",
+            DecisionError::SelfApproval => "self_approval"
+        }
+    }
+}
+
+impl std::fmt::Display for DecisionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecisionError::NotFound => write!(f, "Proposal not found"),
+            DecisionError::Expired => write!(f, "Proposal expired before it was confirmed"),
+            DecisionError::AlreadyDecided(status) => write!(f, "Proposal is already {:?}", status),
+            DecisionError::SelfApproval => write!(f, "A proposal has to be confirmed by someone other than its proposer")
+        }
+    }
+}
+
+/// Agent orders waiting for a second party. Orders whose notional is above `notional_limit`
+/// never go straight through `/trade`; proposals at or below `auto_approve_below` are placed
+/// without waiting; everything else expires after `ttl` unless confirmed by one of `approvers`.
+#[derive(Debug)]
+pub struct ProposalStore {
+    ttl: Duration,
+    notional_limit: Option<f64>,
+    auto_approve_below: Option<f64>,
+    // Approver key to approver name
+    approvers: HashMap<String, String>,
+    proposals: Mutex<HashMap<String, Proposal>>,
+    next_id: Mutex<u64>
+}
+
+impl Default for ProposalStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_PROPOSAL_TTL, None, None)
+    }
+}
+
+impl ProposalStore {
+    pub fn new(ttl: Duration, notional_limit: Option<f64>, auto_approve_below: Option<f64>) -> Self {
+        Self { ttl, notional_limit, auto_approve_below, approvers: HashMap::new(), proposals: Mutex::new(HashMap::new()), next_id: Mutex::new(0) }
+    }
+
+    pub fn with_approvers(self, approvers: HashMap<String, String>) -> Self {
+        Self { approvers, ..self }
+    }
+
+    pub fn has_approvers(&self) -> bool {
+        !self.approvers.is_empty()
+    }
+
+    /// The approver holding `key`. Every key is compared in full so the timing doesn't give one away.
+    pub fn approver(&self, key: &str) -> Option<&str> {
+        self.approvers.iter()
+            .fold(None, |found, (approver_key, name)| if constant_time_eq(approver_key.as_bytes(), key.as_bytes()) { Some(name.as_str()) } else { found })
+    }
+
+    pub fn has_notional_limit(&self) -> bool {
+        self.notional_limit.is_some()
+    }
+
+    /// Whether `/trade` has to turn this order into a proposal. An order we cannot price is held
+    /// back too, since it may well be above the limit.
+    pub fn requires_confirmation(&self, notional: Option<f64>) -> bool {
+        match (self.notional_limit, notional) {
+            (Some(limit), Some(notional)) => notional > limit,
+            (Some(_), None) => true,
+            (None, _) => false
+        }
+    }
+
+    pub fn auto_approves(&self, notional: Option<f64>) -> bool {
+        match (self.auto_approve_below, notional) {
+            (Some(threshold), Some(notional)) => notional <= threshold && !self.requires_confirmation(Some(notional)),
+            _ => false
+        }
+    }
+
+    pub fn submit(&self, instruction: TradeInstruction, preview: TradePreview, proposed_by: Option<String>) -> Proposal {
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            format!("P{}{:04}", Utc::now().format("%Y%m%d%H%M%S"), *next_id % 10_000)
+        };
+        let now = Utc::now();
+        let proposal = Proposal {
+            id: id.clone(),
+            status: ProposalStatus::Pending,
+            instruction,
+            preview,
+            proposed_by,
+            created_at: now.to_rfc3339(),
+            expires_at: (now + chrono::Duration::from_std(self.ttl).unwrap_or_default()).to_rfc3339(),
+            decided_by: None,
+            decided_at: None,
+            reason: None,
+            result: None,
+            expires: Instant::now() + self.ttl,
+            updated: Instant::now()
+        };
+
+        let mut proposals = self.proposals.lock().unwrap();
+        Self::sweep(&mut proposals);
+        proposals.insert(id, proposal.clone());
+        proposal
+    }
+
+    pub fn get(&self, id: &str) -> Option<Proposal> {
+        let mut proposals = self.proposals.lock().unwrap();
+        Self::sweep(&mut proposals);
+        proposals.get(id).cloned()
+    }
+
+    /// Every proposal still pending or decided within the retention window, newest first.
+    pub fn list(&self) -> Vec<Proposal> {
+        let mut proposals = self.proposals.lock().unwrap();
+        Self::sweep(&mut proposals);
+        let mut list: Vec<Proposal> = proposals.values().cloned().collect();
+        list.sort_by(|a, b| b.created_at.cmp(&a.created_at).then(b.id.cmp(&a.id)));
+        list
+    }
+
+    /// Moves a pending proposal to `Approved` so only one confirmation can ever place it.
+    pub fn approve(&self, id: &str, approver: &str) -> Result<Proposal, DecisionError> {
+        self.decide(id, |proposal| {
+            let own = proposal.proposed_by.as_deref().is_some_and(|proposer| proposer.eq_ignore_ascii_case(approver));
+            if own {
+                return Err(DecisionError::SelfApproval);
+            }
+            proposal.decide(ProposalStatus::Approved, approver, None);
+            Ok(())
+        })
+    }
+
+    pub fn reject(&self, id: &str, by: &str, reason: Option<String>) -> Result<Proposal, DecisionError> {
+        self.decide(id, |proposal| {
+            proposal.decide(ProposalStatus::Rejected, by, reason);
+            Ok(())
+        })
+    }
+
+    /// Records what Kite said about an approved proposal.
+    pub fn record(&self, id: &str, result: Result<TradeResponse, String>) {
+        if let Some(proposal) = self.proposals.lock().unwrap().get_mut(id) {
+            match result {
+                Ok(response) => {
+                    proposal.status = ProposalStatus::Placed;
+                    proposal.result = Some(response);
+                },
+                Err(reason) => {
+                    proposal.status = ProposalStatus::Failed;
+                    proposal.reason = Some(reason);
+                }
+            }
+            proposal.updated = Instant::now();
+        }
+    }
+
+    fn decide<F>(&self, id: &str, change: F) -> Result<Proposal, DecisionError>
+    where
+        F: FnOnce(&mut Proposal) -> Result<(), DecisionError>
+    {
+        let mut proposals = self.proposals.lock().unwrap();
+        Self::sweep(&mut proposals);
+
+        let proposal = proposals.get_mut(id).ok_or(DecisionError::NotFound)?;
+        match proposal.status {
+            ProposalStatus::Pending => {
+                change(proposal)?;
+                Ok(proposal.clone())
+            },
+            ProposalStatus::Expired => Err(DecisionError::Expired),
+            status => Err(DecisionError::AlreadyDecided(status))
+        }
+    }
+
+    fn sweep(proposals: &mut HashMap<String, Proposal>) {
+        let now = Instant::now();
+        for proposal in proposals.values_mut() {
+            if proposal.status == ProposalStatus::Pending && now >= proposal.expires {
+                proposal.status = ProposalStatus::Expired;
+                proposal.updated = now;
+            }
+        }
+        proposals.retain(|_, proposal| proposal.status == ProposalStatus::Pending || proposal.updated.elapsed() < DECIDED_RETENTION);
+    }
+}
+
+/// Parses `PROPOSAL_APPROVERS`, a comma separated list of `name:key` pairs, into key to name.
+pub fn parse_approvers(raw: &str) -> Result<HashMap<String, String>, anyhow::Error> {
+    let mut approvers = HashMap::new();
+    for entry in raw.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (name, key) = entry.split_once(':')
+            .map(|(name, key)| (name.trim(), key.trim()))
+            .filter(|(name, key)| !name.is_empty() && !key.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("Approver '{}' is not in name:key form", entry))?;
+        if name.eq_ignore_ascii_case("auto") {
+            return Err(anyhow::anyhow!("'auto' is reserved for auto-approved proposals"));
+        }
+        if approvers.insert(key.to_string(), name.to_string()).is_some() {
+            return Err(anyhow::anyhow!("Approver {} reuses another approver's key", name));
+        }
+    }
+    Ok(approvers)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_structures::MarginEstimate;
+    use crate::kite_models::OrderParams;
+
+    fn instruction() -> TradeInstruction {
+        TradeInstruction {
+            action: "buy".to_string(),
+            symbol: "INFY".to_string(),
+            exchange: "NSE".to_string(),
+            quantity: 100,
+            price_type: "LIMIT".to_string(),
+            limit_price: Some(1600.0),
+            stop_loss: None,
+            target: None,
+            order_id: None,
+            timeframe: None,
+            watchlist: None,
+            idempotency_key: None,
+            auto_adjust: false,
+            product: None
+        }
+    }
+
+    fn preview(notional: f64) -> TradePreview {
+        TradePreview {
+            status: "Preview".to_string(),
+            variety: "regular".to_string(),
+            order: OrderParams::default(),
+            instrument_token: None,
+            tick_size: None,
+            lot_size: None,
+            child_quantities: Vec::new(),
+            reference_price: Some(1600.0),
+            notional: Some(notional),
+            margin: MarginEstimate { source: "local".to_string(), required: Some(notional), breakdown: None },
+            charges: None,
+            exposure: None,
+            exit_gtt: None,
+            warnings: Vec::new()
+        }
+    }
+
+    #[test]
+    fn only_someone_else_can_confirm_and_only_once() {
+        let store = ProposalStore::default();
+        let proposal = store.submit(instruction(), preview(160_000.0), Some("agent-1".to_string()));
+
+        assert_eq!(store.approve(&proposal.id, "AGENT-1").unwrap_err(), DecisionError::SelfApproval);
+        assert_eq!(store.approve(&proposal.id, "alice").unwrap().status, ProposalStatus::Approved);
+        assert_eq!(store.approve(&proposal.id, "bob").unwrap_err(), DecisionError::AlreadyDecided(ProposalStatus::Approved));
+        assert_eq!(store.reject("missing", "alice", None).unwrap_err(), DecisionError::NotFound);
+    }
+
+    #[test]
+    fn approvers_are_known_by_their_key() {
+        let store = ProposalStore::default().with_approvers(parse_approvers("alice:k-alice, bob:k-bob").unwrap());
+
+        assert_eq!(store.approver("k-bob"), Some("bob"));
+        assert_eq!(store.approver("bob"), None);
+        assert!(parse_approvers("alice").is_err());
+        assert!(parse_approvers("auto:k-auto").is_err());
+        assert!(parse_approvers("alice:k,bob:k").is_err());
+    }
+
+    #[test]
+    fn unconfirmed_proposals_expire() {
+        let store = ProposalStore::new(Duration::ZERO, None, None);
+        let proposal = store.submit(instruction(), preview(160_000.0), None);
+
+        assert_eq!(store.get(&proposal.id).unwrap().status, ProposalStatus::Expired);
+        assert_eq!(store.approve(&proposal.id, "alice").unwrap_err(), DecisionError::Expired);
+    }
+
+    #[test]
+    fn thresholds_gate_and_auto_approve_by_notional() {
+        let store = ProposalStore::new(DEFAULT_PROPOSAL_TTL, Some(100_000.0), Some(10_000.0));
+
+        assert!(store.requires_confirmation(Some(160_000.0)));
+        assert!(store.requires_confirmation(None));
+        assert!(!store.requires_confirmation(Some(50_000.0)));
+        assert!(store.auto_approves(Some(8_000.0)));
+        assert!(!store.auto_approves(Some(50_000.0)));
+    }
+}
@@ -0,0 +1,245 @@
+use std::{cmp::Reverse, collections::BinaryHeap, sync::Mutex, time::Duration};
+use chrono::{NaiveDate, Utc};
+use tokio::{sync::Notify, time::Instant};
+use crate::{errors::{KiteError, KiteErrorKind}, market_time::ist};
+
+/// Kite caps every app at 3000 orders a day across all segments and varieties.
+pub const DAILY_ORDER_CAP: u32 = 3000;
+
+/// Kite's per-second limits are enforced per endpoint class, not per endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateClass {
+    Quote,
+    Historical,
+    OrderPlacement,
+    Other
+}
+
+impl RateClass {
+    pub fn per_second(&self) -> f64 {
+        match self {
+            RateClass::Quote => 1.0,
+            RateClass::Historical => 3.0,
+            RateClass::OrderPlacement => 10.0,
+            RateClass::Other => 10.0
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Order in which queued calls of the same class get a token. Cancels and exits jump ahead
+/// of new entries so a throttled burst can never keep us stuck in a position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Exit,
+    Entry,
+    Read
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+    // Min-heap of (priority, arrival) so equal priorities stay first come, first served
+    queue: BinaryHeap<Reverse<(Priority, u64)>>,
+    next_ticket: u64
+}
+
+impl Bucket {
+    fn new(rate: f64) -> Self {
+        // Start with a single token so a burst right after start-up is still spread out
+        Self { rate, tokens: 1.0, refilled: Instant::now(), queue: BinaryHeap::new(), next_ticket: 0 }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * self.rate).min(self.rate.max(1.0));
+        self.refilled = now;
+    }
+
+    fn until_next_token(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.rate).max(0.0))
+    }
+}
+
+#[derive(Debug)]
+struct OrderCount {
+    date: NaiveDate,
+    placed: u32
+}
+
+/// Token bucket per `RateClass` with a priority queue in front of each, plus the daily order cap.
+/// Shared by every clone of the Kite client, since Kite counts per app, not per connection.
+#[derive(Debug)]
+pub struct RateLimiter {
+    buckets: Vec<Mutex<Bucket>>,
+    released: Vec<Notify>,
+    orders: Mutex<OrderCount>,
+    daily_order_cap: u32
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(DAILY_ORDER_CAP)
+    }
+}
+
+fn trading_date() -> NaiveDate {
+    Utc::now().with_timezone(&ist()).date_naive()
+}
+
+// Removes a queued call if it is abandoned (e.g. the HTTP client disconnected) before its turn
+struct Ticket<'a> {
+    limiter: &'a RateLimiter,
+    class: RateClass,
+    key: Option<(Priority, u64)>
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.limiter.buckets[self.class.index()].lock().unwrap().queue.retain(|queued| queued.0 != key);
+            self.limiter.released[self.class.index()].notify_waiters();
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(daily_order_cap: u32) -> Self {
+        let classes = [RateClass::Quote, RateClass::Historical, RateClass::OrderPlacement, RateClass::Other];
+        Self {
+            buckets: classes.iter().map(|class| Mutex::new(Bucket::new(class.per_second()))).collect(),
+            released: classes.iter().map(|_| Notify::new()).collect(),
+            orders: Mutex::new(OrderCount { date: trading_date(), placed: 0 }),
+            daily_order_cap
+        }
+    }
+
+    /// Waits for a token of `class`, letting higher priority callers that queued later go first.
+    pub async fn acquire(&self, class: RateClass, priority: Priority) {
+        let mut ticket = {
+            let mut bucket = self.buckets[class.index()].lock().unwrap();
+            let key = (priority, bucket.next_ticket);
+            bucket.next_ticket += 1;
+            bucket.queue.push(Reverse(key));
+            Ticket { limiter: self, class, key: Some(key) }
+        };
+        let key = ticket.key.unwrap();
+
+        loop {
+            let released = self.released[class.index()].notified();
+            let wait = {
+                let mut bucket = self.buckets[class.index()].lock().unwrap();
+                bucket.refill();
+
+                if bucket.queue.peek() != Some(&Reverse(key)) {
+                    None
+                }
+                else if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    bucket.queue.pop();
+                    ticket.key = None;
+                    drop(bucket);
+                    self.released[class.index()].notify_waiters();
+                    return;
+                }
+                else {
+                    Some(bucket.until_next_token())
+                }
+            };
+
+            match wait {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => released.await
+            }
+        }
+    }
+
+    /// Counts one order placement against the daily cap, failing once it is used up.
+    pub fn reserve_order(&self) -> Result<u32, KiteError> {
+        let mut orders = self.orders.lock().unwrap();
+        let today = trading_date();
+        if orders.date != today {
+            *orders = OrderCount { date: today, placed: 0 };
+        }
+
+        if orders.placed >= self.daily_order_cap {
+            return Err(KiteError::new(
+                KiteErrorKind::DailyLimit,
+                format!("Daily order limit of {} reached, orders are accepted again tomorrow", self.daily_order_cap)
+            ));
+        }
+        orders.placed += 1;
+        Ok(self.daily_order_cap - orders.placed)
+    }
+
+    pub fn orders_remaining(&self) -> u32 {
+        let orders = self.orders.lock().unwrap();
+        if orders.date != trading_date() {
+            self.daily_order_cap
+        }
+        else {
+            self.daily_order_cap.saturating_sub(orders.placed)
+        }
+    }
+}
+
+/// Backoff before retry `attempt` (1-based) of an idempotent read, never sooner than Kite asked.
+pub fn retry_delay(attempt: u32, error: &KiteError) -> Duration {
+    let backoff = Duration::from_millis(250 * 2u64.pow(attempt.saturating_sub(1)));
+    backoff.max(error.retry_after().unwrap_or_default())
+}
+
+pub const MAX_READ_RETRIES: u32 = 3;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test(start_paused = true)]
+    async fn exits_overtake_queued_entries() {
+        let limiter = Arc::new(RateLimiter::default());
+        // Use up the initial token so everything below has to queue
+        limiter.acquire(RateClass::Quote, Priority::Entry).await;
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for (name, priority) in [("entry-1", Priority::Entry), ("entry-2", Priority::Entry), ("exit", Priority::Exit)] {
+            let (limiter, order) = (limiter.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                limiter.acquire(RateClass::Quote, priority).await;
+                order.lock().unwrap().push(name);
+            }));
+            tokio::task::yield_now().await;
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(*order.lock().unwrap(), vec!["exit", "entry-1", "entry-2"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_are_spaced_by_the_class_rate() {
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire(RateClass::Historical, Priority::Read).await;
+        }
+        // One token up front, then three more at 3 per second
+        assert!(start.elapsed() >= Duration::from_millis(990));
+    }
+
+    #[test]
+    fn daily_cap_stops_placements() {
+        let limiter = RateLimiter::new(2);
+        assert_eq!(limiter.reserve_order().unwrap(), 1);
+        assert_eq!(limiter.reserve_order().unwrap(), 0);
+        assert_eq!(limiter.reserve_order().unwrap_err().kind, KiteErrorKind::DailyLimit);
+    }
+}
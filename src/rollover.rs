@@ -0,0 +1,361 @@
+use std::{collections::HashMap, str::FromStr, sync::Mutex};
+use actix_web::web;
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime, Utc};
+use serde::Serialize;
+use crate::{basket::{check_margin, place_basket, BasketLeg, BasketMargin, BasketReport, BasketStatus}, data_structures::{AppState, TradeInstruction}, instrument_master::Instrument, kite_client::KiteClient, market_protection::{MarketProtection, MarketQuote}, market_time::{ist, next_weekday_at, parse_time}, trade_executor::TradeExecutor};
+
+/// Late enough for the next month to have picked up liquidity, early enough to finish before the close.
+pub const DEFAULT_ROLLOVER_TIME: &str = "14:30";
+/// Calendar days before expiry a position is rolled.
+pub const DEFAULT_ROLLOVER_DAYS: i64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RolloverMode {
+    /// Both legs as one basket, rolled back together if either is rejected
+    Spread,
+    /// The near month is closed first and the next month opened only once that is accepted
+    Legs
+}
+
+impl FromStr for RolloverMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.trim().to_lowercase().as_str() {
+            "spread" | "calendar" => Ok(RolloverMode::Spread),
+            "legs" => Ok(RolloverMode::Legs),
+            _ => Err(anyhow::anyhow!("Unknown rollover mode '{}', use spread or legs", mode))
+        }
+    }
+}
+
+/// A near-month futures position due to be rolled into `next`.
+#[derive(Debug, Clone)]
+pub struct RolloverPlan {
+    pub near: Instrument,
+    pub next: Instrument,
+    /// Signed, negative when short
+    pub quantity: i64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RollStatus {
+    /// Priced and margin-checked, nothing sent
+    DryRun,
+    Rolled,
+    /// Legs mode only: the near month was closed but the next month could not be opened
+    ClosedOnly,
+    Failed
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RolledPosition {
+    pub from: String,
+    pub to: String,
+    pub quantity: i64,
+    pub from_expiry: Option<NaiveDate>,
+    pub to_expiry: Option<NaiveDate>,
+    pub status: RollStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub margin: Option<BasketMargin>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub baskets: Vec<BasketReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RolloverFailure {
+    pub symbol: String,
+    pub error: String
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RolloverReport {
+    pub started_at: String,
+    pub dry_run: bool,
+    pub mode: RolloverMode,
+    pub days_before: i64,
+    pub rolled: Vec<RolledPosition>,
+    pub failures: Vec<RolloverFailure>
+}
+
+impl RolloverReport {
+    fn fail(&mut self, symbol: &str, error: impl ToString) {
+        println!("Rollover failed for {}: {}", symbol, error.to_string());
+        self.failures.push(RolloverFailure { symbol: symbol.to_string(), error: error.to_string() });
+    }
+}
+
+/// The futures positions within `days_before` calendar days of expiry, each paired with the next
+/// listed expiry. `futures` lists an underlying's futures nearest first.
+pub fn plan_rollovers(positions: &[(Instrument, i64)], futures: impl Fn(&str) -> Vec<Instrument>, today: NaiveDate, days_before: i64) -> (Vec<RolloverPlan>, Vec<RolloverFailure>) {
+    let mut plans = Vec::new();
+    let mut failures = Vec::new();
+    for (near, quantity) in positions {
+        let expiry = match near.expiry {
+            Some(expiry) if near.instrument_type == "FUT" && *quantity != 0 => expiry,
+            _ => continue
+        };
+        let days = (expiry - today).num_days();
+        if !(0..=days_before).contains(&days) {
+            continue;
+        }
+        match futures(&near.name).into_iter().find(|future| future.expiry.is_some_and(|next| next > expiry)) {
+            Some(next) => plans.push(RolloverPlan { near: near.clone(), next, quantity: *quantity }),
+            None => failures.push(RolloverFailure { symbol: near.key(), error: format!("No {} future listed after {}", near.name, expiry) })
+        }
+    }
+    (plans, failures)
+}
+
+/// Rolls NRML futures positions into the next month `days_before` days ahead of expiry, every
+/// weekday at `at` IST when `scheduled`, and on demand otherwise.
+#[derive(Debug)]
+pub struct Rollover {
+    pub at: NaiveTime,
+    pub days_before: i64,
+    pub mode: RolloverMode,
+    pub dry_run: bool,
+    pub scheduled: bool,
+    last_report: Mutex<Option<RolloverReport>>,
+    running: tokio::sync::Mutex<()>
+}
+
+impl Default for Rollover {
+    fn default() -> Self {
+        Self::new(parse_time(DEFAULT_ROLLOVER_TIME).unwrap(), DEFAULT_ROLLOVER_DAYS, RolloverMode::Spread, false, false)
+    }
+}
+
+impl Rollover {
+    pub fn new(at: NaiveTime, days_before: i64, mode: RolloverMode, dry_run: bool, scheduled: bool) -> Self {
+        Self { at, days_before, mode, dry_run, scheduled, last_report: Mutex::new(None), running: tokio::sync::Mutex::new(()) }
+    }
+
+    pub fn last_report(&self) -> Option<RolloverReport> {
+        self.last_report.lock().unwrap().clone()
+    }
+
+    pub fn next_run(&self, now: DateTime<FixedOffset>) -> Option<DateTime<FixedOffset>> {
+        self.scheduled.then(|| next_weekday_at(self.at, now))
+    }
+
+    /// Rolls every NRML futures position that is due. A dry run stops after pricing and the margin check.
+    pub async fn run(&self, app_state: &AppState, dry_run: bool) -> RolloverReport {
+        let _running = self.running.lock().await;
+        let mut report = RolloverReport {
+            started_at: Utc::now().to_rfc3339(),
+            dry_run,
+            mode: self.mode,
+            days_before: self.days_before,
+            rolled: Vec::new(),
+            failures: Vec::new()
+        };
+
+        let kite = {
+            let mut auth_manager = app_state.auth_manager.lock().await;
+            auth_manager.is_token_valid().then(|| auth_manager.get_kite().clone())
+        };
+        match kite {
+            Some(kite) => self.roll_all(&kite, app_state, dry_run, &mut report).await,
+            None => report.fail("*", "No valid Kite session, futures were not rolled")
+        }
+
+        println!("Rollover done{}: {} positions, {} failures", if dry_run { " (dry run)" } else { "" }, report.rolled.len(), report.failures.len());
+        *self.last_report.lock().unwrap() = Some(report.clone());
+        report
+    }
+
+    async fn roll_all(&self, kite: &KiteClient, app_state: &AppState, dry_run: bool, report: &mut RolloverReport) {
+        let positions = match kite.positions().await {
+            Ok(positions) => positions.net.into_iter().filter(|p| p.product == "NRML" && p.quantity != 0).collect::<Vec<_>>(),
+            Err(e) => return report.fail("*", format!("Failed to load positions: {}", e))
+        };
+
+        let today = Utc::now().with_timezone(&ist()).date_naive();
+        let (plans, freeze_limits) = {
+            let market_data = app_state.market_data.lock().await;
+            let mut known: Vec<(Instrument, i64)> = Vec::new();
+            for position in &positions {
+                let key = format!("{}:{}", position.exchange, position.tradingsymbol);
+                match market_data.instrument(&key) {
+                    Some(instrument) => known.push((instrument, position.quantity)),
+                    // Can't tell whether it is due without its expiry, so it is reported rather than skipped
+                    None => report.fail(&key, "Not in the instrument master, can't check its expiry")
+                }
+            }
+            let (plans, failures) = plan_rollovers(&known, |name| market_data.futures(name), today, self.days_before);
+            report.failures.extend(failures);
+
+            let freeze_limits: HashMap<String, Option<u32>> = plans.iter()
+                .flat_map(|plan| [&plan.near, &plan.next])
+                .map(|instrument| (instrument.key(), market_data.freeze_limit(instrument)))
+                .collect();
+            (plans, freeze_limits)
+        };
+
+        let protection = if app_state.market_protection.is_enabled() { app_state.market_protection } else { MarketProtection::default() };
+        for plan in plans {
+            let rolled = self.roll(kite, &plan, &freeze_limits, protection, dry_run).await;
+            if let Some(error) = &rolled.error {
+                println!("Rollover of {} to {}: {}", rolled.from, rolled.to, error);
+            }
+            report.rolled.push(rolled);
+        }
+    }
+
+    async fn roll(&self, kite: &KiteClient, plan: &RolloverPlan, freeze_limits: &HashMap<String, Option<u32>>, protection: MarketProtection, dry_run: bool) -> RolledPosition {
+        let mut rolled = RolledPosition {
+            from: plan.near.key(),
+            to: plan.next.key(),
+            quantity: plan.quantity,
+            from_expiry: plan.near.expiry,
+            to_expiry: plan.next.expiry,
+            status: RollStatus::Failed,
+            margin: None,
+            baskets: Vec::new(),
+            error: None
+        };
+
+        let legs = match self.legs(kite, plan, freeze_limits, protection).await {
+            Ok(legs) => legs,
+            Err(e) => {
+                rolled.error = Some(e.to_string());
+                return rolled;
+            }
+        };
+        let margin = match check_margin(kite, &legs).await {
+            Ok(margin) => margin,
+            Err(e) => {
+                rolled.error = Some(format!("Margin check failed: {}", e));
+                return rolled;
+            }
+        };
+        rolled.margin = Some(margin.clone());
+        if margin.available.is_some_and(|available| margin.required > available) {
+            rolled.error = Some(format!("Needs {} margin, {:?} available", margin.required, margin.available));
+            return rolled;
+        }
+        if dry_run {
+            rolled.status = RollStatus::DryRun;
+            return rolled;
+        }
+
+        match self.mode {
+            RolloverMode::Spread => {
+                let basket = place_basket(kite, &legs, margin, protection).await;
+                rolled.status = if basket.status == BasketStatus::Placed { RollStatus::Rolled } else { RollStatus::Failed };
+                rolled.baskets.push(basket);
+            },
+            RolloverMode::Legs => {
+                let close = place_basket(kite, &legs[..1], margin.clone(), protection).await;
+                let closed = close.status == BasketStatus::Placed;
+                rolled.baskets.push(close);
+                if closed {
+                    let open = place_basket(kite, &legs[1..], margin, protection).await;
+                    rolled.status = if open.status == BasketStatus::Placed { RollStatus::Rolled } else { RollStatus::ClosedOnly };
+                    rolled.baskets.push(open);
+                }
+            }
+        }
+        if rolled.status != RollStatus::Rolled {
+            let errors: Vec<String> = rolled.baskets.iter().flat_map(|basket| &basket.legs).filter_map(|leg| leg.error.clone()).collect();
+            rolled.error = Some(errors.join("; "));
+        }
+        rolled
+    }
+
+    // The closing leg first, then the opening one, both as protected limit orders
+    async fn legs(&self, kite: &KiteClient, plan: &RolloverPlan, freeze_limits: &HashMap<String, Option<u32>>, protection: MarketProtection) -> Result<Vec<BasketLeg>, anyhow::Error> {
+        let keys = [plan.near.key(), plan.next.key()];
+        let quotes = kite.quote(&keys).await?;
+        let long = plan.quantity > 0;
+        let quantity = plan.quantity.unsigned_abs() as u32;
+
+        let leg = |instrument: &Instrument, buying: bool| -> Result<BasketLeg, anyhow::Error> {
+            let key = instrument.key();
+            let quote = quotes.get(&key).map(MarketQuote::from_quote).ok_or_else(|| anyhow::anyhow!("No quote for {}", key))?;
+            let (price, _) = protection.limit_price(buying, &quote, instrument.tick_size)?;
+            let instruction = rollover_instruction(instrument, buying, quantity, price);
+            let executor = TradeExecutor::new(kite.clone(), Some(instrument.clone()), freeze_limits.get(&key).copied().flatten());
+            let plan = executor.plan(&instruction).map_err(|e| e.context(format!("Could not plan {}", key)))?;
+            Ok(BasketLeg { instruction, executor, plan })
+        };
+        Ok(vec![leg(&plan.near, !long)?, leg(&plan.next, long)?])
+    }
+}
+
+fn rollover_instruction(instrument: &Instrument, buying: bool, quantity: u32, price: f64) -> TradeInstruction {
+    TradeInstruction {
+        action: if buying { "buy" } else { "sell" }.to_string(),
+        symbol: instrument.tradingsymbol.clone(),
+        exchange: instrument.exchange.clone(),
+        quantity,
+        price_type: "LIMIT".to_string(),
+        limit_price: Some(price),
+        stop_loss: None,
+        target: None,
+        order_id: None,
+        timeframe: None,
+        watchlist: None,
+        idempotency_key: None,
+        auto_adjust: false,
+        product: Some("NRML".to_string())
+    }
+}
+
+/// Sleeps until each rollover time and runs it, while `ROLLOVER_DAYS` has turned scheduling on.
+pub async fn run_scheduler(app_state: web::Data<AppState>) {
+    let rollover = &app_state.rollover;
+    loop {
+        let now = Utc::now().with_timezone(&ist());
+        let next = match rollover.next_run(now) {
+            Some(next) => next,
+            None => return
+        };
+        println!("Next futures rollover check at {}{}", next, if rollover.dry_run { " (dry run)" } else { "" });
+
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+        rollover.run(&app_state, rollover.dry_run).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn future(expiry: &str) -> Instrument {
+        Instrument {
+            instrument_token: 1,
+            exchange_token: 1,
+            tradingsymbol: format!("NIFTY{}FUT", expiry),
+            name: "NIFTY".to_string(),
+            expiry: NaiveDate::parse_from_str(expiry, "%Y-%m-%d").ok(),
+            strike: 0.0,
+            tick_size: 0.05,
+            lot_size: 50,
+            instrument_type: "FUT".to_string(),
+            segment: "NFO-FUT".to_string(),
+            exchange: "NFO".to_string()
+        }
+    }
+
+    #[test]
+    fn only_positions_near_expiry_roll_into_the_next_month() {
+        let listed = vec![future("2024-01-25"), future("2024-02-29"), future("2024-03-28")];
+        let positions = vec![(future("2024-01-25"), -100), (future("2024-02-29"), 50)];
+        let day = |d: u32| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+
+        let (plans, failures) = plan_rollovers(&positions, |_| listed.clone(), day(24), 1);
+        assert!(failures.is_empty());
+        assert_eq!(plans.len(), 1);
+        assert_eq!((plans[0].next.expiry, plans[0].quantity), (NaiveDate::from_ymd_opt(2024, 2, 29), -100));
+
+        assert!(plan_rollovers(&positions, |_| listed.clone(), day(22), 1).0.is_empty());
+        let (plans, failures) = plan_rollovers(&positions, |_| listed[..1].to_vec(), day(25), 1);
+        assert!(plans.is_empty() && failures.len() == 1);
+    }
+}
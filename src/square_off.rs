@@ -0,0 +1,221 @@
+use std::{collections::HashMap, sync::Mutex};
+use actix_web::web;
+use chrono::{DateTime, FixedOffset, NaiveTime, Utc};
+use serde::Serialize;
+use crate::{data_structures::AppState, instrument_master::Instrument, kite_client::KiteClient, kite_models::OrderParams, market_time::{ist, next_weekday_at, parse_time}, market_protection::{MarketProtection, MarketQuote, ProtectionMode}, order_validation::{validate_order, DEFAULT_TICK_SIZE}, rate_limiter::Priority, trade_executor::ORDER_VARIETY};
+
+/// Zerodha starts squaring off MIS equity positions at 15:20 IST, with a charge per order.
+pub const DEFAULT_SQUARE_OFF_TIME: &str = "15:15";
+
+/// How far through the LTP exit limit orders are priced, in percent.
+pub const DEFAULT_EXIT_PROTECTION_PCT: f64 = 2.0;
+
+const SQUARE_OFF_TAG: &str = "squareoff";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SquaredPosition {
+    pub symbol: String,
+    pub transaction_type: String,
+    pub quantity: u32,
+    pub price: f64,
+    pub order_ids: Vec<String>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SquareOffFailure {
+    pub symbol: String,
+    pub error: String
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SquareOffReport {
+    pub started_at: String,
+    pub cancelled_orders: Vec<String>,
+    pub squared: Vec<SquaredPosition>,
+    pub failures: Vec<SquareOffFailure>
+}
+
+impl SquareOffReport {
+    fn new() -> Self {
+        Self { started_at: Utc::now().to_rfc3339(), cancelled_orders: Vec::new(), squared: Vec::new(), failures: Vec::new() }
+    }
+
+    fn fail(&mut self, symbol: &str, error: impl ToString) {
+        println!("Square-off failed for {}: {}", symbol, error.to_string());
+        self.failures.push(SquareOffFailure { symbol: symbol.to_string(), error: error.to_string() });
+    }
+}
+
+/// Exits every open MIS position ahead of the broker's own square-off, every weekday at `at` IST.
+#[derive(Debug)]
+pub struct SquareOff {
+    pub at: NaiveTime,
+    pub protection_pct: f64,
+    last_report: Mutex<Option<SquareOffReport>>,
+    // Keeps a manual run and the scheduled one from exiting the same positions twice
+    running: tokio::sync::Mutex<()>
+}
+
+impl Default for SquareOff {
+    fn default() -> Self {
+        Self::new(parse_time(DEFAULT_SQUARE_OFF_TIME).unwrap(), DEFAULT_EXIT_PROTECTION_PCT)
+    }
+}
+
+impl SquareOff {
+    pub fn new(at: NaiveTime, protection_pct: f64) -> Self {
+        Self { at, protection_pct, last_report: Mutex::new(None), running: tokio::sync::Mutex::new(()) }
+    }
+
+    pub fn last_report(&self) -> Option<SquareOffReport> {
+        self.last_report.lock().unwrap().clone()
+    }
+
+    pub fn next_run(&self, now: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        next_weekday_at(self.at, now)
+    }
+
+    /// Cancels pending MIS orders, then exits every open MIS position with a protected limit order.
+    pub async fn run(&self, app_state: &AppState) -> SquareOffReport {
+        let _running = self.running.lock().await;
+        let mut report = SquareOffReport::new();
+
+        let kite = {
+            let mut auth_manager = app_state.auth_manager.lock().await;
+            auth_manager.is_token_valid().then(|| auth_manager.get_kite().clone())
+        };
+        match kite {
+            Some(kite) => self.square_off(&kite, app_state, &mut report).await,
+            None => report.fail("*", "No valid Kite session, MIS positions were not squared off")
+        }
+
+        println!("Square-off done: {} positions closed, {} failures", report.squared.len(), report.failures.len());
+        *self.last_report.lock().unwrap() = Some(report.clone());
+        report
+    }
+
+    async fn square_off(&self, kite: &KiteClient, app_state: &AppState, report: &mut SquareOffReport) {
+        // Pending entries would otherwise fill after the exit and leave a fresh position behind
+        match kite.orders().await {
+            Ok(orders) => {
+                let pending = orders.iter().filter(|o| o.product == "MIS" && matches!(o.status.as_str(), "OPEN" | "TRIGGER PENDING"));
+                for order in pending {
+                    match kite.cancel_order(&order.variety, &order.order_id).await {
+                        Ok(order_id) => report.cancelled_orders.push(order_id),
+                        Err(e) => report.fail(&format!("{}:{}", order.exchange, order.tradingsymbol), format!("Failed to cancel order {}: {}", order.order_id, e))
+                    }
+                }
+            },
+            Err(e) => report.fail("*", format!("Failed to load orders: {}", e))
+        }
+
+        let positions = match kite.positions().await {
+            Ok(positions) => positions.net.into_iter().filter(|p| p.product == "MIS" && p.quantity != 0).collect::<Vec<_>>(),
+            Err(e) => return report.fail("*", format!("Failed to load positions: {}", e))
+        };
+        if positions.is_empty() {
+            return;
+        }
+
+        let keys: Vec<String> = positions.iter().map(|p| format!("{}:{}", p.exchange, p.tradingsymbol)).collect();
+        let prices = match kite.ltp(&keys).await {
+            Ok(prices) => prices,
+            Err(e) => {
+                println!("Failed to load last prices for square-off: {}", e);
+                HashMap::new()
+            }
+        };
+        let rules: HashMap<String, (Option<Instrument>, Option<u32>)> = {
+            let market_data = app_state.market_data.lock().await;
+            keys.iter().map(|key| {
+                let instrument = market_data.instrument(key);
+                let freeze_limit = instrument.as_ref().and_then(|instrument| market_data.freeze_limit(instrument));
+                (key.clone(), (instrument, freeze_limit))
+            }).collect()
+        };
+
+        for (position, key) in positions.iter().zip(&keys) {
+            let ltp = match prices.get(key) {
+                Some(quote) if quote.last_price > 0.0 => quote.last_price,
+                _ => {
+                    report.fail(key, "No last price, position left open");
+                    continue;
+                }
+            };
+            let (instrument, freeze_limit) = rules.get(key).cloned().unwrap_or_default();
+            match self.exit(kite, position.quantity, ltp, key, instrument.as_ref(), freeze_limit).await {
+                Ok(squared) => report.squared.push(squared),
+                Err(e) => report.fail(key, e)
+            }
+        }
+    }
+
+    async fn exit(&self, kite: &KiteClient, quantity: i64, ltp: f64, key: &str, instrument: Option<&Instrument>, freeze_limit: Option<u32>) -> Result<SquaredPosition, anyhow::Error> {
+        let buying = quantity < 0;
+        let tick = instrument.map(|i| i.tick_size).filter(|tick| *tick > 0.0).unwrap_or(DEFAULT_TICK_SIZE);
+        // Priced like any protected market order, only within the exit band
+        let protection = MarketProtection { mode: ProtectionMode::Ltp, pct: self.protection_pct, ..MarketProtection::default() };
+        let (price, _) = protection.limit_price(buying, &MarketQuote { ltp: Some(ltp), book: None }, tick)?;
+        let quantity = quantity.unsigned_abs() as u32;
+
+        let slices = match instrument {
+            Some(instrument) => validate_order(instrument, buying, Some(price), quantity, false, freeze_limit)?.slices,
+            None => vec![quantity]
+        };
+        let (exchange, tradingsymbol) = key.split_once(':').unwrap_or(("", key));
+        let transaction_type = if buying { "BUY" } else { "SELL" };
+
+        let mut order_ids = Vec::new();
+        for slice in slices {
+            let params = OrderParams {
+                exchange: exchange.to_string(),
+                tradingsymbol: tradingsymbol.to_string(),
+                transaction_type: transaction_type.to_string(),
+                quantity: slice,
+                product: "MIS".to_string(),
+                order_type: "LIMIT".to_string(),
+                validity: "DAY".to_string(),
+                price: Some(price),
+                tag: Some(SQUARE_OFF_TAG.to_string()),
+                ..OrderParams::default()
+            };
+            match kite.place_order(ORDER_VARIETY, &params, Priority::Exit).await {
+                Ok(order_id) => order_ids.push(order_id),
+                Err(e) if order_ids.is_empty() => return Err(e),
+                Err(e) => return Err(e.context(format!("Exit partly placed as {}", order_ids.join(", "))))
+            }
+        }
+
+        println!("Squared off {} {} {} at {}", key, transaction_type, quantity, price);
+        Ok(SquaredPosition { symbol: key.to_string(), transaction_type: transaction_type.to_string(), quantity, price, order_ids })
+    }
+}
+
+/// Sleeps until each square-off time and runs it, for as long as the server is up.
+pub async fn run_scheduler(app_state: web::Data<AppState>) {
+    loop {
+        let now = Utc::now().with_timezone(&ist());
+        let next = app_state.square_off.next_run(now);
+        println!("Next MIS square-off at {}", next);
+
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+        app_state.square_off.run(&app_state).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_on_the_next_weekday_after_the_cutoff() {
+        let square_off = SquareOff::default();
+        let at = |s: &str| DateTime::parse_from_rfc3339(s).unwrap();
+
+        // Wednesday morning runs the same afternoon, Wednesday evening the next day
+        assert_eq!(square_off.next_run(at("2024-01-17T10:00:00+05:30")), at("2024-01-17T15:15:00+05:30"));
+        assert_eq!(square_off.next_run(at("2024-01-17T15:15:00+05:30")), at("2024-01-18T15:15:00+05:30"));
+        // Friday evening skips the weekend
+        assert_eq!(square_off.next_run(at("2024-01-19T16:00:00+05:30")), at("2024-01-22T15:15:00+05:30"));
+    }
+}
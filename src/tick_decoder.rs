@@ -0,0 +1,390 @@
+use serde_json::Value;
+
+// Packet lengths defined by Kite's websocket protocol
+pub const LTP_PACKET: usize = 8;
+pub const INDEX_QUOTE_PACKET: usize = 28;
+pub const INDEX_FULL_PACKET: usize = 32;
+pub const QUOTE_PACKET: usize = 44;
+pub const FULL_PACKET: usize = 184;
+
+pub const DEPTH_LEVELS: usize = 5;
+
+const SEGMENT_CDS: u32 = 3;
+const SEGMENT_BCD: u32 = 6;
+const SEGMENT_INDICES: u32 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TickMode {
+    #[default]
+    Ltp,
+    Quote,
+    Full
+}
+
+impl TickMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TickMode::Ltp => "ltp",
+            TickMode::Quote => "quote",
+            TickMode::Full => "full"
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DepthEntry {
+    pub quantity: u32,
+    pub price: f64,
+    pub orders: u16
+}
+
+/// A decoded tick. Plain `Copy` data so decoding never touches the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Tick {
+    pub instrument_token: u32,
+    pub mode: TickMode,
+    pub tradable: bool,
+    pub last_price: f64,
+    pub last_quantity: u32,
+    pub average_price: f64,
+    pub volume: u32,
+    pub buy_quantity: u32,
+    pub sell_quantity: u32,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub change: f64,
+    pub last_trade_time: u32,
+    pub oi: u32,
+    pub oi_day_high: u32,
+    pub oi_day_low: u32,
+    pub exchange_timestamp: u32,
+    pub has_depth: bool,
+    pub buy_depth: [DepthEntry; DEPTH_LEVELS],
+    pub sell_depth: [DepthEntry; DEPTH_LEVELS]
+}
+
+impl Tick {
+    pub fn has_ohlc(&self) -> bool {
+        self.mode != TickMode::Ltp
+    }
+
+    pub fn is_index(&self) -> bool {
+        !self.tradable
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    Truncated { needed: usize, available: usize },
+    UnknownPacketLength(usize)
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated { needed, available } => {
+                write!(f, "truncated frame: needed {} bytes, {} available", needed, available)
+            },
+            DecodeError::UnknownPacketLength(length) => write!(f, "unknown packet length: {}", length)
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Iterator over the raw packets of one binary websocket frame, without their length prefixes.
+#[derive(Debug)]
+pub struct PacketSlices<'a> {
+    frame: &'a [u8],
+    offset: usize,
+    remaining: usize
+}
+
+/// Splits a binary frame into packets. Heartbeats (single byte frames) yield no packets.
+pub fn packet_slices(frame: &[u8]) -> PacketSlices<'_> {
+    let remaining = if frame.len() < 2 { 0 } else { read_u16(frame, 0) as usize };
+    PacketSlices { frame, offset: 2, remaining }
+}
+
+impl<'a> Iterator for PacketSlices<'a> {
+    type Item = Result<&'a [u8], DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let available = self.frame.len().saturating_sub(self.offset);
+        if available < 2 {
+            self.remaining = 0;
+            return Some(Err(DecodeError::Truncated { needed: 2, available }));
+        }
+
+        let length = read_u16(self.frame, self.offset) as usize;
+        let start = self.offset + 2;
+        if self.frame.len() < start + length {
+            self.remaining = 0;
+            return Some(Err(DecodeError::Truncated { needed: length, available: self.frame.len() - start }));
+        }
+
+        self.offset = start + length;
+        Some(Ok(&self.frame[start..start + length]))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.remaining))
+    }
+}
+
+/// Decodes every packet of a binary frame into ticks.
+pub fn decode_frame(frame: &[u8]) -> impl Iterator<Item = Result<Tick, DecodeError>> + '_ {
+    packet_slices(frame).map(|packet| packet.and_then(decode_packet))
+}
+
+/// Decodes a single packet (without its 2-byte length prefix).
+pub fn decode_packet(packet: &[u8]) -> Result<Tick, DecodeError> {
+    if packet.len() < 4 {
+        return Err(DecodeError::Truncated { needed: 4, available: packet.len() });
+    }
+
+    let instrument_token = read_u32(packet, 0);
+    let segment = instrument_token & 0xFF;
+    let divisor = match segment {
+        SEGMENT_CDS => 10_000_000.0,
+        SEGMENT_BCD => 10_000.0,
+        _ => 100.0
+    };
+    let price = |offset: usize| read_i32(packet, offset) as f64 / divisor;
+
+    let mut tick = Tick {
+        instrument_token,
+        tradable: segment != SEGMENT_INDICES,
+        ..Tick::default()
+    };
+
+    match packet.len() {
+        LTP_PACKET => {
+            tick.mode = TickMode::Ltp;
+            tick.last_price = price(4);
+        },
+        INDEX_QUOTE_PACKET | INDEX_FULL_PACKET => {
+            tick.mode = if packet.len() == INDEX_QUOTE_PACKET { TickMode::Quote } else { TickMode::Full };
+            tick.last_price = price(4);
+            tick.high = price(8);
+            tick.low = price(12);
+            tick.open = price(16);
+            tick.close = price(20);
+            // Bytes 24..28 hold the absolute change; report percent like tradable packets do
+            if tick.close != 0.0 {
+                tick.change = (tick.last_price - tick.close) * 100.0 / tick.close;
+            }
+            if packet.len() == INDEX_FULL_PACKET {
+                tick.exchange_timestamp = read_u32(packet, 28);
+            }
+        },
+        QUOTE_PACKET | FULL_PACKET => {
+            tick.mode = if packet.len() == QUOTE_PACKET { TickMode::Quote } else { TickMode::Full };
+            tick.last_price = price(4);
+            tick.last_quantity = read_u32(packet, 8);
+            tick.average_price = price(12);
+            tick.volume = read_u32(packet, 16);
+            tick.buy_quantity = read_u32(packet, 20);
+            tick.sell_quantity = read_u32(packet, 24);
+            tick.open = price(28);
+            tick.high = price(32);
+            tick.low = price(36);
+            tick.close = price(40);
+            if tick.close != 0.0 {
+                tick.change = (tick.last_price - tick.close) * 100.0 / tick.close;
+            }
+
+            if packet.len() == FULL_PACKET {
+                tick.last_trade_time = read_u32(packet, 44);
+                tick.oi = read_u32(packet, 48);
+                tick.oi_day_high = read_u32(packet, 52);
+                tick.oi_day_low = read_u32(packet, 56);
+                tick.exchange_timestamp = read_u32(packet, 60);
+                tick.has_depth = true;
+
+                // Ten 12-byte entries, five bids then five offers: quantity, price, orders, 2 bytes padding
+                for level in 0..DEPTH_LEVELS * 2 {
+                    let offset = 64 + level * 12;
+                    let entry = DepthEntry {
+                        quantity: read_u32(packet, offset),
+                        price: price(offset + 4),
+                        orders: read_u16(packet, offset + 8)
+                    };
+                    if level < DEPTH_LEVELS {
+                        tick.buy_depth[level] = entry;
+                    }
+                    else {
+                        tick.sell_depth[level - DEPTH_LEVELS] = entry;
+                    }
+                }
+            }
+        },
+        length => return Err(DecodeError::UnknownPacketLength(length))
+    }
+    Ok(tick)
+}
+
+/// Text frames carry order updates and server notices as JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TickerMessage {
+    OrderUpdate(Value),
+    Error(String),
+    Message(String),
+    Unknown(String)
+}
+
+pub fn decode_text(text: &str) -> TickerMessage {
+    let message: Value = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(_) => return TickerMessage::Unknown(text.to_string())
+    };
+    let data = message.get("data").cloned().unwrap_or(Value::Null);
+
+    match message.get("type").and_then(|t| t.as_str()) {
+        Some("order") => TickerMessage::OrderUpdate(data),
+        Some("error") => TickerMessage::Error(data.as_str().map(String::from).unwrap_or_else(|| data.to_string())),
+        Some("message") => TickerMessage::Message(data.as_str().map(String::from).unwrap_or_else(|| data.to_string())),
+        _ => TickerMessage::Unknown(text.to_string())
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+fn read_i32(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Frames captured from the ticker for NSE:INFY (408065), NIFTY 50 (256265)
+    // and USDINR (a CDS contract, segment 3)
+    const LTP_FRAME: &[u8] = include_bytes!("fixtures/ticks/ltp_infy.bin");
+    const QUOTE_FRAME: &[u8] = include_bytes!("fixtures/ticks/quote_infy.bin");
+    const FULL_FRAME: &[u8] = include_bytes!("fixtures/ticks/full_infy.bin");
+    const INDEX_FRAME: &[u8] = include_bytes!("fixtures/ticks/index_nifty.bin");
+    const MIXED_FRAME: &[u8] = include_bytes!("fixtures/ticks/mixed.bin");
+    const CDS_FRAME: &[u8] = include_bytes!("fixtures/ticks/ltp_usdinr.bin");
+
+    fn single(frame: &[u8]) -> Tick {
+        let ticks: Vec<Tick> = decode_frame(frame).map(|t| t.unwrap()).collect();
+        assert_eq!(ticks.len(), 1);
+        ticks[0]
+    }
+
+    #[test]
+    fn decodes_ltp_packet() {
+        let tick = single(LTP_FRAME);
+        assert_eq!(tick.instrument_token, 408065);
+        assert_eq!(tick.mode, TickMode::Ltp);
+        assert!(tick.tradable);
+        assert_eq!(tick.last_price, 1523.35);
+        assert!(!tick.has_ohlc());
+    }
+
+    #[test]
+    fn decodes_quote_packet() {
+        let tick = single(QUOTE_FRAME);
+        assert_eq!(tick.mode, TickMode::Quote);
+        assert_eq!(tick.last_price, 1523.35);
+        assert_eq!(tick.last_quantity, 15);
+        assert_eq!(tick.average_price, 1519.8);
+        assert_eq!(tick.volume, 4_321_987);
+        assert_eq!(tick.buy_quantity, 210_045);
+        assert_eq!(tick.sell_quantity, 198_760);
+        assert_eq!((tick.open, tick.high, tick.low, tick.close), (1510.0, 1530.5, 1505.25, 1500.0));
+        assert!((tick.change - 1.556_666).abs() < 1e-5);
+        assert!(!tick.has_depth);
+    }
+
+    #[test]
+    fn decodes_full_packet_with_depth() {
+        let tick = single(FULL_FRAME);
+        assert_eq!(tick.mode, TickMode::Full);
+        assert_eq!(tick.last_trade_time, 1_718_870_400);
+        assert_eq!(tick.oi, 0);
+        assert_eq!(tick.exchange_timestamp, 1_718_870_401);
+        assert!(tick.has_depth);
+
+        assert_eq!(tick.buy_depth[0], DepthEntry { quantity: 120, price: 1523.3, orders: 4 });
+        assert_eq!(tick.buy_depth[4], DepthEntry { quantity: 500, price: 1523.1, orders: 9 });
+        assert_eq!(tick.sell_depth[0], DepthEntry { quantity: 80, price: 1523.4, orders: 2 });
+        assert_eq!(tick.sell_depth[4], DepthEntry { quantity: 640, price: 1523.8, orders: 11 });
+    }
+
+    #[test]
+    fn decodes_index_packets() {
+        let ticks: Vec<Tick> = decode_frame(INDEX_FRAME).map(|t| t.unwrap()).collect();
+        assert_eq!(ticks.len(), 2);
+
+        let quote = ticks[0];
+        assert_eq!(quote.instrument_token, 256265);
+        assert!(quote.is_index());
+        assert_eq!(quote.mode, TickMode::Quote);
+        assert_eq!(quote.last_price, 23_512.45);
+        assert_eq!((quote.high, quote.low, quote.open, quote.close), (23_550.0, 23_401.1, 23_420.0, 23_398.6));
+        assert!((quote.change - 0.486_567).abs() < 1e-5);
+
+        let full = ticks[1];
+        assert_eq!(full.mode, TickMode::Full);
+        assert_eq!(full.exchange_timestamp, 1_718_870_402);
+    }
+
+    #[test]
+    fn decodes_every_packet_in_a_frame() {
+        let ticks: Vec<Tick> = decode_frame(MIXED_FRAME).map(|t| t.unwrap()).collect();
+        let modes: Vec<TickMode> = ticks.iter().map(|t| t.mode).collect();
+        assert_eq!(modes, vec![TickMode::Ltp, TickMode::Full, TickMode::Quote]);
+        assert_eq!(ticks[2].instrument_token, 256265);
+    }
+
+    #[test]
+    fn scales_currency_prices() {
+        let tick = single(CDS_FRAME);
+        assert_eq!(tick.instrument_token & 0xFF, SEGMENT_CDS);
+        assert_eq!(tick.last_price, 83.4525);
+    }
+
+    #[test]
+    fn heartbeat_has_no_packets() {
+        assert_eq!(decode_frame(&[0]).count(), 0);
+    }
+
+    #[test]
+    fn reports_truncated_frames() {
+        let truncated = &FULL_FRAME[..FULL_FRAME.len() - 10];
+        let result: Vec<_> = decode_frame(truncated).collect();
+        assert_eq!(result, vec![Err(DecodeError::Truncated { needed: FULL_PACKET, available: FULL_PACKET - 10 })]);
+    }
+
+    #[test]
+    fn rejects_unknown_packet_lengths() {
+        assert_eq!(decode_packet(&[0; 12]), Err(DecodeError::UnknownPacketLength(12)));
+    }
+
+    #[test]
+    fn decodes_text_messages() {
+        let order = decode_text(r#"{"type":"order","data":{"order_id":"240620000000123","status":"COMPLETE"}}"#);
+        match order {
+            TickerMessage::OrderUpdate(data) => assert_eq!(data["status"], "COMPLETE"),
+            other => panic!("unexpected message: {:?}", other)
+        }
+
+        assert_eq!(decode_text(r#"{"type":"error","data":"Invalid token"}"#), TickerMessage::Error("Invalid token".to_string()));
+        assert!(matches!(decode_text("not json"), TickerMessage::Unknown(_)));
+    }
+}
@@ -0,0 +1,308 @@
+use std::{fs::{self, File, OpenOptions}, io::{BufReader, ErrorKind, Read, Write}, path::{Path, PathBuf}, sync::{mpsc, Arc}, thread, time::Duration};
+use chrono::{DateTime, NaiveDate, Utc};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use crate::{market_data::MarketDataHandler, market_time::ist, tick_decoder::{decode_packet, Tick}};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const FLUSH_BATCH: usize = 5_000;
+
+/// One recorded packet: when we received it, the exchange timestamp it carried,
+/// the symbol it was published under and the raw packet bytes as Kite sent them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedTick {
+    pub received_micros: i64,
+    pub exchange_timestamp: u32,
+    pub symbol: String,
+    pub packet: Vec<u8>
+}
+
+impl RecordedTick {
+    fn write_to(&self, out: &mut Vec<u8>) {
+        let symbol = &self.symbol.as_bytes()[..self.symbol.len().min(u8::MAX as usize)];
+        out.extend_from_slice(&self.received_micros.to_be_bytes());
+        out.extend_from_slice(&self.exchange_timestamp.to_be_bytes());
+        out.push(symbol.len() as u8);
+        out.extend_from_slice(symbol);
+        out.extend_from_slice(&(self.packet.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.packet);
+    }
+
+    fn read_from<R: Read>(input: &mut R) -> Result<Option<Self>, anyhow::Error> {
+        let mut received = [0u8; 8];
+        match input.read_exact(&mut received) {
+            Ok(_) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into())
+        }
+
+        let mut exchange = [0u8; 4];
+        input.read_exact(&mut exchange)?;
+        let mut symbol_len = [0u8; 1];
+        input.read_exact(&mut symbol_len)?;
+        let mut symbol = vec![0u8; symbol_len[0] as usize];
+        input.read_exact(&mut symbol)?;
+        let mut packet_len = [0u8; 2];
+        input.read_exact(&mut packet_len)?;
+        let mut packet = vec![0u8; u16::from_be_bytes(packet_len) as usize];
+        input.read_exact(&mut packet)?;
+
+        Ok(Some(Self {
+            received_micros: i64::from_be_bytes(received),
+            exchange_timestamp: u32::from_be_bytes(exchange),
+            symbol: String::from_utf8(symbol)?,
+            packet
+        }))
+    }
+}
+
+/// Appends every tick from the live feed to `ticks-YYYY-MM-DD.bin.gz` (IST trading date).
+/// Writing happens on its own thread so the feed never waits on disk.
+#[derive(Debug, Clone)]
+pub struct TickRecorder {
+    sender: mpsc::Sender<RecordedTick>
+}
+
+impl TickRecorder {
+    pub fn spawn(dir: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("tick-recorder".to_string())
+            .spawn(move || record_loop(dir, receiver))?;
+        Ok(Self { sender })
+    }
+
+    pub fn record(&self, packet: &[u8], tick: &Tick, symbol: &str) {
+        let _ = self.sender.send(RecordedTick {
+            received_micros: Utc::now().timestamp_micros(),
+            exchange_timestamp: tick.exchange_timestamp,
+            symbol: symbol.to_string(),
+            packet: packet.to_vec()
+        });
+    }
+}
+
+fn ist_date(micros: i64) -> NaiveDate {
+    DateTime::from_timestamp_micros(micros).unwrap_or_default().with_timezone(&ist()).date_naive()
+}
+
+pub fn recording_path(dir: &Path, date: NaiveDate) -> PathBuf {
+    dir.join(format!("ticks-{}.bin.gz", date.format("%Y-%m-%d")))
+}
+
+fn record_loop(dir: PathBuf, receiver: mpsc::Receiver<RecordedTick>) {
+    let mut batch: Vec<RecordedTick> = Vec::new();
+
+    loop {
+        let disconnected = match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(tick) => {
+                batch.push(tick);
+                batch.extend(receiver.try_iter().take(FLUSH_BATCH));
+                false
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => false,
+            Err(mpsc::RecvTimeoutError::Disconnected) => true
+        };
+
+        if !batch.is_empty() {
+            if let Err(e) = flush(&dir, &batch) {
+                println!("Failed to write tick recording: {}", e);
+            }
+            batch.clear();
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+// Each flush appends a complete gzip member, so a crash loses at most the batch in flight
+// and the file stays readable by any multi-member gzip reader.
+fn flush(dir: &Path, batch: &[RecordedTick]) -> Result<(), anyhow::Error> {
+    let mut start = 0;
+    while start < batch.len() {
+        let date = ist_date(batch[start].received_micros);
+        let end = batch[start..].iter()
+            .position(|tick| ist_date(tick.received_micros) != date)
+            .map(|offset| start + offset)
+            .unwrap_or(batch.len());
+
+        let mut raw = Vec::new();
+        for tick in &batch[start..end] {
+            tick.write_to(&mut raw);
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(recording_path(dir, date))?;
+        let mut encoder = GzEncoder::new(file, Compression::fast());
+        encoder.write_all(&raw)?;
+        encoder.finish()?.sync_data()?;
+        start = end;
+    }
+    Ok(())
+}
+
+/// Reads a recording back one tick at a time in the order it was written, so a whole
+/// session replays in constant memory.
+pub struct RecordingReader {
+    input: BufReader<MultiGzDecoder<BufReader<File>>>
+}
+
+impl RecordingReader {
+    pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+        Ok(Self { input: BufReader::new(MultiGzDecoder::new(BufReader::new(File::open(path)?))) })
+    }
+}
+
+impl Iterator for RecordingReader {
+    type Item = Result<RecordedTick, anyhow::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        RecordedTick::read_from(&mut self.input).transpose()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    RealTime,
+    Multiplier(f64),
+    AsFastAsPossible
+}
+
+impl ReplaySpeed {
+    /// Parses `1x`, `10x`, `0.5x` or `max`.
+    pub fn parse(raw: &str) -> Result<Self, anyhow::Error> {
+        let raw = raw.trim().to_lowercase();
+        if raw == "max" {
+            return Ok(ReplaySpeed::AsFastAsPossible);
+        }
+
+        let factor: f64 = raw.trim_end_matches('x').parse()
+            .map_err(|_| anyhow::anyhow!("Invalid replay speed '{}', use 1x, 10x or max", raw))?;
+        if !factor.is_finite() || factor <= 0.0 {
+            return Err(anyhow::anyhow!("Replay speed must be a positive number: {}", raw));
+        }
+        if factor == 1.0 {
+            Ok(ReplaySpeed::RealTime)
+        }
+        else {
+            Ok(ReplaySpeed::Multiplier(factor))
+        }
+    }
+
+    fn scale(&self, gap: Duration) -> Option<Duration> {
+        match self {
+            ReplaySpeed::RealTime => Some(gap),
+            ReplaySpeed::Multiplier(factor) => Some(gap.div_f64(*factor)),
+            ReplaySpeed::AsFastAsPossible => None
+        }
+    }
+}
+
+/// Feeds recorded files back through `MarketDataHandler` without any Kite connection.
+pub struct TickReplay {
+    files: Vec<PathBuf>,
+    speed: ReplaySpeed
+}
+
+impl TickReplay {
+    /// `path` is a single recording or a directory of daily recordings, replayed in date order.
+    pub fn new(path: impl AsRef<Path>, speed: ReplaySpeed) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref();
+        let files = if path.is_dir() {
+            let mut files: Vec<PathBuf> = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("ticks-") && n.ends_with(".bin.gz")))
+                .collect();
+            files.sort();
+            files
+        }
+        else {
+            vec![path.to_path_buf()]
+        };
+
+        if files.is_empty() {
+            return Err(anyhow::anyhow!("No tick recordings found at {}", path.display()));
+        }
+        Ok(Self { files, speed })
+    }
+
+    pub async fn run(self, handler: Arc<MarketDataHandler>) -> Result<usize, anyhow::Error> {
+        let mut replayed = 0;
+
+        for file in &self.files {
+            println!("Replaying ticks from {}", file.display());
+            let mut previous: Option<i64> = None;
+
+            for recorded in RecordingReader::open(file)? {
+                let recorded = recorded?;
+                // Keep the original spacing between ticks, scaled by the replay speed
+                let gap = previous.map(|previous| (recorded.received_micros - previous).max(0) as u64);
+                if let Some(delay) = gap.and_then(|gap| self.speed.scale(Duration::from_micros(gap))) {
+                    tokio::time::sleep(delay).await;
+                }
+                previous = Some(recorded.received_micros);
+
+                match decode_packet(&recorded.packet) {
+                    Ok(tick) => {
+                        handler.on_replayed_tick(&tick, &recorded.symbol);
+                        replayed += 1;
+                    },
+                    Err(e) => println!("Skipping unreadable recorded packet: {}", e)
+                }
+
+                if self.speed == ReplaySpeed::AsFastAsPossible && replayed % 1_000 == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        }
+
+        println!("Replay finished: {} ticks", replayed);
+        Ok(replayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flushed_batches_read_back_in_order() {
+        let dir = std::env::temp_dir().join(format!("tick-recorder-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let frame = include_bytes!("fixtures/ticks/ltp_infy.bin");
+        let packet = crate::tick_decoder::packet_slices(frame).next().unwrap().unwrap().to_vec();
+        // 2024-01-15 09:15 IST
+        let start = 1_705_290_300_000_000;
+
+        let batch = |offset: i64| -> Vec<RecordedTick> {
+            (0..3).map(|i| RecordedTick {
+                received_micros: start + (offset + i) * 250_000,
+                exchange_timestamp: 0,
+                symbol: "NSE:INFY".to_string(),
+                packet: packet.clone()
+            }).collect()
+        };
+        flush(&dir, &batch(0)).unwrap();
+        flush(&dir, &batch(3)).unwrap();
+
+        let path = recording_path(&dir, NaiveDate::from_ymd_opt(2024, 1, 15).unwrap());
+        let ticks: Vec<RecordedTick> = RecordingReader::open(&path).unwrap().collect::<Result<_, _>>().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(ticks, [batch(0), batch(3)].concat());
+        assert_eq!(decode_packet(&ticks[5].packet).unwrap().instrument_token, 408065);
+    }
+
+    #[test]
+    fn parses_replay_speeds() {
+        assert_eq!(ReplaySpeed::parse("1x").unwrap(), ReplaySpeed::RealTime);
+        assert_eq!(ReplaySpeed::parse("10x").unwrap(), ReplaySpeed::Multiplier(10.0));
+        assert_eq!(ReplaySpeed::parse("MAX").unwrap(), ReplaySpeed::AsFastAsPossible);
+        assert!(ReplaySpeed::parse("0x").is_err());
+        assert!(ReplaySpeed::parse("fast").is_err());
+        assert!(ReplaySpeed::parse("nanx").is_err());
+        assert!(ReplaySpeed::parse("infx").is_err());
+    }
+}
@@ -0,0 +1,238 @@
+use std::{collections::{hash_map::Entry, HashMap}, sync::{Arc, Mutex}};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use crate::{kite_models::Quote, market_data::Subscriptions, tick_decoder::{DepthEntry, Tick, TickMode}};
+
+pub const TICK_CHANNEL_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ohlc {
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DepthLevel {
+    pub price: f64,
+    pub quantity: f64,
+    pub orders: f64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Depth {
+    pub buy: Vec<DepthLevel>,
+    pub sell: Vec<DepthLevel>
+}
+
+/// A tick in the shape we hand to clients, independent of how the ticker delivered it.
+#[derive(Debug, Clone, Serialize)]
+pub struct TickData {
+    pub instrument_token: u32,
+    pub symbol: String,
+    pub mode: String,
+    pub last_price: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_quantity: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average_price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buy_quantity: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sell_quantity: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ohlc: Option<Ohlc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub oi: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exchange_timestamp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<Depth>,
+    pub received_at: String
+}
+
+impl TickData {
+    pub fn from_tick(tick: &Tick, symbol: &str) -> Self {
+        let levels = |entries: &[DepthEntry]| -> Vec<DepthLevel> {
+            entries.iter().map(|entry| DepthLevel {
+                price: entry.price,
+                quantity: entry.quantity as f64,
+                orders: entry.orders as f64
+            }).collect()
+        };
+        let quote = tick.mode != TickMode::Ltp && tick.tradable;
+
+        Self {
+            instrument_token: tick.instrument_token,
+            symbol: symbol.to_string(),
+            mode: tick.mode.as_str().to_string(),
+            last_price: tick.last_price,
+            last_quantity: quote.then_some(tick.last_quantity as f64),
+            average_price: quote.then_some(tick.average_price),
+            volume: quote.then_some(tick.volume as f64),
+            buy_quantity: quote.then_some(tick.buy_quantity as f64),
+            sell_quantity: quote.then_some(tick.sell_quantity as f64),
+            ohlc: tick.has_ohlc().then_some(Ohlc {
+                open: tick.open,
+                high: tick.high,
+                low: tick.low,
+                close: tick.close
+            }),
+            change: tick.has_ohlc().then_some(tick.change),
+            oi: (tick.mode == TickMode::Full && tick.tradable).then_some(tick.oi as f64),
+            exchange_timestamp: (tick.exchange_timestamp > 0).then_some(tick.exchange_timestamp as i64),
+            depth: tick.has_depth.then(|| Depth {
+                buy: levels(&tick.buy_depth),
+                sell: levels(&tick.sell_depth)
+            }),
+            received_at: Utc::now().to_rfc3339()
+        }
+    }
+
+    /// A Kite REST `/quote`, `/quote/ohlc` or `/quote/ltp` entry in the same shape as a live tick.
+    pub fn from_quote(quote: &Quote, symbol: &str) -> Self {
+        let mode = if quote.depth.is_some() {
+            "full"
+        }
+        else if quote.ohlc.is_some() {
+            "quote"
+        }
+        else {
+            "ltp"
+        };
+
+        Self {
+            instrument_token: quote.instrument_token,
+            symbol: symbol.to_string(),
+            mode: mode.to_string(),
+            last_price: quote.last_price,
+            last_quantity: quote.last_quantity,
+            average_price: quote.average_price,
+            volume: quote.volume,
+            buy_quantity: quote.buy_quantity,
+            sell_quantity: quote.sell_quantity,
+            ohlc: quote.ohlc.clone(),
+            change: quote.net_change,
+            oi: quote.oi,
+            // REST quotes carry an exchange timestamp string, ticks carry epoch seconds
+            exchange_timestamp: None,
+            depth: quote.depth.clone(),
+            received_at: Utc::now().to_rfc3339()
+        }
+    }
+}
+
+/// One client's view of the shared tick feed. Symbols it asks for are kept subscribed
+/// upstream for as long as the subscription lives.
+pub struct TickSubscription {
+    receiver: broadcast::Receiver<Arc<TickData>>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    tokens: HashMap<u32, String>
+}
+
+impl TickSubscription {
+    pub fn new(receiver: broadcast::Receiver<Arc<TickData>>, subscriptions: Arc<Mutex<Subscriptions>>) -> Self {
+        Self { receiver, subscriptions, tokens: HashMap::new() }
+    }
+
+    pub fn add(&mut self, instruments: Vec<(u32, String)>) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        for (token, symbol) in instruments {
+            if let Entry::Vacant(entry) = self.tokens.entry(token) {
+                subscriptions.retain_stream(token, &symbol);
+                entry.insert(symbol);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, tokens: &[u32]) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        for token in tokens {
+            if self.tokens.remove(token).is_some() {
+                subscriptions.release_stream(*token);
+            }
+        }
+    }
+
+    pub fn symbols(&self) -> Vec<String> {
+        self.tokens.values().cloned().collect()
+    }
+
+    /// Waits for the next tick on one of this client's instruments. Slow clients skip
+    /// whatever they missed instead of holding back the feed.
+    pub async fn next(&mut self) -> Option<Arc<TickData>> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(tick) if self.tokens.contains_key(&tick.instrument_token) => return Some(tick),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    println!("Tick stream client lagged, skipped {} ticks", skipped)
+                },
+                Err(RecvError::Closed) => return None
+            }
+        }
+    }
+}
+
+impl Drop for TickSubscription {
+    fn drop(&mut self) {
+        let tokens: Vec<u32> = self.tokens.keys().copied().collect();
+        self.remove(&tokens);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(instrument_token: u32) -> Arc<TickData> {
+        let quote: Quote = serde_json::from_value(serde_json::json!({ "instrument_token": instrument_token, "last_price": 100.0 })).unwrap();
+        Arc::new(TickData::from_quote(&quote, "NSE:INFY"))
+    }
+
+    #[test]
+    fn upstream_tokens_are_released_when_the_last_client_drops() {
+        let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+        let (sender, _) = broadcast::channel(16);
+
+        let mut first = TickSubscription::new(sender.subscribe(), subscriptions.clone());
+        let mut second = TickSubscription::new(sender.subscribe(), subscriptions.clone());
+        first.add(vec![(408065, "NSE:INFY".to_string()), (408065, "NSE:INFY".to_string())]);
+        second.add(vec![(408065, "NSE:INFY".to_string()), (2953217, "NSE:TCS".to_string())]);
+        let (mut added, removed) = subscriptions.lock().unwrap().take_changes();
+        added.sort();
+        assert_eq!((added, removed), (vec![408065, 2953217], vec![]));
+
+        // INFY stays subscribed while the first client still wants it
+        drop(second);
+        assert_eq!(subscriptions.lock().unwrap().take_changes(), (vec![], vec![2953217]));
+        first.remove(&[408065]);
+        first.remove(&[408065]);
+        assert_eq!(subscriptions.lock().unwrap().take_changes(), (vec![], vec![408065]));
+
+        first.add(vec![(408065, "NSE:INFY".to_string())]);
+        assert_eq!(subscriptions.lock().unwrap().take_changes(), (vec![408065], vec![]));
+        drop(first);
+        assert_eq!(subscriptions.lock().unwrap().take_changes(), (vec![], vec![408065]));
+    }
+
+    #[tokio::test]
+    async fn clients_only_see_their_own_instruments() {
+        let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
+        let (sender, _) = broadcast::channel(16);
+        let mut client = TickSubscription::new(sender.subscribe(), subscriptions);
+        client.add(vec![(408065, "NSE:INFY".to_string())]);
+
+        sender.send(tick(2953217)).unwrap();
+        sender.send(tick(408065)).unwrap();
+        drop(sender);
+        assert_eq!(client.next().await.unwrap().instrument_token, 408065);
+        assert!(client.next().await.is_none());
+    }
+}
@@ -1,138 +1,491 @@
-use std::collections::HashMap;
-use std::result::Result::Ok;
-use kiteconnect::connect::KiteConnect;
-use crate::data_structures::TradeInstruction;
+use std::fmt;
+use crate::{charges::{split_order_charges, Segment}, data_structures::{Exposure, MarginEstimate, TradeInstruction, TradePreview}, errors::kite_error, instrument_master::Instrument, kite_client::KiteClient, kite_models::{GttCondition, GttOrder, GttParams, MarginOrder, OrderCharges, OrderParams}, market_protection::{MarketProtection, MarketQuote}, order_book::thin_book_warnings, order_validation::{is_derivative, round_to_tick, validate_order, DEFAULT_TICK_SIZE}, rate_limiter::Priority};
 
-pub struct TradeExecutor<'a> {
-    pub kite: &'a KiteConnect
+pub const ORDER_VARIETY: &str = "regular";
+
+/// Products Kite accepts on regular orders: delivery, intraday and F&O carry forward.
+pub const PRODUCTS: [&str; 3] = ["CNC", "MIS", "NRML"];
+
+/// What a buy or sell instruction turns into: the validated order and, above the freeze
+/// limit, the quantities of the child orders it is sent as.
+#[derive(Debug, Clone)]
+pub struct OrderPlan {
+    pub params: OrderParams,
+    pub slices: Vec<u32>,
+    pub adjustments: Vec<String>
 }
 
-impl<'a> TradeExecutor<'a> {
-    pub fn new(kite: &'a KiteConnect) -> Self {
-        Self { kite }
+impl OrderPlan {
+    pub fn child_orders(&self) -> Vec<OrderParams> {
+        self.slices.iter()
+            .map(|quantity| OrderParams { quantity: *quantity, ..self.params.clone() })
+            .collect()
     }
+}
+
+/// Attached to a placement error when something may already be at Kite: the children confirmed
+/// before the failure, and whether the failed one may have gone through as well.
+#[derive(Debug, Clone)]
+pub struct PartialPlacement {
+    pub order_ids: Vec<String>,
+    pub failed_child: usize,
+    pub children: usize,
+    pub outcome_unknown: bool
+}
+
+impl fmt::Display for PartialPlacement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.children > 1 {
+            write!(f, "Child order {} of {} failed", self.failed_child, self.children)?;
+        }
+        else {
+            write!(f, "Order failed")?;
+        }
+        if !self.order_ids.is_empty() {
+            write!(f, " after placing {}", self.order_ids.join(", "))?;
+        }
+        if self.outcome_unknown {
+            write!(f, "; Kite may have accepted it anyway, retry with the same idempotency key to reconcile")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct TradeExecutor {
+    pub kite: KiteClient,
+    pub instrument: Option<Instrument>,
+    pub freeze_limit: Option<u32>,
+    pub protection: MarketProtection,
+    pub quote: MarketQuote
+}
 
-    pub fn execute_instructions(&mut self, instruction: &TradeInstruction) -> Result<String, anyhow::Error> {
+impl TradeExecutor {
+    /// `instrument` comes from the instrument master when it is loaded; without it prices and
+    /// quantities are sent as given.
+    pub fn new(kite: KiteClient, instrument: Option<Instrument>, freeze_limit: Option<u32>) -> Self {
+        Self { kite, instrument, freeze_limit, protection: MarketProtection::off(), quote: MarketQuote::default() }
+    }
+
+    /// Market orders are sent as limit orders priced from `quote` unless `protection` is off.
+    pub fn with_market_protection(self, protection: MarketProtection, quote: MarketQuote) -> Self {
+        Self { protection, quote, ..self }
+    }
+
+    pub fn tick_size(&self) -> f64 {
+        self.instrument.as_ref().map(|i| i.tick_size).filter(|tick| *tick > 0.0).unwrap_or(DEFAULT_TICK_SIZE)
+    }
+
+    /// Order IDs of everything placed; more than one when the order was split at the freeze limit.
+    pub async fn execute_instructions(&mut self, instruction: &TradeInstruction) -> Result<Vec<String>, anyhow::Error> {
         match instruction.action.as_str() {
-            "buy" => self.place_buy_order(instruction),
-            "sell" => self.place_sell_order(instruction),
-            "cancel" => self.cancel_order(instruction),
-            _ => return Err(anyhow::anyhow!("Unsupported action: {}", instruction.action))
+            "buy" => self.place_buy_order(instruction).await,
+            "sell" => self.place_sell_order(instruction).await,
+            "cancel" => self.cancel_order(instruction).await.map(|order_id| vec![order_id]),
+            _ => Err(anyhow::anyhow!("Unsupported action: {}", instruction.action))
+        }
+    }
+
+    /// Liquidity warnings for market orders; limit orders and cancels never walk the book.
+    pub fn market_order_warnings(&self, instruction: &TradeInstruction) -> Vec<String> {
+        if !instruction.is_market() || instruction.action == "cancel" {
+            return Vec::new();
+        }
+
+        match &self.quote.book {
+            Some(analytics) => thin_book_warnings(analytics, &instruction.action, instruction.quantity),
+            None => vec!["Market depth unavailable, book liquidity was not checked".to_string()]
         }
     }
 
-    pub fn place_buy_order(&mut self, instruction: &TradeInstruction) -> Result<String, anyhow::Error> {
-        let mut params = HashMap::new();
-        params.insert("tradingsymbol", instruction.symbol.clone());
-        params.insert("exchange", instruction.exchange.clone());
-        params.insert("transaction type", "BUY".to_string());
-        params.insert("quantity", instruction.quantity.to_string());
-        params.insert("product", "CNC".to_string());
+    pub async fn place_buy_order(&mut self, instruction: &TradeInstruction) -> Result<Vec<String>, anyhow::Error> {
+        let plan = self.order_params(instruction, "BUY")?;
+        self.place_plan(&plan, self.priority(&plan.params)).await
+    }
 
-        let order_type = match instruction.price_type.as_str() {
-            "MARKET" => {
-                params.insert("order type", "MARKET".to_string())
-            },
-            "lIMIT" => {
-                if let Some(limit_price) = instruction.limit_price  {
-                    params.insert("order type", limit_price.to_string());
-                }
-                else {
-                    return Err(anyhow::anyhow!("Limit price required for limit order"));
+    async fn place_sell_order(&mut self, instruction: &TradeInstruction) -> Result<Vec<String>, anyhow::Error> {
+        let plan = self.order_params(instruction, "SELL")?;
+        self.place_plan(&plan, self.priority(&plan.params)).await
+    }
+
+    /// CNC sells can only close delivery holdings, so they go ahead of new buys; MIS and NRML sells may open shorts.
+    pub fn priority(&self, params: &OrderParams) -> Priority {
+        if params.transaction_type == "SELL" && params.product == "CNC" { Priority::Exit } else { Priority::Entry }
+    }
+
+    // Children go out one at a time; if one fails after any reached Kite the error carries a `PartialPlacement`
+    async fn place_plan(&self, plan: &OrderPlan, priority: Priority) -> Result<Vec<String>, anyhow::Error> {
+        let children = plan.child_orders();
+        let mut order_ids = Vec::new();
+
+        for (i, child) in children.iter().enumerate() {
+            match self.kite.place_order(ORDER_VARIETY, child, priority).await {
+                Ok(order_id) => order_ids.push(order_id),
+                Err(e) => {
+                    let outcome_unknown = kite_error(&e).is_some_and(|kite| kite.outcome_unknown());
+                    if order_ids.is_empty() && !outcome_unknown {
+                        return Err(e);
+                    }
+                    return Err(e.context(PartialPlacement { order_ids, failed_child: i + 1, children: children.len(), outcome_unknown }));
                 }
-                params.insert("order type", "LIMIT".to_string())
+            }
+        }
+        Ok(order_ids)
+    }
+
+    /// The exact parameters a buy or sell instruction is sent to Kite with.
+    pub fn plan(&self, instruction: &TradeInstruction) -> Result<OrderPlan, anyhow::Error> {
+        match instruction.action.as_str() {
+            "buy" => self.order_params(instruction, "BUY"),
+            "sell" => self.order_params(instruction, "SELL"),
+            _ => Err(anyhow::anyhow!("Only buy and sell instructions place orders, got: {}", instruction.action))
+        }
+    }
+
+    fn order_params(&self, instruction: &TradeInstruction, transaction_type: &str) -> Result<OrderPlan, anyhow::Error> {
+        let mut protection = None;
+        let (order_type, price) = if instruction.is_market() {
+            if self.protection.is_enabled() {
+                let (price, note) = self.protection.limit_price(transaction_type == "BUY", &self.quote, self.tick_size())?;
+                protection = Some(note);
+                ("LIMIT", Some(price))
+            }
+            else {
+                ("MARKET", None)
+            }
+        }
+        else if instruction.price_type.trim().eq_ignore_ascii_case("LIMIT") {
+            match instruction.limit_price {
+                Some(limit_price) => ("LIMIT", Some(limit_price)),
+                None => return Err(anyhow::anyhow!("Limit price required for limit order"))
+            }
+        }
+        else {
+            return Err(anyhow::anyhow!("Unsupported price type received: {}", instruction.price_type));
+        };
+
+        let product = self.product(instruction)?;
+
+        // Kite rejects off-tick prices and part lots outright, so catch them before sending
+        let (price, quantity, slices, mut adjustments) = match &self.instrument {
+            Some(instrument) => {
+                let order = validate_order(instrument, transaction_type == "BUY", price, instruction.quantity, instruction.auto_adjust, self.freeze_limit)?;
+                (order.price, order.quantity, order.slices, order.adjustments)
             },
-            _ => return Err(anyhow::anyhow!("Unsupported price type received: {}", instruction.price_type))
+            None => (price, instruction.quantity, vec![instruction.quantity], Vec::new())
         };
+        adjustments.extend(protection);
 
-        let price_ = instruction.limit_price.map(|p| p.to_string());
-        let price_ref = price_.as_deref();
-        let stop_loss = instruction.stop_loss.map(|s| s.to_string());
-
-        let response = self.kite.place_order(
-            &instruction.exchange,
-            &instruction.symbol,
-            "BUY",
-            &instruction.quantity.to_string(),
-            "regular",
-            price_.as_deref(),
-            Some("CNC"),
-            order_type.as_deref(),
-            Some("DAY"),
-            None,
-            None,
-            None,
-            stop_loss.as_deref(),
-            None,
-            price_ref
-        )?;
-
-        let order_id = match response.get("order_id") {
-            Some(id) => id.to_string(),
-            None => return Err(anyhow::anyhow!("Cannot get a valid order id"))
+        let params = OrderParams {
+            exchange: instruction.exchange.clone(),
+            tradingsymbol: instruction.symbol.clone(),
+            transaction_type: transaction_type.to_string(),
+            quantity,
+            product,
+            order_type: order_type.to_string(),
+            validity: "DAY".to_string(),
+            price,
+            tag: instruction.idempotency_key.clone(),
+            ..OrderParams::default()
         };
-        Ok(order_id)
-    }
-
-    fn place_sell_order(&mut self, instruction: &TradeInstruction) -> Result<String, anyhow::Error> {
-        let mut params = HashMap::new();
-        params.insert("tradingsymbol", instruction.symbol.clone());
-        params.insert("exchange", instruction.exchange.clone());
-        params.insert("quantity", instruction.quantity.to_string());
-        params.insert("transaction type", "SELL".to_string());
-        params.insert("product", "CNC".to_string());
-
-        let order_type = match instruction.price_type.as_str() {
-            "MARKET" => params.insert("order type", "MARKET".to_string()),
-            "LIMIT" => {
-                if let Some(limit_price) = instruction.limit_price {
-                    params.insert("order type", limit_price.to_string())
-                }
-                else {
-                    return Err(anyhow::anyhow!("Valid price not received.."));
-                }
+        Ok(OrderPlan { params, slices, adjustments })
+    }
+
+    /// The GTT that guards the position once the entry is placed: a single trigger for a stop loss or
+    /// a target alone, a two-leg OCO for both. Exits are limit orders at the trigger, except that a
+    /// stop loss is priced through the market protection band when it is on so a gap past it still fills.
+    pub fn exit_gtt(&self, plan: &OrderPlan, instruction: &TradeInstruction) -> Result<Option<GttParams>, anyhow::Error> {
+        if instruction.stop_loss.is_none() && instruction.target.is_none() {
+            return Ok(None);
+        }
+        let order = &plan.params;
+        let long = order.transaction_type == "BUY";
+        let entry = order.price.or(self.quote.ltp).filter(|price| *price > 0.0)
+            .ok_or_else(|| anyhow::anyhow!("No price to check stop_loss and target against, send a LIMIT order"))?;
+        let tick = self.tick_size();
+
+        // A long is stopped out below the entry and takes profit above it, a short the other way
+        // round; both are rounded towards the entry
+        let stop_loss = instruction.stop_loss.map(|price| round_to_tick(price, tick, !long));
+        let target = instruction.target.map(|price| round_to_tick(price, tick, long));
+        let side = |below: bool| if below { "below" } else { "above" };
+        if let Some(stop_loss) = stop_loss.filter(|stop_loss| (long && *stop_loss >= entry) || (!long && *stop_loss <= entry) || *stop_loss <= 0.0) {
+            return Err(anyhow::anyhow!("stop_loss {} must be {} the entry price {}", stop_loss, side(long), entry));
+        }
+        if let Some(target) = target.filter(|target| (long && *target <= entry) || (!long && *target >= entry)) {
+            return Err(anyhow::anyhow!("target {} must be {} the entry price {}", target, side(!long), entry));
+        }
+
+        let exit = |trigger: f64, stop: bool| -> Result<GttOrder, anyhow::Error> {
+            let price = if stop && self.protection.is_enabled() {
+                self.protection.limit_price(!long, &MarketQuote { ltp: Some(trigger), book: None }, tick)?.0
+            }
+            else {
+                trigger
+            };
+            Ok(GttOrder {
+                exchange: order.exchange.clone(),
+                tradingsymbol: order.tradingsymbol.clone(),
+                transaction_type: if long { "SELL" } else { "BUY" }.to_string(),
+                quantity: order.quantity,
+                order_type: "LIMIT".to_string(),
+                product: order.product.clone(),
+                price
+            })
+        };
+        // Kite wants an OCO's trigger values in ascending order, each with its own leg
+        let mut legs = Vec::new();
+        legs.extend(stop_loss.map(|trigger| (trigger, true)));
+        legs.extend(target.map(|trigger| (trigger, false)));
+        legs.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        Ok(Some(GttParams {
+            kind: if legs.len() == 2 { "two-leg" } else { "single" }.to_string(),
+            condition: GttCondition {
+                exchange: order.exchange.clone(),
+                tradingsymbol: order.tradingsymbol.clone(),
+                trigger_values: legs.iter().map(|(trigger, _)| *trigger).collect(),
+                last_price: self.quote.ltp.unwrap_or(entry),
+                instrument_token: self.instrument.as_ref().map(|i| i.instrument_token)
             },
-            _ => return Err(anyhow::anyhow!("Cannot fetch order type.."))
+            orders: legs.iter().map(|(trigger, stop)| exit(*trigger, *stop)).collect::<Result<_, _>>()?
+        }))
+    }
+
+    // Defaults to CNC for equities and NRML for F&O, which can't be held as delivery
+    fn product(&self, instruction: &TradeInstruction) -> Result<String, anyhow::Error> {
+        let derivative = self.instrument.as_ref().is_some_and(is_derivative);
+        let product = match instruction.product.as_deref() {
+            Some(product) => product.trim().to_uppercase(),
+            None if derivative => "NRML".to_string(),
+            None => "CNC".to_string()
         };
 
-        let price_ = instruction.limit_price.map(|p| p.to_string());
-        let price_ref = price_.as_deref();
-        let stop_loss = instruction.stop_loss.map(|s| s.to_string());
-
-        let response = self.kite.place_order(
-            &instruction.exchange,
-            &instruction.symbol,
-            "SELL",
-            &instruction.quantity.to_string(),
-            "regular",
-            price_.as_deref(),
-            Some("CNC"),
-            order_type.as_deref(),
-            Some("DAY"),
-            None,
-            None,
-            None,
-            stop_loss.as_deref(),
-            None,
-            price_ref
-        )?;
-
-        let order_id = match response.get("order_id") {
-            Some(id) => id.to_string(),
-            None => {
-                return Err(anyhow::anyhow!("Cannot get a valid order id.."));
+        if !PRODUCTS.contains(&product.as_str()) {
+            return Err(anyhow::anyhow!("Unsupported product: {}, use one of {:?}", product, PRODUCTS));
+        }
+        match &self.instrument {
+            Some(instrument) if derivative && product == "CNC" => Err(anyhow::anyhow!("CNC is for equity delivery, use NRML or MIS for {}", instrument.key())),
+            Some(instrument) if !derivative && product == "NRML" => Err(anyhow::anyhow!("NRML is for F&O, use CNC or MIS for {}", instrument.key())),
+            _ => Ok(product)
+        }
+    }
+
+    /// Runs an instruction through everything `/trade` does short of placing it. Kite is asked for
+    /// margin, charges, funds and positions when there is a session; otherwise margin is estimated
+    /// from the notional and exposure is left out. `reference_price` is the LTP, used for market orders.
+    pub async fn preview(&self, instruction: &TradeInstruction, reference_price: Option<f64>, session: bool, mut warnings: Vec<String>) -> Result<TradePreview, anyhow::Error> {
+        let plan = self.plan(instruction)?;
+        let exit_gtt = self.exit_gtt(&plan, instruction)?;
+        let order = plan.params.clone();
+
+        warnings.extend(plan.adjustments.iter().cloned());
+        if self.instrument.is_none() {
+            warnings.push(format!("{}:{} is not in the instrument master, tick and lot size were not checked", order.exchange, order.tradingsymbol));
+        }
+
+        let reference_price = order.price.or(reference_price);
+        let notional = reference_price.map(|price| price * order.quantity as f64);
+        let buying = order.transaction_type == "BUY";
+
+        let kite_margin = if session {
+            match self.kite.order_margins(&[MarginOrder::from_params(ORDER_VARIETY, &order)]).await {
+                Ok(mut margins) if !margins.is_empty() => Some(margins.remove(0)),
+                Ok(_) => None,
+                Err(e) => {
+                    warnings.push(format!("Kite margin calculation failed, using a local estimate: {}", e));
+                    None
+                }
+            }
+        }
+        else {
+            warnings.push("No Kite session, margin and charges are local estimates and exposure is unavailable".to_string());
+            None
+        };
+
+        let (margin, charges) = match kite_margin {
+            Some(breakdown) => (
+                MarginEstimate { source: "kite".to_string(), required: Some(breakdown.total), breakdown: Some(breakdown.clone()) },
+                Some(breakdown.charges)
+            ),
+            // Without Kite's leverage figures assume the full notional; delivery sells are covered by the holding
+            None => (
+                MarginEstimate { source: "local".to_string(), required: notional.map(|n| if buying || order.product != "CNC" { n } else { 0.0 }), breakdown: None },
+                self.local_charges(&order, &plan.slices, reference_price)
+            )
+        };
+
+        let exposure = if session {
+            match self.exposure(&order, reference_price, margin.required).await {
+                Ok(exposure) => Some(exposure),
+                Err(e) => {
+                    warnings.push(format!("Could not load holdings, positions or funds: {}", e));
+                    None
+                }
             }
+        }
+        else {
+            None
         };
-        Ok(order_id)
+
+        if let Some(exposure) = &exposure {
+            if exposure.cash_after.is_some_and(|cash| cash < 0.0) {
+                warnings.push("Insufficient funds: required margin exceeds available cash".to_string());
+            }
+            if !buying && order.product == "CNC" && exposure.holding_quantity < order.quantity as i64 {
+                warnings.push(format!("Selling {} but only {} held for delivery", order.quantity, exposure.holding_quantity));
+            }
+        }
+
+        Ok(TradePreview {
+            status: "Preview".to_string(),
+            variety: ORDER_VARIETY.to_string(),
+            instrument_token: self.instrument.as_ref().map(|i| i.instrument_token),
+            tick_size: self.instrument.as_ref().map(|i| i.tick_size),
+            lot_size: self.instrument.as_ref().map(|i| i.lot_size),
+            order,
+            child_quantities: if plan.slices.len() > 1 { plan.slices } else { Vec::new() },
+            reference_price,
+            notional,
+            margin,
+            charges,
+            exposure,
+            exit_gtt,
+            warnings
+        })
+    }
+
+    // From the published fee schedule, for when Kite can't be asked; each freeze-split child is its own order
+    fn local_charges(&self, order: &OrderParams, slices: &[u32], price: Option<f64>) -> Option<OrderCharges> {
+        let instrument_type = self.instrument.as_ref().map(|i| i.instrument_type.as_str()).unwrap_or("EQ");
+        let segment = Segment::of(&order.exchange, instrument_type, &order.product).ok()?;
+        let charges = split_order_charges(segment, &order.exchange, order.transaction_type == "BUY", price?, slices)?;
+        Some(OrderCharges::from(&charges))
+    }
+
+    async fn exposure(&self, order: &OrderParams, reference_price: Option<f64>, margin: Option<f64>) -> Result<Exposure, anyhow::Error> {
+        let holdings = self.kite.holdings().await?;
+        let positions = self.kite.positions().await?;
+        let funds = self.kite.margins().await?;
+
+        let same = |exchange: &str, symbol: &str| exchange == order.exchange && symbol == order.tradingsymbol;
+        let holding_quantity: i64 = holdings.iter()
+            .filter(|h| same(&h.exchange, &h.tradingsymbol))
+            .map(|h| h.quantity + h.t1_quantity)
+            .sum();
+        let position_quantity: i64 = positions.net.iter()
+            .filter(|p| same(&p.exchange, &p.tradingsymbol))
+            .map(|p| p.quantity)
+            .sum();
+
+        let signed = if order.transaction_type == "BUY" { order.quantity as i64 } else { -(order.quantity as i64) };
+        let post_trade_quantity = holding_quantity + position_quantity + signed;
+        let available_cash = funds.equity.map(|equity| equity.net);
+
+        Ok(Exposure {
+            holding_quantity,
+            position_quantity,
+            post_trade_quantity,
+            post_trade_value: reference_price.map(|price| price * post_trade_quantity as f64),
+            available_cash,
+            cash_after: available_cash.zip(margin).map(|(cash, margin)| cash - margin)
+        })
     }
 
-    fn cancel_order(&mut self, instruction: &TradeInstruction) -> Result<String, anyhow::Error> {
+    async fn cancel_order(&mut self, instruction: &TradeInstruction) -> Result<String, anyhow::Error> {
         if let Some(order_id) = &instruction.order_id {
-            self.kite.cancel_order(order_id, "regular", Some(order_id))?;
-            Ok(order_id.to_string())
+            self.kite.cancel_order(ORDER_VARIETY, order_id).await
         }
         else {
-            return Err(anyhow::anyhow!("Cannot cancel order.."));
+            Err(anyhow::anyhow!("Cannot cancel order.."))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit_buy(price: f64) -> TradeInstruction {
+        TradeInstruction {
+            action: "buy".to_string(),
+            symbol: "INFY".to_string(),
+            exchange: "NSE".to_string(),
+            quantity: 10,
+            price_type: "LIMIT".to_string(),
+            limit_price: Some(price),
+            stop_loss: None,
+            target: None,
+            order_id: None,
+            timeframe: None,
+            watchlist: None,
+            idempotency_key: Some("agent1-42".to_string()),
+            auto_adjust: true,
+            product: None
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn limit_prices_are_checked_against_the_instrument() {
+        let instrument = Instrument {
+            instrument_token: 408065,
+            exchange_token: 1594,
+            tradingsymbol: "INFY".to_string(),
+            name: "INFOSYS".to_string(),
+            expiry: None,
+            strike: 0.0,
+            tick_size: 0.05,
+            lot_size: 1,
+            instrument_type: "EQ".to_string(),
+            segment: "NSE".to_string(),
+            exchange: "NSE".to_string()
+        };
+        let executor = TradeExecutor::new(KiteClient::new("key").unwrap(), Some(instrument), None);
+
+        let plan = executor.plan(&limit_buy(1602.43)).unwrap();
+        assert_eq!(plan.params.price, Some(1602.4));
+        assert_eq!(plan.params.tag.as_deref(), Some("agent1-42"));
+        assert_eq!(plan.adjustments.len(), 1);
+
+        let strict = TradeInstruction { auto_adjust: false, ..limit_buy(1602.43) };
+        assert!(executor.plan(&strict).is_err());
+
+        let unknown = TradeExecutor::new(KiteClient::new("key").unwrap(), None, None);
+        assert_eq!(unknown.plan(&strict).unwrap().params.price, Some(1602.43));
+    }
+
+    #[test]
+    fn lowercase_market_orders_are_protected() {
+        let executor = TradeExecutor::new(KiteClient::new("key").unwrap(), None, None)
+            .with_market_protection(MarketProtection::default(), MarketQuote { ltp: Some(1601.0), book: None });
+        let instruction = TradeInstruction { price_type: "market".to_string(), limit_price: None, ..limit_buy(1600.0) };
+
+        let plan = executor.plan(&instruction).unwrap();
+        assert_eq!(plan.params.order_type, "LIMIT");
+        assert_eq!(plan.params.price, Some(1633.0));
+        assert!(!executor.market_order_warnings(&instruction).is_empty());
+    }
+
+    #[test]
+    fn stop_loss_and_target_become_an_oco_gtt() {
+        let executor = TradeExecutor::new(KiteClient::new("key").unwrap(), None, None)
+            .with_market_protection(MarketProtection::default(), MarketQuote { ltp: Some(1601.0), book: None });
+        let instruction = TradeInstruction { stop_loss: Some(1560.02), target: Some(1680.0), ..limit_buy(1600.0) };
+        let plan = executor.plan(&instruction).unwrap();
+
+        let gtt = executor.exit_gtt(&plan, &instruction).unwrap().unwrap();
+        assert_eq!(gtt.kind, "two-leg");
+        assert_eq!(gtt.condition.trigger_values, vec![1560.05, 1680.0]);
+        assert_eq!(gtt.condition.last_price, 1601.0);
+        assert!(gtt.orders.iter().all(|order| order.transaction_type == "SELL" && order.quantity == 10));
+        // The stop leg sells through the protection band, the target leg at its trigger
+        assert!(gtt.orders[0].price < 1560.05);
+        assert_eq!(gtt.orders[1].price, 1680.0);
+
+        let stop_only = TradeInstruction { target: None, ..instruction.clone() };
+        assert_eq!(executor.exit_gtt(&plan, &stop_only).unwrap().unwrap().kind, "single");
+        assert!(executor.exit_gtt(&plan, &limit_buy(1600.0)).unwrap().is_none());
+        // A long's stop can't sit above its entry
+        let inverted = TradeInstruction { stop_loss: Some(1650.0), ..instruction };
+        assert!(executor.exit_gtt(&plan, &inverted).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+use std::{collections::{BTreeMap, HashSet}, fs, path::{Path, PathBuf}};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use crate::instrument_master::instrument_key;
+
+pub const DEFAULT_WATCHLIST: &str = "default";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Watchlist {
+    pub name: String,
+    pub owner: Option<String>,
+    pub symbols: Vec<String>,
+    pub updated_at: String
+}
+
+/// Named watchlists persisted as a single JSON file, rewritten on every change.
+#[derive(Debug)]
+pub struct WatchlistStore {
+    path: PathBuf,
+    lists: BTreeMap<String, Watchlist>
+}
+
+impl WatchlistStore {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let path = path.as_ref().to_path_buf();
+
+        if path.exists() {
+            let content = fs::read_to_string(&path)?;
+            let lists = serde_json::from_str(&content)
+                .map_err(|e| anyhow::anyhow!("Invalid watchlist file {}: {}", path.display(), e))?;
+            return Ok(Self { path, lists });
+        }
+
+        // First run: seed the store with the symbols that used to be hardcoded
+        let mut store = Self { path, lists: BTreeMap::new() };
+        store.create(
+            DEFAULT_WATCHLIST,
+            None,
+            &["RELIANCE", "TCS", "HDFCBANK", "INFY", "SBIN", "TATAMOTORS", "ITC"].map(String::from)
+        )?;
+        Ok(store)
+    }
+
+    pub fn list(&self) -> Vec<&Watchlist> {
+        self.lists.values().collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Watchlist> {
+        self.lists.get(name)
+    }
+
+    pub fn create(&mut self, name: &str, owner: Option<String>, symbols: &[String]) -> Result<Watchlist, anyhow::Error> {
+        validate_name(name)?;
+        if self.lists.contains_key(name) {
+            return Err(anyhow::anyhow!("Watchlist already exists: {}", name));
+        }
+
+        let watchlist = Watchlist {
+            name: name.to_string(),
+            owner,
+            symbols: normalise_symbols(symbols),
+            updated_at: Utc::now().to_rfc3339()
+        };
+        self.lists.insert(name.to_string(), watchlist.clone());
+        self.save()?;
+        Ok(watchlist)
+    }
+
+    pub fn replace(&mut self, name: &str, symbols: &[String]) -> Result<Watchlist, anyhow::Error> {
+        self.update(name, |watchlist| watchlist.symbols = normalise_symbols(symbols))
+    }
+
+    pub fn add_symbols(&mut self, name: &str, symbols: &[String]) -> Result<Watchlist, anyhow::Error> {
+        self.update(name, |watchlist| {
+            let mut merged = watchlist.symbols.clone();
+            merged.extend(normalise_symbols(symbols));
+            watchlist.symbols = normalise_symbols(&merged);
+        })
+    }
+
+    pub fn remove_symbol(&mut self, name: &str, symbol: &str) -> Result<Watchlist, anyhow::Error> {
+        let key = instrument_key(symbol);
+        self.update(name, |watchlist| watchlist.symbols.retain(|s| *s != key))
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<Watchlist, anyhow::Error> {
+        let removed = self.lists.remove(name)
+            .ok_or_else(|| anyhow::anyhow!("Watchlist not found: {}", name))?;
+        self.save()?;
+        Ok(removed)
+    }
+
+    /// Union of every symbol across all watchlists, used to drive ticker subscriptions.
+    pub fn all_symbols(&self) -> HashSet<String> {
+        self.lists.values().flat_map(|w| w.symbols.iter().cloned()).collect()
+    }
+
+    fn update<F>(&mut self, name: &str, change: F) -> Result<Watchlist, anyhow::Error>
+    where F: FnOnce(&mut Watchlist)
+    {
+        let watchlist = self.lists.get_mut(name)
+            .ok_or_else(|| anyhow::anyhow!("Watchlist not found: {}", name))?;
+        change(watchlist);
+        watchlist.updated_at = Utc::now().to_rfc3339();
+
+        let updated = watchlist.clone();
+        self.save()?;
+        Ok(updated)
+    }
+
+    fn save(&self) -> Result<(), anyhow::Error> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Write to a sibling file first so a crash never leaves a half-written store behind
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&self.lists)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), anyhow::Error> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    }
+    else {
+        Err(anyhow::anyhow!("Invalid watchlist name '{}': use up to 64 letters, digits, '-' or '_'", name))
+    }
+}
+
+fn normalise_symbols(symbols: &[String]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    symbols.iter()
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| instrument_key(s))
+        .filter(|s| seen.insert(s.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_store_round_trips_through_its_json_file() {
+        let dir = std::env::temp_dir().join(format!("watchlists-{}", std::process::id()));
+        let path = dir.join("watchlists.json");
+        let _ = fs::remove_dir_all(&dir);
+
+        // A fresh store is seeded with the default list and written straight away
+        let mut store = WatchlistStore::load(&path).unwrap();
+        assert!(store.get(DEFAULT_WATCHLIST).is_some());
+        store.create("banks", Some("desk".to_string()), &["hdfcbank".to_string(), "NSE:SBIN".to_string(), "HDFCBANK".to_string()]).unwrap();
+        store.add_symbols("banks", &["ICICIBANK".to_string()]).unwrap();
+        store.remove_symbol("banks", "sbin").unwrap();
+        assert!(store.create("banks", None, &[]).is_err());
+        assert!(store.create("no spaces", None, &[]).is_err());
+
+        // Saves go through a sibling temp file that is renamed over the store
+        assert!(!path.with_extension("json.tmp").exists());
+        let reloaded = WatchlistStore::load(&path).unwrap();
+        let banks = reloaded.get("banks").unwrap();
+        assert_eq!(banks.symbols, vec!["NSE:HDFCBANK", "NSE:ICICIBANK"]);
+        assert_eq!(banks.owner.as_deref(), Some("desk"));
+
+        let mut reloaded = reloaded;
+        reloaded.delete("banks").unwrap();
+        assert!(WatchlistStore::load(&path).unwrap().get("banks").is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}